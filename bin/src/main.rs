@@ -6,8 +6,8 @@ use atm0s_sdn::{
     sans_io_runtime::backend::{PollBackend, PollingBackend},
     services::visualization::ConnectionInfo,
 };
+use atm0s_sdn::{MulticastTransport, SdnBuilder, SdnExtOut, SdnOwner};
 use atm0s_sdn::{NodeAddr, NodeId, SdnControllerUtils};
-use atm0s_sdn::{SdnBuilder, SdnExtOut, SdnOwner};
 use clap::{Parser, ValueEnum};
 use futures_util::{SinkExt, StreamExt};
 #[cfg(not(feature = "embed"))]
@@ -30,7 +30,7 @@ use serde::{Deserialize, Serialize};
 use std::time::Instant;
 use std::{
     collections::HashMap,
-    net::SocketAddr,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -101,6 +101,30 @@ struct Args {
     /// Collector node, which will have UI for monitoring network structure
     #[arg(env, long)]
     collector: bool,
+
+    /// Automatic neighbour selection
+    #[arg(env, long)]
+    topology: bool,
+
+    /// Cluster token for LAN multicast discovery, nodes with the same token connect automatically
+    #[arg(env, long)]
+    lan_token: Option<String>,
+
+    /// Multicast group for LAN discovery
+    #[arg(env, long, default_value = "239.255.97.48:45900")]
+    lan_group: SocketAddrV4,
+
+    /// Also listen tcp on udp port, which is used by other nodes when UDP is blocked
+    #[arg(env, long)]
+    tcp: bool,
+
+    /// Listen WebSocket on this port, which is used by browsers and clients behind http-only firewalls
+    #[arg(env, long)]
+    ws_port: Option<u16>,
+
+    /// Dial WebSocket addrs of seeds, for running as a leaf client behind http-only firewalls
+    #[arg(env, long)]
+    ws_client: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -263,7 +287,22 @@ async fn main() {
     let mut builder = SdnBuilder::<(), SC, SE, TC, TW, VisualNodeInfo>::new(args.node_id, &addrs, args.custom_addrs);
 
     builder.set_authorization(StaticKeyAuthorization::new(&args.password));
+    if args.tcp {
+        builder.enable_tcp();
+    }
+    if let Some(port) = args.ws_port {
+        builder.set_websocket_listen(SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port));
+    } else if args.ws_client {
+        builder.enable_websocket();
+    }
     builder.set_manual_discovery(args.local_tags, args.connect_tags);
+    if args.topology {
+        builder.set_topology(Default::default());
+    }
+    if let Some(token) = &args.lan_token {
+        let transport = MulticastTransport::new(args.lan_group, Ipv4Addr::UNSPECIFIED).expect("Should join LAN discovery group");
+        builder.set_lan_discovery(token, Arc::new(transport));
+    }
 
     if args.vpn {
         builder.enable_vpn();
@@ -339,6 +378,7 @@ async fn main() {
                     }
                 },
                 SdnExtOut::FeaturesEvent(_, _) => {}
+                SdnExtOut::RateLimited(..) => {}
             }
         }
         tokio::time::sleep(Duration::from_millis(10)).await;
@@ -89,6 +89,7 @@ fn main() {
                 }),
                 services: services.clone(),
                 history: history.clone(),
+                shaping: Default::default(),
                 #[cfg(feature = "vpn")]
                 vpn_tun_fd: None,
             },
@@ -108,6 +109,7 @@ fn main() {
                     controller: None,
                     services: services.clone(),
                     history: history.clone(),
+                    shaping: Default::default(),
                     #[cfg(feature = "vpn")]
                     vpn_tun_fd: None,
                 },
@@ -141,6 +141,8 @@ impl WorkerInner<RunnerOwner, ExtIn, ExtOut, ChannelId, Event, ICfg, SCfg> for R
                     worker_id: 0,
                     services: cfg.sdn.services.clone(),
                     history: cfg.sdn.history.clone(),
+                    shaping: cfg.sdn.shaping.clone(),
+                    send_budget: cfg.sdn.send_budget,
                 },
             }),
             sfu: SfuWorker::build(worker),
@@ -269,6 +271,7 @@ impl RunnerWorker {
                     }
                 }
                 SdnExtOut::ServicesEvent(..) => {}
+                SdnExtOut::RateLimited(..) => {}
             },
             SdnWorkerOutput::Net(out) => match out {
                 NetOutput::UdpPacket(remote, data) => self.queue.push_back(WorkerInnerOutput::Net(
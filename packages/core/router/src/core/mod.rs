@@ -5,8 +5,8 @@ mod router;
 mod table;
 
 pub use self::registry::{Registry, RegistryDelta, RegistryDestDelta, RegistrySync};
-pub use self::router::{Router, RouterDelta, RouterSync};
-pub use self::table::{DestDelta, Metric, Path, TableDelta, TableSync, BANDWIDTH_LIMIT};
+pub use self::router::{MetricExt, Router, RouterDelta, RouterSync, RouterSyncExt};
+pub use self::table::{DestDelta, Metric, Path, TableDelta, TableSync, BANDWIDTH_LIMIT, MTU_UNLIMITED};
 
 #[derive(PartialEq, Debug)]
 pub enum ServiceDestination {
@@ -20,6 +20,54 @@ pub type Layer = u8;
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RouterSync(pub RegistrySync, pub [Option<TableSync>; 4]);
 
+/// Metric fields which are not in the legacy RouterSync format
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
+pub struct MetricExt {
+    pub mtu: u16,
+    pub penalty: u16,
+}
+
+/// Extension of a RouterSync, which is only sent to neighbours that can parse it.
+/// It contains one MetricExt per metric, in the order of the registry then the tables.
+/// With it the sync can be bigger than a datagram, which is fine because those neighbours also reassemble fragments.
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct RouterSyncExt(pub Vec<MetricExt>);
+
+impl RouterSync {
+    fn metrics(&self) -> impl Iterator<Item = &Metric> {
+        let tables = self.1.iter().flatten().flat_map(|table| table.0.iter());
+        self.0 .0.iter().chain(tables).map(|(_, metric)| metric)
+    }
+
+    fn metrics_mut(&mut self) -> impl Iterator<Item = &mut Metric> {
+        let tables = self.1.iter_mut().flatten().flat_map(|table| table.0.iter_mut());
+        self.0 .0.iter_mut().chain(tables).map(|(_, metric)| metric)
+    }
+
+    pub fn ext(&self) -> RouterSyncExt {
+        RouterSyncExt(
+            self.metrics()
+                .map(|metric| MetricExt {
+                    mtu: metric.mtu,
+                    penalty: metric.penalty,
+                })
+                .collect(),
+        )
+    }
+
+    /// Apply the extension, returns false and keeps metrics unchanged if it doesn't match the sync
+    pub fn apply_ext(&mut self, ext: &RouterSyncExt) -> bool {
+        if self.metrics().count() != ext.0.len() {
+            return false;
+        }
+        for (metric, ext) in self.metrics_mut().zip(ext.0.iter()) {
+            metric.mtu = ext.mtu;
+            metric.penalty = ext.penalty;
+        }
+        true
+    }
+}
+
 pub struct Router {
     node_id: NodeId,
     tables: [Table; 4],
@@ -174,7 +222,7 @@ mod tests {
     use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
 
     use crate::core::registry::REGISTRY_LOCAL_BW;
-    use crate::core::{table::TableSync, Metric, Path, Router, RouterSync};
+    use crate::core::{table::TableSync, Metric, MetricExt, Path, Router, RouterSync, RouterSyncExt, MTU_UNLIMITED};
     use crate::core::{RegistrySync, ServiceDestination};
 
     #[test]
@@ -212,6 +260,28 @@ mod tests {
         (node_id, ConnId::from_out(0, node_id as u64), Router::new(node_id))
     }
 
+    #[test]
+    fn sync_ext_roundtrip() {
+        let mut sync = RouterSync(
+            RegistrySync(vec![(1, Metric::new(0, vec![1], 0).with_mtu(1400))]),
+            [Some(TableSync(vec![(3, Metric::new(0, vec![3], 0).with_mtu(1300).with_penalty(20))])), None, None, None],
+        );
+        let ext = sync.ext();
+        assert_eq!(ext, RouterSyncExt(vec![MetricExt { mtu: 1400, penalty: 0 }, MetricExt { mtu: 1300, penalty: 20 }]));
+
+        let buf = bincode::serialize(&sync).expect("Should serialize");
+        let mut decoded: RouterSync = bincode::deserialize(&buf).expect("Should deserialize");
+        assert_eq!(
+            decoded.ext(),
+            RouterSyncExt(vec![MetricExt { mtu: MTU_UNLIMITED, penalty: 0 }, MetricExt { mtu: MTU_UNLIMITED, penalty: 0 }])
+        );
+        assert!(!decoded.apply_ext(&RouterSyncExt(vec![MetricExt { mtu: 1400, penalty: 0 }])));
+        assert!(decoded.apply_ext(&ext));
+        assert_eq!(decoded.ext(), ext);
+        sync.0 .0.clear();
+        assert!(!sync.apply_ext(&ext));
+    }
+
     #[test]
     fn simple_relay_route() {
         // 2 - 1 - 3
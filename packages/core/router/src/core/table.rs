@@ -4,7 +4,7 @@ use atm0s_sdn_identity::{ConnId, NodeId, NodeIdType};
 use serde::{Deserialize, Serialize};
 
 pub use dest::{Dest, DestDelta};
-pub use metric::{Metric, BANDWIDTH_LIMIT};
+pub use metric::{Metric, BANDWIDTH_LIMIT, MTU_UNLIMITED};
 pub use path::Path;
 
 mod dest;
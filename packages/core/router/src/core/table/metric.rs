@@ -6,6 +6,11 @@ use serde::{Deserialize, Serialize};
 pub const BANDWIDTH_LIMIT: u32 = 10000; //10Mbps
 const BANDWIDTH_SCORE_PENALTY: u32 = 1000; //1s
 const HOP_PLUS_RTT: u16 = 10; //10ms each hops
+pub const MTU_UNLIMITED: u16 = u16::MAX; //used for local or when the link mtu is not known
+
+fn mtu_unlimited() -> u16 {
+    MTU_UNLIMITED
+}
 
 /// Concatenate two hops array, with condition that the last hop of `a` is the first hop of `b`, if not return None
 pub fn concat_hops(a: &[NodeId], b: &[NodeId]) -> Vec<NodeId> {
@@ -23,13 +28,35 @@ pub struct Metric {
     pub latency: u16,      //in milliseconds
     pub hops: Vec<NodeId>, //in hops, from 1 (direct)
     pub bandwidth: u32,    //in kbps
-                           // pub lost: f32,
-                           // pub jitter: u16,
+    // extension fields are synced in MetricExt for keeping the legacy format
+    #[serde(skip, default = "mtu_unlimited")]
+    pub mtu: u16, //in bytes, minimum of all links in path
+    #[serde(skip)]
+    pub penalty: u16, //in milliseconds, sum of transport penalties of all links in path, for preferring transports
+                      // pub lost: f32,
+                      // pub jitter: u16,
 }
 
 impl Metric {
     pub fn new(latency: u16, hops: Vec<NodeId>, bandwidth: u32) -> Self {
-        Metric { latency, hops, bandwidth }
+        Metric {
+            latency,
+            hops,
+            bandwidth,
+            mtu: MTU_UNLIMITED,
+            penalty: 0,
+        }
+    }
+
+    pub fn with_mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Extra cost which is added to the score, for example TCP links are less preferred than UDP links with same rtt
+    pub fn with_penalty(mut self, penalty: u16) -> Self {
+        self.penalty = penalty;
+        self
     }
 
     pub fn contain_in_hops(&self, node_id: NodeId) -> bool {
@@ -41,11 +68,13 @@ impl Metric {
             latency: self.latency + other.latency,
             hops: concat_hops(&self.hops, &other.hops),
             bandwidth: std::cmp::min(self.bandwidth, other.bandwidth),
+            mtu: std::cmp::min(self.mtu, other.mtu),
+            penalty: self.penalty.saturating_add(other.penalty),
         }
     }
 
     pub fn score(&self) -> u32 {
-        let based_score = self.latency as u32 + (self.hops.len() as u32 * HOP_PLUS_RTT as u32);
+        let based_score = self.latency as u32 + self.penalty as u32 + (self.hops.len() as u32 * HOP_PLUS_RTT as u32);
         if self.bandwidth >= BANDWIDTH_LIMIT {
             based_score
         } else {
@@ -130,6 +159,47 @@ mod tests {
         assert_eq!(m1.add(&m2), Metric::new(3, vec![1, 2, 3], 10000));
     }
 
+    #[test]
+    fn serialize_in_legacy_format() {
+        #[derive(serde::Serialize)]
+        struct LegacyMetric {
+            latency: u16,
+            hops: Vec<u32>,
+            bandwidth: u32,
+        }
+
+        let metric = Metric::new(1, vec![1, 2], 10000).with_mtu(1400).with_penalty(20);
+        let legacy = LegacyMetric {
+            latency: 1,
+            hops: vec![1, 2],
+            bandwidth: 10000,
+        };
+        let buf = bincode::serialize(&metric).expect("Should serialize");
+        assert_eq!(buf, bincode::serialize(&legacy).expect("Should serialize"));
+        let decoded: Metric = bincode::deserialize(&buf).expect("Should deserialize");
+        assert_eq!(decoded.mtu, super::MTU_UNLIMITED);
+        assert_eq!(decoded.penalty, 0);
+    }
+
+    #[test]
+    fn add_take_path_min_mtu() {
+        let m1 = Metric::new(1, vec![1, 2], 10000).with_mtu(1400);
+        let m2 = Metric::new(2, vec![3], 20000).with_mtu(1200);
+        let m3 = Metric::new(2, vec![3], 20000);
+        assert_eq!(m1.add(&m2).mtu, 1200);
+        assert_eq!(m1.add(&m3).mtu, 1400);
+    }
+
+    #[test]
+    fn penalty_affect_score() {
+        let udp = Metric::new(10, vec![1], 10000);
+        let tcp = Metric::new(5, vec![1], 10000).with_penalty(20);
+        assert!(udp < tcp);
+
+        let path = Metric::new(1, vec![1, 2], 10000).with_penalty(20);
+        assert_eq!(path.add(&tcp).penalty, 40);
+    }
+
     #[test]
     fn hops_has_affect_latancy() {
         let m1 = Metric::new(1, vec![1, 2], 10000);
@@ -51,10 +51,18 @@ pub enum RouteRule {
     ToNode(NodeId),
     ToService(u8),
     /// First is service id, second is the level, and third is seq of message
-    ToServices(u8, ServiceBroadcastLevel, u16),
+    ToServices(u8, ServiceBroadcastLevel, u32),
     ToKey(NodeId),
 }
 
+/// Flag in the seq of ToServices which marks a broadcast relayed by legacy nodes, only low 16 bits of the seq are valid then
+pub const LEGACY_BROADCAST_SEQ: u32 = 1 << 31;
+
+/// Next seq for sending ToServices, it wraps before the LEGACY_BROADCAST_SEQ flag
+pub fn next_broadcast_seq(seq: u32) -> u32 {
+    seq.wrapping_add(1) & !LEGACY_BROADCAST_SEQ
+}
+
 /// Determine the destination of an action/message
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum RouteAction<Remote> {
@@ -95,7 +103,7 @@ pub trait RouterTable<Remote> {
     fn path_to_service(&self, service_id: u8) -> RouteAction<Remote>;
     /// Determine the next action if we need broadcast to all node running a service.
     /// If relay_from is set, it should not sending back for avoiding loop
-    fn path_to_services(&self, service_id: u8, seq: u16, level: ServiceBroadcastLevel, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote>;
+    fn path_to_services(&self, service_id: u8, seq: u32, level: ServiceBroadcastLevel, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote>;
     /// Determine next action for incoming messages
     /// given the route rule and service id
     fn derive_action(&self, route: &RouteRule, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote> {
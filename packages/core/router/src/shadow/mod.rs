@@ -2,18 +2,42 @@ use std::{fmt::Debug, hash::Hash, sync::Arc};
 
 use atm0s_sdn_identity::{NodeId, NodeIdType};
 
-use crate::{RouteAction, RouterTable, ServiceBroadcastLevel};
+use crate::{RouteAction, RouterTable, ServiceBroadcastLevel, LEGACY_BROADCAST_SEQ};
 
 use self::{service::Service, table::ShadowTable};
 
 mod service;
 mod table;
 
+/// Key of a broadcast in ShadowRouterHistory. Legacy nodes truncate the seq to 16 bits, so a broadcast which arrives over
+/// both new and legacy paths is deduplicated with the low bits, while broadcasts over new paths only are still deduplicated with full seq.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BroadcastKey {
+    Full(u32),
+    /// Low bits of a full seq
+    Low(u16),
+    /// Seq of a broadcast which is relayed by legacy nodes
+    Legacy(u16),
+}
+
+impl BroadcastKey {
+    /// Returns keys for checking if the broadcast is already received, and keys for recording it
+    pub fn of(seq: u32) -> ([BroadcastKey; 2], [BroadcastKey; 2]) {
+        let low = seq as u16;
+        if seq & LEGACY_BROADCAST_SEQ != 0 {
+            ([Self::Legacy(low), Self::Low(low)], [Self::Legacy(low), Self::Low(low)])
+        } else {
+            ([Self::Full(seq), Self::Legacy(low)], [Self::Full(seq), Self::Low(low)])
+        }
+    }
+}
+
 #[mockall::automock]
 pub trait ShadowRouterHistory: Send + Sync {
     /// This method will check if the broadcast message is already received or not
-    /// If not received, it will cache the message and return true
-    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool;
+    /// If not received, it will cache the message and return true.
+    /// Implementations should use BroadcastKey for handling seqs which are relayed by legacy nodes
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u32) -> bool;
 
     /// For set current time ms
     fn set_ts(&self, now: u64);
@@ -125,7 +149,7 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
         }
     }
 
-    fn path_to_services(&self, service_id: u8, seq: u16, level: ServiceBroadcastLevel, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote> {
+    fn path_to_services(&self, service_id: u8, seq: u32, level: ServiceBroadcastLevel, source: Option<NodeId>, relay_from: Option<NodeId>) -> RouteAction<Remote> {
         if self.cached.already_received_broadcast(source, service_id, seq) {
             return RouteAction::Reject;
         }
@@ -144,9 +168,38 @@ impl<Remote: Debug + Hash + Eq + Clone + Copy> RouterTable<Remote> for ShadowRou
 mod tests {
     use std::sync::Arc;
 
-    use crate::{shadow::MockShadowRouterHistory, RouteAction, RouterTable, ServiceBroadcastLevel};
+    use std::collections::HashSet;
 
-    use super::{ShadowRouter, ShadowRouterDelta};
+    use crate::{shadow::MockShadowRouterHistory, RouteAction, RouterTable, ServiceBroadcastLevel, LEGACY_BROADCAST_SEQ};
+
+    use super::{BroadcastKey, ShadowRouter, ShadowRouterDelta};
+
+    fn already_received(history: &mut HashSet<BroadcastKey>, seq: u32) -> bool {
+        let (checks, records) = BroadcastKey::of(seq);
+        let received = checks.iter().any(|key| history.contains(key));
+        history.extend(records);
+        received
+    }
+
+    #[test]
+    fn broadcast_key_dedup_legacy_relayed() {
+        // full seq first, then the copy which is truncated by legacy relays
+        let mut history = HashSet::new();
+        assert!(!already_received(&mut history, 0x12345));
+        assert!(already_received(&mut history, LEGACY_BROADCAST_SEQ | 0x2345));
+
+        // legacy copy first
+        let mut history = HashSet::new();
+        assert!(!already_received(&mut history, LEGACY_BROADCAST_SEQ | 0x2345));
+        assert!(already_received(&mut history, 0x12345));
+        assert!(already_received(&mut history, LEGACY_BROADCAST_SEQ | 0x2345));
+
+        // without legacy relays, seqs with same low bits are different messages
+        let mut history = HashSet::new();
+        assert!(!already_received(&mut history, 0x2345));
+        assert!(!already_received(&mut history, 0x12345));
+        assert!(already_received(&mut history, 0x12345));
+    }
 
     #[test]
     fn should_route_to_next_service_local() {
@@ -0,0 +1,144 @@
+use std::net::IpAddr;
+
+use atm0s_sdn_identity::{NodeId, NodeIdType};
+use serde::{Deserialize, Serialize};
+
+use super::NeighboursConnectError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AccessRule {
+    Node(NodeId),
+    /// Nodes in a zone, which is geo1 and geo2 of NodeId like `NodeId::build2`
+    Zone(u16),
+    /// Remote ip in range, second param is prefix length like CIDR notation
+    IpRange(IpAddr, u8),
+}
+
+impl AccessRule {
+    pub fn matches(&self, node: NodeId, ip: Option<IpAddr>) -> bool {
+        match self {
+            AccessRule::Node(id) => *id == node,
+            AccessRule::Zone(zone) => ((node.geo1() as u16) << 8 | node.geo2() as u16) == *zone,
+            AccessRule::IpRange(range, prefix) => ip.is_some_and(|ip| ip_in_range(ip, *range, *prefix)),
+        }
+    }
+
+    /// Ip rules can't be decided without the remote ip
+    fn decidable(&self, ip: Option<IpAddr>) -> bool {
+        ip.is_some() || !matches!(self, AccessRule::IpRange(..))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccessControl {
+    Allow(AccessRule),
+    Deny(AccessRule),
+    /// Remove the rule from both allow and deny lists
+    Remove(AccessRule),
+}
+
+/// Neighbour access list, deny rules take precedence. When allow list is not empty, only matched nodes are accepted.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    allow: Vec<AccessRule>,
+    deny: Vec<AccessRule>,
+}
+
+impl AccessList {
+    pub fn apply(&mut self, control: AccessControl) {
+        match control {
+            AccessControl::Allow(rule) => {
+                self.deny.retain(|r| *r != rule);
+                if !self.allow.contains(&rule) {
+                    self.allow.push(rule);
+                }
+            }
+            AccessControl::Deny(rule) => {
+                self.allow.retain(|r| *r != rule);
+                if !self.deny.contains(&rule) {
+                    self.deny.push(rule);
+                }
+            }
+            AccessControl::Remove(rule) => {
+                self.allow.retain(|r| *r != rule);
+                self.deny.retain(|r| *r != rule);
+            }
+        }
+    }
+
+    /// Check a node with optional remote ip, ip rules are skipped if ip is unknown.
+    /// Without ip, an allow list which only has ip rules accepts all nodes, they are checked again when the ip is known.
+    pub fn check(&self, node: NodeId, ip: Option<IpAddr>) -> Result<(), NeighboursConnectError> {
+        if self.deny.iter().any(|r| r.matches(node, ip)) {
+            return Err(NeighboursConnectError::Denied);
+        }
+        let mut allow = self.allow.iter().filter(|r| r.decidable(ip)).peekable();
+        if allow.peek().is_some() && !allow.any(|r| r.matches(node, ip)) {
+            return Err(NeighboursConnectError::NotAllowed);
+        }
+        Ok(())
+    }
+}
+
+fn ip_in_range(ip: IpAddr, range: IpAddr, prefix: u8) -> bool {
+    match (ip, range) {
+        (IpAddr::V4(ip), IpAddr::V4(range)) => {
+            let mask = u32::MAX.checked_shl(32 - prefix.min(32) as u32).unwrap_or(0);
+            u32::from(ip) & mask == u32::from(range) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(range)) => {
+            let mask = u128::MAX.checked_shl(128 - prefix.min(128) as u32).unwrap_or(0);
+            u128::from(ip) & mask == u128::from(range) & mask
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use atm0s_sdn_identity::{NodeId, NodeIdType};
+
+    use crate::base::NeighboursConnectError;
+
+    use super::{AccessControl, AccessList, AccessRule};
+
+    #[test]
+    fn deny_take_precedence() {
+        let mut list = AccessList::default();
+        let node = NodeId::build(1, 2, 3, 4);
+        assert_eq!(list.check(node, None), Ok(()));
+
+        list.apply(AccessControl::Allow(AccessRule::Zone(0x0102)));
+        assert_eq!(list.check(node, None), Ok(()));
+        assert_eq!(list.check(NodeId::build(1, 3, 3, 4), None), Err(NeighboursConnectError::NotAllowed));
+
+        list.apply(AccessControl::Deny(AccessRule::Node(node)));
+        assert_eq!(list.check(node, None), Err(NeighboursConnectError::Denied));
+        assert_eq!(list.check(NodeId::build(1, 2, 3, 5), None), Ok(()));
+
+        list.apply(AccessControl::Remove(AccessRule::Node(node)));
+        assert_eq!(list.check(node, None), Ok(()));
+    }
+
+    #[test]
+    fn ip_range() {
+        let mut list = AccessList::default();
+        list.apply(AccessControl::Deny(AccessRule::IpRange("10.0.0.0".parse().expect("Should parse"), 8)));
+        assert_eq!(list.check(1, Some("10.1.2.3".parse().expect("Should parse"))), Err(NeighboursConnectError::Denied));
+        assert_eq!(list.check(1, Some("11.1.2.3".parse().expect("Should parse"))), Ok(()));
+        assert_eq!(list.check(1, Some("::1".parse().expect("Should parse"))), Ok(()));
+        assert_eq!(list.check(1, None), Ok(()));
+
+        list.apply(AccessControl::Allow(AccessRule::IpRange("fd00::".parse().expect("Should parse"), 8)));
+        assert_eq!(list.check(1, Some("fd12::1".parse().expect("Should parse"))), Ok(()));
+        assert_eq!(list.check(1, Some("fe80::1".parse().expect("Should parse"))), Err(NeighboursConnectError::NotAllowed));
+        // unknown ip is decided later
+        assert_eq!(list.check(1, None), Ok(()));
+
+        // node rules are still checked without ip
+        list.apply(AccessControl::Allow(AccessRule::Node(2)));
+        assert_eq!(list.check(1, None), Err(NeighboursConnectError::NotAllowed));
+        assert_eq!(list.check(2, None), Ok(()));
+        assert_eq!(list.check(1, Some("fd12::1".parse().expect("Should parse"))), Ok(()));
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use atm0s_sdn_identity::NodeId;
 use bincode::Options;
 use serde::{Deserialize, Serialize};
@@ -6,12 +8,35 @@ use super::Authorization;
 
 const MSG_TIMEOUT_MS: u64 = 10000;
 
+/// Remote node can parse broadcast route with 32 bits seq
+pub const CAPABILITY_BROADCAST_SEQ_EXT: u32 = 1;
+/// Remote node can reassemble fragmented datagrams
+pub const CAPABILITY_FRAGMENT: u32 = 1 << 1;
+/// Remote node can parse header extension with priority class
+pub const CAPABILITY_QOS: u32 = 1 << 2;
+/// Remote node accepts ConnectCookie, it is advertised in ConnectRetry because it is needed before connected
+pub const CAPABILITY_COOKIE: u32 = 1 << 3;
+/// Remote node can parse Observed
+pub const CAPABILITY_OBSERVED: u32 = 1 << 4;
+/// Remote node can parse RouterSyncExt which is appended to RouterSync
+pub const CAPABILITY_METRIC_EXT: u32 = 1 << 5;
+/// All capabilities which this node supports, it is exchanged with neighbours after connected
+pub const LOCAL_CAPABILITIES: u32 = CAPABILITY_BROADCAST_SEQ_EXT | CAPABILITY_FRAGMENT | CAPABILITY_QOS | CAPABILITY_COOKIE | CAPABILITY_OBSERVED | CAPABILITY_METRIC_EXT;
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NeighboursConnectError {
     AlreadyConnected,
     InvalidSignature,
     InvalidData,
     InvalidState,
+    /// Rejected by deny list
+    Denied,
+    /// Not matched by allow list
+    NotAllowed,
+    /// Responder reached max connections
+    Overloaded,
+    /// Remote did not answer in time
+    Timeout,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -22,12 +47,76 @@ pub enum NeighboursDisconnectReason {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub enum NeighboursControlCmds {
-    ConnectRequest { to: NodeId, session: u64, handshake: Vec<u8> },
-    ConnectResponse { session: u64, result: Result<Vec<u8>, NeighboursConnectError> },
-    Ping { session: u64, seq: u64, sent_ms: u64 },
-    Pong { session: u64, seq: u64, sent_ms: u64 },
-    DisconnectRequest { session: u64, reason: NeighboursDisconnectReason },
-    DisconnectResponse { session: u64 },
+    ConnectRequest {
+        to: NodeId,
+        session: u64,
+        handshake: Vec<u8>,
+    },
+    ConnectResponse {
+        session: u64,
+        result: Result<Vec<u8>, NeighboursConnectError>,
+    },
+    Ping {
+        session: u64,
+        seq: u64,
+        sent_ms: u64,
+    },
+    Pong {
+        session: u64,
+        seq: u64,
+        sent_ms: u64,
+    },
+    DisconnectRequest {
+        session: u64,
+        reason: NeighboursDisconnectReason,
+    },
+    DisconnectResponse {
+        session: u64,
+    },
+    /// Sent after connected for negotiating optional features, old nodes will ignore it because cannot parse.
+    /// `received` is true if the sender already got the capabilities of the receiver.
+    Capabilities {
+        session: u64,
+        caps: u32,
+        received: bool,
+    },
+    /// Ping with padding for probing path MTU, `size` is the size of whole datagram
+    PaddedPing {
+        session: u64,
+        size: u16,
+        padding: Vec<u8>,
+    },
+    PaddedPong {
+        session: u64,
+        size: u16,
+    },
+    /// Ask a connected neighbour to introduce us with the target node, which is used for NAT hole punching
+    PunchRequest {
+        target: NodeId,
+    },
+    /// Sent by the relay to both sides with the peer addresses which it observed, both sides will connect simultaneously
+    PunchIntroduce {
+        peer: NodeId,
+        addrs: Vec<SocketAddr>,
+    },
+    /// Stateless challenge from a loaded responder, `caps` must contain CAPABILITY_COOKIE for the requester to answer it.
+    /// Old requesters can't parse it and keep retrying ConnectRequest, which is rejected until the responder is not loaded.
+    ConnectRetry {
+        session: u64,
+        cookie: u64,
+        caps: u32,
+    },
+    /// Echo of the cookie from ConnectRetry, it is sent before resending ConnectRequest of the same session
+    ConnectCookie {
+        session: u64,
+        cookie: u64,
+    },
+    /// Address of the receiver which is seen by the sender, used for reflexive address discovery.
+    /// It is only sent to neighbours which have CAPABILITY_OBSERVED.
+    Observed {
+        session: u64,
+        addr: SocketAddr,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -53,6 +142,40 @@ impl NeighboursControl {
         let signature = auth.sign(&cmd);
         Self { from, cmd, signature }
     }
+
+    /// Build a PaddedPing which is serialized to a datagram with `size` bytes, padding is adjusted because of varint encoding
+    pub fn build_padded_ping(now: u64, from: NodeId, session: u64, size: u16, auth: &dyn Authorization) -> Self {
+        let mut padding = 0;
+        let mut control = Self::build(now, from, NeighboursControlCmds::PaddedPing { session, size, padding: vec![] }, auth);
+        for _ in 0..4 {
+            let current = control.datagram_size();
+            if current == size as usize {
+                break;
+            }
+            padding = (padding + size as usize).saturating_sub(current);
+            control = Self::build(
+                now,
+                from,
+                NeighboursControlCmds::PaddedPing {
+                    session,
+                    size,
+                    padding: vec![0; padding],
+                },
+                auth,
+            );
+        }
+        control
+    }
+
+    /// Size of the datagram after serialized, including the first byte
+    pub fn datagram_size(&self) -> usize {
+        1 + bincode::DefaultOptions::new().serialized_size(self).unwrap_or(0) as usize
+    }
+
+    /// Check if the first byte of a datagram is a NeighboursControl
+    pub fn is_control(first_byte: u8) -> bool {
+        first_byte == 255
+    }
 }
 
 impl TryFrom<&[u8]> for NeighboursControl {
@@ -96,4 +219,84 @@ mod tests {
         assert_eq!(control.validate(0, &auth), Ok(cmd));
         assert_eq!(control.validate(MSG_TIMEOUT_MS + 1, &auth), Err(()));
     }
+
+    /// Commands of nodes which are released before capabilities, they must be decoded as the same commands
+    #[derive(Serialize)]
+    enum LegacyControlCmds {
+        ConnectRequest { to: NodeId, session: u64, handshake: Vec<u8> },
+        ConnectResponse { session: u64, result: Result<Vec<u8>, NeighboursConnectError> },
+        Ping { session: u64, seq: u64, sent_ms: u64 },
+        Pong { session: u64, seq: u64, sent_ms: u64 },
+        DisconnectRequest { session: u64, reason: NeighboursDisconnectReason },
+        DisconnectResponse { session: u64 },
+    }
+
+    #[test]
+    fn test_decode_legacy_cmds() {
+        let auth = StaticKeyAuthorization::new("demo_key");
+        let cases = vec![
+            (
+                LegacyControlCmds::ConnectRequest {
+                    to: 2,
+                    session: 1000,
+                    handshake: vec![1, 2, 3],
+                },
+                NeighboursControlCmds::ConnectRequest {
+                    to: 2,
+                    session: 1000,
+                    handshake: vec![1, 2, 3],
+                },
+            ),
+            (
+                LegacyControlCmds::Ping { session: 1000, seq: 1, sent_ms: 100 },
+                NeighboursControlCmds::Ping { session: 1000, seq: 1, sent_ms: 100 },
+            ),
+            (
+                LegacyControlCmds::ConnectResponse {
+                    session: 1000,
+                    result: Err(NeighboursConnectError::InvalidState),
+                },
+                NeighboursControlCmds::ConnectResponse {
+                    session: 1000,
+                    result: Err(NeighboursConnectError::InvalidState),
+                },
+            ),
+            (
+                LegacyControlCmds::Pong { session: 1000, seq: 1, sent_ms: 100 },
+                NeighboursControlCmds::Pong { session: 1000, seq: 1, sent_ms: 100 },
+            ),
+            (
+                LegacyControlCmds::DisconnectRequest {
+                    session: 1000,
+                    reason: NeighboursDisconnectReason::Shutdown,
+                },
+                NeighboursControlCmds::DisconnectRequest {
+                    session: 1000,
+                    reason: NeighboursDisconnectReason::Shutdown,
+                },
+            ),
+            (LegacyControlCmds::DisconnectResponse { session: 1000 }, NeighboursControlCmds::DisconnectResponse { session: 1000 }),
+        ];
+
+        for (legacy, cmd) in cases {
+            let legacy_buf = bincode::DefaultOptions::new().with_limit(1499).serialize(&(100u64, legacy)).expect("Should serialize");
+            let signature = auth.sign(&legacy_buf);
+            let control = NeighboursControl { from: 1, cmd: legacy_buf, signature };
+            assert_eq!(control.validate(100, &auth), Ok(cmd.clone()));
+            // new nodes send the same bytes, so legacy nodes can decode them
+            assert_eq!(NeighboursControl::build(100, 1, cmd, &auth).cmd, control.cmd);
+        }
+    }
+
+    #[test]
+    fn test_padded_ping_size() {
+        let auth = StaticKeyAuthorization::new("demo_key");
+        for size in [200, 1200, 1280, 1400, 1500] {
+            let control = NeighboursControl::build_padded_ping(1_700_000_000_000, 1, u64::MAX, size, &auth);
+            let buf: Vec<u8> = (&control).try_into().expect("Should serialize");
+            assert_eq!(buf.len(), size as usize);
+            let parsed = NeighboursControl::try_from(buf.as_slice()).expect("Should parse");
+            assert!(matches!(parsed.validate(1_700_000_000_000, &auth), Ok(NeighboursControlCmds::PaddedPing { size: s, .. }) if s == size));
+        }
+    }
 }
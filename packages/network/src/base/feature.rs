@@ -1,10 +1,12 @@
+use std::{collections::HashMap, hash::Hash};
+
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use atm0s_sdn_router::{shadow::ShadowRouter, RouteRule};
 use sans_io_runtime::TaskSwitcherChild;
 
 use crate::data_plane::NetPair;
 
-use super::{Buffer, ConnectionCtx, ConnectionEvent, ServiceId, TransportMsgHeader, Ttl};
+use super::{AccessControl, Buffer, ConnectionCtx, ConnectionEvent, MsgPriority, ServiceId, TransportMsgHeader, Ttl};
 
 #[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct NetIncomingMeta {
@@ -12,11 +14,22 @@ pub struct NetIncomingMeta {
     pub ttl: Ttl,
     pub meta: u8,
     pub secure: bool,
+    /// Congestion is experienced on the path
+    pub congested: bool,
+    /// The remote echoes congestion which is experienced by our sent msgs
+    pub congestion_echo: bool,
 }
 
 impl NetIncomingMeta {
     pub fn new(source: Option<NodeId>, ttl: Ttl, meta: u8, secure: bool) -> Self {
-        Self { source, ttl, meta, secure }
+        Self {
+            source,
+            ttl,
+            meta,
+            secure,
+            congested: false,
+            congestion_echo: false,
+        }
     }
 }
 
@@ -27,6 +40,8 @@ impl From<&TransportMsgHeader> for NetIncomingMeta {
             ttl: Ttl(value.ttl),
             meta: value.meta,
             secure: value.encrypt,
+            congested: value.congested,
+            congestion_echo: value.congestion_echo,
         }
     }
 }
@@ -37,11 +52,20 @@ pub struct NetOutgoingMeta {
     pub ttl: Ttl,
     pub meta: u8,
     pub secure: bool,
+    pub priority: MsgPriority,
+    pub congestion_echo: bool,
 }
 
 impl NetOutgoingMeta {
     pub fn new(source: bool, ttl: Ttl, meta: u8, secure: bool) -> Self {
-        Self { source, ttl, meta, secure }
+        Self {
+            source,
+            ttl,
+            meta,
+            secure,
+            priority: MsgPriority::Normal,
+            congestion_echo: false,
+        }
     }
 
     pub fn secure() -> Self {
@@ -50,9 +74,21 @@ impl NetOutgoingMeta {
             ttl: Ttl::default(),
             meta: 0,
             secure: true,
+            priority: MsgPriority::Normal,
+            congestion_echo: false,
         }
     }
 
+    pub fn set_priority(mut self, priority: MsgPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn set_congestion_echo(mut self, echo: bool) -> Self {
+        self.congestion_echo = echo;
+        self
+    }
+
     pub fn to_header(&self, feature: u8, rule: RouteRule, node_id: NodeId) -> TransportMsgHeader {
         TransportMsgHeader::build(feature, self.meta, rule)
             .set_ttl(*self.ttl)
@@ -62,6 +98,8 @@ impl NetOutgoingMeta {
                 None
             })
             .set_encrypt(self.secure)
+            .set_priority(self.priority)
+            .set_congestion_echo(self.congestion_echo)
     }
 
     pub fn to_incoming(&self, node_id: NodeId) -> NetIncomingMeta {
@@ -74,10 +112,42 @@ impl NetOutgoingMeta {
             ttl: self.ttl,
             meta: self.meta,
             secure: self.secure,
+            congested: false,
+            congestion_echo: self.congestion_echo,
         }
     }
 }
 
+const CONGESTION_ECHO_INTERVAL_MS: u64 = 100;
+
+/// Limit how often a feature echoes congestion back to each remote endpoint
+pub struct CongestionEchoLimiter<K> {
+    last_echo: HashMap<K, u64>,
+}
+
+impl<K> Default for CongestionEchoLimiter<K> {
+    fn default() -> Self {
+        Self { last_echo: HashMap::new() }
+    }
+}
+
+impl<K: Hash + Eq> CongestionEchoLimiter<K> {
+    /// Return true if we should echo congestion to the key now
+    pub fn should_echo(&mut self, now_ms: u64, key: K) -> bool {
+        match self.last_echo.get(&key) {
+            Some(last) if now_ms < last + CONGESTION_ECHO_INTERVAL_MS => false,
+            _ => {
+                self.last_echo.insert(key, now_ms);
+                true
+            }
+        }
+    }
+
+    pub fn on_tick(&mut self, now_ms: u64) {
+        self.last_echo.retain(|_, last| now_ms < *last + CONGESTION_ECHO_INTERVAL_MS);
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, Eq, PartialEq)]
 pub enum FeatureControlActor<UserData> {
     Controller(UserData),
@@ -102,6 +172,8 @@ impl<UserData> FeatureControlActor<UserData> {
 pub enum FeatureSharedInput {
     Tick(u64),
     Connection(ConnectionEvent),
+    /// Node is draining before shutdown, features should stop attracting traffic to it
+    Drain,
 }
 
 #[derive(Debug, Clone)]
@@ -121,6 +193,7 @@ pub enum FeatureOutput<UserData, Event, ToWorker> {
     SendRoute(RouteRule, NetOutgoingMeta, Buffer),
     NeighboursConnectTo(NodeAddr),
     NeighboursDisconnectFrom(NodeId),
+    NeighboursAccess(AccessControl),
 }
 
 impl<UserData, Event, ToWorker> FeatureOutput<UserData, Event, ToWorker> {
@@ -137,6 +210,7 @@ impl<UserData, Event, ToWorker> FeatureOutput<UserData, Event, ToWorker> {
             FeatureOutput::SendRoute(rule, ttl, buf) => FeatureOutput::SendRoute(rule, ttl, buf),
             FeatureOutput::NeighboursConnectTo(addr) => FeatureOutput::NeighboursConnectTo(addr),
             FeatureOutput::NeighboursDisconnectFrom(id) => FeatureOutput::NeighboursDisconnectFrom(id),
+            FeatureOutput::NeighboursAccess(control) => FeatureOutput::NeighboursAccess(control),
         }
     }
 }
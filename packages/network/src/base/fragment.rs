@@ -0,0 +1,132 @@
+use super::Buffer;
+
+/// First byte of a fragment, it cannot conflict with TransportMsgHeader (version 0 => first byte < 64) or NeighboursControl (255)
+pub const FRAGMENT_MAGIC: u8 = 254;
+pub const FRAGMENT_HEADER_SIZE: usize = 7;
+/// Default max size of a datagram, receive buffers inside runtime are 1500 bytes so we keep some space for tunnels
+pub const DEFAULT_MTU: u16 = 1400;
+pub const MAX_FRAGMENTS: usize = u8::MAX as usize;
+
+/// FragmentHeader is prepended to each part of a datagram which is bigger than the connection MTU.
+/// Fragmentation is done after encryption, so each fragment only contains a raw chunk of the original datagram.
+///
+/// The header has the following format:
+///
+/// - Magic: 8 bits, always FRAGMENT_MAGIC
+/// - Msg Id: 32 bits, unique per sender for each fragmented datagram
+/// - Index: 8 bits, index of this fragment
+/// - Count: 8 bits, total fragments of the datagram
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub msg_id: u32,
+    pub index: u8,
+    pub count: u8,
+}
+
+impl FragmentHeader {
+    /// Check if the first byte of a datagram is a fragment
+    pub fn is_fragment(first_byte: u8) -> bool {
+        first_byte == FRAGMENT_MAGIC
+    }
+
+    /// Serializes the header into the given output buffer, returns None if the buffer is too small
+    pub fn to_bytes(&self, output: &mut [u8]) -> Option<usize> {
+        if output.len() < FRAGMENT_HEADER_SIZE {
+            return None;
+        }
+        output[0] = FRAGMENT_MAGIC;
+        output[1..5].copy_from_slice(&self.msg_id.to_be_bytes());
+        output[5] = self.index;
+        output[6] = self.count;
+        Some(FRAGMENT_HEADER_SIZE)
+    }
+
+    /// Split a datagram into fragments, each fragment including header is not bigger than `mtu`.
+    ///
+    /// # Returns
+    ///
+    /// None if the datagram is too big even after splitting or the mtu is too small
+    pub fn split(msg_id: u32, data: &[u8], mtu: usize) -> Option<Vec<Buffer>> {
+        let chunk_size = mtu.checked_sub(FRAGMENT_HEADER_SIZE).filter(|s| *s > 0)?;
+        let count = data.len().div_ceil(chunk_size);
+        if count > MAX_FRAGMENTS {
+            return None;
+        }
+        let fragments = data
+            .chunks(chunk_size)
+            .enumerate()
+            .map(|(index, chunk)| {
+                let header = FragmentHeader {
+                    msg_id,
+                    index: index as u8,
+                    count: count as u8,
+                };
+                let mut buf = Buffer::build(chunk, FRAGMENT_HEADER_SIZE, 0);
+                header.to_bytes(buf.front_mut(FRAGMENT_HEADER_SIZE)).expect("Should serialize fragment header");
+                buf.move_front_left(FRAGMENT_HEADER_SIZE).expect("Should have space for fragment header");
+                buf
+            })
+            .collect();
+        Some(fragments)
+    }
+}
+
+impl TryFrom<&[u8]> for FragmentHeader {
+    type Error = ();
+
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() < FRAGMENT_HEADER_SIZE || value[0] != FRAGMENT_MAGIC {
+            return Err(());
+        }
+        let header = Self {
+            msg_id: u32::from_be_bytes([value[1], value[2], value[3], value[4]]),
+            index: value[5],
+            count: value[6],
+        };
+        if header.count == 0 || header.index >= header.count {
+            return Err(());
+        }
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_and_parse() {
+        let data = (0..100).collect::<Vec<u8>>();
+        let fragments = FragmentHeader::split(1000, &data, 50).expect("Should split");
+        assert_eq!(fragments.len(), 3);
+        let mut joined = vec![];
+        for (index, frag) in fragments.iter().enumerate() {
+            assert!(frag.len() <= 50);
+            assert!(FragmentHeader::is_fragment(frag[0]));
+            let header = FragmentHeader::try_from(&frag[..]).expect("Should parse");
+            assert_eq!(
+                header,
+                FragmentHeader {
+                    msg_id: 1000,
+                    index: index as u8,
+                    count: 3
+                }
+            );
+            joined.extend_from_slice(&frag[FRAGMENT_HEADER_SIZE..]);
+        }
+        assert_eq!(joined, data);
+    }
+
+    #[test]
+    fn split_too_big() {
+        assert_eq!(FragmentHeader::split(1, &[0; 1000], 7), None);
+        assert_eq!(FragmentHeader::split(1, &vec![0; 256 * 10], 17), None);
+    }
+
+    #[test]
+    fn parse_invalid() {
+        assert_eq!(FragmentHeader::try_from(&[FRAGMENT_MAGIC, 0, 0, 0, 1][..]), Err(()));
+        assert_eq!(FragmentHeader::try_from(&[FRAGMENT_MAGIC, 0, 0, 0, 1, 2, 2][..]), Err(()));
+        assert_eq!(FragmentHeader::try_from(&[0, 0, 0, 0, 1, 0, 2][..]), Err(()));
+    }
+}
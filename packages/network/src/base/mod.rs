@@ -1,13 +1,19 @@
+mod access;
 mod control;
 mod feature;
+mod fragment;
 mod msg;
+mod resolver;
 mod secure;
 mod service;
 
+pub use access::*;
 use atm0s_sdn_identity::{ConnId, NodeId};
 pub use control::*;
 pub use feature::*;
+pub use fragment::*;
 pub use msg::*;
+pub use resolver::*;
 pub use sans_io_runtime::Buffer;
 pub use secure::*;
 pub use service::*;
@@ -21,14 +27,41 @@ pub struct ConnectionCtx {
     pub pair: NetPair,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct ConnectionStats {
     pub rtt_ms: u32,
+    /// discovered path mtu, this is DEFAULT_MTU before probing finished
+    pub mtu: u16,
+    /// percent of last pings which are not answered in time
+    pub loss_percent: u8,
+    /// total bytes sent over the connection, including relayed data
+    pub sent_bytes: u64,
+    /// total bytes received over the connection, including relayed data
+    pub recv_bytes: u64,
+    /// capabilities of the remote node, 0 before they are negotiated
+    pub caps: u32,
+}
+
+/// Why a connection is closed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Closed by local side, like DisconnectFrom, access list, pruning or shutdown
+    Local,
+    /// Remote requested to close with its reason
+    Remote(NeighboursDisconnectReason),
+    /// Remote did not answer pings in time
+    Timeout,
 }
 
 #[derive(Debug, Clone)]
 pub enum ConnectionEvent {
     Connected(ConnectionCtx, SecureContext),
     Stats(ConnectionCtx, ConnectionStats),
-    Disconnected(ConnectionCtx),
+    Disconnected(ConnectionCtx, DisconnectReason),
+    /// Connection is selected as primary of the node, when the node is connected or the previous primary is lost or pruned
+    Primary(ConnectionCtx),
+    /// Connecting to the node is failed or rejected, like by access list
+    ConnectError(NodeId, NeighboursConnectError),
+    /// Persistent node is not reconnected anymore because all reconnect attempts failed
+    GaveUp(NodeId),
 }
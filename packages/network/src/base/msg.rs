@@ -1,5 +1,5 @@
 use atm0s_sdn_identity::NodeId;
-use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel, LEGACY_BROADCAST_SEQ};
 use atm0s_sdn_utils::simple_pub_type;
 use bytes::BufMut;
 use sans_io_runtime::Buffer;
@@ -13,6 +13,34 @@ const ROUTE_RULE_TO_NODE: u8 = 1;
 const ROUTE_RULE_TO_SERVICE: u8 = 2;
 const ROUTE_RULE_TO_SERVICES: u8 = 3;
 const ROUTE_RULE_TO_KEY: u8 = 4;
+const ROUTE_RULE_TO_SERVICES_EXT: u8 = 5;
+const EXT_BIT: u8 = 1 << 3;
+const EXT_CE_BIT: u8 = 1 << 5;
+const EXT_ECE_BIT: u8 = 1 << 4;
+const ROUTE_TYPE_MASK: u8 = 7;
+
+pub const MSG_PRIORITY_CLASSES: usize = 4;
+
+/// Priority class of a message, higher class is sent first and dropped last inside send queues.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MsgPriority {
+    Bulk = 0,
+    #[default]
+    Normal = 1,
+    Interactive = 2,
+    Control = 3,
+}
+
+impl From<u8> for MsgPriority {
+    fn from(value: u8) -> Self {
+        match value & 3 {
+            0 => MsgPriority::Bulk,
+            1 => MsgPriority::Normal,
+            2 => MsgPriority::Interactive,
+            _ => MsgPriority::Control,
+        }
+    }
+}
 
 simple_pub_type!(Ttl, u8);
 
@@ -35,8 +63,10 @@ pub enum TransportMsgHeaderError {
 ///     0                   1                   2                   3
 ///     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-///    |V=0|E|N|   R   |      TTL      |  Feature       |     Meta     |
+///    |V=0|E|N|X|  R  |      TTL      |  Feature       |     Meta     |
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+///    |P=1|C|Z| Rsv.  |  Extension (Opt, only if X is set)
+///    +-+-+-+-+-+-+-+-+
 ///    |                         Route destination (Opt)               |
 ///    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
 ///    |                         FromNodeId (Opt)                      |
@@ -48,21 +78,33 @@ pub enum TransportMsgHeaderError {
 /// - Version (V) : 2 bits (now is 0)
 /// - Encrypt (E): 1 bits, If this bit is set, this msg should be encrypted
 /// - From Node (N)    : 1 bits, If this bit is set, from node_id will occupy 32 bits in header
-/// - Route Type (R): 4 bits
+/// - Extension (X): 1 bits, If this bit is set, a extension byte is placed after Meta. Old nodes will reject it as invalid route type
+/// - Route Type (R): 3 bits
 ///
 ///     - 0: Direct : which node received this msg will handle it, no route destination
 ///     - 1: ToNode : which node received this msg will route it to node_id
 ///     - 2: ToService : which node received this msg will route it to service meta
-///     - 3: ToKey : which node received this msg will route it to key
+///     - 3: ToServices : legacy broadcast to services with 16 bits seq, only used for sending to old nodes
+///     - 4: ToKey : which node received this msg will route it to key
+///     - 5: ToServices : broadcast to services with 32 bits seq
 ///     - .. Not used
 ///
 /// - Ttl (TTL): 8 bits
 /// - Feature Id: 8 bits
 ///
-/// - Route destination (Route Destination): 32 bits (if R is not Direct)
+/// - Extension: 8 bits (optional if X bit is set)
+///
+///     - Priority (P): 2 bits, MsgPriority of the message, default is Normal (1) if the extension is not present
+///     - Congestion Experienced (C): 1 bits, set by relays when the outgoing queue of next link is backing up
+///     - Congestion Echo (Z): 1 bits, set by destination when it echoes congestion back to the source
+///     - Reserved: 4 bits
+///
+/// - Route destination (Route Destination): 32 bits (if R is not Direct), 64 bits with extended ToServices
 ///
 ///     - If route type is ToNode, this field is 32bit node_id
 ///     - If route type is ToService, this field is 32bit service meta
+///     - If route type is legacy ToServices, this field is 8bit service, 8bit level and 16bit seq
+///     - If route type is ToServices, this field is 8bit service, 8bit level, 16bit reserved and 32bit seq
 ///     - If route type is ToKey, this field is 32bit key
 ///
 /// - From Node Id: 32 bits (optional if N bit is set)
@@ -78,6 +120,11 @@ pub struct TransportMsgHeader {
     pub meta: u8,
     /// Which can be anonymous or specific node
     pub from_node: Option<NodeId>,
+    pub priority: MsgPriority,
+    /// Congestion experienced on the path, marked by relays
+    pub congested: bool,
+    /// This msg is echo of congestion from destination
+    pub congestion_echo: bool,
 }
 
 impl Default for TransportMsgHeader {
@@ -101,6 +148,9 @@ impl TransportMsgHeader {
             feature: 0,
             meta: 0,
             from_node: None,
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         }
     }
 
@@ -113,6 +163,9 @@ impl TransportMsgHeader {
             feature,
             meta,
             from_node: None,
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         }
     }
 
@@ -152,6 +205,36 @@ impl TransportMsgHeader {
         self
     }
 
+    /// Set priority
+    pub fn set_priority(mut self, priority: MsgPriority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    /// Set congestion echo flag
+    pub fn set_congestion_echo(mut self, echo: bool) -> Self {
+        self.congestion_echo = echo;
+        self
+    }
+
+    fn has_ext(&self) -> bool {
+        self.priority != MsgPriority::Normal || self.congested || self.congestion_echo
+    }
+
+    fn ext_byte(&self) -> u8 {
+        let ce = if self.congested {
+            EXT_CE_BIT
+        } else {
+            0
+        };
+        let ece = if self.congestion_echo {
+            EXT_ECE_BIT
+        } else {
+            0
+        };
+        ((self.priority as u8) << 6) | ce | ece
+    }
+
     /// Converts the message to a byte representation and appends it to the given output vector.
     ///
     /// # Arguments
@@ -182,15 +265,25 @@ impl TransportMsgHeader {
             RouteRule::Direct => ROUTE_RULE_DIRECT,
             RouteRule::ToNode(_) => ROUTE_RULE_TO_NODE,
             RouteRule::ToService(_) => ROUTE_RULE_TO_SERVICE,
-            RouteRule::ToServices(_, _, _) => ROUTE_RULE_TO_SERVICES,
+            RouteRule::ToServices(_, _, _) => ROUTE_RULE_TO_SERVICES_EXT,
             RouteRule::ToKey(_) => ROUTE_RULE_TO_KEY,
         };
 
-        output[0] = (self.version << 6) | e_bit | n_bit | (route_type & 15);
+        let x_bit = if self.has_ext() {
+            EXT_BIT
+        } else {
+            0
+        };
+
+        output[0] = (self.version << 6) | e_bit | n_bit | x_bit | (route_type & ROUTE_TYPE_MASK);
         output[1] = self.ttl;
         output[2] = self.feature;
         output[3] = self.meta;
         let mut ptr = 4;
+        if self.has_ext() {
+            output[ptr] = self.ext_byte();
+            ptr += 1;
+        }
         match self.route {
             RouteRule::Direct => {
                 // Dont need append anything
@@ -206,8 +299,9 @@ impl TransportMsgHeader {
             RouteRule::ToServices(service, level, seq) => {
                 output[ptr] = service;
                 output[ptr + 1] = level.into();
-                output[ptr + 2..ptr + 4].copy_from_slice(&seq.to_be_bytes());
-                ptr += 4;
+                output[ptr + 2..ptr + 4].copy_from_slice(&[0, 0]);
+                output[ptr + 4..ptr + 8].copy_from_slice(&seq.to_be_bytes());
+                ptr += 8;
             }
             RouteRule::ToKey(key) => {
                 output[ptr..ptr + 4].copy_from_slice(&key.to_be_bytes());
@@ -219,17 +313,7 @@ impl TransportMsgHeader {
             ptr += 4;
         }
 
-        Some(
-            4 + if self.from_node.is_some() {
-                4
-            } else {
-                0
-            } + if self.route == RouteRule::Direct {
-                0
-            } else {
-                4
-            },
-        )
+        Some(self.serialize_size())
     }
 
     /// Rewrite the ttl in the given buffer with the new ttl.
@@ -272,16 +356,157 @@ impl TransportMsgHeader {
         true
     }
 
+    /// Check if the serialized header is using extended broadcast route, which old nodes cannot parse.
+    pub fn is_ext_broadcast(first_byte: u8) -> bool {
+        first_byte & ROUTE_TYPE_MASK == ROUTE_RULE_TO_SERVICES_EXT
+    }
+
+    /// Check if the first byte of a header has extension byte
+    pub fn is_ext(first_byte: u8) -> bool {
+        first_byte & EXT_BIT != 0
+    }
+
+    /// Get priority of a serialized plain (not encrypted) header, Normal is returned if the extension is not present.
+    pub fn priority_of(buf: &[u8]) -> MsgPriority {
+        if buf.len() > 4 && Self::is_ext(buf[0]) {
+            MsgPriority::from(buf[4] >> 6)
+        } else {
+            MsgPriority::Normal
+        }
+    }
+
+    /// Mark congestion experienced in the given buffer, extension byte is inserted if it is not present.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - A mutable buffer which contains a plain (not encrypted) serialized header.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing `()` if the header was successfully marked, or `None` if the buffer is too small.
+    pub fn mark_congested(buf: &mut Buffer) -> Option<()> {
+        if buf.len() < 4 {
+            return None;
+        }
+        if Self::is_ext(buf[0]) {
+            if buf.len() < 5 {
+                return None;
+            }
+            buf[4] |= EXT_CE_BIT;
+            return Some(());
+        }
+        // Buffer::ensure_front miscalculates the range when the buffer already has some front space, so it is rebuilt
+        let mut marked = Buffer::build(&buf[4..], 5, 0);
+        marked.move_front_left(5)?;
+        marked[0..4].copy_from_slice(&buf[0..4]);
+        marked[0] |= EXT_BIT;
+        marked[4] = ((MsgPriority::Normal as u8) << 6) | EXT_CE_BIT;
+        *buf = marked;
+        Some(())
+    }
+
+    /// Remove the extension byte in the given buffer, this is used when sending to neighbours which don't support it.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - A mutable buffer which contains a plain (not encrypted) serialized header.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing `()` if the extension was successfully removed or it don't need to remove,
+    /// or `None` if the buffer is too small.
+    pub fn strip_ext(buf: &mut Buffer) -> Option<()> {
+        if buf.is_empty() || !Self::is_ext(buf[0]) {
+            return Some(());
+        }
+        if buf.len() < 5 {
+            return None;
+        }
+        let mut fixed = [0; 4];
+        fixed.copy_from_slice(&buf[0..4]);
+        fixed[0] &= !EXT_BIT;
+        buf.move_front_right(1)?;
+        buf[0..4].copy_from_slice(&fixed);
+        Some(())
+    }
+
+    /// Rewrite the extended broadcast route in the given buffer to the legacy format with 16 bits seq.
+    /// This is used when sending to neighbours which don't support the extended format.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - A mutable buffer which contains a plain (not encrypted) serialized header without extension.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing `()` if the header was successfully rewritten or it don't need to rewrite,
+    /// or `None` if the buffer don't contain a valid header.
+    pub fn downgrade_broadcast_seq(buf: &mut Buffer) -> Option<()> {
+        if buf.is_empty() || !Self::is_ext_broadcast(buf[0]) {
+            return Some(());
+        }
+        let first_byte = buf[0];
+        let header = Self::try_from(&buf[..]).ok()?;
+        if let RouteRule::ToServices(service, level, seq) = header.route {
+            // legacy route destination is 4 bytes shorter than the extended one
+            buf.move_front_right(4)?;
+            buf[0] = (first_byte & !(EXT_BIT | ROUTE_TYPE_MASK)) | ROUTE_RULE_TO_SERVICES;
+            buf[1] = header.ttl;
+            buf[2] = header.feature;
+            buf[3] = header.meta;
+            buf[4] = service;
+            buf[5] = level.into();
+            buf[6..8].copy_from_slice(&(seq as u16).to_be_bytes());
+            if let Some(from_node) = header.from_node {
+                buf[8..12].copy_from_slice(&from_node.to_be_bytes());
+            }
+        }
+        Some(())
+    }
+
+    /// Rewrite the legacy broadcast route in the given buffer to the extended format.
+    /// This is used with incoming packets from old neighbours, so that the rest of the node only needs to handle the extended format.
+    /// The seq is marked with LEGACY_BROADCAST_SEQ because old neighbours truncate it to 16 bits when relaying.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - A mutable buffer which contains a plain (not encrypted) serialized header.
+    ///
+    /// # Returns
+    ///
+    /// An `Option` containing `()` if the header was successfully rewritten or it don't need to rewrite,
+    /// or `None` if the buffer don't contain a valid header.
+    pub fn upgrade_broadcast_seq(buf: &mut Buffer) -> Option<()> {
+        if buf.is_empty() || buf[0] & (EXT_BIT | ROUTE_TYPE_MASK) != ROUTE_RULE_TO_SERVICES {
+            return Some(());
+        }
+        let mut header = Self::try_from(&buf[..]).ok()?;
+        if let RouteRule::ToServices(_, _, seq) = &mut header.route {
+            *seq |= LEGACY_BROADCAST_SEQ;
+        }
+        let header_size = header.serialize_size();
+        // legacy route destination is 4 bytes shorter than the extended one
+        let mut new_buf = Buffer::build(buf.get(header_size - 4..)?, header_size, 0);
+        header.to_bytes(new_buf.front_mut(header_size))?;
+        new_buf.move_front_left(header_size)?;
+        *buf = new_buf;
+        Some(())
+    }
+
     /// Returns the size of the serialized message.
     pub fn serialize_size(&self) -> usize {
-        4 + if self.from_node.is_some() {
-            4
+        4 + if self.has_ext() {
+            1
         } else {
             0
-        } + if self.route == RouteRule::Direct {
-            0
-        } else {
+        } + if self.from_node.is_some() {
             4
+        } else {
+            0
+        } + match self.route {
+            RouteRule::Direct => 0,
+            RouteRule::ToServices(..) => 8,
+            _ => 4,
         }
     }
 }
@@ -444,7 +669,8 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
         let version = bytes[0] >> 6; //2 bits
         let e_bit = (bytes[0] >> 5) & 1 == 1; //1 bit
         let n_bit = (bytes[0] >> 4) & 1 == 1; //1 bit
-        let route_type = bytes[0] & 15; //4 bits
+        let x_bit = bytes[0] & EXT_BIT != 0; //1 bit
+        let route_type = bytes[0] & ROUTE_TYPE_MASK; //3 bits
 
         if version != 0 {
             return Err(TransportMsgHeaderError::InvalidVersion);
@@ -455,6 +681,15 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
         let meta = bytes[3];
 
         let mut ptr = 4;
+        let ext = if x_bit {
+            if bytes.len() < ptr + 1 {
+                return Err(TransportMsgHeaderError::TooSmall);
+            }
+            ptr += 1;
+            bytes[4]
+        } else {
+            (MsgPriority::Normal as u8) << 6
+        };
 
         let route = match route_type {
             ROUTE_RULE_DIRECT => RouteRule::Direct,
@@ -478,10 +713,19 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
                 if bytes.len() < ptr + 4 {
                     return Err(TransportMsgHeaderError::TooSmall);
                 }
-                let rr = RouteRule::ToServices(bytes[ptr], ServiceBroadcastLevel::from(bytes[ptr + 1]), u16::from_be_bytes([bytes[ptr + 2], bytes[ptr + 3]]));
+                let rr = RouteRule::ToServices(bytes[ptr], ServiceBroadcastLevel::from(bytes[ptr + 1]), u16::from_be_bytes([bytes[ptr + 2], bytes[ptr + 3]]) as u32);
                 ptr += 4;
                 rr
             }
+            ROUTE_RULE_TO_SERVICES_EXT => {
+                if bytes.len() < ptr + 8 {
+                    return Err(TransportMsgHeaderError::TooSmall);
+                }
+                let seq = u32::from_be_bytes([bytes[ptr + 4], bytes[ptr + 5], bytes[ptr + 6], bytes[ptr + 7]]);
+                let rr = RouteRule::ToServices(bytes[ptr], ServiceBroadcastLevel::from(bytes[ptr + 1]), seq);
+                ptr += 8;
+                rr
+            }
             ROUTE_RULE_TO_KEY => {
                 if bytes.len() < ptr + 4 {
                     return Err(TransportMsgHeaderError::TooSmall);
@@ -512,6 +756,9 @@ impl TryFrom<&[u8]> for TransportMsgHeader {
             feature,
             meta,
             from_node,
+            priority: MsgPriority::from(ext >> 6),
+            congested: ext & EXT_CE_BIT != 0,
+            congestion_echo: ext & EXT_ECE_BIT != 0,
         })
     }
 }
@@ -532,6 +779,9 @@ mod tests {
             route: RouteRule::Direct,
             encrypt: true,
             from_node: None,
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 4);
@@ -558,6 +808,9 @@ mod tests {
             route: RouteRule::ToNode(4),
             encrypt: true,
             from_node: None,
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 8);
@@ -582,9 +835,12 @@ mod tests {
             route: RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 1000),
             encrypt: true,
             from_node: None,
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
-        assert_eq!(header.serialize_size(), 8);
+        assert_eq!(header.serialize_size(), 12);
         let header = TransportMsgHeader::try_from(&buf[0..size]).expect("");
         assert_eq!(header.version, 0);
         assert_eq!(header.ttl, 1);
@@ -606,6 +862,9 @@ mod tests {
             route: RouteRule::ToService(4),
             encrypt: true,
             from_node: Some(5),
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         assert_eq!(header.serialize_size(), 12);
@@ -618,6 +877,110 @@ mod tests {
         assert_eq!(header.from_node, Some(5));
     }
 
+    /// test legacy broadcast header with 16 bits seq still can be decoded and upgraded
+    #[test]
+    fn test_header_with_legacy_service_dest() {
+        let mut buf = Buffer::from(vec![ROUTE_RULE_TO_SERVICES, 1, 2, 3, 4, ServiceBroadcastLevel::Geo2.into(), 0x03, 0xe8, 9]);
+        let header = TransportMsgHeader::try_from(&buf[..]).expect("");
+        assert_eq!(header.route, RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 1000));
+
+        TransportMsgHeader::upgrade_broadcast_seq(&mut buf).expect("should upgrade");
+        assert!(TransportMsgHeader::is_ext_broadcast(buf[0]));
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert_eq!(msg.header.route, RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, LEGACY_BROADCAST_SEQ | 1000));
+        assert_eq!(msg.header.ttl, 1);
+        assert_eq!(msg.payload(), &[9]);
+    }
+
+    /// test downgrade extended broadcast header to legacy format
+    #[test]
+    fn test_downgrade_broadcast_seq() {
+        let header = TransportMsgHeader {
+            version: 0,
+            ttl: 1,
+            feature: 2,
+            meta: 3,
+            route: RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, 0x10003),
+            encrypt: false,
+            from_node: Some(5),
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
+        };
+        let mut buf = TransportMsg::build_raw(header, Buffer::build(&[1, 2, 3, 4], 16, 0)).take();
+        assert!(TransportMsgHeader::is_ext_broadcast(buf[0]));
+        let ext_len = buf.len();
+
+        TransportMsgHeader::downgrade_broadcast_seq(&mut buf).expect("should downgrade");
+        assert_eq!(buf.len(), ext_len - 4);
+        assert!(!TransportMsgHeader::is_ext_broadcast(buf[0]));
+
+        TransportMsgHeader::upgrade_broadcast_seq(&mut buf).expect("should upgrade");
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert_eq!(msg.header.route, RouteRule::ToServices(4, ServiceBroadcastLevel::Geo2, LEGACY_BROADCAST_SEQ | 3));
+        assert_eq!(msg.header.from_node, Some(5));
+        assert_eq!(msg.header.feature, 2);
+        assert_eq!(msg.header.meta, 3);
+        assert_eq!(msg.payload(), &[1, 2, 3, 4]);
+    }
+
+    /// test header with priority extension
+    #[test]
+    fn test_header_with_priority() {
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToNode(4)).set_from_node(Some(5)).set_priority(MsgPriority::Control);
+        assert_eq!(header.serialize_size(), 13);
+        let mut buf = TransportMsg::build_raw(header, Buffer::build(&[1, 2, 3, 4], 16, 0)).take();
+        assert_eq!(TransportMsgHeader::priority_of(&buf), MsgPriority::Control);
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert_eq!(msg.header.priority, MsgPriority::Control);
+        assert_eq!(msg.header.route, RouteRule::ToNode(4));
+        assert_eq!(msg.header.from_node, Some(5));
+        assert_eq!(msg.payload(), &[1, 2, 3, 4]);
+
+        TransportMsgHeader::strip_ext(&mut buf).expect("should strip");
+        assert_eq!(TransportMsgHeader::priority_of(&buf), MsgPriority::Normal);
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert_eq!(msg.header.priority, MsgPriority::Normal);
+        assert_eq!(msg.header.route, RouteRule::ToNode(4));
+        assert_eq!(msg.header.from_node, Some(5));
+        assert_eq!(msg.header.feature, 2);
+        assert_eq!(msg.header.meta, 3);
+        assert_eq!(msg.payload(), &[1, 2, 3, 4]);
+    }
+
+    /// test mark congestion on header without and with extension
+    #[test]
+    fn test_mark_congested() {
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToNode(4)).set_from_node(Some(5));
+        let mut buf = TransportMsg::build_raw(header, Buffer::build(&[1, 2, 3, 4], 16, 0)).take();
+        TransportMsgHeader::mark_congested(&mut buf).expect("should mark");
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert!(msg.header.congested);
+        assert!(!msg.header.congestion_echo);
+        assert_eq!(msg.header.priority, MsgPriority::Normal);
+        assert_eq!(msg.header.route, RouteRule::ToNode(4));
+        assert_eq!(msg.header.from_node, Some(5));
+        assert_eq!(msg.payload(), &[1, 2, 3, 4]);
+
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToNode(4)).set_priority(MsgPriority::Bulk).set_congestion_echo(true);
+        let mut buf = TransportMsg::build_raw(header, Buffer::build(&[1, 2, 3, 4], 16, 0)).take();
+        TransportMsgHeader::mark_congested(&mut buf).expect("should mark");
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert!(msg.header.congested);
+        assert!(msg.header.congestion_echo);
+        assert_eq!(msg.header.priority, MsgPriority::Bulk);
+        assert_eq!(msg.payload(), &[1, 2, 3, 4]);
+
+        // relayed packets are received without any front space
+        let header = TransportMsgHeader::build(2, 3, RouteRule::ToNode(4));
+        let mut buf = Buffer::from(TransportMsg::build_raw(header, Buffer::from(vec![1, 2, 3, 4])).take().to_vec());
+        TransportMsgHeader::mark_congested(&mut buf).expect("should mark");
+        buf.ensure_back(12 + 16);
+        let msg = TransportMsg::try_from(&buf[..]).expect("");
+        assert!(msg.header.congested);
+        assert_eq!(msg.payload(), &[1, 2, 3, 4]);
+    }
+
     /// test with invalid version
     #[test]
     fn test_with_invalid_version() {
@@ -630,6 +993,9 @@ mod tests {
             route: RouteRule::ToNode(4),
             encrypt: true,
             from_node: Some(5),
+            priority: MsgPriority::Normal,
+            congested: false,
+            congestion_echo: false,
         };
         let size = header.to_bytes(&mut buf).expect("should serialize");
         let err = TransportMsgHeader::try_from(&buf[0..size]).unwrap_err();
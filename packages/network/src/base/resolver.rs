@@ -0,0 +1,10 @@
+use std::net::IpAddr;
+
+/// Resolver for dns names inside NodeAddr, like `/dns4/seed.example.com/udp/10000`.
+/// The controller is sans-io, so resolving is started with `resolve` and results are polled on each tick, both methods must not block.
+#[mockall::automock]
+pub trait DnsResolver: Send + Sync {
+    fn resolve(&self, name: &str);
+    /// Return a finished resolution, failed one is returned with empty addrs
+    fn poll(&self) -> Option<(String, Vec<IpAddr>)>;
+}
@@ -1,3 +1,5 @@
+use std::net::SocketAddr;
+
 use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_utils::simple_pub_type;
 use sans_io_runtime::TaskSwitcherChild;
@@ -18,6 +20,12 @@ pub enum ServiceControlActor<UserData> {
 pub enum ServiceSharedInput {
     Tick(u64),
     Connection(ConnectionEvent),
+    /// Our public addresses which are observed by neighbours are changed
+    ObservedAddrs(Vec<SocketAddr>),
+    /// A local addr is bound at runtime and should be advertised
+    Bind(SocketAddr),
+    /// A local addr is unbound at runtime and should not be advertised anymore
+    Unbind(SocketAddr),
 }
 
 #[derive(Debug)]
@@ -1,4 +1,10 @@
-use std::{collections::VecDeque, fmt::Debug, hash::Hash, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+    net::SocketAddr,
+    sync::Arc,
+};
 
 use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_router::shadow::ShadowRouterHistory;
@@ -7,19 +13,37 @@ use sans_io_runtime::{return_if_none, return_if_some, TaskSwitcher, TaskSwitcher
 
 use crate::{
     base::{
-        Authorization, ConnectionEvent, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, HandshakeBuilder, ServiceBuilder, ServiceControlActor, ServiceCtx,
-        ServiceInput, ServiceOutput, ServiceSharedInput,
+        Authorization, ConnectionEvent, DnsResolver, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, HandshakeBuilder, ServiceBuilder, ServiceControlActor,
+        ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput,
     },
+    data_plane::TransportPenalty,
     features::{FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut, LogicControl, LogicEvent,
 };
 
 use self::{features::FeatureManager, neighbours::NeighboursManager, services::ServiceManager};
 
+pub use self::neighbours::{Keepalive, KeepaliveCfg, NeighboursLimits, ReconnectCfg};
+
 mod features;
 mod neighbours;
 mod services;
 
+/// Drain before shutdown, routes are withdrawn and neighbours are kept connected until transit traffic stops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DrainCfg {
+    /// Minimum time for neighbours to reconverge after we withdrawn our routes
+    pub settle_ms: u64,
+    /// Maximum time waiting for transit traffic to stop before disconnecting
+    pub timeout_ms: u64,
+}
+
+impl Default for DrainCfg {
+    fn default() -> Self {
+        Self { settle_ms: 2000, timeout_ms: 30000 }
+    }
+}
+
 #[derive(Debug, Clone, convert_enum::From)]
 pub enum Input<UserData, SC, SE, TC> {
     Ext(ExtIn<UserData, SC>),
@@ -51,12 +75,32 @@ pub struct ControllerPlaneCfg<UserData, SC, SE, TC, TW> {
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub authorization: Arc<dyn Authorization>,
     pub handshake_builder: Arc<dyn HandshakeBuilder>,
+    /// Resolver for dns parts of NodeAddr, dns seeds are ignored if it is not set
+    pub resolver: Option<Arc<dyn DnsResolver>>,
+    pub reconnect: ReconnectCfg,
+    pub keepalive: KeepaliveCfg,
+    /// Keep only best connections by rtt to each node, None for unlimited
+    pub max_conns_per_node: Option<usize>,
+    pub limits: NeighboursLimits,
+    /// Extra route cost of connections per transport
+    pub transport_penalty: TransportPenalty,
+    /// Drain before disconnecting neighbours at shutdown, None for disconnecting immediately
+    pub drain: Option<DrainCfg>,
     pub random: Box<dyn RngCore + Send + Sync>,
     pub history: Arc<dyn ShadowRouterHistory>,
 }
 
+struct Drain {
+    started_ms: u64,
+    /// last reported relayed packets per worker
+    transit: HashMap<u16, u64>,
+}
+
 pub struct ControllerPlane<UserData, SC, SE, TC, TW> {
     tick_count: u64,
+    shutdown: bool,
+    drain_cfg: Option<DrainCfg>,
+    drain: Option<Drain>,
     feature_ctx: FeatureContext,
     service_ctx: ServiceCtx,
     neighbours: TaskSwitcherBranch<NeighboursManager, neighbours::Output>,
@@ -88,13 +132,27 @@ where
 
         Self {
             tick_count: 0,
+            shutdown: false,
+            drain_cfg: cfg.drain,
+            drain: None,
             feature_ctx: FeatureContext { node_id, session: cfg.session },
             service_ctx: ServiceCtx { node_id, session: cfg.session },
             neighbours: TaskSwitcherBranch::new(
-                NeighboursManager::new(node_id, cfg.bind_addrs, cfg.authorization, cfg.handshake_builder, cfg.random),
+                NeighboursManager::new(
+                    node_id,
+                    cfg.bind_addrs,
+                    cfg.authorization,
+                    cfg.handshake_builder,
+                    cfg.resolver,
+                    cfg.reconnect,
+                    cfg.keepalive,
+                    cfg.max_conns_per_node,
+                    cfg.limits,
+                    cfg.random,
+                ),
                 TaskType::Neighbours,
             ),
-            features: TaskSwitcherBranch::new(FeatureManager::new(node_id, cfg.session, service_ids), TaskType::Feature),
+            features: TaskSwitcherBranch::new(FeatureManager::new(node_id, cfg.session, service_ids, cfg.transport_penalty), TaskType::Feature),
             services: TaskSwitcherBranch::new(ServiceManager::new(cfg.services), TaskType::Service),
             switcher: TaskSwitcher::new(3), //3 types: Neighbours, Feature, Service
             queue: VecDeque::new(),
@@ -113,16 +171,34 @@ where
             .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Tick(self.tick_count));
         self.tick_count += 1;
         self.history.set_ts(now_ms);
+        self.check_drain(now_ms);
+    }
+
+    /// Fast tick is called at each worker tick without tick_ms filtering, it only drives fast probe keepalive
+    pub fn on_fast_tick(&mut self, now_ms: u64) {
+        self.neighbours.input(&mut self.switcher).on_fast_tick(now_ms);
     }
 
     pub fn on_event(&mut self, now_ms: u64, event: Input<UserData, SC, SE, TC>) {
         match event {
             Input::Ext(ExtIn::ConnectTo(addr)) => {
-                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectTo(addr));
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectTo(addr, false));
+            }
+            Input::Ext(ExtIn::ConnectToPersistent(addr)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectTo(addr, true));
+            }
+            Input::Ext(ExtIn::ConnectToSeed(addr)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectToSeed(addr));
             }
             Input::Ext(ExtIn::DisconnectFrom(node)) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::DisconnectFrom(node));
             }
+            Input::Ext(ExtIn::AddBind(addr)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::AddBind(addr));
+            }
+            Input::Ext(ExtIn::RemoveBind(addr)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::RemoveBind(addr));
+            }
             Input::Ext(ExtIn::FeaturesControl(userdata, control)) => {
                 self.features.input(&mut self.switcher).on_input(
                     &self.feature_ctx,
@@ -174,12 +250,48 @@ where
             Input::Control(LogicControl::ExtServicesEvent(service, userdata, event)) => {
                 self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event)));
             }
+            Input::Control(LogicControl::Traffic(traffic)) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Traffic(traffic));
+            }
+            Input::Control(LogicControl::TransitLoad(worker, relayed)) => {
+                if let Some(drain) = &mut self.drain {
+                    drain.transit.insert(worker, relayed);
+                }
+            }
             Input::ShutdownRequest => {
-                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ShutdownRequest);
+                if self.shutdown {
+                    return;
+                }
+                self.shutdown = true;
+                if self.drain_cfg.is_none() {
+                    self.queue.push_back(Output::Event(LogicEvent::Drained));
+                    self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ShutdownRequest);
+                    return;
+                }
+                log::info!("[ControllerPlane] start draining before shutdown");
+                self.drain = Some(Drain {
+                    started_ms: now_ms,
+                    transit: HashMap::new(),
+                });
+                self.features.input(&mut self.switcher).on_shared_input(&self.feature_ctx, now_ms, FeatureSharedInput::Drain);
             }
         }
     }
 
+    /// Disconnect all neighbours after transit traffic stopped or drain timeout
+    fn check_drain(&mut self, now_ms: u64) {
+        let drain = return_if_none!(self.drain.as_ref());
+        let cfg = return_if_none!(self.drain_cfg);
+        let elapsed = now_ms - drain.started_ms;
+        let idle = drain.transit.values().all(|relayed| *relayed == 0);
+        if elapsed >= cfg.timeout_ms || (elapsed >= cfg.settle_ms && idle) {
+            log::info!("[ControllerPlane] drain finished after {elapsed} ms, idle {idle}");
+            self.drain = None;
+            self.queue.push_back(Output::Event(LogicEvent::Drained));
+            self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ShutdownRequest);
+        }
+    }
+
     fn pop_neighbours(&mut self, now_ms: u64) {
         let out = return_if_none!(self.neighbours.pop_output(now_ms, &mut self.switcher));
         match out {
@@ -194,9 +306,26 @@ where
                 match event {
                     ConnectionEvent::Connected(ctx, secure) => self.queue.push_back(Output::Event(LogicEvent::Pin(ctx.conn, ctx.node, ctx.pair, secure))),
                     ConnectionEvent::Stats(_ctx, _stats) => {}
-                    ConnectionEvent::Disconnected(ctx) => self.queue.push_back(Output::Event(LogicEvent::UnPin(ctx.conn))),
+                    ConnectionEvent::Disconnected(ctx, _) => self.queue.push_back(Output::Event(LogicEvent::UnPin(ctx.conn))),
+                    ConnectionEvent::Primary(_) | ConnectionEvent::ConnectError(..) | ConnectionEvent::GaveUp(_) => {}
                 }
             }
+            neighbours::Output::Capabilities(conn, caps) => self.queue.push_back(Output::Event(LogicEvent::SetCapabilities(conn, caps))),
+            neighbours::Output::Mtu(conn, mtu) => self.queue.push_back(Output::Event(LogicEvent::SetMtu(conn, mtu))),
+            neighbours::Output::Congested(conn, congested) => self.queue.push_back(Output::Event(LogicEvent::SetCongested(conn, congested))),
+            neighbours::Output::ObservedAddrs(addrs) => {
+                self.services
+                    .input(&mut self.switcher)
+                    .on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::ObservedAddrs(addrs));
+            }
+            neighbours::Output::Bind(addr) => {
+                self.services.input(&mut self.switcher).on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Bind(addr));
+                self.queue.push_back(Output::Event(LogicEvent::Bind(addr)));
+            }
+            neighbours::Output::Unbind(addr) => {
+                self.services.input(&mut self.switcher).on_shared_input(&self.service_ctx, now_ms, ServiceSharedInput::Unbind(addr));
+                self.queue.push_back(Output::Event(LogicEvent::Unbind(addr)));
+            }
             neighbours::Output::ShutdownResponse => self.queue.push_back(Output::ShutdownSuccess),
         }
     }
@@ -225,11 +354,14 @@ where
                 self.queue.push_back(Output::Event(LogicEvent::NetRoute(feature, rule, ttl, buf)))
             }
             FeatureOutput::NeighboursConnectTo(addr) => {
-                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectTo(addr));
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::ConnectTo(addr, false));
             }
             FeatureOutput::NeighboursDisconnectFrom(node) => {
                 self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::DisconnectFrom(node));
             }
+            FeatureOutput::NeighboursAccess(control) => {
+                self.neighbours.input(&mut self.switcher).on_input(now_ms, neighbours::Input::Access(control));
+            }
         }
     }
 
@@ -5,6 +5,7 @@ use atm0s_sdn_identity::NodeId;
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::base::{Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput};
+use crate::data_plane::TransportPenalty;
 use crate::features::*;
 
 pub type FeaturesInput<'a, UserData> = FeatureInput<'a, UserData, FeaturesControl, FeaturesToController>;
@@ -31,11 +32,11 @@ pub struct FeatureManager<UserData> {
 }
 
 impl<UserData: 'static + Hash + Eq + Copy + Debug> FeatureManager<UserData> {
-    pub fn new(node: NodeId, session: u64, services: Vec<u8>) -> Self {
+    pub fn new(node: NodeId, session: u64, services: Vec<u8>, transport_penalty: TransportPenalty) -> Self {
         Self {
             neighbours: TaskSwitcherBranch::default(Features::Neighbours as usize),
             data: TaskSwitcherBranch::default(Features::Data as usize),
-            router_sync: TaskSwitcherBranch::new(router_sync::RouterSyncFeature::new(node, services), Features::RouterSync as usize),
+            router_sync: TaskSwitcherBranch::new(router_sync::RouterSyncFeature::new(node, services, transport_penalty), Features::RouterSync as usize),
             vpn: TaskSwitcherBranch::default(Features::Vpn as usize),
             dht_kv: TaskSwitcherBranch::new(dht_kv::DhtKvFeature::new(node, session), Features::DhtKv as usize),
             pubsub: TaskSwitcherBranch::new(pubsub::PubSubFeature::new(), Features::PubSub as usize),
@@ -1,57 +1,312 @@
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::BuildHasher,
     net::{IpAddr, SocketAddr},
     sync::Arc,
 };
 
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId, Protocol};
-use sans_io_runtime::TaskSwitcherChild;
+use sans_io_runtime::{return_if_none, TaskSwitcherChild};
 
 use crate::{
-    base::{self, Authorization, ConnectionCtx, HandshakeBuilder, NeighboursControl, NeighboursControlCmds, SecureContext},
-    data_plane::NetPair,
+    base::{
+        self, AccessControl, AccessList, Authorization, ConnectionCtx, DnsResolver, HandshakeBuilder, NeighboursConnectError, NeighboursControl, NeighboursControlCmds, NeighboursDisconnectReason,
+        SecureContext, CAPABILITY_COOKIE,
+    },
+    data_plane::{NetPair, Transport},
 };
 
 use self::connection::{ConnectionEvent, NeighbourConnection};
 
 mod connection;
+mod pmtu;
+
+/// Reconnect policy for persistent nodes. Delay is doubled after each failed attempt until `max_delay_ms`,
+/// and a random jitter in range [delay / 2, delay] is applied for avoiding all nodes retry at the same time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectCfg {
+    pub initial_delay_ms: u64,
+    pub max_delay_ms: u64,
+    /// None for retrying forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectCfg {
+    fn default() -> Self {
+        Self {
+            initial_delay_ms: 1000,
+            max_delay_ms: 60_000,
+            max_attempts: Some(20),
+        }
+    }
+}
+
+impl ReconnectCfg {
+    fn delay_ms(&self, attempts: u32, random: u64) -> u64 {
+        let delay = self.initial_delay_ms.saturating_mul(1 << attempts.min(32)).min(self.max_delay_ms);
+        delay / 2 + random % (delay / 2 + 1)
+    }
+}
+
+/// How a connection detects that the remote is gone
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keepalive {
+    /// Ping each `interval_ms` at controller ticks, disconnect after no pong in `timeout_ms`
+    Ping { interval_ms: u64, timeout_ms: u64 },
+    /// BFD-like probing with `interval_ms`, which is driven by fast ticks and can be shorter than the tick interval.
+    /// The connection is disconnected after `misses` probe intervals without pong.
+    FastProbe { interval_ms: u64, misses: u8 },
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::Ping {
+            interval_ms: 1000,
+            timeout_ms: 10_000,
+        }
+    }
+}
+
+/// Keepalive per connection class, connections to `critical_nodes` use `critical`, others use `default`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepaliveCfg {
+    pub default: Keepalive,
+    pub critical: Keepalive,
+    pub critical_nodes: Vec<NodeId>,
+}
+
+impl Default for KeepaliveCfg {
+    fn default() -> Self {
+        Self {
+            default: Keepalive::default(),
+            critical: Keepalive::FastProbe { interval_ms: 50, misses: 3 },
+            critical_nodes: vec![],
+        }
+    }
+}
+
+impl KeepaliveCfg {
+    /// Interval of fast ticks which are needed, None if no connection uses fast probe
+    pub fn fast_interval_ms(&self) -> Option<u64> {
+        [(self.default, true), (self.critical, !self.critical_nodes.is_empty())]
+            .into_iter()
+            .filter_map(|(keepalive, used)| match keepalive {
+                Keepalive::FastProbe { interval_ms, .. } if used => Some(interval_ms),
+                _ => None,
+            })
+            .min()
+    }
+
+    fn keepalive(&self, node: NodeId) -> Keepalive {
+        if self.critical_nodes.contains(&node) {
+            self.critical
+        } else {
+            self.default
+        }
+    }
+}
+
+struct Persistent {
+    addr: NodeAddr,
+    /// failed attempts since last connected
+    attempts: u32,
+    next_ms: Option<u64>,
+    seed: bool,
+}
+
+/// Agreeing neighbours which are needed before an observed address is advertised
+const MIN_OBSERVED_VOTES: usize = 2;
+
+/// Cookie is valid in current and previous period
+const COOKIE_PERIOD_MS: u64 = 10_000;
+/// Window for counting accepted handshakes, which is used for detecting load
+const HANDSHAKE_WINDOW_MS: u64 = 1000;
+
+/// Protection against handshake floods, all checks are done before allocating a connection and running the handshake
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NeighboursLimits {
+    /// Max connections including handshaking ones, new connect requests are rejected with Overloaded
+    pub max_connections: usize,
+    /// Token bucket per remote ip for controls from unknown pairs, exceeded ones are dropped silently
+    pub handshakes_per_sec: u64,
+    pub handshakes_burst: u64,
+    /// When this number of handshakes are accepted in a second, new requesters must echo a cookie from ConnectRetry first
+    pub cookie_threshold: u32,
+}
+
+impl Default for NeighboursLimits {
+    fn default() -> Self {
+        Self {
+            max_connections: 1024,
+            handshakes_per_sec: 10,
+            handshakes_burst: 20,
+            cookie_threshold: 32,
+        }
+    }
+}
+
+/// Handshake token bucket of a remote ip, tokens are in milli for avoiding lost of fractional refill
+struct IpBucket {
+    tokens: u64,
+    last_ms: u64,
+}
+
+/// Interval for re-resolving dns seeds, which allows seeds to move behind dns names
+const DNS_REFRESH_MS: u64 = 60_000;
+/// Time to wait for an UDP connection before trying tcp and WebSocket addrs of the node
+const TCP_FALLBACK_MS: u64 = 5000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DnsFamily {
+    Any,
+    V4,
+    V6,
+}
+
+impl DnsFamily {
+    fn accept(&self, ip: &IpAddr) -> bool {
+        match self {
+            DnsFamily::Any => true,
+            DnsFamily::V4 => ip.is_ipv4(),
+            DnsFamily::V6 => ip.is_ipv6(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct DnsSeed {
+    name: String,
+    family: DnsFamily,
+    port: u16,
+}
+
+impl DnsSeed {
+    fn dests(&self, ips: &[IpAddr]) -> Vec<SocketAddr> {
+        ips.iter().filter(|ip| self.family.accept(ip)).map(|ip| SocketAddr::new(*ip, self.port)).collect()
+    }
+}
 
 pub enum Input {
-    ConnectTo(NodeAddr),
+    /// Second param is persistent flag, persistent nodes are reconnected after lost
+    ConnectTo(NodeAddr, bool),
+    /// Seeds are persistent nodes which are reconnected forever, regardless of `ReconnectCfg::max_attempts`
+    ConnectToSeed(NodeAddr),
     DisconnectFrom(NodeId),
+    AddBind(SocketAddr),
+    RemoveBind(SocketAddr),
+    Access(AccessControl),
     Control(NetPair, NeighboursControl),
+    /// Bytes sent and received per connection, reported by data plane workers
+    Traffic(Vec<(ConnId, u64, u64)>),
     ShutdownRequest,
 }
 
 pub enum Output {
     Control(NetPair, NeighboursControl),
     Event(base::ConnectionEvent),
+    Capabilities(ConnId, u32),
+    Mtu(ConnId, u16),
+    Congested(ConnId, bool),
+    /// Our public addresses which are agreed by the majority of neighbours, in order of bind addrs
+    ObservedAddrs(Vec<SocketAddr>),
+    /// Open a socket on the addr which is added at runtime
+    Bind(SocketAddr),
+    /// Close the socket of a removed addr, it is emitted after disconnect requests over the addr
+    Unbind(SocketAddr),
     ShutdownResponse,
 }
 
 pub struct NeighboursManager {
     node_id: NodeId,
     bind_addrs: Vec<SocketAddr>,
+    /// removed bind addrs which are closed after pending controls over them are sent
+    unbinding: Vec<SocketAddr>,
     connections: HashMap<NetPair, NeighbourConnection>,
     neighbours: HashMap<ConnId, ConnectionCtx>,
+    /// local bind addr and observed addr which are reported by each neighbour
+    observations: HashMap<ConnId, (SocketAddr, SocketAddr)>,
+    observed_addrs: Vec<SocketAddr>,
     queue: VecDeque<Output>,
     shutdown: bool,
     authorization: Arc<dyn Authorization>,
     handshake_builder: Arc<dyn HandshakeBuilder>,
+    resolver: Option<Arc<dyn DnsResolver>>,
+    /// dns parts of NodeAddr which we connected to, they are re-resolved periodically until DisconnectFrom
+    dns_seeds: HashMap<NodeId, Vec<DnsSeed>>,
+    dns_cache: HashMap<String, Vec<IpAddr>>,
+    dns_pending: HashSet<String>,
+    dns_refreshed_ms: u64,
+    reconnect: ReconnectCfg,
+    keepalive: KeepaliveCfg,
+    /// stream addrs of nodes and the time they are tried if the node is still not connected over UDP
+    fallbacks: HashMap<NodeId, (u64, Vec<(Transport, SocketAddr)>)>,
+    persistent: HashMap<NodeId, Persistent>,
+    /// keep only best connections by rtt to each node, None for unlimited
+    max_conns_per_node: Option<usize>,
+    rtts: HashMap<ConnId, u32>,
+    primaries: HashMap<NodeId, ConnId>,
+    /// redundant connections which are disconnecting
+    pruned: HashSet<ConnId>,
+    /// pairs of pruned connections, which are not reconnected while the node is still connected over other pairs
+    pruned_pairs: HashMap<NetPair, NodeId>,
+    access: AccessList,
+    limits: NeighboursLimits,
+    ip_buckets: HashMap<IpAddr, IpBucket>,
+    /// start of the current window and accepted handshakes in it
+    handshake_window: (u64, u32),
+    /// randomly keyed hasher, which is the secret of cookies
+    cookie_key: RandomState,
+    /// requesters which echoed a valid cookie and the time it expires
+    verified_cookies: HashMap<(NetPair, NodeId, u64), u64>,
     random: Box<dyn rand::RngCore>,
 }
 
 impl NeighboursManager {
-    pub fn new(node_id: NodeId, bind_addrs: Vec<SocketAddr>, authorization: Arc<dyn Authorization>, handshake_builder: Arc<dyn HandshakeBuilder>, random: Box<dyn rand::RngCore>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        node_id: NodeId,
+        bind_addrs: Vec<SocketAddr>,
+        authorization: Arc<dyn Authorization>,
+        handshake_builder: Arc<dyn HandshakeBuilder>,
+        resolver: Option<Arc<dyn DnsResolver>>,
+        reconnect: ReconnectCfg,
+        keepalive: KeepaliveCfg,
+        max_conns_per_node: Option<usize>,
+        limits: NeighboursLimits,
+        random: Box<dyn rand::RngCore>,
+    ) -> Self {
         Self {
             node_id,
             bind_addrs,
+            unbinding: Vec::new(),
             connections: HashMap::new(),
             neighbours: HashMap::new(),
+            observations: HashMap::new(),
+            observed_addrs: Vec::new(),
             queue: VecDeque::new(),
             shutdown: false,
             authorization,
             handshake_builder,
+            resolver,
+            dns_seeds: HashMap::new(),
+            dns_cache: HashMap::new(),
+            dns_pending: HashSet::new(),
+            dns_refreshed_ms: 0,
+            reconnect,
+            keepalive,
+            fallbacks: HashMap::new(),
+            persistent: HashMap::new(),
+            max_conns_per_node,
+            rtts: HashMap::new(),
+            primaries: HashMap::new(),
+            pruned: HashSet::new(),
+            pruned_pairs: HashMap::new(),
+            access: AccessList::default(),
+            limits,
+            ip_buckets: HashMap::new(),
+            handshake_window: (0, 0),
+            cookie_key: RandomState::new(),
+            verified_cookies: HashMap::new(),
             random,
         }
     }
@@ -61,41 +316,170 @@ impl NeighboursManager {
     }
 
     pub fn on_tick(&mut self, now_ms: u64, _tick_count: u64) {
+        let limits = self.limits;
+        self.ip_buckets.retain(|_, bucket| Self::refill(&limits, bucket, now_ms) < limits.handshakes_burst * 1000);
+        self.verified_cookies.retain(|_, expire_ms| *expire_ms >= now_ms);
+
         for conn in self.connections.values_mut() {
             conn.on_tick(now_ms);
         }
+
+        let retries = self
+            .persistent
+            .values_mut()
+            .filter(|p| p.next_ms.is_some_and(|next| next <= now_ms))
+            .map(|p| {
+                p.next_ms = None;
+                p.addr.clone()
+            })
+            .collect::<Vec<_>>();
+        for addr in retries {
+            log::info!("[Neighbours] Reconnecting persistent node {}", addr.node_id());
+            self.connect(now_ms, addr);
+        }
+
+        let fallbacks = self.fallbacks.iter().filter(|(_, (at_ms, _))| *at_ms <= now_ms).map(|(node, _)| *node).collect::<Vec<_>>();
+        for node in fallbacks {
+            let (_, dests) = self.fallbacks.remove(&node).expect("Should have fallback");
+            if self.neighbours.values().any(|ctx| ctx.node == node) {
+                continue;
+            }
+            log::info!("[Neighbours] Node {node} is not connected over UDP after {TCP_FALLBACK_MS} ms, fallback to streams {dests:?}");
+            self.connect_to_streams(now_ms, node, &dests);
+        }
+
+        if let Some(resolver) = self.resolver.clone() {
+            while let Some((name, ips)) = resolver.poll() {
+                self.on_resolved(now_ms, name, ips);
+            }
+            if now_ms >= self.dns_refreshed_ms + DNS_REFRESH_MS {
+                self.dns_refreshed_ms = now_ms;
+                let names = self.dns_seeds.values().flatten().map(|seed| seed.name.clone()).collect::<HashSet<_>>();
+                for name in names {
+                    self.resolve(&name);
+                }
+            }
+        }
+    }
+
+    /// Drive connections in fast probe mode, this is called more often than on_tick
+    pub fn on_fast_tick(&mut self, now_ms: u64) {
+        for conn in self.connections.values_mut() {
+            conn.on_fast_tick(now_ms);
+        }
     }
 
     pub fn on_input(&mut self, now_ms: u64, input: Input) {
         match input {
-            Input::ConnectTo(addr) => {
-                let dest_node = addr.node_id();
-                let dests = get_node_addr_dests(addr);
-                for local in &self.bind_addrs {
-                    for remote in &dests {
-                        if local.is_ipv4() != remote.is_ipv4() {
-                            continue;
-                        }
-
-                        let pair = NetPair::new(*local, *remote);
-                        if self.connections.contains_key(&pair) {
-                            continue;
-                        }
-                        log::info!("[Neighbours] Sending connect request from {local} to {remote}, dest_node {dest_node}");
-                        let session_id = self.random.next_u64();
-                        let conn = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.node_id, dest_node, session_id, pair, now_ms);
-                        self.connections.insert(pair, conn);
-                    }
+            Input::ConnectTo(addr, persistent) => {
+                if persistent {
+                    self.persistent.insert(
+                        addr.node_id(),
+                        Persistent {
+                            addr: addr.clone(),
+                            attempts: 0,
+                            next_ms: None,
+                            seed: false,
+                        },
+                    );
                 }
+                self.connect(now_ms, addr);
+            }
+            Input::ConnectToSeed(addr) => {
+                self.persistent.insert(
+                    addr.node_id(),
+                    Persistent {
+                        addr: addr.clone(),
+                        attempts: 0,
+                        next_ms: None,
+                        seed: true,
+                    },
+                );
+                self.connect(now_ms, addr);
             }
             Input::DisconnectFrom(node) => {
+                self.dns_seeds.remove(&node);
+                self.fallbacks.remove(&node);
+                self.persistent.remove(&node);
+                self.pruned_pairs.retain(|_, pruned_node| *pruned_node != node);
                 for conn in self.connections.values_mut() {
                     if conn.dest_node() == node {
-                        conn.disconnect(now_ms);
+                        conn.disconnect(now_ms, NeighboursDisconnectReason::Other);
+                    }
+                }
+            }
+            Input::AddBind(addr) => {
+                // the socket would get a random port which is unknown here, so pairs and advertised addrs can't match it
+                if addr.port() == 0 {
+                    log::warn!("[Neighbours] Reject bind addr {addr} without port");
+                    return;
+                }
+                if self.bind_addrs.contains(&addr) {
+                    log::warn!("[Neighbours] Bind addr {addr} already exists");
+                    return;
+                }
+                log::info!("[Neighbours] Add bind addr {addr}");
+                self.bind_addrs.push(addr);
+                self.queue.push_back(Output::Bind(addr));
+                // connect to UDP neighbours over the new addr too, so they know it before the old addr is gone
+                let targets = self
+                    .neighbours
+                    .values()
+                    .filter(|ctx| ctx.pair.transport == Transport::Udp)
+                    .map(|ctx| (ctx.node, ctx.pair.remote))
+                    .collect::<HashSet<_>>();
+                for (node, remote) in targets {
+                    self.connect_from(now_ms, addr, node, remote, Transport::Udp);
+                }
+            }
+            Input::RemoveBind(addr) => {
+                if !self.bind_addrs.contains(&addr) {
+                    log::warn!("[Neighbours] Bind addr {addr} not found for removing");
+                    return;
+                }
+                log::info!("[Neighbours] Remove bind addr {addr}");
+                self.bind_addrs.retain(|local| *local != addr);
+                self.unbinding.push(addr);
+                let pairs = self.connections.keys().filter(|pair| pair.local == addr).copied().collect::<Vec<_>>();
+                let mut targets = HashSet::new();
+                for pair in pairs {
+                    let conn = self.connections.get_mut(&pair).expect("Should have connection");
+                    if pair.transport == Transport::Udp {
+                        targets.insert((conn.dest_node(), pair.remote));
+                    }
+                    // the answer can't arrive after the socket is closed, so we don't wait for it
+                    if !conn.close(now_ms, NeighboursDisconnectReason::Other) {
+                        self.connections.remove(&pair);
+                    }
+                }
+                let observed_changed = self.observations.values().any(|(local, _)| *local == addr);
+                self.observations.retain(|_, (local, _)| *local != addr);
+                if observed_changed {
+                    self.check_observed_addrs();
+                }
+                // migrate connections to remaining bind addrs, pairs which were pruned in favor of the removed addr are usable again
+                self.pruned_pairs.retain(|pair, node| !targets.contains(&(*node, pair.remote)));
+                for (node, remote) in targets {
+                    for local in self.bind_addrs.clone() {
+                        self.connect_from(now_ms, local, node, remote, Transport::Udp);
+                    }
+                }
+            }
+            Input::Access(control) => {
+                log::info!("[Neighbours] Apply access control {control:?}");
+                self.access.apply(control);
+                for (pair, conn) in self.connections.iter_mut() {
+                    if let Err(err) = self.access.check(conn.dest_node(), Some(pair.remote.ip())) {
+                        log::info!("[Neighbours] Disconnect {} at {pair} because of {err:?}", conn.dest_node());
+                        conn.disconnect(now_ms, NeighboursDisconnectReason::Other);
                     }
                 }
             }
             Input::Control(addr, control) => {
+                if !self.connections.contains_key(&addr) && !self.allow_handshake(now_ms, addr.remote.ip()) {
+                    log::debug!("[Neighbours] Handshake rate limited from {addr}");
+                    return;
+                }
                 let cmd: NeighboursControlCmds = match control.validate(now_ms, &*self.authorization) {
                     Ok(cmd) => cmd,
                     Err(_) => {
@@ -105,12 +489,45 @@ impl NeighboursManager {
                 };
 
                 log::debug!("[NeighboursManager] received Control(addr: {:?}, cmd: {:?})", addr, cmd);
-                if let Some(conn) = self.connections.get_mut(&addr) {
+                if let NeighboursControlCmds::PunchRequest { .. } | NeighboursControlCmds::PunchIntroduce { .. } = cmd {
+                    self.on_punch_cmd(now_ms, addr, control.from, cmd);
+                } else if let NeighboursControlCmds::ConnectCookie { session, cookie } = cmd {
+                    self.on_connect_cookie(now_ms, addr, control.from, session, cookie);
+                } else if let Some(conn) = self.connections.get_mut(&addr) {
                     conn.on_input(now_ms, control.from, cmd);
                 } else {
                     match cmd {
                         NeighboursControlCmds::ConnectRequest { session, .. } => {
+                            if let Err(err) = self.access.check(control.from, Some(addr.remote.ip())) {
+                                log::warn!("[Neighbours] Reject connect request from {} at {addr} because of {err:?}", control.from);
+                                self.send_control(now_ms, addr, NeighboursControlCmds::ConnectResponse { session, result: Err(err) });
+                                self.queue.push_back(Output::Event(base::ConnectionEvent::ConnectError(control.from, err)));
+                                return;
+                            }
+                            if self.connections.len() >= self.limits.max_connections {
+                                log::warn!("[Neighbours] Reject connect request from {} at {addr} because of max connections", control.from);
+                                let result = Err(NeighboursConnectError::Overloaded);
+                                self.send_control(now_ms, addr, NeighboursControlCmds::ConnectResponse { session, result });
+                                return;
+                            }
+                            let verified = self.verified_cookies.remove(&(addr, control.from, session)).is_some_and(|expire_ms| expire_ms >= now_ms);
+                            if !verified && self.is_loaded(now_ms) {
+                                log::info!("[Neighbours] Under load, send connect retry to {} at {addr}", control.from);
+                                let cookie = self.cookie(now_ms / COOKIE_PERIOD_MS, addr, control.from, session);
+                                self.send_control(
+                                    now_ms,
+                                    addr,
+                                    NeighboursControlCmds::ConnectRetry {
+                                        session,
+                                        cookie,
+                                        caps: CAPABILITY_COOKIE,
+                                    },
+                                );
+                                return;
+                            }
+                            self.handshake_window.1 += 1;
                             let mut conn = NeighbourConnection::new_incoming(self.handshake_builder.clone(), self.node_id, control.from, session, addr, now_ms);
+                            conn.set_keepalive(self.keepalive.keepalive(control.from));
                             conn.on_input(now_ms, control.from, cmd);
                             self.connections.insert(addr, conn);
                         }
@@ -120,10 +537,20 @@ impl NeighboursManager {
                     }
                 }
             }
+            Input::Traffic(traffic) => {
+                for (conn, sent_bytes, recv_bytes) in traffic {
+                    let Some(ctx) = self.neighbours.get(&conn) else {
+                        continue;
+                    };
+                    if let Some(connection) = self.connections.get_mut(&ctx.pair) {
+                        connection.add_traffic(sent_bytes, recv_bytes);
+                    }
+                }
+            }
             Input::ShutdownRequest => {
                 self.shutdown = true;
                 for conn in self.connections.values_mut() {
-                    conn.disconnect(now_ms);
+                    conn.disconnect(now_ms, NeighboursDisconnectReason::Shutdown);
                 }
                 if self.connections.is_empty() {
                     self.queue.push_back(Output::ShutdownResponse);
@@ -133,14 +560,321 @@ impl NeighboursManager {
     }
 }
 
+impl NeighboursManager {
+    fn connect(&mut self, now_ms: u64, addr: NodeAddr) {
+        let dest_node = addr.node_id();
+        if let Err(err) = self.access.check(dest_node, None) {
+            log::warn!("[Neighbours] Skip connect to {dest_node} because of {err:?}");
+            self.queue.push_back(Output::Event(base::ConnectionEvent::ConnectError(dest_node, err)));
+            return;
+        }
+        let (dests, stream_dests, seeds) = get_node_addr_dests(addr);
+        self.connect_to(now_ms, dest_node, &dests, Transport::Udp);
+        if !stream_dests.is_empty() {
+            if dests.is_empty() && seeds.is_empty() {
+                self.connect_to_streams(now_ms, dest_node, &stream_dests);
+            } else {
+                self.fallbacks.insert(dest_node, (now_ms + TCP_FALLBACK_MS, stream_dests));
+            }
+        }
+        if !seeds.is_empty() {
+            self.add_dns_seeds(now_ms, dest_node, seeds);
+        }
+        self.request_punch(now_ms, dest_node);
+    }
+
+    fn connect_to(&mut self, now_ms: u64, dest_node: NodeId, dests: &[SocketAddr], transport: Transport) {
+        for local in self.bind_addrs.clone() {
+            for remote in dests {
+                self.connect_from(now_ms, local, dest_node, *remote, transport);
+            }
+        }
+    }
+
+    fn connect_from(&mut self, now_ms: u64, local: SocketAddr, dest_node: NodeId, remote: SocketAddr, transport: Transport) {
+        if local.is_ipv4() != remote.is_ipv4() {
+            return;
+        }
+
+        let pair = NetPair { local, remote, transport };
+        if self.connections.contains_key(&pair) {
+            return;
+        }
+        if self.pruned_pairs.get(&pair) == Some(&dest_node) && self.neighbours.values().any(|ctx| ctx.node == dest_node) {
+            log::debug!("[Neighbours] Skip connect to {dest_node} at pruned pair {pair}");
+            return;
+        }
+        if let Err(err) = self.access.check(dest_node, Some(remote.ip())) {
+            log::warn!("[Neighbours] Skip connect to {dest_node} at {remote} because of {err:?}");
+            self.queue.push_back(Output::Event(base::ConnectionEvent::ConnectError(dest_node, err)));
+            return;
+        }
+        log::info!("[Neighbours] Sending connect request from {local} to {remote}, dest_node {dest_node}");
+        let session_id = self.random.next_u64();
+        let mut conn = NeighbourConnection::new_outgoing(self.handshake_builder.clone(), self.node_id, dest_node, session_id, pair, now_ms);
+        conn.set_keepalive(self.keepalive.keepalive(dest_node));
+        self.connections.insert(pair, conn);
+    }
+
+    fn connect_to_streams(&mut self, now_ms: u64, dest_node: NodeId, dests: &[(Transport, SocketAddr)]) {
+        for (transport, dest) in dests {
+            self.connect_to(now_ms, dest_node, &[*dest], *transport);
+        }
+    }
+
+    fn add_dns_seeds(&mut self, now_ms: u64, dest_node: NodeId, seeds: Vec<DnsSeed>) {
+        if self.resolver.is_none() {
+            log::warn!("[Neighbours] Dns resolver is not configured, ignore dns seeds {seeds:?} of {dest_node}");
+            return;
+        }
+        for seed in &seeds {
+            if let Some(ips) = self.dns_cache.get(&seed.name) {
+                let dests = seed.dests(ips);
+                self.connect_to(now_ms, dest_node, &dests, Transport::Udp);
+            } else {
+                self.resolve(&seed.name);
+            }
+        }
+        self.dns_seeds.insert(dest_node, seeds);
+    }
+
+    fn resolve(&mut self, name: &str) {
+        let resolver = return_if_none!(self.resolver.as_ref());
+        if self.dns_pending.insert(name.to_string()) {
+            log::debug!("[Neighbours] Resolving dns {name}");
+            resolver.resolve(name);
+        }
+    }
+
+    /// Connect to seeds which are behind the name if the addrs are changed or the seed node is not connected
+    fn on_resolved(&mut self, now_ms: u64, name: String, ips: Vec<IpAddr>) {
+        self.dns_pending.remove(&name);
+        if ips.is_empty() {
+            log::warn!("[Neighbours] Resolve dns {name} failed, keep previous addrs");
+            return;
+        }
+        log::debug!("[Neighbours] Resolved dns {name} => {ips:?}");
+        let changed = self.dns_cache.get(&name) != Some(&ips);
+        let mut targets = vec![];
+        for (node, seeds) in &self.dns_seeds {
+            let connected = self.connections.values().any(|conn| conn.dest_node() == *node);
+            if !changed && connected {
+                continue;
+            }
+            for seed in seeds.iter().filter(|seed| seed.name == name) {
+                targets.push((*node, seed.dests(&ips)));
+            }
+        }
+        self.dns_cache.insert(name, ips);
+        for (node, dests) in targets {
+            self.connect_to(now_ms, node, &dests, Transport::Udp);
+        }
+    }
+
+    /// Ask all connected neighbours to introduce us with the dest node, only neighbours which are connected to it will answer
+    fn request_punch(&mut self, now_ms: u64, dest_node: NodeId) {
+        if dest_node == self.node_id || self.neighbours.values().any(|ctx| ctx.node == dest_node) {
+            return;
+        }
+        let relays = self.neighbours.values().map(|ctx| ctx.pair).collect::<Vec<_>>();
+        for pair in relays {
+            log::info!("[Neighbours] Sending punch request for {dest_node} to relay {pair}");
+            self.send_control(now_ms, pair, NeighboursControlCmds::PunchRequest { target: dest_node });
+        }
+    }
+
+    fn on_punch_cmd(&mut self, now_ms: u64, pair: NetPair, from: NodeId, cmd: NeighboursControlCmds) {
+        // punching is only coordinated through connected neighbours
+        if !self.neighbours.values().any(|ctx| ctx.pair == pair && ctx.node == from) {
+            log::warn!("[Neighbours] Punch cmd from not connected neighbour {from} {pair}");
+            return;
+        }
+        match cmd {
+            NeighboursControlCmds::PunchRequest { target } => {
+                // hole punching is only for UDP, stream addrs are not reachable by punching
+                if pair.transport != Transport::Udp {
+                    return;
+                }
+                let target_pair = return_if_none!(self.neighbours.values().find(|ctx| ctx.node == target && ctx.pair.transport == Transport::Udp).map(|ctx| ctx.pair));
+                log::info!("[Neighbours] Introduce {from} at {} with {target} at {}", pair.remote, target_pair.remote);
+                self.send_control(now_ms, target_pair, NeighboursControlCmds::PunchIntroduce { peer: from, addrs: vec![pair.remote] });
+                self.send_control(
+                    now_ms,
+                    pair,
+                    NeighboursControlCmds::PunchIntroduce {
+                        peer: target,
+                        addrs: vec![target_pair.remote],
+                    },
+                );
+            }
+            NeighboursControlCmds::PunchIntroduce { peer, addrs } => {
+                if peer == self.node_id {
+                    return;
+                }
+                log::info!("[Neighbours] Introduced to {peer} at {addrs:?} by {from}, start punching");
+                self.connect_to(now_ms, peer, &addrs, Transport::Udp);
+            }
+            _ => {}
+        }
+    }
+
+    /// Each neighbour votes for the address which it observed, an address is accepted if it is voted by the majority of neighbours on the same bind addr
+    /// and by at least MIN_OBSERVED_VOTES neighbours, so a single neighbour can't make us advertise a wrong address
+    fn check_observed_addrs(&mut self) {
+        let mut observed_addrs = vec![];
+        for local in &self.bind_addrs {
+            let mut votes: HashMap<SocketAddr, usize> = HashMap::new();
+            let mut total = 0;
+            for (_, observed) in self.observations.values().filter(|(l, _)| l == local) {
+                *votes.entry(*observed).or_default() += 1;
+                total += 1;
+            }
+            if let Some((addr, _)) = votes.into_iter().find(|(_, count)| *count >= MIN_OBSERVED_VOTES && *count * 2 > total) {
+                observed_addrs.push(addr);
+            }
+        }
+        if observed_addrs != self.observed_addrs {
+            log::info!("[Neighbours] Observed addrs changed {:?} => {:?}", self.observed_addrs, observed_addrs);
+            self.observed_addrs = observed_addrs.clone();
+            self.queue.push_back(Output::ObservedAddrs(observed_addrs));
+        }
+    }
+
+    fn refill(limits: &NeighboursLimits, bucket: &mut IpBucket, now_ms: u64) -> u64 {
+        let elapsed = now_ms.saturating_sub(bucket.last_ms);
+        bucket.last_ms = now_ms.max(bucket.last_ms);
+        bucket.tokens = (bucket.tokens + elapsed * limits.handshakes_per_sec).min(limits.handshakes_burst * 1000);
+        bucket.tokens
+    }
+
+    fn allow_handshake(&mut self, now_ms: u64, ip: IpAddr) -> bool {
+        let burst = self.limits.handshakes_burst * 1000;
+        let bucket = self.ip_buckets.entry(ip).or_insert(IpBucket { tokens: burst, last_ms: now_ms });
+        if Self::refill(&self.limits, bucket, now_ms) >= 1000 {
+            bucket.tokens -= 1000;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn is_loaded(&mut self, now_ms: u64) -> bool {
+        if now_ms >= self.handshake_window.0 + HANDSHAKE_WINDOW_MS {
+            self.handshake_window = (now_ms, 0);
+        }
+        self.handshake_window.1 >= self.limits.cookie_threshold
+    }
+
+    /// Stateless cookie which is bound to the remote addr, node and session
+    fn cookie(&self, period: u64, pair: NetPair, from: NodeId, session: u64) -> u64 {
+        self.cookie_key.hash_one((period, pair, from, session))
+    }
+
+    fn valid_cookie(&self, now_ms: u64, pair: NetPair, from: NodeId, session: u64, cookie: u64) -> bool {
+        let period = now_ms / COOKIE_PERIOD_MS;
+        cookie == self.cookie(period, pair, from, session) || (period > 0 && cookie == self.cookie(period - 1, pair, from, session))
+    }
+
+    /// Remember a requester which echoed a valid cookie, so its next ConnectRequest of the same session passes the load check.
+    /// The set is bounded by max connections, so a cookie flood can't grow it.
+    fn on_connect_cookie(&mut self, now_ms: u64, pair: NetPair, from: NodeId, session: u64, cookie: u64) {
+        if self.connections.contains_key(&pair) {
+            return;
+        }
+        if !self.valid_cookie(now_ms, pair, from, session, cookie) {
+            log::warn!("[Neighbours] Invalid connect cookie from {from} at {pair}");
+            return;
+        }
+        if self.verified_cookies.len() >= self.limits.max_connections {
+            log::warn!("[Neighbours] Too many verified cookies, drop cookie from {from} at {pair}");
+            return;
+        }
+        self.verified_cookies.insert((pair, from, session), now_ms + COOKIE_PERIOD_MS);
+    }
+
+    /// Schedule next reconnect attempt when the last connection to a persistent node is gone
+    fn on_persistent_lost(&mut self, now_ms: u64, node: NodeId) {
+        if self.shutdown || self.connections.values().any(|conn| conn.dest_node() == node) {
+            return;
+        }
+        let persistent = return_if_none!(self.persistent.get_mut(&node));
+        if persistent.next_ms.is_some() {
+            return;
+        }
+        if !persistent.seed && self.reconnect.max_attempts.is_some_and(|max| persistent.attempts >= max) {
+            log::warn!("[Neighbours] Gave up persistent node {node} after {} attempts", persistent.attempts);
+            self.persistent.remove(&node);
+            self.dns_seeds.remove(&node);
+            self.queue.push_back(Output::Event(base::ConnectionEvent::GaveUp(node)));
+            return;
+        }
+        let delay = self.reconnect.delay_ms(persistent.attempts, self.random.next_u64());
+        persistent.attempts += 1;
+        persistent.next_ms = Some(now_ms + delay);
+        log::info!("[Neighbours] Persistent node {node} lost, reconnect after {delay} ms, attempt {}", persistent.attempts);
+    }
+
+    /// Close redundant connections to the node, only best `max_conns_per_node` connections by rtt are kept.
+    /// Only the side with lower node id prunes, for avoiding both sides closing different connections.
+    fn dedup_conns(&mut self, now_ms: u64, node: NodeId) {
+        let max = return_if_none!(self.max_conns_per_node);
+        if self.node_id > node {
+            return;
+        }
+        let mut measured = self
+            .neighbours
+            .values()
+            .filter(|ctx| ctx.node == node && !self.pruned.contains(&ctx.conn))
+            .filter_map(|ctx| Some((*self.rtts.get(&ctx.conn)?, ctx.conn, ctx.pair)))
+            .collect::<Vec<_>>();
+        if measured.len() <= max {
+            return;
+        }
+        measured.sort();
+        for (rtt, conn, pair) in measured.into_iter().skip(max) {
+            log::info!("[Neighbours] Prune redundant connection {conn} to {node} at {pair} with rtt {rtt} ms");
+            self.pruned.insert(conn);
+            self.pruned_pairs.insert(pair, node);
+            if let Some(connection) = self.connections.get_mut(&pair) {
+                connection.disconnect(now_ms, NeighboursDisconnectReason::Other);
+            }
+            if self.primaries.get(&node) == Some(&conn) {
+                self.primaries.remove(&node);
+                self.promote_primary(node);
+            }
+        }
+    }
+
+    /// Select the best remaining connection as primary of the node
+    fn promote_primary(&mut self, node: NodeId) {
+        let ctx = return_if_none!(self
+            .neighbours
+            .values()
+            .filter(|ctx| ctx.node == node && !self.pruned.contains(&ctx.conn))
+            .min_by_key(|ctx| (self.rtts.get(&ctx.conn).copied().unwrap_or(u32::MAX), ctx.conn))
+            .cloned());
+        log::info!("[Neighbours] Connection {} at {} is primary of {node}", ctx.conn, ctx.pair);
+        self.primaries.insert(node, ctx.conn);
+        self.queue.push_back(Output::Event(base::ConnectionEvent::Primary(ctx)));
+    }
+
+    fn send_control(&mut self, now_ms: u64, pair: NetPair, cmd: NeighboursControlCmds) {
+        let control = NeighboursControl::build(now_ms, self.node_id, cmd, &*self.authorization);
+        self.queue.push_back(Output::Control(pair, control));
+    }
+}
+
 impl TaskSwitcherChild<Output> for NeighboursManager {
     type Time = u64;
-    fn pop_output(&mut self, _now: u64) -> Option<Output> {
+    fn pop_output(&mut self, now_ms: u64) -> Option<Output> {
         if let Some(output) = self.queue.pop_front() {
             return Some(output);
         }
 
         let mut to_remove = Vec::new();
+        let mut observed_changed = false;
+        let mut need_primary = Vec::new();
+        let mut need_dedup = Vec::new();
         for (remote, conn) in self.connections.iter_mut() {
             while let Some(output) = conn.pop_output() {
                 match output {
@@ -149,25 +883,65 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
                             ConnectionEvent::Connected(encryptor, decryptor) => {
                                 let ctx = conn.ctx();
                                 self.neighbours.insert(ctx.conn, ctx.clone());
+                                self.fallbacks.remove(&ctx.node);
+                                if let Some(persistent) = self.persistent.get_mut(&ctx.node) {
+                                    persistent.attempts = 0;
+                                    persistent.next_ms = None;
+                                }
+                                if !self.primaries.contains_key(&ctx.node) {
+                                    need_primary.push(ctx.node);
+                                }
                                 Some(base::ConnectionEvent::Connected(ctx, SecureContext { encryptor, decryptor }))
                             }
-                            ConnectionEvent::ConnectError(_) => {
+                            ConnectionEvent::ConnectError(err) => {
                                 to_remove.push(*remote);
-                                None
+                                Some(base::ConnectionEvent::ConnectError(conn.dest_node(), err))
                             }
                             ConnectionEvent::ConnectTimeout => {
                                 to_remove.push(*remote);
-                                None
+                                Some(base::ConnectionEvent::ConnectError(conn.dest_node(), NeighboursConnectError::Timeout))
                             }
                             ConnectionEvent::Stats(stats) => {
                                 let ctx = conn.ctx();
+                                self.rtts.insert(ctx.conn, stats.rtt_ms);
+                                need_dedup.push(ctx.node);
                                 Some(base::ConnectionEvent::Stats(ctx, stats))
                             }
-                            ConnectionEvent::Disconnected => {
+                            ConnectionEvent::Capabilities(caps) => {
+                                self.queue.push_back(Output::Capabilities(conn.ctx().conn, caps));
+                                None
+                            }
+                            ConnectionEvent::Mtu(mtu) => {
+                                self.queue.push_back(Output::Mtu(conn.ctx().conn, mtu));
+                                None
+                            }
+                            ConnectionEvent::Congested(congested) => {
+                                self.queue.push_back(Output::Congested(conn.ctx().conn, congested));
+                                None
+                            }
+                            ConnectionEvent::Observed(addr) => {
+                                // stream remotes observe our ephemeral port, which is useless for others
+                                if remote.transport == Transport::Udp {
+                                    self.observations.insert(conn.ctx().conn, (remote.local, addr));
+                                    observed_changed = true;
+                                }
+                                None
+                            }
+                            ConnectionEvent::Disconnected(reason) => {
                                 let ctx = conn.ctx();
                                 self.neighbours.remove(&ctx.conn);
+                                observed_changed |= self.observations.remove(&ctx.conn).is_some();
+                                self.rtts.remove(&ctx.conn);
+                                self.pruned.remove(&ctx.conn);
+                                if !self.neighbours.values().any(|other| other.node == ctx.node) {
+                                    self.pruned_pairs.retain(|_, node| *node != ctx.node);
+                                }
+                                if self.primaries.get(&ctx.node) == Some(&ctx.conn) {
+                                    self.primaries.remove(&ctx.node);
+                                    need_primary.push(ctx.node);
+                                }
                                 to_remove.push(*remote);
-                                Some(base::ConnectionEvent::Disconnected(ctx))
+                                Some(base::ConnectionEvent::Disconnected(ctx, reason))
                             }
                         };
                         if let Some(event) = event {
@@ -176,43 +950,304 @@ impl TaskSwitcherChild<Output> for NeighboursManager {
                     }
                     connection::Output::Net(now_ms, remote, cmd) => {
                         log::debug!("[NeighboursManager] pop_output Net(remote: {:?}, cmd: {:?})", remote, cmd);
-                        self.queue.push_back(Output::Control(remote, NeighboursControl::build(now_ms, self.node_id, cmd, &*self.authorization)));
+                        let control = if let NeighboursControlCmds::PaddedPing { session, size, .. } = cmd {
+                            NeighboursControl::build_padded_ping(now_ms, self.node_id, session, size, &*self.authorization)
+                        } else {
+                            NeighboursControl::build(now_ms, self.node_id, cmd, &*self.authorization)
+                        };
+                        self.queue.push_back(Output::Control(remote, control));
                     }
                 }
             }
         }
 
+        if observed_changed {
+            self.check_observed_addrs();
+        }
+
+        for node in need_primary {
+            if !self.primaries.contains_key(&node) {
+                self.promote_primary(node);
+            }
+        }
+
+        for node in need_dedup {
+            self.dedup_conns(now_ms, node);
+        }
+
         for remote in to_remove {
-            self.connections.remove(&remote);
+            if let Some(conn) = self.connections.remove(&remote) {
+                self.on_persistent_lost(now_ms, conn.dest_node());
+            }
 
             if self.shutdown && self.connections.is_empty() {
                 self.queue.push_back(Output::ShutdownResponse);
             }
         }
 
+        // sockets are closed after the last controls over them
+        for addr in self.unbinding.drain(..) {
+            self.queue.push_back(Output::Unbind(addr));
+        }
+
         self.queue.pop_front()
     }
 }
 
-fn get_node_addr_dests(addr: NodeAddr) -> Vec<SocketAddr> {
+enum DestHost {
+    Ip(IpAddr),
+    Dns(String, DnsFamily),
+}
+
+/// Split NodeAddr into static UDP socket addrs, static stream socket addrs and dns seeds which need to be resolved.
+/// Stream addrs are `/tcp/{port}`, `/tcp/{port}/ws` for WebSocket or `/memory/{port}` for in-memory transport.
+fn get_node_addr_dests(addr: NodeAddr) -> (Vec<SocketAddr>, Vec<(Transport, SocketAddr)>, Vec<DnsSeed>) {
     let mut dests = Vec::new();
+    let mut stream_dests = Vec::new();
+    let mut seeds = Vec::new();
     log::info!("Connect to: addr {}", addr);
-    let mut dest_ip = None;
+    let mut dest_host = None;
+    let mut prev_tcp = false;
     for part in addr.multiaddr().iter() {
+        let is_tcp = matches!(part, Protocol::Tcp(_));
         match part {
             Protocol::Ip4(i) => {
-                dest_ip = Some(IpAddr::V4(i));
+                dest_host = Some(DestHost::Ip(IpAddr::V4(i)));
             }
             Protocol::Ip6(i) => {
-                dest_ip = Some(IpAddr::V6(i));
+                dest_host = Some(DestHost::Ip(IpAddr::V6(i)));
+            }
+            Protocol::Dns(name) => {
+                dest_host = Some(DestHost::Dns(name.to_string(), DnsFamily::Any));
             }
-            Protocol::Udp(port) => {
-                if let Some(ip) = dest_ip {
-                    dests.push(SocketAddr::new(ip, port));
+            Protocol::Dns4(name) => {
+                dest_host = Some(DestHost::Dns(name.to_string(), DnsFamily::V4));
+            }
+            Protocol::Dns6(name) => {
+                dest_host = Some(DestHost::Dns(name.to_string(), DnsFamily::V6));
+            }
+            Protocol::Udp(port) => match &dest_host {
+                Some(DestHost::Ip(ip)) => dests.push(SocketAddr::new(*ip, port)),
+                Some(DestHost::Dns(name, family)) => seeds.push(DnsSeed {
+                    name: name.clone(),
+                    family: *family,
+                    port,
+                }),
+                None => {}
+            },
+            Protocol::Tcp(port) => match &dest_host {
+                Some(DestHost::Ip(ip)) => stream_dests.push((Transport::Tcp, SocketAddr::new(*ip, port))),
+                Some(DestHost::Dns(name, _)) => log::warn!("[Neighbours] Tcp over dns seed {name} is not supported, ignore it"),
+                None => {}
+            },
+            Protocol::Ws(_) => {
+                if let Some((transport, _)) = stream_dests.last_mut().filter(|_| prev_tcp) {
+                    *transport = Transport::WebSocket;
                 }
             }
+            Protocol::Memory(port) => match (&dest_host, u16::try_from(port)) {
+                (Some(DestHost::Ip(ip)), Ok(port)) => stream_dests.push((Transport::Memory, SocketAddr::new(*ip, port))),
+                _ => log::warn!("[Neighbours] Memory addr need an ip host and an u16 port, ignore it"),
+            },
             _ => {}
         }
+        prev_tcp = is_tcp;
+    }
+    (dests, stream_dests, seeds)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::VecDeque,
+        net::{IpAddr, SocketAddr},
+        sync::Arc,
+    };
+
+    use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, Protocol};
+    use parking_lot::Mutex;
+    use rand::rngs::mock::StepRng;
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{DnsResolver, MockDnsResolver},
+        secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
+    };
+
+    use super::{Input, KeepaliveCfg, NeighboursLimits, NeighboursManager, Output, ReconnectCfg, DNS_REFRESH_MS};
+
+    fn create_manager(bind_addrs: &[&str]) -> NeighboursManager {
+        create_manager_with_resolver(bind_addrs, None)
+    }
+
+    fn create_manager_with_resolver(bind_addrs: &[&str], resolver: Option<Arc<dyn DnsResolver>>) -> NeighboursManager {
+        NeighboursManager::new(
+            1,
+            bind_addrs.iter().map(|addr| addr.parse().expect("Should parse")).collect(),
+            Arc::new(StaticKeyAuthorization::new("demo_key")),
+            Arc::new(HandshakeBuilderXDA),
+            resolver,
+            ReconnectCfg::default(),
+            KeepaliveCfg::default(),
+            None,
+            NeighboursLimits::default(),
+            Box::new(StepRng::new(1000, 5)),
+        )
+    }
+
+    /// Mock resolver which records resolving names and returns results which are pushed by the test
+    #[allow(clippy::type_complexity)]
+    fn mock_resolver() -> (Arc<dyn DnsResolver>, Arc<Mutex<Vec<String>>>, Arc<Mutex<VecDeque<(String, Vec<IpAddr>)>>>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let results = Arc::new(Mutex::new(VecDeque::new()));
+        let mut resolver = MockDnsResolver::new();
+        let requests_c = requests.clone();
+        resolver.expect_resolve().returning(move |name| requests_c.lock().push(name.to_string()));
+        let results_c = results.clone();
+        resolver.expect_poll().returning(move || results_c.lock().pop_front());
+        (Arc::new(resolver), requests, results)
+    }
+
+    fn dns_addr(node: u32, protocol: Protocol<'static>) -> NodeAddr {
+        let mut builder = NodeAddrBuilder::new(node);
+        builder.add_protocol(protocol);
+        builder.add_protocol(Protocol::Udp(2000));
+        builder.addr()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().expect("Should parse")
+    }
+
+    fn remotes(manager: &NeighboursManager) -> Vec<SocketAddr> {
+        let mut remotes = manager.connections.keys().map(|pair| pair.remote).collect::<Vec<_>>();
+        remotes.sort();
+        remotes.dedup();
+        remotes
+    }
+
+    #[test]
+    fn dns_connect_on_resolved() {
+        let (resolver, requests, results) = mock_resolver();
+        let mut manager = create_manager_with_resolver(&["127.0.0.1:1000"], Some(resolver));
+
+        manager.on_input(0, Input::ConnectTo(dns_addr(2, Protocol::Dns4("seed.local".into())), false));
+        assert_eq!(*requests.lock(), vec!["seed.local".to_string()]);
+        assert_eq!(remotes(&manager), vec![]);
+
+        results.lock().push_back(("seed.local".to_string(), vec![ip("10.0.0.1")]));
+        manager.on_tick(100, 1);
+        assert_eq!(remotes(&manager), vec!["10.0.0.1:2000".parse().expect("Should parse")]);
+    }
+
+    #[test]
+    fn dns_filter_by_family() {
+        let binds = ["127.0.0.1:1000", "[::1]:1000"];
+        let resolved = vec![ip("10.0.0.1"), ip("fd00::1")];
+        let cases = [
+            (Protocol::Dns4("seed.local".into()), vec!["10.0.0.1:2000"]),
+            (Protocol::Dns6("seed.local".into()), vec!["[fd00::1]:2000"]),
+            (Protocol::Dns("seed.local".into()), vec!["10.0.0.1:2000", "[fd00::1]:2000"]),
+        ];
+        for (protocol, expected) in cases {
+            let (resolver, _requests, results) = mock_resolver();
+            let mut manager = create_manager_with_resolver(&binds, Some(resolver));
+            manager.on_input(0, Input::ConnectTo(dns_addr(2, protocol), false));
+            results.lock().push_back(("seed.local".to_string(), resolved.clone()));
+            manager.on_tick(100, 1);
+            let expected = expected.iter().map(|addr| addr.parse().expect("Should parse")).collect::<Vec<SocketAddr>>();
+            assert_eq!(remotes(&manager), expected);
+        }
+    }
+
+    #[test]
+    fn dns_reconnect_after_addr_changed() {
+        let (resolver, requests, results) = mock_resolver();
+        let mut manager = create_manager_with_resolver(&["127.0.0.1:1000"], Some(resolver));
+
+        manager.on_input(0, Input::ConnectTo(dns_addr(2, Protocol::Dns4("seed.local".into())), false));
+        results.lock().push_back(("seed.local".to_string(), vec![ip("10.0.0.1")]));
+        manager.on_tick(100, 1);
+        assert_eq!(remotes(&manager), vec!["10.0.0.1:2000".parse().expect("Should parse")]);
+
+        // the name is resolved again after refresh interval and the new addr is connected
+        manager.on_tick(DNS_REFRESH_MS, 2);
+        assert_eq!(requests.lock().len(), 2);
+        results.lock().push_back(("seed.local".to_string(), vec![ip("10.0.0.2")]));
+        manager.on_tick(DNS_REFRESH_MS + 100, 3);
+        assert!(remotes(&manager).contains(&"10.0.0.2:2000".parse().expect("Should parse")));
+    }
+
+    #[test]
+    fn dns_keep_addrs_when_resolve_failed() {
+        let (resolver, requests, results) = mock_resolver();
+        let mut manager = create_manager_with_resolver(&["127.0.0.1:1000"], Some(resolver));
+
+        manager.on_input(0, Input::ConnectTo(dns_addr(2, Protocol::Dns4("seed.local".into())), false));
+        results.lock().push_back(("seed.local".to_string(), vec![ip("10.0.0.1")]));
+        manager.on_tick(100, 1);
+
+        manager.on_tick(DNS_REFRESH_MS, 2);
+        assert_eq!(requests.lock().len(), 2);
+        results.lock().push_back(("seed.local".to_string(), vec![]));
+        manager.on_tick(DNS_REFRESH_MS + 100, 3);
+        assert_eq!(manager.dns_cache.get("seed.local"), Some(&vec![ip("10.0.0.1")]));
+
+        // other nodes behind the same name still connect to cached addrs
+        let mut addr3 = NodeAddrBuilder::new(3);
+        addr3.add_protocol(Protocol::Dns4("seed.local".into()));
+        addr3.add_protocol(Protocol::Udp(3000));
+        manager.on_input(DNS_REFRESH_MS + 200, Input::ConnectTo(addr3.addr(), false));
+        assert!(manager
+            .connections
+            .iter()
+            .any(|(pair, conn)| conn.dest_node() == 3 && pair.remote == "10.0.0.1:3000".parse().expect("Should parse")));
+    }
+
+    fn observed_addrs(manager: &mut NeighboursManager) -> Option<Vec<std::net::SocketAddr>> {
+        let mut res = None;
+        while let Some(out) = manager.pop_output(0) {
+            if let Output::ObservedAddrs(addrs) = out {
+                res = Some(addrs);
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn observed_addr_need_two_agreeing_neighbours() {
+        let local = "192.168.1.10:10000".parse().expect("Should parse");
+        let public = "1.2.3.4:20000".parse().expect("Should parse");
+        let mut manager = create_manager(&["192.168.1.10:10000"]);
+
+        // single neighbour is not trusted
+        manager.observations.insert(ConnId::from_out(0, 1), (local, public));
+        manager.check_observed_addrs();
+        assert_eq!(observed_addrs(&mut manager), None);
+
+        manager.observations.insert(ConnId::from_out(0, 2), (local, public));
+        manager.check_observed_addrs();
+        assert_eq!(observed_addrs(&mut manager), Some(vec![public]));
+
+        // a tie is not a majority
+        let other = "1.2.3.4:30000".parse().expect("Should parse");
+        manager.observations.insert(ConnId::from_out(0, 3), (local, other));
+        manager.observations.insert(ConnId::from_out(0, 4), (local, other));
+        manager.check_observed_addrs();
+        assert_eq!(observed_addrs(&mut manager), Some(vec![]));
+    }
+
+    #[test]
+    fn add_bind_reject_addr_without_port() {
+        let mut manager = create_manager(&["192.168.1.10:10000"]);
+        while manager.pop_output(0).is_some() {}
+
+        manager.on_input(0, Input::AddBind("192.168.1.11:0".parse().expect("Should parse")));
+        assert!(manager.pop_output(0).is_none());
+        assert_eq!(manager.bind_addrs, vec!["192.168.1.10:10000".parse().expect("Should parse")]);
+
+        let addr = "192.168.1.11:10000".parse().expect("Should parse");
+        manager.on_input(0, Input::AddBind(addr));
+        assert!(matches!(manager.pop_output(0), Some(Output::Bind(a)) if a == addr));
     }
-    dests
 }
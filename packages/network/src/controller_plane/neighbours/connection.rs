@@ -1,16 +1,29 @@
-use std::{collections::VecDeque, fmt::Debug, sync::Arc};
+use std::{collections::VecDeque, fmt::Debug, net::SocketAddr, sync::Arc};
 
 use atm0s_sdn_identity::{ConnId, NodeId};
 
 use crate::{
-    base::{ConnectionCtx, ConnectionStats, Decryptor, Encryptor, HandshakeBuilder, HandshakeRequester, NeighboursConnectError, NeighboursControlCmds, NeighboursDisconnectReason},
+    base::{
+        ConnectionCtx, ConnectionStats, Decryptor, DisconnectReason, Encryptor, HandshakeBuilder, HandshakeRequester, NeighboursConnectError, NeighboursControlCmds, NeighboursDisconnectReason,
+        CAPABILITY_COOKIE, CAPABILITY_OBSERVED, DEFAULT_MTU, LOCAL_CAPABILITIES,
+    },
     data_plane::NetPair,
 };
 
+use super::{pmtu::PathMtuProber, Keepalive};
+
 const INIT_RTT_MS: u32 = 1000;
 const RETRY_CMD_MS: u64 = 1000;
 const CONNECT_TIMEOUT_MS: u64 = 30000; //we need connect more time
 const CONNECTION_TIMEOUT_MS: u64 = 10000;
+const STATS_INTERVAL_MS: u64 = 1000;
+const CAPABILITIES_RETRY: u8 = 5; //old nodes will not answer, so we only retry some times
+const LOSS_WINDOW: usize = 20;
+const OBSERVED_PING_INTERVAL: u64 = 10;
+/// Rtt samples for the base rtt of the link, which is the rtt without queueing
+const RTT_WINDOW: usize = 60;
+/// Link is congested when the rtt is bigger than the base rtt by this queueing delay
+const CONGESTION_QUEUE_DELAY_MS: u32 = 50;
 
 enum State {
     OutgoingWait {
@@ -33,6 +46,7 @@ enum State {
     },
     Disconnecting {
         at_ms: u64,
+        reason: NeighboursDisconnectReason,
     },
     Disconnected,
 }
@@ -42,7 +56,13 @@ pub enum ConnectionEvent {
     ConnectError(NeighboursConnectError),
     ConnectTimeout,
     Stats(ConnectionStats),
-    Disconnected,
+    Capabilities(u32),
+    Mtu(u16),
+    /// Link queueing delay is over or back under CONGESTION_QUEUE_DELAY_MS
+    Congested(bool),
+    /// Our address which is observed by remote node
+    Observed(SocketAddr),
+    Disconnected(DisconnectReason),
 }
 
 impl Debug for ConnectionEvent {
@@ -52,7 +72,11 @@ impl Debug for ConnectionEvent {
             ConnectionEvent::ConnectError(err) => write!(f, "ConnectError({:?})", err),
             ConnectionEvent::ConnectTimeout => write!(f, "ConnectTimeout"),
             ConnectionEvent::Stats(_) => write!(f, "Stats"),
-            ConnectionEvent::Disconnected => write!(f, "Disconnected"),
+            ConnectionEvent::Capabilities(caps) => write!(f, "Capabilities({})", caps),
+            ConnectionEvent::Mtu(mtu) => write!(f, "Mtu({})", mtu),
+            ConnectionEvent::Congested(congested) => write!(f, "Congested({})", congested),
+            ConnectionEvent::Observed(addr) => write!(f, "Observed({})", addr),
+            ConnectionEvent::Disconnected(reason) => write!(f, "Disconnected({:?})", reason),
         }
     }
 }
@@ -64,7 +88,11 @@ impl PartialEq for ConnectionEvent {
             (ConnectionEvent::ConnectError(err1), ConnectionEvent::ConnectError(err2)) => err1 == err2,
             (ConnectionEvent::ConnectTimeout, ConnectionEvent::ConnectTimeout) => true,
             (ConnectionEvent::Stats(_), ConnectionEvent::Stats(_)) => true,
-            (ConnectionEvent::Disconnected, ConnectionEvent::Disconnected) => true,
+            (ConnectionEvent::Capabilities(caps1), ConnectionEvent::Capabilities(caps2)) => caps1 == caps2,
+            (ConnectionEvent::Mtu(mtu1), ConnectionEvent::Mtu(mtu2)) => mtu1 == mtu2,
+            (ConnectionEvent::Congested(c1), ConnectionEvent::Congested(c2)) => c1 == c2,
+            (ConnectionEvent::Observed(addr1), ConnectionEvent::Observed(addr2)) => addr1 == addr2,
+            (ConnectionEvent::Disconnected(reason1), ConnectionEvent::Disconnected(reason2)) => reason1 == reason2,
             _ => false,
         }
     }
@@ -84,6 +112,26 @@ pub struct NeighbourConnection {
     state: State,
     output: VecDeque<Output>,
     handshake_builder: Arc<dyn HandshakeBuilder>,
+    remote_caps: Option<u32>,
+    /// remote node already received our capabilities
+    remote_received_caps: bool,
+    caps_sent: u8,
+    pmtu: PathMtuProber,
+    observed: Option<SocketAddr>,
+    /// cookie from ConnectRetry, which is echoed before following connect requests
+    cookie: Option<u64>,
+    /// highest ping seq which is answered
+    pong_seq: u64,
+    /// last pings are answered before next ping or not, for loss estimation
+    probes: VecDeque<bool>,
+    keepalive: Keepalive,
+    /// None for sending ping at next keepalive tick
+    last_ping_ms: Option<u64>,
+    /// Stats event is limited to once per STATS_INTERVAL_MS, because fast probes can create too many pongs
+    last_stats_ms: Option<u64>,
+    /// last rtt samples, the smallest one is used as base rtt
+    rtts: VecDeque<u32>,
+    congested: bool,
 }
 
 impl NeighbourConnection {
@@ -92,29 +140,59 @@ impl NeighbourConnection {
         let handshake = requester.create_public_request().expect("Should have handshake");
         let state = State::OutgoingWait { at_ms: now_ms, requester };
         Self {
-            conn: ConnId::from_out(0, session),
+            conn: ConnId::from_out(pair.transport.protocol(), session),
             local,
             node,
             pair,
             state,
             output: VecDeque::from([Output::Net(now_ms, pair, NeighboursControlCmds::ConnectRequest { to: node, session, handshake })]),
             handshake_builder,
+            remote_caps: None,
+            remote_received_caps: false,
+            caps_sent: 0,
+            pmtu: PathMtuProber::default(),
+            observed: None,
+            cookie: None,
+            pong_seq: 0,
+            probes: VecDeque::new(),
+            keepalive: Keepalive::default(),
+            last_ping_ms: None,
+            last_stats_ms: None,
+            rtts: VecDeque::new(),
+            congested: false,
         }
     }
 
     pub fn new_incoming(handshake_builder: Arc<dyn HandshakeBuilder>, local: NodeId, node: NodeId, session: u64, pair: NetPair, now_ms: u64) -> Self {
         let state: State = State::IncomingWait { at_ms: now_ms };
         Self {
-            conn: ConnId::from_in(0, session),
+            conn: ConnId::from_in(pair.transport.protocol(), session),
             local,
             node,
             pair,
             state,
             output: VecDeque::new(),
             handshake_builder,
+            remote_caps: None,
+            remote_received_caps: false,
+            caps_sent: 0,
+            pmtu: PathMtuProber::default(),
+            observed: None,
+            cookie: None,
+            pong_seq: 0,
+            probes: VecDeque::new(),
+            keepalive: Keepalive::default(),
+            last_ping_ms: None,
+            last_stats_ms: None,
+            rtts: VecDeque::new(),
+            congested: false,
         }
     }
 
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = keepalive;
+    }
+
     pub fn dest_node(&self) -> NodeId {
         self.node
     }
@@ -127,18 +205,13 @@ impl NeighbourConnection {
         }
     }
 
-    pub fn disconnect(&mut self, now_ms: u64) {
+    pub fn disconnect(&mut self, now_ms: u64, reason: NeighboursDisconnectReason) {
         match &mut self.state {
             State::OutgoingWait { .. } | State::Connected { .. } => {
-                log::info!("[NeighbourConnection] Sending disconnect request with remote {}", self.pair);
-                self.state = State::Disconnecting { at_ms: now_ms };
-                self.output.push_back(self.generate_control(
-                    now_ms,
-                    NeighboursControlCmds::DisconnectRequest {
-                        session: self.conn.session(),
-                        reason: NeighboursDisconnectReason::Other,
-                    },
-                ));
+                log::info!("[NeighbourConnection] Sending disconnect request with remote {}, reason {:?}", self.pair, reason);
+                self.state = State::Disconnecting { at_ms: now_ms, reason };
+                self.output
+                    .push_back(self.generate_control(now_ms, NeighboursControlCmds::DisconnectRequest { session: self.conn.session(), reason }));
             }
             _ => {
                 log::warn!("[NeighbourConnection] Invalid state for performing disconnect request with remote {}", self.pair);
@@ -146,6 +219,26 @@ impl NeighbourConnection {
         }
     }
 
+    /// Close without waiting for the remote answer, which is used when the local addr is gone and the answer can't arrive.
+    /// The disconnect request is still sent once, so the remote can close its side early. Returns false if the connection
+    /// is not established yet, in that case nothing is sent and no event is emitted.
+    pub fn close(&mut self, now_ms: u64, reason: NeighboursDisconnectReason) -> bool {
+        match &self.state {
+            State::Connected { .. } | State::Disconnecting { .. } => {
+                log::info!("[NeighbourConnection] Closing connection with remote {}, reason {:?}", self.pair, reason);
+                self.output
+                    .push_back(self.generate_control(now_ms, NeighboursControlCmds::DisconnectRequest { session: self.conn.session(), reason }));
+                self.state = State::Disconnected;
+                self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Local)));
+                true
+            }
+            _ => {
+                self.state = State::Disconnected;
+                false
+            }
+        }
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         match &mut self.state {
             State::OutgoingWait { at_ms, requester } => {
@@ -155,6 +248,10 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Connection timeout to {} after {} ms", self.pair, CONNECT_TIMEOUT_MS);
                 } else if now_ms - *at_ms >= RETRY_CMD_MS {
                     if let Ok(request_buf) = requester.create_public_request() {
+                        if let Some(cookie) = self.cookie {
+                            self.output
+                                .push_back(self.generate_control(now_ms, NeighboursControlCmds::ConnectCookie { session: self.conn.session(), cookie }));
+                        }
                         self.output.push_back(self.generate_control(
                             now_ms,
                             NeighboursControlCmds::ConnectRequest {
@@ -176,40 +273,56 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Connection timeout from {} after {} ms", self.pair, CONNECT_TIMEOUT_MS);
                 }
             }
-            State::Connected { ping_seq, last_pong_ms, .. } => {
-                if now_ms - *last_pong_ms >= CONNECTION_TIMEOUT_MS {
-                    log::warn!("[NeighbourConnection] Connection timeout {} after a while not received pong, last {last_pong_ms}", self.pair);
-                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
-                } else {
-                    log::debug!("[NeighbourConnection] Send ping {}", self.pair);
-                    *ping_seq += 1;
-                    let cmd = NeighboursControlCmds::Ping {
-                        session: self.conn.session(),
-                        seq: *ping_seq,
-                        sent_ms: now_ms,
-                    };
-                    self.output.push_back(self.generate_control(now_ms, cmd));
+            State::Connected { .. } => {
+                if let Keepalive::Ping { interval_ms, timeout_ms } = self.keepalive {
+                    self.keepalive(now_ms, interval_ms, timeout_ms);
                 }
             }
-            State::Disconnecting { at_ms } => {
+            State::Disconnecting { at_ms, reason } => {
                 if now_ms - *at_ms >= CONNECTION_TIMEOUT_MS {
                     self.state = State::Disconnected;
-                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
+                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Local)));
                     log::warn!("[NeighbourConnection] Disconnect request timeout {} after {} ms", self.pair, CONNECTION_TIMEOUT_MS);
                 } else {
                     *at_ms = now_ms;
-                    self.output.push_back(self.generate_control(
-                        now_ms,
-                        NeighboursControlCmds::DisconnectRequest {
-                            session: self.conn.session(),
-                            reason: NeighboursDisconnectReason::Other,
-                        },
-                    ));
+                    let reason = *reason;
+                    self.output
+                        .push_back(self.generate_control(now_ms, NeighboursControlCmds::DisconnectRequest { session: self.conn.session(), reason }));
                     log::info!("[NeighbourConnection] Resend disconnect request {}", self.pair);
                 }
             }
             _ => {}
         }
+
+        if matches!(self.state, State::Connected { .. }) && (self.remote_caps.is_none() || !self.remote_received_caps) && self.caps_sent < CAPABILITIES_RETRY {
+            self.caps_sent += 1;
+            let cmd = NeighboursControlCmds::Capabilities {
+                session: self.conn.session(),
+                caps: LOCAL_CAPABILITIES,
+                received: self.remote_caps.is_some(),
+            };
+            self.output.push_back(self.generate_control(now_ms, cmd));
+        }
+
+        if matches!(self.state, State::Connected { .. }) {
+            if let Some(size) = self.pmtu.on_tick(now_ms) {
+                // padding will be filled when building NeighboursControl, because we need to know the size of whole datagram
+                let cmd = NeighboursControlCmds::PaddedPing {
+                    session: self.conn.session(),
+                    size,
+                    padding: vec![],
+                };
+                self.output.push_back(self.generate_control(now_ms, cmd));
+            }
+            self.process_mtu_changed();
+        }
+    }
+
+    /// Called with a short interval for connections which are using fast probe mode
+    pub fn on_fast_tick(&mut self, now_ms: u64) {
+        if let Keepalive::FastProbe { interval_ms, misses } = self.keepalive {
+            self.keepalive(now_ms, interval_ms, interval_ms * misses as u64);
+        }
     }
 
     pub fn on_input(&mut self, now_ms: u64, from: NodeId, cmd: NeighboursControlCmds) {
@@ -225,7 +338,11 @@ impl NeighbourConnection {
                                     self.state = State::Connected {
                                         last_pong_ms: now_ms,
                                         ping_seq: 0,
-                                        stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                        stats: ConnectionStats {
+                                            rtt_ms: INIT_RTT_MS,
+                                            mtu: DEFAULT_MTU,
+                                            ..Default::default()
+                                        },
                                         handshake: Some((handshake, response.clone(), session)),
                                     };
                                     log::info!("[NeighbourConnection] Connected {} as incoming conn", self.pair);
@@ -255,7 +372,11 @@ impl NeighbourConnection {
                                         self.state = State::Connected {
                                             last_pong_ms: now_ms,
                                             ping_seq: 0,
-                                            stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                            stats: ConnectionStats {
+                                                rtt_ms: INIT_RTT_MS,
+                                                mtu: DEFAULT_MTU,
+                                                ..Default::default()
+                                            },
                                             handshake: Some((handshake, response.clone(), session)),
                                         };
                                         log::info!("[NeighbourConnection] Connected {} as incoming conn", self.pair);
@@ -324,7 +445,11 @@ impl NeighbourConnection {
                                     self.state = State::Connected {
                                         last_pong_ms: now_ms,
                                         ping_seq: 0,
-                                        stats: ConnectionStats { rtt_ms: INIT_RTT_MS },
+                                        stats: ConnectionStats {
+                                            rtt_ms: INIT_RTT_MS,
+                                            mtu: DEFAULT_MTU,
+                                            ..Default::default()
+                                        },
                                         handshake: None,
                                     };
                                     log::info!("Connected to {} as outgoing conn", self.pair);
@@ -335,6 +460,12 @@ impl NeighbourConnection {
                                     self.output.push_back(Output::Event(ConnectionEvent::ConnectError(NeighboursConnectError::InvalidData)));
                                 }
                             },
+                            (_, Err(err @ (NeighboursConnectError::Denied | NeighboursConnectError::NotAllowed))) => {
+                                // rejected by remote access list, retrying is useless
+                                log::warn!("Connect rejected by {}: {:?}", self.pair, err);
+                                self.state = State::ConnectError(err);
+                                self.output.push_back(Output::Event(ConnectionEvent::ConnectError(err)));
+                            }
                             (_, Err(err)) => {
                                 // We don't need to fire error here, we will reconnect utils timeout
                                 log::warn!("Connect response error from {}: {:?}", self.pair, err);
@@ -351,6 +482,10 @@ impl NeighbourConnection {
                 if session == self.conn.session() {
                     if let State::Connected { .. } = &self.state {
                         self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::Pong { session, seq, sent_ms }));
+                        // resend periodically because the first one can be lost
+                        if seq % OBSERVED_PING_INTERVAL == 0 {
+                            self.send_observed(now_ms);
+                        }
                     } else {
                         log::warn!("[NeighbourConnection] Invalid state, should be Connected for ping from {}", self.pair);
                     }
@@ -358,13 +493,28 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Invalid session in ping from {}", self.pair);
                 }
             }
-            NeighboursControlCmds::Pong { session, sent_ms, .. } => {
+            NeighboursControlCmds::Pong { session, seq, sent_ms } => {
                 if session == self.conn.session() {
                     if let State::Connected { last_pong_ms, stats, .. } = &mut self.state {
                         *last_pong_ms = now_ms;
+                        self.pong_seq = self.pong_seq.max(seq);
                         if sent_ms <= now_ms {
                             stats.rtt_ms = (now_ms - sent_ms) as u32;
-                            self.output.push_back(Output::Event(ConnectionEvent::Stats(stats.clone())));
+                            if self.rtts.len() == RTT_WINDOW {
+                                self.rtts.pop_front();
+                            }
+                            self.rtts.push_back(stats.rtt_ms);
+                            let base_rtt = self.rtts.iter().min().copied().unwrap_or(stats.rtt_ms);
+                            let congested = stats.rtt_ms > base_rtt + CONGESTION_QUEUE_DELAY_MS;
+                            if congested != self.congested {
+                                log::info!("[NeighbourConnection] Link {} congested {congested}, rtt {} base {base_rtt}", self.pair, stats.rtt_ms);
+                                self.congested = congested;
+                                self.output.push_back(Output::Event(ConnectionEvent::Congested(congested)));
+                            }
+                            if !matches!(self.last_stats_ms, Some(at) if now_ms < at + STATS_INTERVAL_MS) {
+                                self.last_stats_ms = Some(now_ms);
+                                self.output.push_back(Output::Event(ConnectionEvent::Stats(stats.clone())));
+                            }
                             log::trace!("Received pong from {} after {}", self.pair, stats.rtt_ms);
                         } else {
                             log::warn!("[NeighbourConnection] Invalid sent_ms in pong from {}", self.pair);
@@ -376,12 +526,73 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Invalid session in ping from {}", self.pair);
                 }
             }
-            NeighboursControlCmds::DisconnectRequest { session, .. } => {
+            NeighboursControlCmds::PaddedPing { session, size, .. } => {
+                if session == self.conn.session() {
+                    if let State::Connected { .. } = &self.state {
+                        self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::PaddedPong { session, size }));
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for padded ping from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in padded ping from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::PaddedPong { session, size } => {
+                if session == self.conn.session() {
+                    if let State::Connected { .. } = &self.state {
+                        self.pmtu.on_ack(now_ms, size);
+                        self.process_mtu_changed();
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for padded pong from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in padded pong from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::ConnectRetry { session, cookie, caps } => {
+                if session != self.conn.session() {
+                    log::warn!("[NeighbourConnection] Invalid session in connect retry from {}", self.pair);
+                    return;
+                }
+                if caps & CAPABILITY_COOKIE == 0 {
+                    log::warn!("[NeighbourConnection] Connect retry without cookie capability from {}", self.pair);
+                    return;
+                }
+                if let State::OutgoingWait { requester, .. } = &self.state {
+                    log::info!("[NeighbourConnection] Connect retry with cookie from {}", self.pair);
+                    self.cookie = Some(cookie);
+                    if let Ok(handshake) = requester.create_public_request() {
+                        self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::ConnectCookie { session, cookie }));
+                        let cmd = NeighboursControlCmds::ConnectRequest { to: self.node, session, handshake };
+                        self.output.push_back(self.generate_control(now_ms, cmd));
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid state, should be OutgoingWait for connect retry from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::PunchRequest { .. } | NeighboursControlCmds::PunchIntroduce { .. } => {
+                log::warn!("[NeighbourConnection] Punch cmd should be handled by NeighboursManager {}", self.pair);
+            }
+            NeighboursControlCmds::ConnectCookie { .. } => {
+                log::debug!("[NeighbourConnection] Ignore connect cookie for existing connection {}", self.pair);
+            }
+            NeighboursControlCmds::Observed { session, addr } => {
+                if session == self.conn.session() {
+                    if let State::Connected { .. } = &self.state {
+                        self.on_observed(addr);
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for observed from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in observed from {}", self.pair);
+                }
+            }
+            NeighboursControlCmds::DisconnectRequest { session, reason } => {
                 if session == self.conn.session() {
                     self.state = State::Disconnected;
                     self.output.push_back(self.generate_control(now_ms, NeighboursControlCmds::DisconnectResponse { session }));
-                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
-                    log::info!("[NeighbourConnection] Disconnect request from {}", self.pair);
+                    self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Remote(reason))));
+                    log::info!("[NeighbourConnection] Disconnect request from {}, reason {:?}", self.pair, reason);
                 } else {
                     log::warn!("[NeighbourConnection] Invalid session in disconnect request from {}", self.pair);
                 }
@@ -390,7 +601,7 @@ impl NeighbourConnection {
                 if session == self.conn.session() {
                     if let State::Disconnecting { .. } = self.state {
                         self.state = State::Disconnected;
-                        self.output.push_back(Output::Event(ConnectionEvent::Disconnected));
+                        self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Local)));
                         log::info!("[NeighbourConnection] Disconnected response from {}", self.pair);
                     } else {
                         log::warn!("[NeighbourConnection] Invalid state, should be Disconnecting for disconnect response from {}", self.pair);
@@ -399,6 +610,44 @@ impl NeighbourConnection {
                     log::warn!("[NeighbourConnection] Invalid session in disconnect response from {}", self.pair);
                 }
             }
+            NeighboursControlCmds::Capabilities { session, caps, received } => {
+                if session == self.conn.session() {
+                    if let State::Connected { .. } = &self.state {
+                        let first_time = self.remote_caps.is_none();
+                        if first_time {
+                            log::info!("[NeighbourConnection] Received capabilities {caps} from {}", self.pair);
+                            self.remote_caps = Some(caps);
+                            self.output.push_back(Output::Event(ConnectionEvent::Capabilities(caps)));
+                            if let State::Connected { stats, .. } = &mut self.state {
+                                stats.caps = caps;
+                                self.output.push_back(Output::Event(ConnectionEvent::Stats(stats.clone())));
+                            }
+                            self.send_observed(now_ms);
+                        }
+                        self.remote_received_caps |= received;
+                        if !received || first_time {
+                            let cmd = NeighboursControlCmds::Capabilities {
+                                session,
+                                caps: LOCAL_CAPABILITIES,
+                                received: true,
+                            };
+                            self.output.push_back(self.generate_control(now_ms, cmd));
+                        }
+                    } else {
+                        log::warn!("[NeighbourConnection] Invalid state, should be Connected for capabilities from {}", self.pair);
+                    }
+                } else {
+                    log::warn!("[NeighbourConnection] Invalid session in capabilities from {}", self.pair);
+                }
+            }
+        }
+    }
+
+    /// Add bytes which are sent and received over this connection by data plane workers
+    pub fn add_traffic(&mut self, sent_bytes: u64, recv_bytes: u64) {
+        if let State::Connected { stats, .. } = &mut self.state {
+            stats.sent_bytes += sent_bytes;
+            stats.recv_bytes += recv_bytes;
         }
     }
 
@@ -406,13 +655,76 @@ impl NeighbourConnection {
         self.output.pop_front()
     }
 
+    /// Disconnect if no pong after timeout_ms, otherwise send ping if interval_ms is elapsed
+    fn keepalive(&mut self, now_ms: u64, interval_ms: u64, timeout_ms: u64) {
+        let State::Connected { ping_seq, last_pong_ms, stats, .. } = &mut self.state else {
+            return;
+        };
+        if now_ms - *last_pong_ms >= timeout_ms {
+            log::warn!("[NeighbourConnection] Connection timeout {} after a while not received pong, last {last_pong_ms}", self.pair);
+            self.state = State::Disconnected;
+            self.output.push_back(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Timeout)));
+            return;
+        }
+        if self.last_ping_ms.is_some_and(|at| now_ms < at + interval_ms) {
+            return;
+        }
+        self.last_ping_ms = Some(now_ms);
+        log::debug!("[NeighbourConnection] Send ping {}", self.pair);
+        if *ping_seq > 0 {
+            if self.probes.len() == LOSS_WINDOW {
+                self.probes.pop_front();
+            }
+            self.probes.push_back(self.pong_seq >= *ping_seq);
+            let lost = self.probes.iter().filter(|answered| !**answered).count();
+            stats.loss_percent = (lost * 100 / self.probes.len()) as u8;
+        }
+        *ping_seq += 1;
+        let cmd = NeighboursControlCmds::Ping {
+            session: self.conn.session(),
+            seq: *ping_seq,
+            sent_ms: now_ms,
+        };
+        self.output.push_back(self.generate_control(now_ms, cmd));
+    }
+
+    fn process_mtu_changed(&mut self) {
+        if let Some(mtu) = self.pmtu.take_changed() {
+            if let State::Connected { stats, .. } = &mut self.state {
+                log::info!("[NeighbourConnection] Path mtu to {} changed to {mtu}", self.pair);
+                stats.mtu = mtu;
+                self.output.push_back(Output::Event(ConnectionEvent::Stats(stats.clone())));
+                self.output.push_back(Output::Event(ConnectionEvent::Mtu(mtu)));
+            }
+        }
+    }
+
+    /// Tell the remote which address we see it from, only if it can parse Observed
+    fn send_observed(&mut self, now_ms: u64) {
+        if self.remote_caps.is_some_and(|caps| caps & CAPABILITY_OBSERVED != 0) {
+            let cmd = NeighboursControlCmds::Observed {
+                session: self.conn.session(),
+                addr: self.pair.remote,
+            };
+            self.output.push_back(self.generate_control(now_ms, cmd));
+        }
+    }
+
+    fn on_observed(&mut self, observed: SocketAddr) {
+        if self.observed != Some(observed) {
+            log::info!("[NeighbourConnection] Observed address {observed} by {}", self.pair);
+            self.observed = Some(observed);
+            self.output.push_back(Output::Event(ConnectionEvent::Observed(observed)));
+        }
+    }
+
     fn generate_control(&self, now_ms: u64, control: NeighboursControlCmds) -> Output {
         Output::Net(now_ms, self.pair, control)
     }
 
     fn switch_to_incoming(&mut self, session: u64) {
         let old = self.conn;
-        self.conn = ConnId::from_in(0, session);
+        self.conn = ConnId::from_in(self.pair.transport.protocol(), session);
         log::warn!("Switching to incoming connection from {}, rewriting conn from {old} to {}", self.pair, self.conn);
     }
 }
@@ -444,7 +756,7 @@ mod tests {
                 NeighboursControlCmds::ConnectRequest {
                     to: 2,
                     session: 1000,
-                    handshake: vec![1, 2, 3]
+                    handshake: vec![1, 2, 3],
                 }
             ))
         );
@@ -464,6 +776,281 @@ mod tests {
         );
     }
 
+    #[test]
+    fn should_close_without_waiting_answer() {
+        let mut client_handshake = MockHandshakeBuilder::default();
+        client_handshake.expect_requester().returning(move || {
+            let mut requester = MockHandshakeRequester::default();
+            requester.expect_create_public_request().returning(|| Ok(vec![1, 2, 3]));
+            requester
+                .expect_process_public_response()
+                .return_once(move |_| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))));
+            Box::new(requester)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+
+        // not established connection is closed silently
+        let mut pending = NeighbourConnection::new_outgoing(Arc::new(client_handshake), 1, 2, 1000, pair, 100);
+        assert!(pending.pop_output().is_some());
+        assert!(!pending.close(200, NeighboursDisconnectReason::Other));
+        assert_eq!(pending.pop_output(), None);
+
+        let mut client_handshake = MockHandshakeBuilder::default();
+        client_handshake.expect_requester().returning(move || {
+            let mut requester = MockHandshakeRequester::default();
+            requester.expect_create_public_request().returning(|| Ok(vec![1, 2, 3]));
+            requester
+                .expect_process_public_response()
+                .return_once(move |_| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()))));
+            Box::new(requester)
+        });
+        let mut client = NeighbourConnection::new_outgoing(Arc::new(client_handshake), 1, 2, 1000, pair, 100);
+        client.on_input(
+            100,
+            2,
+            NeighboursControlCmds::ConnectResponse {
+                session: 1000,
+                result: Ok(vec![2, 3, 4]),
+            },
+        );
+        while client.pop_output().is_some() {}
+
+        assert!(client.close(200, NeighboursDisconnectReason::Other));
+        assert_eq!(
+            client.pop_output(),
+            Some(Output::Net(
+                200,
+                pair,
+                NeighboursControlCmds::DisconnectRequest {
+                    session: 1000,
+                    reason: NeighboursDisconnectReason::Other
+                }
+            ))
+        );
+        assert_eq!(client.pop_output(), Some(Output::Event(ConnectionEvent::Disconnected(DisconnectReason::Local))));
+        assert_eq!(client.pop_output(), None);
+
+        // no more disconnect retries
+        client.on_tick(1200);
+        assert_eq!(client.pop_output(), None);
+    }
+
+    #[test]
+    fn should_resend_connect_request_with_cookie() {
+        let mut client_handshake = MockHandshakeBuilder::default();
+        client_handshake.expect_requester().returning(move || {
+            let mut requester = MockHandshakeRequester::default();
+            requester.expect_create_public_request().returning(|| Ok(vec![1, 2, 3]));
+            Box::new(requester)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut client = NeighbourConnection::new_outgoing(Arc::new(client_handshake), 1, 2, 1000, pair, 100);
+        assert!(client.pop_output().is_some());
+
+        // wrong session or missing capability is ignored
+        client.on_input(
+            200,
+            2,
+            NeighboursControlCmds::ConnectRetry {
+                session: 1001,
+                cookie: 123,
+                caps: CAPABILITY_COOKIE,
+            },
+        );
+        client.on_input(200, 2, NeighboursControlCmds::ConnectRetry { session: 1000, cookie: 123, caps: 0 });
+        assert_eq!(client.pop_output(), None);
+
+        client.on_input(
+            200,
+            2,
+            NeighboursControlCmds::ConnectRetry {
+                session: 1000,
+                cookie: 123,
+                caps: CAPABILITY_COOKIE,
+            },
+        );
+        let cookie = NeighboursControlCmds::ConnectCookie { session: 1000, cookie: 123 };
+        let request = NeighboursControlCmds::ConnectRequest {
+            to: 2,
+            session: 1000,
+            handshake: vec![1, 2, 3],
+        };
+        assert_eq!(client.pop_output(), Some(Output::Net(200, pair, cookie.clone())));
+        assert_eq!(client.pop_output(), Some(Output::Net(200, pair, request.clone())));
+
+        // retries are also preceded by the cookie
+        client.on_tick(1200);
+        assert_eq!(client.pop_output(), Some(Output::Net(1200, pair, cookie)));
+        assert_eq!(client.pop_output(), Some(Output::Net(1200, pair, request)));
+    }
+
+    #[test]
+    fn should_estimate_loss_from_unanswered_pings() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        server.on_input(
+            100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+            },
+        );
+
+        let last_stats = |server: &mut NeighbourConnection| {
+            let mut res = None;
+            while let Some(out) = server.pop_output() {
+                if let Output::Event(ConnectionEvent::Stats(stats)) = out {
+                    res = Some(stats);
+                }
+            }
+            res
+        };
+
+        // ping 1 and 2 are lost
+        server.on_tick(1000);
+        server.on_tick(2000);
+        server.on_tick(3000);
+        assert_eq!(last_stats(&mut server), None);
+        let pong = |seq: u64, sent_ms: u64| NeighboursControlCmds::Pong { session: 1000, seq, sent_ms };
+        server.on_input(3010, 2, pong(3, 3000));
+        let stats = last_stats(&mut server).expect("Should have stats");
+        assert_eq!(stats.rtt_ms, 10);
+        assert_eq!(stats.loss_percent, 100);
+
+        // ping 3 is answered
+        server.on_tick(4000);
+        server.on_input(4010, 2, pong(4, 4000));
+        assert_eq!(last_stats(&mut server).expect("Should have stats").loss_percent, 66);
+
+        server.add_traffic(100, 200);
+        server.on_tick(5000);
+        server.on_input(5010, 2, pong(5, 5000));
+        let stats = last_stats(&mut server).expect("Should have stats");
+        assert_eq!(stats.loss_percent, 50);
+        assert_eq!((stats.sent_bytes, stats.recv_bytes), (100, 200));
+    }
+
+    #[test]
+    fn should_detect_congestion_by_queue_delay() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        server.on_input(
+            100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+            },
+        );
+
+        let congested = |server: &mut NeighbourConnection| {
+            let mut res = None;
+            while let Some(out) = server.pop_output() {
+                if let Output::Event(ConnectionEvent::Congested(congested)) = out {
+                    res = Some(congested);
+                }
+            }
+            res
+        };
+        let pong = |seq: u64, sent_ms: u64| NeighboursControlCmds::Pong { session: 1000, seq, sent_ms };
+
+        server.on_tick(1000);
+        server.on_input(1020, 2, pong(1, 1000));
+        assert_eq!(congested(&mut server), None);
+
+        // small jitter is not congestion
+        server.on_tick(2000);
+        server.on_input(2000 + 20 + CONGESTION_QUEUE_DELAY_MS as u64, 2, pong(2, 2000));
+        assert_eq!(congested(&mut server), None);
+
+        server.on_tick(3000);
+        server.on_input(3000 + 21 + CONGESTION_QUEUE_DELAY_MS as u64, 2, pong(3, 3000));
+        assert_eq!(congested(&mut server), Some(true));
+
+        // only changes are reported
+        server.on_tick(4000);
+        server.on_input(4200, 2, pong(4, 4000));
+        assert_eq!(congested(&mut server), None);
+
+        server.on_tick(5000);
+        server.on_input(5030, 2, pong(5, 5000));
+        assert_eq!(congested(&mut server), Some(false));
+    }
+
+    #[test]
+    fn should_disconnect_after_fast_probe_misses() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        server.set_keepalive(Keepalive::FastProbe { interval_ms: 50, misses: 3 });
+        server.on_input(
+            100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+            },
+        );
+
+        let pings_and_disconnected = |server: &mut NeighbourConnection| {
+            let mut pings = vec![];
+            let mut disconnected = None;
+            while let Some(out) = server.pop_output() {
+                match out {
+                    Output::Net(_, _, NeighboursControlCmds::Ping { seq, .. }) => pings.push(seq),
+                    Output::Event(ConnectionEvent::Disconnected(reason)) => disconnected = Some(reason),
+                    _ => {}
+                }
+            }
+            (pings, disconnected)
+        };
+        pings_and_disconnected(&mut server);
+
+        // slow tick don't ping in fast probe mode
+        server.on_tick(150);
+        assert_eq!(pings_and_disconnected(&mut server), (vec![], None));
+
+        server.on_fast_tick(150);
+        server.on_fast_tick(175);
+        server.on_input(180, 2, NeighboursControlCmds::Pong { session: 1000, seq: 1, sent_ms: 150 });
+        server.on_fast_tick(200);
+        assert_eq!(pings_and_disconnected(&mut server).0, vec![1, 2]);
+
+        // 3 probe intervals without pong
+        server.on_fast_tick(250);
+        server.on_fast_tick(300);
+        assert_eq!(pings_and_disconnected(&mut server), (vec![3, 4], None));
+        server.on_fast_tick(330);
+        assert_eq!(pings_and_disconnected(&mut server), (vec![], Some(DisconnectReason::Timeout)));
+    }
+
     #[test]
     fn should_handle_incoming_connect_correct() {
         let mut server_handshake = MockHandshakeBuilder::default();
@@ -497,7 +1084,7 @@ mod tests {
                 pair,
                 NeighboursControlCmds::ConnectResponse {
                     session: 1000,
-                    result: Ok(vec![1, 2, 3])
+                    result: Ok(vec![1, 2, 3]),
                 }
             ))
         );
@@ -520,7 +1107,7 @@ mod tests {
                 pair,
                 NeighboursControlCmds::ConnectResponse {
                     session: 1000,
-                    result: Err(NeighboursConnectError::InvalidData)
+                    result: Err(NeighboursConnectError::InvalidData),
                 }
             ))
         );
@@ -543,10 +1130,210 @@ mod tests {
                 pair,
                 NeighboursControlCmds::ConnectResponse {
                     session: 1000,
-                    result: Ok(vec![1, 2, 3])
+                    result: Ok(vec![1, 2, 3]),
                 }
             ))
         );
         assert_eq!(server.pop_output(), None);
     }
+
+    #[test]
+    fn should_exchange_capabilities_after_connected() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        server.on_input(
+            1100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+            },
+        );
+        while server.pop_output().is_some() {}
+
+        fn pop_caps(conn: &mut NeighbourConnection) -> Vec<Output> {
+            let mut res = vec![];
+            while let Some(out) = conn.pop_output() {
+                if matches!(out, Output::Event(ConnectionEvent::Capabilities(_)) | Output::Net(_, _, NeighboursControlCmds::Capabilities { .. })) {
+                    res.push(out);
+                }
+            }
+            res
+        }
+
+        // should advertise local capabilities on tick
+        server.on_tick(1200);
+        assert_eq!(
+            pop_caps(&mut server),
+            vec![Output::Net(
+                1200,
+                pair,
+                NeighboursControlCmds::Capabilities {
+                    session: 1000,
+                    caps: LOCAL_CAPABILITIES,
+                    received: false
+                }
+            )]
+        );
+
+        // should emit event and ack when received remote capabilities
+        server.on_input(
+            1300,
+            2,
+            NeighboursControlCmds::Capabilities {
+                session: 1000,
+                caps: 1,
+                received: true,
+            },
+        );
+        assert_eq!(
+            pop_caps(&mut server),
+            vec![
+                Output::Event(ConnectionEvent::Capabilities(1)),
+                Output::Net(
+                    1300,
+                    pair,
+                    NeighboursControlCmds::Capabilities {
+                        session: 1000,
+                        caps: LOCAL_CAPABILITIES,
+                        received: true
+                    }
+                )
+            ]
+        );
+
+        // both sides known, should not send anymore
+        server.on_tick(1400);
+        assert_eq!(pop_caps(&mut server), vec![]);
+    }
+
+    #[test]
+    fn should_exchange_observed_with_capable_neighbour() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        server.on_input(
+            1100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+            },
+        );
+        while server.pop_output().is_some() {}
+
+        fn pop_observed(conn: &mut NeighbourConnection) -> Vec<Output> {
+            let mut res = vec![];
+            while let Some(out) = conn.pop_output() {
+                if matches!(out, Output::Event(ConnectionEvent::Observed(_)) | Output::Net(_, _, NeighboursControlCmds::Observed { .. })) {
+                    res.push(out);
+                }
+            }
+            res
+        }
+
+        // should tell remote its address after knowing it can parse Observed
+        server.on_input(
+            1200,
+            2,
+            NeighboursControlCmds::Capabilities {
+                session: 1000,
+                caps: CAPABILITY_OBSERVED,
+                received: true,
+            },
+        );
+        let observed = NeighboursControlCmds::Observed { session: 1000, addr: pair.remote };
+        assert_eq!(pop_observed(&mut server), vec![Output::Net(1200, pair, observed.clone())]);
+
+        // and resend it periodically with pongs
+        server.on_input(1300, 2, NeighboursControlCmds::Ping { session: 1000, seq: 1, sent_ms: 1300 });
+        assert_eq!(pop_observed(&mut server), vec![]);
+        server.on_input(
+            1400,
+            2,
+            NeighboursControlCmds::Ping {
+                session: 1000,
+                seq: 10,
+                sent_ms: 1400,
+            },
+        );
+        assert_eq!(pop_observed(&mut server), vec![Output::Net(1400, pair, observed)]);
+
+        // event is emitted only when the observed address is changed
+        let addr = "5.6.7.8:2000".parse().expect("Should parse");
+        server.on_input(1500, 2, NeighboursControlCmds::Observed { session: 1000, addr });
+        assert_eq!(pop_observed(&mut server), vec![Output::Event(ConnectionEvent::Observed(addr))]);
+        server.on_input(1600, 2, NeighboursControlCmds::Observed { session: 1000, addr });
+        assert_eq!(pop_observed(&mut server), vec![]);
+    }
+
+    #[test]
+    fn should_not_send_observed_to_old_neighbour() {
+        let mut server_handshake = MockHandshakeBuilder::default();
+        server_handshake.expect_responder().returning(move || {
+            let mut responder = MockHandshakeResponder::default();
+            responder
+                .expect_process_public_request()
+                .return_once(|req| Ok((Box::new(MockEncryptor::default()), Box::new(MockDecryptor::default()), req.to_vec())));
+            Box::new(responder)
+        });
+        let pair = NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse");
+        let mut server = NeighbourConnection::new_incoming(Arc::new(server_handshake), 1, 2, 1000, pair, 100);
+        server.on_input(
+            1100,
+            2,
+            NeighboursControlCmds::ConnectRequest {
+                to: 1,
+                session: 1000,
+                handshake: vec![1, 2, 3],
+            },
+        );
+        server.on_input(
+            1200,
+            2,
+            NeighboursControlCmds::Ping {
+                session: 1000,
+                seq: 10,
+                sent_ms: 1200,
+            },
+        );
+        server.on_input(
+            1300,
+            2,
+            NeighboursControlCmds::Capabilities {
+                session: 1000,
+                caps: CAPABILITY_COOKIE,
+                received: true,
+            },
+        );
+        server.on_input(
+            1400,
+            2,
+            NeighboursControlCmds::Ping {
+                session: 1000,
+                seq: 20,
+                sent_ms: 1400,
+            },
+        );
+        while let Some(out) = server.pop_output() {
+            assert!(!matches!(out, Output::Net(_, _, NeighboursControlCmds::Observed { .. })), "Should not send observed");
+        }
+    }
 }
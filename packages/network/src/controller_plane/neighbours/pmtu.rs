@@ -0,0 +1,145 @@
+use crate::base::DEFAULT_MTU;
+
+/// Datagram sizes which will be probed in order, receive buffers inside runtime are 1500 bytes so we don't need to probe bigger
+const PROBE_SIZES: [u16; 5] = [1200, 1280, 1400, 1452, 1500];
+const PROBE_RETRY: u8 = 3;
+const REPROBE_INTERVAL_MS: u64 = 600_000;
+
+/// Path MTU discovery for a single neighbour connection.
+/// Each tick we send a padded ping with the next size, if it is acked we continue with bigger size,
+/// if it is lost after PROBE_RETRY times we stop and use the biggest acked size.
+/// Probing is restarted after REPROBE_INTERVAL_MS for detecting path changes.
+///
+/// Don't fragment is not forced on sockets because the runtime backend owns them, and forcing it would also make
+/// normal DEFAULT_MTU datagrams fail on smaller paths instead of being fragmented. So a probe which is fragmented by
+/// the kernel or a router is still acked, and the result can overestimate the real path mtu.
+pub struct PathMtuProber {
+    mtu: u16,
+    index: usize,
+    retries: u8,
+    round_best: Option<u16>,
+    finished_at: Option<u64>,
+    changed: bool,
+}
+
+impl Default for PathMtuProber {
+    fn default() -> Self {
+        Self {
+            mtu: DEFAULT_MTU,
+            index: 0,
+            retries: 0,
+            round_best: None,
+            finished_at: None,
+            changed: false,
+        }
+    }
+}
+
+impl PathMtuProber {
+    /// Return the size of the padded ping which should be sent now
+    pub fn on_tick(&mut self, now_ms: u64) -> Option<u16> {
+        if let Some(finished_at) = self.finished_at {
+            if now_ms < finished_at + REPROBE_INTERVAL_MS {
+                return None;
+            }
+            self.finished_at = None;
+            self.index = 0;
+            self.retries = 0;
+            self.round_best = None;
+        }
+
+        if self.retries >= PROBE_RETRY {
+            self.finish_round(now_ms);
+            return None;
+        }
+        self.retries += 1;
+        PROBE_SIZES.get(self.index).cloned()
+    }
+
+    pub fn on_ack(&mut self, now_ms: u64, size: u16) {
+        if self.finished_at.is_some() || PROBE_SIZES.get(self.index) != Some(&size) {
+            return;
+        }
+        self.round_best = Some(size);
+        self.index += 1;
+        self.retries = 0;
+        if size > self.mtu {
+            self.set_mtu(size);
+        }
+        if self.index == PROBE_SIZES.len() {
+            self.finish_round(now_ms);
+        }
+    }
+
+    /// Return new mtu if it changed after last call
+    pub fn take_changed(&mut self) -> Option<u16> {
+        if self.changed {
+            self.changed = false;
+            Some(self.mtu)
+        } else {
+            None
+        }
+    }
+
+    fn finish_round(&mut self, now_ms: u64) {
+        self.finished_at = Some(now_ms);
+        // if nothing acked, the remote may be an old node which don't support probing, so we keep current value
+        if let Some(best) = self.round_best {
+            if best != self.mtu {
+                self.set_mtu(best);
+            }
+        }
+    }
+
+    fn set_mtu(&mut self, mtu: u16) {
+        log::info!("[PathMtuProber] mtu changed from {} to {}", self.mtu, mtu);
+        self.mtu = mtu;
+        self.changed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn probe_all_sizes() {
+        let mut prober = PathMtuProber::default();
+        for (i, size) in PROBE_SIZES.iter().enumerate() {
+            assert_eq!(prober.on_tick(i as u64 * 1000), Some(*size));
+            prober.on_ack(i as u64 * 1000, *size);
+        }
+        assert_eq!(prober.take_changed(), Some(1500));
+        assert_eq!(prober.take_changed(), None);
+        assert_eq!(prober.on_tick(10000), None);
+    }
+
+    #[test]
+    fn probe_stop_at_lost_size() {
+        let mut prober = PathMtuProber::default();
+        assert_eq!(prober.on_tick(0), Some(1200));
+        prober.on_ack(0, 1200);
+        assert_eq!(prober.on_tick(1000), Some(1280));
+        prober.on_ack(1000, 1280);
+        for i in 0..PROBE_RETRY {
+            assert_eq!(prober.on_tick(2000 + i as u64 * 1000), Some(1400));
+        }
+        assert_eq!(prober.on_tick(5000), None);
+        assert_eq!(prober.take_changed(), Some(1280));
+
+        // reprobe after interval
+        assert_eq!(prober.on_tick(5000 + REPROBE_INTERVAL_MS - 1), None);
+        assert_eq!(prober.on_tick(5000 + REPROBE_INTERVAL_MS), Some(1200));
+    }
+
+    #[test]
+    fn keep_default_without_ack() {
+        let mut prober = PathMtuProber::default();
+        for i in 0..PROBE_RETRY {
+            assert_eq!(prober.on_tick(i as u64 * 1000), Some(1200));
+        }
+        assert_eq!(prober.on_tick(PROBE_RETRY as u64 * 1000), None);
+        assert_eq!(prober.take_changed(), None);
+        assert_eq!(prober.mtu, DEFAULT_MTU);
+    }
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::{Debug, Display},
     hash::Hash,
     net::{AddrParseError, SocketAddr},
@@ -15,18 +15,25 @@ use sans_io_runtime::{collections::DynamicDeque, return_if_err, return_if_none,
 
 use crate::{
     base::{
-        Buffer, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NeighboursControl, NetOutgoingMeta, ServiceBuilder, ServiceControlActor, ServiceId,
-        ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, TransportMsg, TransportMsgHeader,
+        Buffer, FeatureControlActor, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, FragmentHeader, MsgPriority, NeighboursControl, NetOutgoingMeta, ServiceBuilder,
+        ServiceControlActor, ServiceId, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput, TransportMsg, TransportMsgHeader,
     },
     features::{Features, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut, LogicControl, LogicEvent,
 };
 
-use self::{connection::DataPlaneConnection, features::FeatureWorkerManager, services::ServiceWorkerManager};
+use self::{connection::DataPlaneConnection, features::FeatureWorkerManager, fragment::FragmentReassembler, send_queue::SendQueue, services::ServiceWorkerManager, shaper::TrafficShaper};
 
 mod connection;
 mod features;
+mod fragment;
+mod send_queue;
 mod services;
+mod shaper;
+mod transport;
+
+pub use shaper::{RateLimit, ShapingCfg, ShapingKey, ShapingStats};
+pub use transport::{FrameTransport, MemoryNetwork, MemoryTransport, Transport, TransportPenalty};
 
 /// NetPair is a pair between remote addr and local addr.
 /// This is for solving problems with multi-ip-addresses system.
@@ -34,30 +41,69 @@ mod services;
 pub struct NetPair {
     pub local: SocketAddr,
     pub remote: SocketAddr,
+    pub transport: Transport,
 }
 
 impl NetPair {
     pub fn new(local: SocketAddr, remote: SocketAddr) -> Self {
-        Self { local, remote }
+        Self {
+            local,
+            remote,
+            transport: Transport::Udp,
+        }
+    }
+
+    /// Stream pair, local is the bind addr which the stream is opened from or accepted by
+    pub fn new_tcp(local: SocketAddr, remote: SocketAddr) -> Self {
+        Self {
+            local,
+            remote,
+            transport: Transport::Tcp,
+        }
+    }
+
+    /// WebSocket pair, local is the WebSocket listen addr or the bind addr which the client is dialing from
+    pub fn new_ws(local: SocketAddr, remote: SocketAddr) -> Self {
+        Self {
+            local,
+            remote,
+            transport: Transport::WebSocket,
+        }
+    }
+
+    /// In-memory pair, local and remote are the addrs which MemoryTransport bound
+    pub fn new_memory(local: SocketAddr, remote: SocketAddr) -> Self {
+        Self {
+            local,
+            remote,
+            transport: Transport::Memory,
+        }
     }
 
     pub fn new_str(local: &str, remote: &str) -> Result<Self, AddrParseError> {
-        Ok(Self {
-            local: local.parse::<SocketAddr>()?,
-            remote: remote.parse::<SocketAddr>()?,
-        })
+        Ok(Self::new(local.parse::<SocketAddr>()?, remote.parse::<SocketAddr>()?))
     }
 }
 
 impl Display for NetPair {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("[{}-{}]", self.local, self.remote))
+        match self.transport {
+            Transport::Udp => f.write_fmt(format_args!("[{}-{}]", self.local, self.remote)),
+            Transport::Tcp => f.write_fmt(format_args!("[tcp:{}-{}]", self.local, self.remote)),
+            Transport::WebSocket => f.write_fmt(format_args!("[ws:{}-{}]", self.local, self.remote)),
+            Transport::Memory => f.write_fmt(format_args!("[mem:{}-{}]", self.local, self.remote)),
+        }
     }
 }
 
+/// Worker which owns stream transports, it is the worker running controller plane
+const STREAM_WORKER: u16 = 0;
+
 #[derive(Debug)]
 pub enum NetInput {
     UdpPacket(NetPair, Buffer),
+    /// A frame which is received over a FrameTransport, same content as a UDP datagram
+    Frame(NetPair, Buffer),
     #[cfg(feature = "vpn")]
     TunPacket(Buffer),
 }
@@ -66,6 +112,8 @@ pub enum NetInput {
 pub enum CrossWorker<UserData, SE> {
     Feature(UserData, FeaturesEvent),
     Service(ServiceId, UserData, SE),
+    /// Stream frame which is forwarded to the worker owning streams
+    Net(NetPair, Buffer),
 }
 
 #[derive(Debug)]
@@ -81,6 +129,12 @@ pub enum Input<UserData, SC, SE, TW> {
 pub enum NetOutput {
     UdpPacket(NetPair, Buffer),
     UdpPackets(Vec<NetPair>, Buffer),
+    /// A frame which is sent over the FrameTransport of the pair
+    Frame(NetPair, Buffer),
+    /// Open a socket on the addr, which is added at runtime
+    Bind(SocketAddr),
+    /// Close the socket of the addr
+    Unbind(SocketAddr),
     #[cfg(feature = "vpn")]
     TunPacket(Buffer),
 }
@@ -110,6 +164,9 @@ pub struct DataPlaneCfg<UserData, SC, SE, TC, TW> {
     #[allow(clippy::type_complexity)]
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    pub shaping: ShapingCfg,
+    /// Bytes which can be sent to each remote pair, packets over it are queued by priority. None is unlimited
+    pub send_budget: Option<RateLimit>,
 }
 
 pub struct DataPlane<UserData, SC, SE, TC, TW> {
@@ -122,6 +179,21 @@ pub struct DataPlane<UserData, SC, SE, TC, TW> {
     services: TaskSwitcherBranch<ServiceWorkerManager<UserData, SC, SE, TC, TW>, services::Output<UserData, SC, SE, TC>>,
     conns: HashMap<NetPair, DataPlaneConnection>,
     conns_reverse: HashMap<ConnId, NetPair>,
+    fragments: FragmentReassembler,
+    fragment_seq: u32,
+    fragment_queue: VecDeque<NetOutput>,
+    /// kept while the pair is pinned for persisting the send budget, empty queues are not in send_order
+    send_queues: HashMap<NetPair, SendQueue>,
+    send_order: VecDeque<NetPair>,
+    send_budget: Option<RateLimit>,
+    /// removed bind addrs, they are closed after queued packets are sent
+    unbinds: VecDeque<SocketAddr>,
+    shaper: TrafficShaper,
+    /// relayed packets since last tick, reported to controller while draining
+    relayed: u64,
+    draining: bool,
+    /// Drained is broadcasted by the controller worker, it can arrive before ShutdownRequest of this worker
+    drained: bool,
     queue: DynamicDeque<Output<UserData, SC, SE, TC>, 16>,
     switcher: TaskSwitcher,
 }
@@ -145,6 +217,17 @@ where
             services: TaskSwitcherBranch::new(ServiceWorkerManager::new(cfg.services), TaskType::Service),
             conns: HashMap::new(),
             conns_reverse: HashMap::new(),
+            fragments: FragmentReassembler::default(),
+            fragment_seq: 0,
+            fragment_queue: VecDeque::new(),
+            send_queues: HashMap::new(),
+            send_order: VecDeque::new(),
+            send_budget: cfg.send_budget,
+            unbinds: VecDeque::new(),
+            shaper: TrafficShaper::new(cfg.shaping),
+            relayed: 0,
+            draining: false,
+            drained: false,
             queue: DynamicDeque::default(),
             switcher: TaskSwitcher::new(2),
         }
@@ -154,10 +237,34 @@ where
         self.feature_ctx.router.derive_action(&rule, source, relay_from)
     }
 
+    /// Some packets are waiting for send budget, the worker must poll again later
+    pub fn has_queued_sends(&self) -> bool {
+        !self.send_order.is_empty()
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
         log::trace!("[DataPlane] on_tick: {}", now_ms);
+        let conns = &self.conns;
+        self.send_queues.retain(|pair, queue| !queue.is_empty() || conns.contains_key(pair));
         self.features.input(&mut self.switcher).on_tick(&mut self.feature_ctx, now_ms, self.tick_count);
         self.services.input(&mut self.switcher).on_tick(&self.service_ctx, now_ms, self.tick_count);
+        self.fragments.on_tick(now_ms);
+        for (key, stats) in self.shaper.on_tick(now_ms) {
+            log::warn!("[DataPlane] rate limited {key:?}, total dropped {} pkts {} bytes", stats.dropped_pkts, stats.dropped_bytes);
+            self.queue.push_back(Output::Ext(ExtOut::RateLimited(key, stats)));
+        }
+        if self.draining {
+            self.queue.push_back(LogicControl::TransitLoad(self.worker_id, self.relayed).into());
+        }
+        let traffic = self
+            .conns
+            .values_mut()
+            .filter_map(|conn| conn.take_traffic().map(|(sent, recv)| (conn.conn(), sent, recv)))
+            .collect::<Vec<_>>();
+        if !traffic.is_empty() {
+            self.queue.push_back(LogicControl::Traffic(traffic).into());
+        }
+        self.relayed = 0;
         self.tick_count += 1;
     }
 
@@ -167,9 +274,21 @@ where
                 ExtIn::ConnectTo(_remote) => {
                     panic!("ConnectTo is not supported")
                 }
+                ExtIn::ConnectToPersistent(_remote) => {
+                    panic!("ConnectToPersistent is not supported")
+                }
+                ExtIn::ConnectToSeed(_remote) => {
+                    panic!("ConnectToSeed is not supported")
+                }
                 ExtIn::DisconnectFrom(_node) => {
                     panic!("DisconnectFrom is not supported")
                 }
+                ExtIn::AddBind(_addr) => {
+                    panic!("AddBind is not supported")
+                }
+                ExtIn::RemoveBind(_addr) => {
+                    panic!("RemoveBind is not supported")
+                }
                 ExtIn::FeaturesControl(userdata, control) => {
                     let feature: Features = control.to_feature();
                     let actor = FeatureControlActor::Worker(self.worker_id, userdata);
@@ -186,10 +305,23 @@ where
             },
             Input::Worker(CrossWorker::Feature(userdata, event)) => self.queue.push_back(Output::Ext(ExtOut::FeaturesEvent(userdata, event))),
             Input::Worker(CrossWorker::Service(service, userdata, event)) => self.queue.push_back(Output::Ext(ExtOut::ServicesEvent(service, userdata, event))),
-            Input::Net(NetInput::UdpPacket(pair, buf)) => {
+            Input::Worker(CrossWorker::Net(pair, buf)) => self.queue.push_back(Output::Net(NetOutput::Frame(pair, buf))),
+            Input::Net(NetInput::UdpPacket(pair, buf)) | Input::Net(NetInput::Frame(pair, buf)) => {
                 if buf.is_empty() {
                     return;
                 }
+                if let Some(conn) = self.conns.get_mut(&pair) {
+                    conn.add_recv(buf.len());
+                }
+                let buf = if FragmentHeader::is_fragment(buf[0]) {
+                    // only accept fragments from connected neighbours for avoiding memory abuse
+                    if !self.conns.contains_key(&pair) {
+                        return;
+                    }
+                    return_if_none!(self.fragments.on_fragment(now_ms, pair, &buf))
+                } else {
+                    buf
+                };
                 if let Ok(control) = NeighboursControl::try_from(&*buf) {
                     self.queue.push_back(LogicControl::NetNeighbour(pair, control).into());
                 } else {
@@ -224,15 +356,16 @@ where
             Input::Event(LogicEvent::NetNeighbour(pair, control)) => {
                 let buf: Result<Vec<u8>, ()> = (&control).try_into();
                 if let Ok(buf) = buf {
-                    self.queue.push_back(NetOutput::UdpPacket(pair, buf.into()).into());
+                    self.push_net(now_ms, MsgPriority::Control, NetOutput::UdpPacket(pair, buf.into()));
                 }
             }
             Input::Event(LogicEvent::NetDirect(feature, pair, _conn, meta, buf)) => {
                 let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
                 let conn = return_if_none!(self.conns.get_mut(&pair));
+                let priority = header.priority;
                 let msg = TransportMsg::build_raw(header, buf);
                 if let Some(pkt) = Self::build_send_to_from_mut(now_ms, conn, pair, msg.take()) {
-                    self.queue.push_back(pkt.into());
+                    self.push_net(now_ms, priority, pkt);
                 }
             }
             Input::Event(LogicEvent::NetRoute(feature, rule, meta, buf)) => self.outgoing_route(now_ms, feature, rule, meta, buf),
@@ -240,13 +373,61 @@ where
                 self.conns.insert(pair, DataPlaneConnection::new(node, conn, pair, secure));
                 self.conns_reverse.insert(conn, pair);
             }
+            Input::Event(LogicEvent::SetCapabilities(conn, caps)) => {
+                let pair = return_if_none!(self.conns_reverse.get(&conn));
+                let conn = return_if_none!(self.conns.get_mut(pair));
+                log::info!("SetCapabilities: conn: {} <--> addr: {}, caps {caps}", conn.conn(), pair);
+                conn.set_capabilities(caps);
+            }
+            Input::Event(LogicEvent::SetMtu(conn, mtu)) => {
+                let pair = return_if_none!(self.conns_reverse.get(&conn));
+                let conn = return_if_none!(self.conns.get_mut(pair));
+                log::info!("SetMtu: conn: {} <--> addr: {}, mtu {mtu}", conn.conn(), pair);
+                conn.set_mtu(mtu);
+            }
+            Input::Event(LogicEvent::SetCongested(conn, congested)) => {
+                let pair = return_if_none!(self.conns_reverse.get(&conn));
+                let conn = return_if_none!(self.conns.get_mut(pair));
+                log::info!("SetCongested: conn: {} <--> addr: {}, congested {congested}", conn.conn(), pair);
+                conn.set_congested(congested);
+            }
             Input::Event(LogicEvent::UnPin(conn)) => {
                 if let Some(addr) = self.conns_reverse.remove(&conn) {
                     log::info!("UnPin: conn: {} <--> addr: {}", conn, addr);
                     self.conns.remove(&addr);
+                    self.fragments.remove_source(&addr);
+                    // queued data is useless without the connection, but neighbour controls like disconnect ack must still be sent
+                    if let Some(queue) = self.send_queues.get_mut(&addr) {
+                        queue.retain(|buf| NeighboursControl::is_control(buf[0]));
+                        if queue.is_empty() {
+                            self.send_queues.remove(&addr);
+                            self.send_order.retain(|pair| *pair != addr);
+                        }
+                    }
+                }
+            }
+            Input::Event(LogicEvent::Bind(addr)) => {
+                self.queue.push_back(Output::Net(NetOutput::Bind(addr)));
+            }
+            Input::Event(LogicEvent::Unbind(addr)) => {
+                self.unbinds.push_back(addr);
+            }
+            Input::Event(LogicEvent::Drained) => {
+                if self.draining {
+                    self.draining = false;
+                    self.queue.push_back(Output::ShutdownResponse);
+                } else {
+                    self.drained = true;
+                }
+            }
+            Input::ShutdownRequest => {
+                if self.drained {
+                    self.queue.push_back(Output::ShutdownResponse);
+                } else {
+                    // keep relaying until controller finished draining
+                    self.draining = true;
                 }
             }
-            Input::ShutdownRequest => self.queue.push_back(Output::ShutdownResponse),
         }
     }
 
@@ -255,7 +436,11 @@ where
         if TransportMsgHeader::is_secure(buf[0]) {
             return_if_none!(conn.decrypt_if_need(now_ms, &mut buf));
         }
+        return_if_none!(TransportMsgHeader::upgrade_broadcast_seq(&mut buf));
         let header = return_if_err!(TransportMsgHeader::try_from(&buf as &[u8]));
+        let priority = header.priority;
+        let relay_source = header.from_node.unwrap_or(conn.node());
+        let relay_feature = header.feature.try_into().ok();
         let action = self.feature_ctx.router.derive_action(&header.route, header.from_node, Some(conn.node()));
         log::debug!("[DataPlane] Incoming rule: {:?} from: {pair}, node {:?} => action {:?}", header.route, header.from_node, action);
         match action {
@@ -267,13 +452,22 @@ where
                     .input(&mut self.switcher)
                     .on_network_raw(&mut self.feature_ctx, feature, now_ms, conn.conn(), pair, header, buf);
             }
-            RouteAction::Next(pair) => {
+            RouteAction::Next(next) => {
                 if !TransportMsgHeader::decrease_ttl(&mut buf) {
                     log::debug!("TTL is 0, drop packet");
                 }
+                if !self.shaper.allow(now_ms, relay_feature, pair, relay_source, buf.len()) {
+                    log::debug!("[DataPlane] relayed packet from {pair} node {relay_source} is rate limited");
+                    return;
+                }
+                let pair = next;
+                if self.is_congested(&pair) {
+                    TransportMsgHeader::mark_congested(&mut buf);
+                }
                 let target_conn = return_if_none!(self.conns.get_mut(&pair));
+                self.relayed += 1;
                 if let Some(out) = Self::build_send_to_from_mut(now_ms, target_conn, pair, buf) {
-                    self.queue.push_back(out.into());
+                    self.push_net(now_ms, priority, out);
                 }
             }
             RouteAction::Broadcast(local, pairs) => {
@@ -290,8 +484,16 @@ where
                     }
                 }
                 if !pairs.is_empty() {
+                    if !self.shaper.allow(now_ms, relay_feature, pair, relay_source, buf.len()) {
+                        log::debug!("[DataPlane] relayed broadcast from {pair} node {relay_source} is rate limited");
+                        return;
+                    }
+                    if pairs.iter().any(|pair| self.is_congested(pair)) {
+                        TransportMsgHeader::mark_congested(&mut buf);
+                    }
+                    self.relayed += 1;
                     if let Some(out) = self.build_send_to_multi_from_mut(now_ms, pairs, buf) {
-                        self.queue.push_back(out.into());
+                        self.push_net(now_ms, priority, out);
                     }
                 }
             }
@@ -316,7 +518,7 @@ where
                 let msg = TransportMsg::build_raw(header, buf);
                 let conn = return_if_none!(self.conns.get_mut(&remote));
                 if let Some(out) = Self::build_send_to_from_mut(now_ms, conn, remote, msg.take()) {
-                    self.queue.push_back(out.into());
+                    self.push_net(now_ms, meta.priority, out);
                 }
             }
             RouteAction::Broadcast(local, remotes) => {
@@ -332,7 +534,7 @@ where
                 }
                 let msg = TransportMsg::build_raw(header, buf);
                 if let Some(out) = self.build_send_to_multi_from_mut(now_ms, remotes, msg.take()) {
-                    self.queue.push_back(out.into());
+                    self.push_net(now_ms, meta.priority, out);
                 }
             }
         }
@@ -365,7 +567,8 @@ where
                     let conn = self.conns.get_mut(addr).expect("Should have");
                     let header = meta.to_header(feature as u8, RouteRule::Direct, self.feature_ctx.node_id);
                     let msg = TransportMsg::build_raw(header, buf);
-                    self.queue.push_back(Self::build_send_to_from_mut(now_ms, conn, *addr, msg.take()).expect("Should have output").into())
+                    let out = Self::build_send_to_from_mut(now_ms, conn, *addr, msg.take()).expect("Should have output");
+                    self.push_net(now_ms, meta.priority, out);
                 }
             }
             FeatureWorkerOutput::SendRoute(rule, ttl, buf) => {
@@ -375,22 +578,30 @@ where
             FeatureWorkerOutput::RawDirect(conn, buf) => {
                 if let Some(pair) = self.conns_reverse.get(&conn) {
                     let conn = self.conns.get_mut(pair).expect("Should have conn");
-                    self.queue.push_back(Self::build_send_to(now_ms, conn, *pair, buf).expect("Should ok for convert RawDirect").into());
+                    let priority = TransportMsgHeader::priority_of(&buf);
+                    let out = Self::build_send_to(now_ms, conn, *pair, buf).expect("Should ok for convert RawDirect");
+                    self.push_net(now_ms, priority, out);
                 }
             }
             FeatureWorkerOutput::RawBroadcast(conns, buf) => {
                 let addrs = conns.iter().filter_map(|conn| self.conns_reverse.get(conn)).cloned().collect();
-                let out = self.build_send_to_multi(now_ms, addrs, buf).map(|e| e.into()).unwrap_or(Output::Continue);
-                self.queue.push_back(out);
+                let priority = TransportMsgHeader::priority_of(&buf);
+                if let Some(out) = self.build_send_to_multi(now_ms, addrs, buf) {
+                    self.push_net(now_ms, priority, out);
+                }
             }
             FeatureWorkerOutput::RawDirect2(pair, buf) => {
                 if let Some(conn) = self.conns.get_mut(&pair) {
-                    self.queue.push_back(Self::build_send_to(now_ms, conn, pair, buf).expect("Should ok for convert RawDirect2").into());
+                    let priority = TransportMsgHeader::priority_of(&buf);
+                    let out = Self::build_send_to(now_ms, conn, pair, buf).expect("Should ok for convert RawDirect2");
+                    self.push_net(now_ms, priority, out);
                 }
             }
             FeatureWorkerOutput::RawBroadcast2(pairs, buf) => {
-                let out = self.build_send_to_multi(now_ms, pairs, buf).map(|e| e.into()).unwrap_or(Output::Continue);
-                self.queue.push_back(out);
+                let priority = TransportMsgHeader::priority_of(&buf);
+                if let Some(out) = self.build_send_to_multi(now_ms, pairs, buf) {
+                    self.push_net(now_ms, priority, out);
+                }
             }
             #[cfg(feature = "vpn")]
             FeatureWorkerOutput::TunPkt(pkt) => self.queue.push_back(NetOutput::TunPacket(pkt).into()),
@@ -422,47 +633,159 @@ where
         }
     }
 
+    /// Relayed packets to the pair should be marked when its send queue is backing up or its link has high queueing delay
+    fn is_congested(&self, pair: &NetPair) -> bool {
+        self.send_queues.get(pair).map(|q| q.is_congested()).unwrap_or(false) || self.conns.get(pair).map(|c| c.is_congested()).unwrap_or(false)
+    }
+
+    /// Push a network output into the send queue of each destination pair, the transport is decided by the pair when popping
+    fn push_net(&mut self, now_ms: u64, priority: MsgPriority, out: NetOutput) {
+        match out {
+            NetOutput::UdpPacket(pair, buf) | NetOutput::Frame(pair, buf) => self.push_send_queue(now_ms, priority, pair, buf),
+            NetOutput::UdpPackets(pairs, buf) => {
+                // pairs without backlog are sent as a single batch, others wait in their queue
+                let (pairs, queued): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|pair| self.try_send_now(now_ms, *pair, &buf));
+                for pair in queued {
+                    self.push_send_queue(now_ms, priority, pair, buf.clone());
+                }
+                match pairs.len() {
+                    0 => {}
+                    1 => self.queue.push_back(NetOutput::UdpPacket(pairs[0], buf).into()),
+                    _ => self.queue.push_back(NetOutput::UdpPackets(pairs, buf).into()),
+                }
+            }
+            out @ (NetOutput::Bind(_) | NetOutput::Unbind(_)) => self.queue.push_back(out.into()),
+            #[cfg(feature = "vpn")]
+            out => self.queue.push_back(out.into()),
+        }
+    }
+
+    /// A datagram to a pair which has no backlog and enough budget can skip the queue, but not if it needs fragmenting
+    fn try_send_now(&mut self, now_ms: u64, pair: NetPair, buf: &Buffer) -> bool {
+        if pair.transport.is_stream() || self.conns.get(&pair).map(|c| c.support_fragment() && buf.len() > c.mtu()).unwrap_or(false) {
+            return false;
+        }
+        let budget = self.send_budget;
+        let queue = self.send_queues.entry(pair).or_insert_with(|| SendQueue::new(now_ms, budget));
+        if !queue.try_send_now(now_ms, buf.len()) {
+            return false;
+        }
+        if let Some(conn) = self.conns.get_mut(&pair) {
+            conn.add_sent(buf.len());
+        }
+        true
+    }
+
+    fn push_send_queue(&mut self, now_ms: u64, priority: MsgPriority, pair: NetPair, buf: Buffer) {
+        let budget = self.send_budget;
+        let queue = self.send_queues.entry(pair).or_insert_with(|| SendQueue::new(now_ms, budget));
+        if queue.is_empty() {
+            self.send_order.push_back(pair);
+        }
+        let len = buf.len();
+        if !queue.push(priority, buf) {
+            log::warn!("[DataPlane] send queue to {pair} is full, drop {priority:?} packet, total dropped {}", queue.dropped());
+        } else if let Some(conn) = self.conns.get_mut(&pair) {
+            conn.add_sent(len);
+        }
+    }
+
+    /// Pop next packet from send queues, pairs are served in round robin
+    fn pop_send_queue(&mut self, now_ms: u64) -> Option<NetOutput> {
+        let pair = self.send_order.pop_front()?;
+        let queue = self.send_queues.get_mut(&pair).expect("Should have send queue");
+        let buf = queue.pop(now_ms);
+        if !queue.is_empty() {
+            self.send_order.push_back(pair);
+        }
+        if pair.transport.is_stream() {
+            // streams have no mtu, so frames are never fragmented
+            return Some(NetOutput::Frame(pair, buf?));
+        }
+        self.fragment_if_need(pair, buf?)
+    }
+
+    /// Split datagrams which are bigger than the connection MTU, only with neighbours which support fragmentation.
+    /// NeighboursControl is never fragmented because it is also used for probing MTU.
+    fn fragment_if_need(&mut self, pair: NetPair, buf: Buffer) -> Option<NetOutput> {
+        if NeighboursControl::is_control(buf[0]) {
+            return Some(NetOutput::UdpPacket(pair, buf));
+        }
+        let conn = self.conns.get(&pair);
+        if !conn.map(|c| c.support_fragment() && buf.len() > c.mtu()).unwrap_or(false) {
+            return Some(NetOutput::UdpPacket(pair, buf));
+        }
+        let mtu = conn.expect("Should have conn").mtu();
+        let mut fragments = self.split_fragments(&buf, mtu)?.into_iter();
+        let first = fragments.next()?;
+        self.fragment_queue.extend(fragments.map(|frag| NetOutput::UdpPacket(pair, frag)));
+        Some(NetOutput::UdpPacket(pair, first))
+    }
+
+    fn split_fragments(&mut self, buf: &[u8], mtu: usize) -> Option<Vec<Buffer>> {
+        // msg_id is unique per worker, worker_id is in high bits for avoiding collision between workers which share same socket
+        let msg_id = ((self.worker_id as u32) << 24) | (self.fragment_seq & 0x00FF_FFFF);
+        self.fragment_seq = self.fragment_seq.wrapping_add(1);
+        let fragments = FragmentHeader::split(msg_id, buf, mtu);
+        if fragments.is_none() {
+            log::warn!("[DataPlane] datagram with size {} is too big for fragmenting with mtu {mtu}, drop it", buf.len());
+        }
+        fragments
+    }
+
     fn build_send_to_from_mut(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, mut buf: Buffer) -> Option<NetOutput> {
+        conn.downgrade_if_need(&mut buf)?;
         conn.encrypt_if_need(now, &mut buf)?;
         Some(NetOutput::UdpPacket(pair, buf))
     }
 
-    fn build_send_to_multi_from_mut(&mut self, now: u64, mut pairs: Vec<NetPair>, mut buf: Buffer) -> Option<NetOutput> {
+    fn build_send_to_multi_from_mut(&mut self, now: u64, mut pairs: Vec<NetPair>, buf: Buffer) -> Option<NetOutput> {
+        let priority = TransportMsgHeader::priority_of(&buf);
         if TransportMsgHeader::is_secure(buf[0]) {
             let first = pairs.pop()?;
             for pair in pairs {
                 if let Some(conn) = self.conns.get_mut(&pair) {
-                    let mut buf = Buffer::build(&buf, 0, 12 + 16);
-                    if conn.encrypt_if_need(now, &mut buf).is_some() {
-                        let out = NetOutput::UdpPacket(pair, buf);
-                        self.queue.push_back(Output::Net(out));
+                    let buf = Buffer::build(&buf, 0, 12 + 16);
+                    if let Some(out) = Self::build_send_to_from_mut(now, conn, pair, buf) {
+                        self.push_net(now, priority, out);
                     }
                 }
             }
             let conn = self.conns.get_mut(&first)?;
-            conn.encrypt_if_need(now, &mut buf)?;
-            Some(NetOutput::UdpPacket(first, buf))
+            Self::build_send_to_from_mut(now, conn, first, buf)
         } else {
+            // neighbours which don't support some header extensions will receive a downgraded copy
+            let (pairs, legacy_pairs): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|pair| self.conns.get(pair).map(|c| !c.need_downgrade(buf[0])).unwrap_or(true));
+            for pair in legacy_pairs {
+                let conn = self.conns.get(&pair).expect("Should have conn");
+                let mut legacy_buf = Buffer::build(&buf, 0, 0);
+                if conn.downgrade_if_need(&mut legacy_buf).is_some() {
+                    self.push_net(now, priority, NetOutput::UdpPacket(pair, legacy_buf));
+                }
+            }
+            if pairs.is_empty() {
+                return None;
+            }
             Some(NetOutput::UdpPackets(pairs, buf))
         }
     }
 
     fn build_send_to_multi(&mut self, now: u64, pairs: Vec<NetPair>, buf: Buffer) -> Option<NetOutput> {
-        if TransportMsgHeader::is_secure(buf[0]) {
-            let buf = Buffer::build(&buf, 0, 12 + 16);
-            self.build_send_to_multi_from_mut(now, pairs, buf)
+        let buf = if TransportMsgHeader::is_secure(buf[0]) {
+            Buffer::build(&buf, 0, 12 + 16)
         } else {
-            Some(NetOutput::UdpPackets(pairs, buf))
-        }
+            buf
+        };
+        self.build_send_to_multi_from_mut(now, pairs, buf)
     }
 
     fn build_send_to(now: u64, conn: &mut DataPlaneConnection, pair: NetPair, buf: Buffer) -> Option<NetOutput> {
-        if TransportMsgHeader::is_secure(buf[0]) {
-            let buf = Buffer::build(&buf, 0, 12 + 16);
-            Self::build_send_to_from_mut(now, conn, pair, buf)
+        let buf = if TransportMsgHeader::is_secure(buf[0]) {
+            Buffer::build(&buf, 0, 12 + 16)
         } else {
-            Some(NetOutput::UdpPacket(pair, buf))
-        }
+            buf
+        };
+        Self::build_send_to_from_mut(now, conn, pair, buf)
     }
 }
 
@@ -472,6 +795,7 @@ where
 {
     type Time = u64;
     fn pop_output(&mut self, now: u64) -> Option<Output<UserData, SC, SE, TC>> {
+        return_if_some!(self.fragment_queue.pop_front().map(Output::Net));
         return_if_some!(self.queue.pop_front());
 
         while let Some(current) = self.switcher.current() {
@@ -483,6 +807,21 @@ where
             return_if_some!(self.queue.pop_front());
         }
 
+        // all tasks are drained, now we send network packets with priority order, pairs which are out of budget are polled again later
+        for _ in 0..self.send_order.len() {
+            return_if_some!(self.pop_send_queue(now).map(|out| match out {
+                NetOutput::Frame(pair, buf) if self.worker_id != STREAM_WORKER => Output::Worker(STREAM_WORKER, CrossWorker::Net(pair, buf)),
+                out => Output::Net(out),
+            }));
+        }
+
+        if let Some(addr) = self.unbinds.front().copied() {
+            // the socket is only closed after packets which are queued from it are sent
+            if !self.send_order.iter().any(|pair| pair.local == addr) {
+                self.unbinds.pop_front();
+                return Some(Output::Net(NetOutput::Unbind(addr)));
+            }
+        }
         None
     }
 }
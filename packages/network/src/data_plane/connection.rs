@@ -1,6 +1,6 @@
 use atm0s_sdn_identity::{ConnId, NodeId};
 
-use crate::base::{Buffer, SecureContext, TransportMsgHeader};
+use crate::base::{Buffer, SecureContext, TransportMsgHeader, CAPABILITY_BROADCAST_SEQ_EXT, CAPABILITY_FRAGMENT, CAPABILITY_QOS, DEFAULT_MTU};
 
 use super::NetPair;
 
@@ -10,11 +10,27 @@ pub struct DataPlaneConnection {
     #[allow(unused)]
     pair: NetPair,
     secure: SecureContext,
+    caps: u32,
+    mtu: u16,
+    congested: bool,
+    /// bytes since last traffic report
+    sent_bytes: u64,
+    recv_bytes: u64,
 }
 
 impl DataPlaneConnection {
     pub fn new(node: NodeId, conn: ConnId, pair: NetPair, secure: SecureContext) -> Self {
-        Self { node, conn, pair, secure }
+        Self {
+            node,
+            conn,
+            pair,
+            secure,
+            caps: 0,
+            mtu: DEFAULT_MTU,
+            congested: false,
+            sent_bytes: 0,
+            recv_bytes: 0,
+        }
     }
 
     pub fn node(&self) -> NodeId {
@@ -25,6 +41,75 @@ impl DataPlaneConnection {
         self.conn
     }
 
+    pub fn add_sent(&mut self, len: usize) {
+        self.sent_bytes += len as u64;
+    }
+
+    pub fn add_recv(&mut self, len: usize) {
+        self.recv_bytes += len as u64;
+    }
+
+    /// Take sent and received bytes since last call, None if there is no traffic
+    pub fn take_traffic(&mut self) -> Option<(u64, u64)> {
+        if self.sent_bytes == 0 && self.recv_bytes == 0 {
+            return None;
+        }
+        let traffic = (self.sent_bytes, self.recv_bytes);
+        self.sent_bytes = 0;
+        self.recv_bytes = 0;
+        Some(traffic)
+    }
+
+    pub fn set_capabilities(&mut self, caps: u32) {
+        self.caps = caps;
+    }
+
+    pub fn support_ext_broadcast(&self) -> bool {
+        self.caps & CAPABILITY_BROADCAST_SEQ_EXT != 0
+    }
+
+    /// Datagrams bigger than this size should be fragmented if the remote node supports it
+    pub fn mtu(&self) -> usize {
+        self.mtu as usize
+    }
+
+    pub fn set_mtu(&mut self, mtu: u16) {
+        self.mtu = mtu;
+    }
+
+    /// Link queueing delay which is measured by neighbours pings is high
+    pub fn is_congested(&self) -> bool {
+        self.congested
+    }
+
+    pub fn set_congested(&mut self, congested: bool) {
+        self.congested = congested;
+    }
+
+    pub fn support_fragment(&self) -> bool {
+        self.caps & CAPABILITY_FRAGMENT != 0
+    }
+
+    pub fn support_qos(&self) -> bool {
+        self.caps & CAPABILITY_QOS != 0
+    }
+
+    /// Check if a plain datagram with the given first byte need to be rewritten before sending to this connection
+    pub fn need_downgrade(&self, first_byte: u8) -> bool {
+        (!self.support_qos() && TransportMsgHeader::is_ext(first_byte)) || (!self.support_ext_broadcast() && TransportMsgHeader::is_ext_broadcast(first_byte))
+    }
+
+    /// Rewrite the header to legacy format if the remote node don't support it, must call before encrypt
+    pub fn downgrade_if_need(&self, buf: &mut Buffer) -> Option<()> {
+        if !self.support_qos() {
+            TransportMsgHeader::strip_ext(buf)?;
+        }
+        if self.support_ext_broadcast() {
+            return Some(());
+        }
+        TransportMsgHeader::downgrade_broadcast_seq(buf)
+    }
+
     /// This will encrypt without first byte, which is used for TransportMsgHeader meta
     pub fn encrypt_if_need(&mut self, now: u64, buf: &mut Buffer) -> Option<()> {
         if buf.len() < 1 {
@@ -0,0 +1,237 @@
+use std::collections::HashMap;
+
+use crate::base::{Buffer, FragmentHeader, FRAGMENT_HEADER_SIZE};
+
+use super::NetPair;
+
+const FRAGMENT_TIMEOUT_MS: u64 = 5000;
+const MAX_SOURCE_MEMORY: usize = 1024 * 1024;
+const MAX_TOTAL_MEMORY: usize = 16 * 1024 * 1024;
+/// Max incomplete datagrams per source, each one holds a chunk list even before its chunks arrive
+const MAX_SOURCE_SLOTS: usize = 128;
+const CHUNK_ENTRY_SIZE: usize = std::mem::size_of::<Option<Buffer>>();
+
+struct Slot {
+    started_ms: u64,
+    chunks: Vec<Option<Buffer>>,
+    received: usize,
+    size: usize,
+}
+
+impl Slot {
+    /// Received bytes and the chunk list
+    fn memory(&self) -> usize {
+        self.size + self.chunks.len() * CHUNK_ENTRY_SIZE
+    }
+}
+
+#[derive(Default)]
+struct Source {
+    slots: HashMap<u32, Slot>,
+    memory: usize,
+}
+
+/// Reassembling fragmented datagrams from each remote pair.
+/// Incomplete datagrams are dropped after FRAGMENT_TIMEOUT_MS, and memory is limited per source and in total.
+#[derive(Default)]
+pub struct FragmentReassembler {
+    sources: HashMap<NetPair, Source>,
+    memory: usize,
+}
+
+impl FragmentReassembler {
+    /// Process a fragment, return the full datagram when all fragments are received
+    pub fn on_fragment(&mut self, now_ms: u64, pair: NetPair, buf: &[u8]) -> Option<Buffer> {
+        let header = FragmentHeader::try_from(buf).ok()?;
+        let chunk = &buf[FRAGMENT_HEADER_SIZE..];
+        if chunk.is_empty() {
+            log::debug!("[FragmentReassembler] empty fragment {} from {pair}", header.msg_id);
+            return None;
+        }
+        let source = self.sources.entry(pair).or_default();
+        let is_new = !source.slots.contains_key(&header.msg_id);
+        if is_new && source.slots.len() >= MAX_SOURCE_SLOTS {
+            log::warn!("[FragmentReassembler] slots limit reached, drop fragment {} from {pair}", header.msg_id);
+            return None;
+        }
+        let need = chunk.len()
+            + if is_new {
+                header.count as usize * CHUNK_ENTRY_SIZE
+            } else {
+                0
+            };
+        if self.memory + need > MAX_TOTAL_MEMORY || source.memory + need > MAX_SOURCE_MEMORY {
+            log::warn!("[FragmentReassembler] memory limit reached, drop fragment {} from {pair}", header.msg_id);
+            return None;
+        }
+
+        let slot = source.slots.entry(header.msg_id).or_insert_with(|| Slot {
+            started_ms: now_ms,
+            chunks: vec![None; header.count as usize],
+            received: 0,
+            size: 0,
+        });
+        if is_new {
+            source.memory += slot.memory();
+            self.memory += slot.memory();
+        }
+        if slot.chunks.len() != header.count as usize {
+            log::warn!("[FragmentReassembler] fragment count mismatch for {} from {pair}", header.msg_id);
+            return None;
+        }
+        let index = header.index as usize;
+        if slot.chunks[index].is_some() {
+            log::debug!("[FragmentReassembler] duplicated fragment {}/{} from {pair}", header.msg_id, index);
+            return None;
+        }
+        slot.chunks[index] = Some(Buffer::from(chunk.to_vec()));
+        slot.received += 1;
+        slot.size += chunk.len();
+        source.memory += chunk.len();
+        self.memory += chunk.len();
+
+        if slot.received < slot.chunks.len() {
+            return None;
+        }
+
+        let slot = source.slots.remove(&header.msg_id).expect("Should have slot");
+        source.memory -= slot.memory();
+        self.memory -= slot.memory();
+        let mut buf = Buffer::new(0, slot.size);
+        for chunk in slot.chunks.into_iter().flatten() {
+            buf.push_back(&chunk);
+        }
+        Some(buf)
+    }
+
+    /// Drop incomplete datagrams which are timed out
+    pub fn on_tick(&mut self, now_ms: u64) {
+        let mut released = 0;
+        for (pair, source) in self.sources.iter_mut() {
+            source.slots.retain(|msg_id, slot| {
+                if now_ms >= slot.started_ms + FRAGMENT_TIMEOUT_MS {
+                    log::debug!("[FragmentReassembler] fragmented msg {msg_id} from {pair} timeout, received {}/{}", slot.received, slot.chunks.len());
+                    source.memory -= slot.memory();
+                    released += slot.memory();
+                    false
+                } else {
+                    true
+                }
+            });
+        }
+        self.memory -= released;
+        self.sources.retain(|_, source| !source.slots.is_empty());
+    }
+
+    /// Remove all pending fragments from a remote pair, this is called when the connection is removed
+    pub fn remove_source(&mut self, pair: &NetPair) {
+        if let Some(source) = self.sources.remove(pair) {
+            self.memory -= source.memory;
+        }
+    }
+
+    #[allow(unused)]
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::base::FragmentHeader;
+
+    use super::*;
+
+    fn pair() -> NetPair {
+        NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse")
+    }
+
+    #[test]
+    fn reassemble_out_of_order() {
+        let data = (0..200).map(|i| i as u8).collect::<Vec<_>>();
+        let mut fragments = FragmentHeader::split(1, &data, 64).expect("Should split");
+        fragments.reverse();
+        let last = fragments.pop().expect("Should have fragment");
+
+        let mut reassembler = FragmentReassembler::default();
+        for frag in &fragments {
+            assert_eq!(reassembler.on_fragment(0, pair(), frag), None);
+        }
+        // duplicated fragment should be ignored
+        assert_eq!(reassembler.on_fragment(0, pair(), &fragments[0]), None);
+        assert_eq!(reassembler.on_fragment(0, pair(), &last).as_deref(), Some(data.as_slice()));
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn drop_timeout() {
+        let fragments = FragmentHeader::split(1, &[1; 100], 64).expect("Should split");
+        let mut reassembler = FragmentReassembler::default();
+        assert_eq!(reassembler.on_fragment(0, pair(), &fragments[0]), None);
+        assert!(reassembler.memory() > 0);
+
+        reassembler.on_tick(FRAGMENT_TIMEOUT_MS - 1);
+        assert!(reassembler.memory() > 0);
+
+        reassembler.on_tick(FRAGMENT_TIMEOUT_MS);
+        assert_eq!(reassembler.memory(), 0);
+
+        // late fragment will start a new slot and not complete the datagram
+        assert_eq!(reassembler.on_fragment(FRAGMENT_TIMEOUT_MS, pair(), &fragments[1]), None);
+        reassembler.remove_source(&pair());
+        assert_eq!(reassembler.memory(), 0);
+    }
+
+    #[test]
+    fn memory_limit_per_source() {
+        let data = vec![1; 1400 * 200];
+        let mut reassembler = FragmentReassembler::default();
+        let mut msg_id = 0;
+        while reassembler.memory() + 1400 <= MAX_SOURCE_MEMORY {
+            msg_id += 1;
+            let fragments = FragmentHeader::split(msg_id, &data, 1400 + FRAGMENT_HEADER_SIZE).expect("Should split");
+            for frag in &fragments[1..] {
+                if reassembler.memory() + 1400 > MAX_SOURCE_MEMORY {
+                    break;
+                }
+                assert_eq!(reassembler.on_fragment(0, pair(), frag), None);
+            }
+        }
+        let memory = reassembler.memory();
+        let fragments = FragmentHeader::split(msg_id + 1, &data, 1400 + FRAGMENT_HEADER_SIZE).expect("Should split");
+        assert_eq!(reassembler.on_fragment(0, pair(), &fragments[0]), None);
+        assert_eq!(reassembler.memory(), memory);
+    }
+
+    #[test]
+    fn empty_fragments_flood_is_bounded() {
+        fn fragment(msg_id: u32, count: u8, chunk_len: usize) -> Vec<u8> {
+            let mut buf = vec![1; FRAGMENT_HEADER_SIZE + chunk_len];
+            FragmentHeader { msg_id, index: 0, count }.to_bytes(&mut buf).expect("Should write header");
+            buf
+        }
+
+        let mut reassembler = FragmentReassembler::default();
+        for msg_id in 0..10_000 {
+            assert_eq!(reassembler.on_fragment(0, pair(), &fragment(msg_id, 255, 0)), None);
+        }
+        assert_eq!(reassembler.memory(), 0);
+
+        // chunk lists of new slots are charged
+        for msg_id in 0..10_000 {
+            assert_eq!(reassembler.on_fragment(0, pair(), &fragment(msg_id, 255, 1)), None);
+        }
+        assert!(reassembler.memory() <= MAX_SOURCE_MEMORY);
+        assert!(reassembler.sources[&pair()].slots.len() < MAX_SOURCE_SLOTS);
+
+        // small slots are limited by count
+        let pair2 = NetPair::new_str("1.1.1.1:1000", "1.2.3.5:1000").expect("Should parse");
+        for msg_id in 0..10_000 {
+            assert_eq!(reassembler.on_fragment(0, pair2, &fragment(msg_id, 2, 1)), None);
+        }
+        assert_eq!(reassembler.sources[&pair2].slots.len(), MAX_SOURCE_SLOTS);
+
+        reassembler.on_tick(FRAGMENT_TIMEOUT_MS);
+        assert_eq!(reassembler.memory(), 0);
+    }
+}
@@ -0,0 +1,222 @@
+use std::collections::VecDeque;
+
+use crate::base::{Buffer, MsgPriority, MSG_PRIORITY_CLASSES};
+
+use super::shaper::{RateLimit, TokenBucket};
+
+const MAX_QUEUE_BYTES: usize = 1024 * 1024;
+/// A packet which is bigger than the bucket size could never be sent, so burst is at least a max datagram
+const MIN_BURST_BYTES: u64 = 65536;
+/// Queue is considered congested when its backlog needs more than this time for draining with the send budget
+const CONGESTION_DELAY_MS: u64 = 20;
+/// How many packets each class can send in a round, index is MsgPriority value
+const CLASS_WEIGHTS: [u32; MSG_PRIORITY_CLASSES] = [1, 2, 4, 8];
+
+/// Outgoing queue of a single NetPair, which has one sub-queue per priority class.
+/// Packets are dequeued with weighted round robin between classes, so lower classes are slowed down but not starved.
+/// When the queue is full, packets from lower classes are dropped first.
+/// With a send budget, packets are only popped while the token bucket has enough bytes, so a backlog is built
+/// when the pair is sending faster than the budget and priority classes decide which packets go first.
+pub struct SendQueue {
+    classes: [VecDeque<Buffer>; MSG_PRIORITY_CLASSES],
+    credits: [u32; MSG_PRIORITY_CLASSES],
+    bytes: usize,
+    capacity: usize,
+    dropped: u64,
+    budget: Option<TokenBucket>,
+}
+
+impl SendQueue {
+    pub fn new(now_ms: u64, budget: Option<RateLimit>) -> Self {
+        Self::new_with_capacity(now_ms, budget, MAX_QUEUE_BYTES)
+    }
+
+    pub fn new_with_capacity(now_ms: u64, budget: Option<RateLimit>, capacity: usize) -> Self {
+        Self {
+            classes: Default::default(),
+            credits: CLASS_WEIGHTS,
+            bytes: 0,
+            capacity,
+            dropped: 0,
+            budget: budget.map(|limit| TokenBucket::new(now_ms, RateLimit::new(limit.bytes_per_sec, limit.burst_bytes.max(MIN_BURST_BYTES)))),
+        }
+    }
+
+    /// Push a packet to the queue, return false if the packet is dropped
+    pub fn push(&mut self, priority: MsgPriority, buf: Buffer) -> bool {
+        let class = priority as usize;
+        while self.bytes + buf.len() > self.capacity {
+            // evict newest packet from the lowest class which is lower than incoming packet
+            let lower = (0..class).find(|c| !self.classes[*c].is_empty());
+            if let Some(evicted) = lower.and_then(|c| self.classes[c].pop_back()) {
+                self.bytes -= evicted.len();
+                self.dropped += 1;
+            } else {
+                self.dropped += 1;
+                return false;
+            }
+        }
+        self.bytes += buf.len();
+        self.classes[class].push_back(buf);
+        true
+    }
+
+    /// Pop next packet, higher classes are served first while they have credits.
+    /// Return None if the queue is empty or the send budget is not enough for the next packet.
+    pub fn pop(&mut self, now_ms: u64) -> Option<Buffer> {
+        let class = self.next_class()?;
+        let len = self.classes[class].front()?.len();
+        if !self.take_budget(now_ms, len) {
+            return None;
+        }
+        self.credits[class] -= 1;
+        self.bytes -= len;
+        self.classes[class].pop_front()
+    }
+
+    /// Keep only packets which match the filter, dropped packets are not counted as they are not caused by congestion
+    pub fn retain<F: Fn(&Buffer) -> bool>(&mut self, f: F) {
+        for class in self.classes.iter_mut() {
+            class.retain(|buf| f(buf));
+        }
+        self.bytes = self.classes.iter().flatten().map(|buf| buf.len()).sum();
+    }
+
+    /// Consume budget for a packet which is sent without queueing, only allowed when there is no backlog
+    pub fn try_send_now(&mut self, now_ms: u64, len: usize) -> bool {
+        self.is_empty() && self.take_budget(now_ms, len)
+    }
+
+    fn take_budget(&mut self, now_ms: u64, len: usize) -> bool {
+        if let Some(budget) = &mut self.budget {
+            budget.refill(now_ms);
+            if !budget.has(len) {
+                return false;
+            }
+            budget.consume(len);
+        }
+        true
+    }
+
+    fn next_class(&mut self) -> Option<usize> {
+        if self.is_empty() {
+            return None;
+        }
+        loop {
+            if let Some(class) = (0..MSG_PRIORITY_CLASSES).rev().find(|c| self.credits[*c] > 0 && !self.classes[*c].is_empty()) {
+                return Some(class);
+            }
+            // all non-empty classes are out of credits, start a new round
+            self.credits = CLASS_WEIGHTS;
+        }
+    }
+
+    /// Queue is backing up, relayed packets should be marked with congestion experienced.
+    /// Without a send budget packets are never kept between polls, so the link queueing delay of the connection is used instead
+    pub fn is_congested(&self) -> bool {
+        match &self.budget {
+            Some(budget) => self.bytes as u64 * 1000 > budget.limit().bytes_per_sec * CONGESTION_DELAY_MS,
+            None => false,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.classes.iter().all(|c| c.is_empty())
+    }
+
+    /// Total dropped packets since created
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pkt(class: u8, size: usize) -> Buffer {
+        Buffer::from(vec![class; size])
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let mut queue = SendQueue::new(0, None);
+        for _ in 0..10 {
+            for priority in [MsgPriority::Bulk, MsgPriority::Normal, MsgPriority::Interactive, MsgPriority::Control] {
+                assert!(queue.push(priority, pkt(priority as u8, 10)));
+            }
+        }
+
+        let round = (0..15).map(|_| queue.pop(0).expect("Should have pkt")[0]).collect::<Vec<_>>();
+        assert_eq!(round, vec![3, 3, 3, 3, 3, 3, 3, 3, 2, 2, 2, 2, 1, 1, 0]);
+
+        let mut rest = 25;
+        while queue.pop(0).is_some() {
+            rest -= 1;
+        }
+        assert_eq!(rest, 0);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn drop_lower_class_first() {
+        let mut queue = SendQueue::new_with_capacity(0, None, 30);
+        assert!(queue.push(MsgPriority::Bulk, pkt(0, 10)));
+        assert!(queue.push(MsgPriority::Normal, pkt(1, 10)));
+        assert!(queue.push(MsgPriority::Interactive, pkt(2, 10)));
+
+        // full, bulk packet is evicted
+        assert!(queue.push(MsgPriority::Control, pkt(3, 10)));
+        assert_eq!(queue.dropped(), 1);
+
+        // full, incoming normal packet cannot evict same or higher classes
+        assert!(!queue.push(MsgPriority::Normal, pkt(1, 10)));
+        assert_eq!(queue.dropped(), 2);
+
+        assert_eq!(queue.pop(0).map(|b| b[0]), Some(3));
+        assert_eq!(queue.pop(0).map(|b| b[0]), Some(2));
+        assert_eq!(queue.pop(0).map(|b| b[0]), Some(1));
+        assert_eq!(queue.pop(0), None);
+    }
+
+    #[test]
+    fn pop_within_budget() {
+        // 100KB/s with the min burst 64KB
+        let mut queue = SendQueue::new(0, Some(RateLimit::new(100_000, 0)));
+        assert!(queue.try_send_now(0, 60_000));
+        assert!(!queue.try_send_now(0, 10_000));
+
+        assert!(queue.push(MsgPriority::Bulk, pkt(0, 1_000)));
+        assert!(!queue.is_congested());
+        assert!(queue.push(MsgPriority::Control, pkt(3, 10_000)));
+        // 11KB backlog needs 110ms at 100KB/s
+        assert!(queue.is_congested());
+        // has backlog, packets must not bypass the queue
+        assert!(!queue.try_send_now(100, 100));
+
+        // 5536 bytes left, need 45ms for refilling enough bytes, higher class goes first
+        assert_eq!(queue.pop(0), None);
+        assert_eq!(queue.pop(44), None);
+        assert_eq!(queue.pop(45).map(|b| b[0]), Some(3));
+        assert!(!queue.is_congested());
+        assert_eq!(queue.pop(54), None);
+        assert_eq!(queue.pop(55).map(|b| b[0]), Some(0));
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn retain_update_bytes() {
+        let mut queue = SendQueue::new_with_capacity(0, None, 30);
+        assert!(queue.push(MsgPriority::Bulk, pkt(0, 10)));
+        assert!(queue.push(MsgPriority::Control, pkt(3, 10)));
+        assert!(queue.push(MsgPriority::Bulk, pkt(0, 10)));
+        queue.retain(|buf| buf[0] == 3);
+        assert_eq!(queue.dropped(), 0);
+
+        // freed bytes can be used again
+        assert!(queue.push(MsgPriority::Bulk, pkt(0, 20)));
+        assert_eq!(queue.pop(0).map(|b| b[0]), Some(3));
+        assert_eq!(queue.pop(0).map(|b| b.len()), Some(20));
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,233 @@
+use std::collections::HashMap;
+
+use atm0s_sdn_identity::NodeId;
+
+use crate::features::Features;
+
+use super::NetPair;
+
+/// Token bucket config, `burst_bytes` is the bucket size and `bytes_per_sec` is the refill rate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: u64,
+    pub burst_bytes: u64,
+}
+
+impl RateLimit {
+    pub fn new(bytes_per_sec: u64, burst_bytes: u64) -> Self {
+        Self { bytes_per_sec, burst_bytes }
+    }
+}
+
+/// Limits which are applied to relayed traffic inside each worker.
+/// Specific limits take precedence over default ones, a packet is dropped if any matched bucket is empty.
+#[derive(Debug, Clone, Default)]
+pub struct ShapingCfg {
+    pub features: HashMap<Features, RateLimit>,
+    pub pairs: HashMap<NetPair, RateLimit>,
+    pub nodes: HashMap<NodeId, RateLimit>,
+    /// Applied to each remote pair which don't have specific limit
+    pub default_pair: Option<RateLimit>,
+    /// Applied to each source node which don't have specific limit
+    pub default_node: Option<RateLimit>,
+}
+
+impl ShapingCfg {
+    fn limit_of(&self, key: &ShapingKey) -> Option<RateLimit> {
+        match key {
+            ShapingKey::Feature(feature) => self.features.get(feature).cloned(),
+            ShapingKey::Pair(pair) => self.pairs.get(pair).cloned().or(self.default_pair),
+            ShapingKey::Node(node) => self.nodes.get(node).cloned().or(self.default_node),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ShapingKey {
+    Feature(Features),
+    Pair(NetPair),
+    Node(NodeId),
+}
+
+/// Drop counters of a key, which are reported with ExtOut::RateLimited
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ShapingStats {
+    pub dropped_pkts: u64,
+    pub dropped_bytes: u64,
+}
+
+pub(super) struct TokenBucket {
+    limit: RateLimit,
+    /// tokens in milli-bytes for avoiding lost of fractional refill
+    tokens: u64,
+    last_ms: u64,
+    stats: ShapingStats,
+    reported_pkts: u64,
+}
+
+impl TokenBucket {
+    pub(super) fn new(now_ms: u64, limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst_bytes * 1000,
+            last_ms: now_ms,
+            stats: Default::default(),
+            reported_pkts: 0,
+        }
+    }
+
+    pub(super) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    pub(super) fn refill(&mut self, now_ms: u64) {
+        let elapsed = now_ms.saturating_sub(self.last_ms);
+        self.last_ms = now_ms.max(self.last_ms);
+        self.tokens = (self.tokens + elapsed * self.limit.bytes_per_sec).min(self.limit.burst_bytes * 1000);
+    }
+
+    pub(super) fn has(&self, len: usize) -> bool {
+        self.tokens >= len as u64 * 1000
+    }
+
+    pub(super) fn consume(&mut self, len: usize) {
+        self.tokens -= len as u64 * 1000;
+    }
+
+    fn record_drop(&mut self, len: usize) {
+        self.stats.dropped_pkts += 1;
+        self.stats.dropped_bytes += len as u64;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.limit.burst_bytes * 1000
+    }
+}
+
+/// Token bucket shaping for relayed traffic, keyed by feature, remote pair and source node.
+pub struct TrafficShaper {
+    cfg: ShapingCfg,
+    buckets: HashMap<ShapingKey, TokenBucket>,
+}
+
+impl TrafficShaper {
+    pub fn new(cfg: ShapingCfg) -> Self {
+        Self { cfg, buckets: HashMap::new() }
+    }
+
+    /// Check if a packet can pass all matched buckets, tokens are only consumed if it is allowed
+    pub fn allow(&mut self, now_ms: u64, feature: Option<Features>, pair: NetPair, source: NodeId, len: usize) -> bool {
+        let keys = [feature.map(ShapingKey::Feature), Some(ShapingKey::Pair(pair)), Some(ShapingKey::Node(source))];
+        let mut allowed = true;
+        for key in keys.iter().flatten() {
+            if let Some(bucket) = self.bucket(now_ms, key) {
+                bucket.refill(now_ms);
+                allowed &= bucket.has(len);
+            }
+        }
+
+        for key in keys.iter().flatten() {
+            if let Some(bucket) = self.buckets.get_mut(key) {
+                if allowed {
+                    bucket.consume(len);
+                } else if !bucket.has(len) {
+                    bucket.record_drop(len);
+                }
+            }
+        }
+        allowed
+    }
+
+    /// Return keys which dropped packets since last call, buckets which are idle are cleaned up
+    pub fn on_tick(&mut self, now_ms: u64) -> Vec<(ShapingKey, ShapingStats)> {
+        let mut reports = vec![];
+        self.buckets.retain(|key, bucket| {
+            if bucket.stats.dropped_pkts > bucket.reported_pkts {
+                bucket.reported_pkts = bucket.stats.dropped_pkts;
+                reports.push((*key, bucket.stats));
+            }
+            bucket.refill(now_ms);
+            // feature buckets are kept for persisting drop counters, others are removed when idle for saving memory
+            matches!(key, ShapingKey::Feature(_)) || !bucket.is_full()
+        });
+        reports
+    }
+
+    #[allow(unused)]
+    pub fn stats(&self, key: &ShapingKey) -> Option<ShapingStats> {
+        self.buckets.get(key).map(|b| b.stats)
+    }
+
+    fn bucket(&mut self, now_ms: u64, key: &ShapingKey) -> Option<&mut TokenBucket> {
+        if !self.buckets.contains_key(key) {
+            let limit = self.cfg.limit_of(key)?;
+            self.buckets.insert(*key, TokenBucket::new(now_ms, limit));
+        }
+        self.buckets.get_mut(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pair() -> NetPair {
+        NetPair::new_str("1.1.1.1:1000", "1.2.3.4:1000").expect("Should parse")
+    }
+
+    #[test]
+    fn no_limit() {
+        let mut shaper = TrafficShaper::new(ShapingCfg::default());
+        for _ in 0..1000 {
+            assert!(shaper.allow(0, Some(Features::Data), pair(), 1, 1000));
+        }
+        assert_eq!(shaper.on_tick(1000), vec![]);
+    }
+
+    #[test]
+    fn limit_per_feature_and_refill() {
+        let mut cfg = ShapingCfg::default();
+        cfg.features.insert(Features::Data, RateLimit::new(1000, 2000));
+        let mut shaper = TrafficShaper::new(cfg);
+
+        assert!(shaper.allow(0, Some(Features::Data), pair(), 1, 1000));
+        assert!(shaper.allow(0, Some(Features::Data), pair(), 1, 1000));
+        assert!(!shaper.allow(0, Some(Features::Data), pair(), 1, 1000));
+        // other features are not limited
+        assert!(shaper.allow(0, Some(Features::PubSub), pair(), 1, 1000));
+
+        // after 500ms we have 500 bytes
+        assert!(!shaper.allow(500, Some(Features::Data), pair(), 1, 1000));
+        assert!(shaper.allow(500, Some(Features::Data), pair(), 1, 500));
+
+        let stats = ShapingStats { dropped_pkts: 2, dropped_bytes: 2000 };
+        assert_eq!(shaper.on_tick(1000), vec![(ShapingKey::Feature(Features::Data), stats)]);
+        // no new drop => no report
+        assert_eq!(shaper.on_tick(2000), vec![]);
+        assert_eq!(shaper.stats(&ShapingKey::Feature(Features::Data)), Some(stats));
+    }
+
+    #[test]
+    fn limit_node_not_consume_other_buckets_when_dropped() {
+        let mut cfg = ShapingCfg {
+            default_pair: Some(RateLimit::new(1000, 1000)),
+            ..Default::default()
+        };
+        cfg.nodes.insert(2, RateLimit::new(1000, 500));
+        let mut shaper = TrafficShaper::new(cfg);
+
+        // node 2 is limited, pair tokens are not consumed
+        assert!(!shaper.allow(0, None, pair(), 2, 600));
+        assert!(shaper.allow(0, None, pair(), 1, 1000));
+        assert!(!shaper.allow(0, None, pair(), 1, 1));
+
+        let reports = shaper.on_tick(0);
+        assert_eq!(reports.len(), 2);
+        assert!(reports.contains(&(ShapingKey::Node(2), ShapingStats { dropped_pkts: 1, dropped_bytes: 600 })));
+        assert!(reports.contains(&(ShapingKey::Pair(pair()), ShapingStats { dropped_pkts: 1, dropped_bytes: 1 })));
+
+        // idle buckets are removed after full
+        assert_eq!(shaper.on_tick(10000), vec![]);
+        assert_eq!(shaper.stats(&ShapingKey::Pair(pair())), None);
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+
+use crate::base::Buffer;
+
+use super::NetPair;
+
+/// Transport which carries datagrams of a NetPair. The protocol id is stored in ConnId, so routes
+/// know which transport a connection is using.
+#[derive(Debug, Hash, PartialEq, Eq, Clone, Copy, PartialOrd, Ord, Default)]
+pub enum Transport {
+    #[default]
+    Udp,
+    /// Length-prefixed frames over a stream, which is used as fallback when UDP is blocked.
+    /// Streams are owned by the controller worker, other workers forward their frames to it.
+    Tcp,
+    /// Binary frames over a WebSocket, which lets browsers and firewalled agents join as leaf neighbours.
+    /// Same as Tcp, streams are owned by the controller worker.
+    WebSocket,
+    /// Frames inside a process, for embedding many nodes in one process or testing
+    Memory,
+}
+
+impl Transport {
+    /// Non-UDP transports carry whole frames without mtu and are owned by the controller worker
+    pub fn is_stream(&self) -> bool {
+        !matches!(self, Transport::Udp)
+    }
+
+    /// Protocol id in ConnId
+    pub fn protocol(&self) -> u8 {
+        match self {
+            Transport::Udp => 0,
+            Transport::Tcp => 1,
+            Transport::WebSocket => 2,
+            Transport::Memory => 3,
+        }
+    }
+
+    pub fn from_protocol(protocol: u8) -> Option<Self> {
+        match protocol {
+            0 => Some(Transport::Udp),
+            1 => Some(Transport::Tcp),
+            2 => Some(Transport::WebSocket),
+            3 => Some(Transport::Memory),
+            _ => None,
+        }
+    }
+}
+
+/// Extra route cost in milliseconds per transport, streams suffer head-of-line blocking so UDP paths are preferred with same rtt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TransportPenalty {
+    pub udp: u16,
+    pub tcp: u16,
+    pub websocket: u16,
+    pub memory: u16,
+}
+
+impl Default for TransportPenalty {
+    fn default() -> Self {
+        Self {
+            udp: 0,
+            tcp: 20,
+            websocket: 30,
+            memory: 0,
+        }
+    }
+}
+
+impl TransportPenalty {
+    pub fn penalty_ms(&self, transport: Transport) -> u16 {
+        match transport {
+            Transport::Udp => self.udp,
+            Transport::Tcp => self.tcp,
+            Transport::WebSocket => self.websocket,
+            Transport::Memory => self.memory,
+        }
+    }
+}
+
+/// A frame transport which is polled by the controller worker, for all transports except UDP which is
+/// handled by the runtime backend.
+pub trait FrameTransport: Send {
+    fn transport(&self) -> Transport;
+    /// Called with each bound UDP addr, transports which share addrs with UDP can listen on them
+    fn on_bind(&mut self, _addr: SocketAddr) {}
+    /// Called when a bound UDP addr is removed at runtime
+    fn on_unbind(&mut self, _addr: SocketAddr) {}
+    fn send(&mut self, pair: NetPair, frame: &[u8]);
+    /// Returns all received frames
+    fn poll(&mut self) -> Vec<(NetPair, Buffer)>;
+}
+
+/// Shared inboxes of in-memory transports, keyed by bound addr
+#[derive(Default)]
+pub struct MemoryNetwork {
+    inboxes: Mutex<HashMap<SocketAddr, VecDeque<(NetPair, Buffer)>>>,
+}
+
+/// In-memory transport, frames are delivered to the transport which bound the remote addr of the pair
+pub struct MemoryTransport {
+    network: Arc<MemoryNetwork>,
+    addrs: Vec<SocketAddr>,
+}
+
+impl MemoryTransport {
+    pub fn new(network: Arc<MemoryNetwork>) -> Self {
+        Self { network, addrs: vec![] }
+    }
+}
+
+impl FrameTransport for MemoryTransport {
+    fn transport(&self) -> Transport {
+        Transport::Memory
+    }
+
+    fn on_bind(&mut self, addr: SocketAddr) {
+        self.network.inboxes.lock().entry(addr).or_default();
+        self.addrs.push(addr);
+    }
+
+    fn on_unbind(&mut self, addr: SocketAddr) {
+        self.network.inboxes.lock().remove(&addr);
+        self.addrs.retain(|a| *a != addr);
+    }
+
+    fn send(&mut self, pair: NetPair, frame: &[u8]) {
+        let mut inboxes = self.network.inboxes.lock();
+        if let Some(inbox) = inboxes.get_mut(&pair.remote) {
+            let in_pair = NetPair {
+                local: pair.remote,
+                remote: pair.local,
+                transport: Transport::Memory,
+            };
+            inbox.push_back((in_pair, Buffer::from(frame.to_vec())));
+        } else {
+            log::debug!("[MemoryTransport] no transport bound {}, drop frame", pair.remote);
+        }
+    }
+
+    fn poll(&mut self) -> Vec<(NetPair, Buffer)> {
+        let mut inboxes = self.network.inboxes.lock();
+        let mut received = vec![];
+        for addr in &self.addrs {
+            if let Some(inbox) = inboxes.get_mut(addr) {
+                received.extend(inbox.drain(..));
+            }
+        }
+        received
+    }
+}
+
+impl Drop for MemoryTransport {
+    fn drop(&mut self) {
+        let mut inboxes = self.network.inboxes.lock();
+        for addr in &self.addrs {
+            inboxes.remove(addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::data_plane::NetPair;
+
+    use super::{FrameTransport, MemoryNetwork, MemoryTransport, Transport, TransportPenalty};
+
+    #[test]
+    fn protocol_roundtrip() {
+        for transport in [Transport::Udp, Transport::Tcp, Transport::WebSocket, Transport::Memory] {
+            assert_eq!(Transport::from_protocol(transport.protocol()), Some(transport));
+        }
+        assert_eq!(Transport::from_protocol(100), None);
+    }
+
+    #[test]
+    fn penalty_from_config() {
+        let default = TransportPenalty::default();
+        assert_eq!(default.penalty_ms(Transport::Udp), 0);
+        assert!(default.penalty_ms(Transport::Tcp) > 0);
+        assert!(default.penalty_ms(Transport::WebSocket) > default.penalty_ms(Transport::Tcp));
+
+        let custom = TransportPenalty { tcp: 5, ..Default::default() };
+        assert_eq!(custom.penalty_ms(Transport::Tcp), 5);
+        assert_eq!(custom.penalty_ms(Transport::WebSocket), default.penalty_ms(Transport::WebSocket));
+    }
+
+    #[test]
+    fn memory_transport_deliver_to_bound_addr() {
+        let network = Arc::new(MemoryNetwork::default());
+        let mut node1 = MemoryTransport::new(network.clone());
+        let mut node2 = MemoryTransport::new(network.clone());
+        let addr1 = "127.0.0.1:1".parse().expect("Should parse");
+        let addr2 = "127.0.0.1:2".parse().expect("Should parse");
+        node1.on_bind(addr1);
+        node2.on_bind(addr2);
+
+        let pair = NetPair {
+            local: addr1,
+            remote: addr2,
+            transport: Transport::Memory,
+        };
+        node1.send(pair, &[1, 2, 3]);
+        assert_eq!(node1.poll().len(), 0);
+        let received = node2.poll();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0.local, addr2);
+        assert_eq!(received[0].0.remote, addr1);
+        assert_eq!(&*received[0].1, &[1, 2, 3]);
+
+        // dropped transport doesn't receive anymore
+        drop(node2);
+        node1.send(pair, &[4]);
+        assert!(network.inboxes.lock().get(&addr2).is_none());
+    }
+}
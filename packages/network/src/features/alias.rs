@@ -4,7 +4,7 @@ use std::{
 };
 
 use atm0s_sdn_identity::NodeId;
-use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+use atm0s_sdn_router::{next_broadcast_seq, RouteRule, ServiceBroadcastLevel};
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 use serde::{Deserialize, Serialize};
@@ -81,7 +81,7 @@ pub struct AliasFeature<UserData> {
     hint_slots: HashMap<u64, HintSlot>,
     local_slots: HashMap<u64, u64>,
     queue: VecDeque<Output<UserData>>,
-    scan_seq: u16,
+    scan_seq: u32,
 }
 
 impl<UserData: Debug + Copy> AliasFeature<UserData> {
@@ -182,8 +182,7 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
                                 self.queries.remove(&alias);
                             } else {
                                 log::debug!("[AliasFeature] Not found alias {alias} at hint {node} => switch to Scan");
-                                let seq = self.scan_seq;
-                                self.scan_seq = self.scan_seq.wrapping_add(1);
+                                let seq = Self::gen_seq(&mut self.scan_seq);
                                 slot.state = QueryState::Scan(now_ms);
                                 Self::send_to(&mut self.queue, RouteRule::ToServices(slot.service, slot.level, seq), Message::Scan(alias));
                             }
@@ -210,9 +209,9 @@ impl<UserData: Debug + Copy> AliasFeature<UserData> {
         queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::new(true, Ttl::default(), 0, true), msg.into()));
     }
 
-    fn gen_seq(scan_seq: &mut u16) -> u16 {
+    fn gen_seq(scan_seq: &mut u32) -> u32 {
         let seq = *scan_seq;
-        *scan_seq = scan_seq.wrapping_add(1);
+        *scan_seq = next_broadcast_seq(*scan_seq);
         seq
     }
 }
@@ -227,8 +226,7 @@ impl<UserData: Debug + Copy> Feature<UserData, Control, Event, ToController, ToW
                         if now >= *started_at + HINT_TIMEOUT_MS {
                             log::debug!("[AliasFeature] check {alias} hint node {hint} timeout => switch to Scan");
 
-                            let seq = self.scan_seq;
-                            self.scan_seq = self.scan_seq.wrapping_add(1);
+                            let seq = Self::gen_seq(&mut self.scan_seq);
                             slot.state = QueryState::Scan(now);
                             Self::send_to(&mut self.queue, RouteRule::ToServices(slot.service, slot.level, seq), Message::Scan(*alias));
                         }
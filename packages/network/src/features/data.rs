@@ -10,7 +10,8 @@ use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 use serde::{Deserialize, Serialize};
 
 use crate::base::{
-    Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, NetIncomingMeta, NetOutgoingMeta,
+    CongestionEchoLimiter, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput, MsgPriority,
+    NetIncomingMeta, NetOutgoingMeta,
 };
 
 pub const FEATURE_ID: u8 = 1;
@@ -19,6 +20,7 @@ pub const FEATURE_NAME: &str = "data_transfer";
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Control {
     Ping(NodeId),
+    /// Port 0 is used by the visualization service and port 1 by the membership service as default
     DataListen(u16),
     DataUnlisten(u16),
     DataSendRule(u16, RouteRule, NetOutgoingMeta, Vec<u8>),
@@ -28,6 +30,8 @@ pub enum Control {
 pub enum Event {
     Pong(NodeId, Option<u16>),
     Recv(u16, NetIncomingMeta, Vec<u8>),
+    /// Remote node echoes that our data to it on the port is experiencing congestion
+    Congestion(u16, NodeId),
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +55,7 @@ pub struct DataFeature<UserData> {
     ping_seq: u64,
     queue: VecDeque<Output<UserData>>,
     data_dest: HashMap<u16, FeatureControlActor<UserData>>,
+    congestion_echo: CongestionEchoLimiter<(NodeId, u16)>,
 }
 
 impl<UserData> Default for DataFeature<UserData> {
@@ -60,6 +65,7 @@ impl<UserData> Default for DataFeature<UserData> {
             ping_seq: 0,
             queue: VecDeque::new(),
             data_dest: HashMap::new(),
+            congestion_echo: Default::default(),
         }
     }
 }
@@ -67,6 +73,7 @@ impl<UserData> Default for DataFeature<UserData> {
 impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> for DataFeature<UserData> {
     fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
         if let FeatureSharedInput::Tick(_) = input {
+            self.congestion_echo.on_tick(now);
             //clean timeout ping
             let mut timeout_list = Vec::new();
             for (id, (sent_ms, _, _)) in self.waits.iter() {
@@ -129,6 +136,20 @@ impl<UserData: Copy> Feature<UserData, Control, Event, ToController, ToWorker> f
                             self.queue.push_back(FeatureOutput::SendRoute(rule, NetOutgoingMeta::default(), msg.into()));
                         }
                         DataMsg::Data(port, data) => {
+                            if meta.congestion_echo {
+                                if let (Some(actor), Some(source)) = (self.data_dest.get(&port), meta.source) {
+                                    self.queue.push_back(FeatureOutput::Event(*actor, Event::Congestion(port, source)));
+                                }
+                                return;
+                            }
+                            if let Some(source) = meta.source.filter(|_| meta.congested) {
+                                if self.congestion_echo.should_echo(now_ms, (source, port)) {
+                                    log::debug!("[DataFeature] echo congestion to {source} port {port}");
+                                    let msg = bincode::serialize(&DataMsg::Data(port, vec![])).expect("should work");
+                                    let meta = NetOutgoingMeta::new(true, Default::default(), 0, true).set_priority(MsgPriority::Control).set_congestion_echo(true);
+                                    self.queue.push_back(FeatureOutput::SendRoute(RouteRule::ToNode(source), meta, msg.into()));
+                                }
+                            }
                             if let Some(actor) = self.data_dest.get(&port) {
                                 self.queue.push_back(FeatureOutput::Event(*actor, Event::Recv(port, meta, data)));
                             }
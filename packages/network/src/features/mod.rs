@@ -12,7 +12,7 @@ pub mod vpn;
 /// This is a helper struct to help FeatureManager to manage the features
 ///
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, num_enum::TryFromPrimitive, num_enum::IntoPrimitive)]
 #[repr(u8)]
 pub enum Features {
     Neighbours = neighbours::FEATURE_ID,
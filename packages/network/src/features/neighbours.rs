@@ -1,10 +1,20 @@
-use std::{collections::VecDeque, fmt::Debug, hash::Hash};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Debug,
+    hash::Hash,
+};
 
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use derivative::Derivative;
 use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
 
-use crate::base::{ConnectionEvent, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerInput, FeatureWorkerOutput};
+use crate::{
+    base::{
+        AccessControl, ConnectionEvent, ConnectionStats, DisconnectReason, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker,
+        FeatureWorkerInput, FeatureWorkerOutput, NeighboursConnectError,
+    },
+    data_plane::NetPair,
+};
 
 pub const FEATURE_ID: u8 = 0;
 pub const FEATURE_NAME: &str = "neighbours_api";
@@ -15,12 +25,32 @@ pub enum Control {
     UnSub,
     ConnectTo(NodeAddr),
     DisconnectFrom(NodeId),
+    /// Update allow/deny list, which is applied to both incoming and outgoing connections
+    Access(AccessControl),
+    /// Query current neighbours, answered with Event::List
+    List,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NeighbourInfo {
+    pub node: NodeId,
+    pub conn: ConnId,
+    pub pair: NetPair,
+    /// None before the first ping is answered
+    pub stats: Option<ConnectionStats>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     Connected(NodeId, ConnId),
-    Disconnected(NodeId, ConnId),
+    Disconnected(NodeId, ConnId, DisconnectReason),
+    /// Periodic stats of the connection, fired after each ping is answered
+    Stats(NodeId, ConnId, ConnectionStats),
+    /// Best connection to the node, other connections are standby
+    Primary(NodeId, ConnId),
+    ConnectError(NodeId, NeighboursConnectError),
+    GaveUp(NodeId),
+    List(Vec<NeighbourInfo>),
 }
 
 #[derive(Debug, Clone)]
@@ -36,6 +66,7 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 #[derivative(Default(bound = ""))]
 pub struct NeighboursFeature<UserData> {
     subs: Vec<FeatureControlActor<UserData>>,
+    conns: BTreeMap<ConnId, NeighbourInfo>,
     output: VecDeque<Output<UserData>>,
 }
 
@@ -44,14 +75,50 @@ impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToCon
         match input {
             FeatureSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
                 log::debug!("[Neighbours] Connected {}, fire event to {:?}", ctx.pair, self.subs);
+                self.conns.insert(
+                    ctx.conn,
+                    NeighbourInfo {
+                        node: ctx.node,
+                        conn: ctx.conn,
+                        pair: ctx.pair,
+                        stats: None,
+                    },
+                );
                 for sub in self.subs.iter() {
                     self.output.push_back(FeatureOutput::Event(*sub, Event::Connected(ctx.node, ctx.conn)));
                 }
             }
-            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
-                log::debug!("[Neighbours] Disconnected {}, fire event to {:?}", ctx.pair, self.subs);
+            FeatureSharedInput::Connection(ConnectionEvent::Disconnected(ctx, reason)) => {
+                log::debug!("[Neighbours] Disconnected {} with reason {reason:?}, fire event to {:?}", ctx.pair, self.subs);
+                self.conns.remove(&ctx.conn);
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Disconnected(ctx.node, ctx.conn, reason)));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Stats(ctx, stats)) => {
+                if let Some(info) = self.conns.get_mut(&ctx.conn) {
+                    info.stats = Some(stats.clone());
+                }
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Stats(ctx.node, ctx.conn, stats.clone())));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::Primary(ctx)) => {
+                log::debug!("[Neighbours] Primary {} of {}, fire event to {:?}", ctx.pair, ctx.node, self.subs);
                 for sub in self.subs.iter() {
-                    self.output.push_back(FeatureOutput::Event(*sub, Event::Disconnected(ctx.node, ctx.conn)));
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::Primary(ctx.node, ctx.conn)));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::ConnectError(node, err)) => {
+                log::debug!("[Neighbours] ConnectError {node} {err:?}, fire event to {:?}", self.subs);
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::ConnectError(node, err)));
+                }
+            }
+            FeatureSharedInput::Connection(ConnectionEvent::GaveUp(node)) => {
+                log::debug!("[Neighbours] GaveUp {node}, fire event to {:?}", self.subs);
+                for sub in self.subs.iter() {
+                    self.output.push_back(FeatureOutput::Event(*sub, Event::GaveUp(node)));
                 }
             }
             _ => {}
@@ -79,6 +146,13 @@ impl<UserData: Debug + Copy + Hash + Eq> Feature<UserData, Control, Event, ToCon
                 Control::DisconnectFrom(node) => {
                     self.output.push_back(FeatureOutput::NeighboursDisconnectFrom(node));
                 }
+                Control::Access(control) => {
+                    self.output.push_back(FeatureOutput::NeighboursAccess(control));
+                }
+                Control::List => {
+                    let list = self.conns.values().cloned().collect();
+                    self.output.push_back(FeatureOutput::Event(actor, Event::List(list)));
+                }
             }
         }
     }
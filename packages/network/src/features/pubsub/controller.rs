@@ -297,8 +297,9 @@ impl<UserData: 'static + Eq + Copy + Debug> Feature<UserData, Control, Event, To
                     self.pop_single_source_hint(ctx, now, channel);
                 }
             }
+            FeatureSharedInput::Drain => {}
             FeatureSharedInput::Connection(event) => {
-                if let ConnectionEvent::Disconnected(ctx) = event {
+                if let ConnectionEvent::Disconnected(ctx, _) = event {
                     for (relay_id, relay) in self.relays.iter_mut() {
                         relay.conn_disconnected(now, ctx.pair);
                         Self::pop_single_relay(*relay_id, relay, &mut self.queue);
@@ -2,15 +2,18 @@ use std::collections::{HashMap, VecDeque};
 
 use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_router::{
-    core::{DestDelta, Metric, RegistryDelta, RegistryDestDelta, Router, RouterDelta, RouterSync, TableDelta},
+    core::{DestDelta, Metric, RegistryDelta, RegistryDestDelta, Router, RouterDelta, RouterSync, RouterSyncExt, TableDelta},
     shadow::ShadowRouterDelta,
 };
 use derivative::Derivative;
-use sans_io_runtime::{collections::DynamicDeque, TaskSwitcherChild};
+use sans_io_runtime::{collections::DynamicDeque, return_if_none, TaskSwitcherChild};
 
 use crate::{
-    base::{ConnectionEvent, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, NetOutgoingMeta},
-    data_plane::NetPair,
+    base::{
+        ConnectionEvent, Feature, FeatureContext, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput, MsgPriority,
+        NetOutgoingMeta, CAPABILITY_METRIC_EXT, DEFAULT_MTU,
+    },
+    data_plane::{NetPair, Transport, TransportPenalty},
 };
 
 pub const FEATURE_ID: u8 = 2;
@@ -30,31 +33,71 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 
 pub struct RouterSyncFeature<UserData> {
     router: Router,
-    conns: HashMap<ConnId, (NodeId, NetPair, Metric)>,
+    /// node, pair, metric and capabilities of the remote node
+    conns: HashMap<ConnId, (NodeId, NetPair, Metric, u32)>,
+    /// Only the primary connection of each node is a direct path, standby connections are kept out of the router
+    primaries: HashMap<NodeId, ConnId>,
     queue: VecDeque<Output<UserData>>,
     services: Vec<u8>,
+    transport_penalty: TransportPenalty,
+    /// Withdraw all routes and services from neighbours while node is draining
+    draining: bool,
 }
 
 impl<UserData> RouterSyncFeature<UserData> {
-    pub fn new(node: NodeId, services: Vec<u8>) -> Self {
+    pub fn new(node: NodeId, services: Vec<u8>, transport_penalty: TransportPenalty) -> Self {
         log::info!("[RouterSync] started node {} with public services {:?}", node, services);
 
         Self {
             router: Router::new(node),
             services,
+            transport_penalty,
             conns: HashMap::new(),
+            primaries: HashMap::new(),
             queue: VecDeque::new(),
+            draining: false,
         }
     }
 
-    fn send_sync_to(router: &Router, queue: &mut VecDeque<Output<UserData>>, conn: ConnId, node: NodeId) {
-        let sync = router.create_sync(node);
+    /// Penalty of the transport which the conn is using, so routes over UDP are preferred to stream fallbacks
+    fn penalty_of(&self, conn: ConnId) -> u16 {
+        Transport::from_protocol(conn.protocol()).map_or(0, |transport| self.transport_penalty.penalty_ms(transport))
+    }
+
+    fn send_sync_to(router: &Router, queue: &mut VecDeque<Output<UserData>>, conn: ConnId, node: NodeId, caps: u32, withdraw: bool) {
+        let mut sync = router.create_sync(node);
+        if withdraw {
+            // keep the layers but empty them, so neighbour removes all paths over us
+            sync.0 .0.clear();
+            for table in sync.1.iter_mut().flatten() {
+                table.0.clear();
+            }
+        }
+        // old nodes only parse the legacy RouterSync, the extension is appended for nodes which can parse it
+        let buf = if caps & CAPABILITY_METRIC_EXT != 0 {
+            bincode::serialize(&(&sync, sync.ext())).expect("")
+        } else {
+            bincode::serialize(&sync).expect("")
+        };
         queue.push_back(FeatureOutput::SendDirect(
             conn,
-            NetOutgoingMeta::new(false, 1.into(), 0, true),
-            bincode::serialize(&sync).expect("").into(),
+            NetOutgoingMeta::new(false, 1.into(), 0, true).set_priority(MsgPriority::Control),
+            buf.into(),
         ));
     }
+
+    /// Parse RouterSync with the optional extension which is appended by nodes that support it
+    fn parse_sync(buf: &[u8]) -> Option<RouterSync> {
+        let mut reader = buf;
+        let mut sync: RouterSync = bincode::deserialize_from(&mut reader).ok()?;
+        if !reader.is_empty() {
+            match bincode::deserialize_from::<_, RouterSyncExt>(&mut reader) {
+                Ok(ext) if sync.apply_ext(&ext) => {}
+                _ => log::warn!("[RouterSync] Invalid sync extension, use legacy metrics"),
+            }
+        }
+        Some(sync)
+    }
 }
 
 impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for RouterSyncFeature<UserData> {
@@ -71,29 +114,55 @@ impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for Rou
                     self.router.register_service(service);
                 }
 
-                for (conn, (node, _, _)) in self.conns.iter() {
-                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node);
+                for (node, conn) in self.primaries.iter() {
+                    let caps = self.conns.get(conn).map_or(0, |(_, _, _, caps)| *caps);
+                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node, caps, self.draining);
+                }
+            }
+            FeatureSharedInput::Drain => {
+                log::info!("[RouterSync] draining, withdraw routes and services from {} neighbours", self.primaries.len());
+                self.draining = true;
+                for (node, conn) in self.primaries.iter() {
+                    let caps = self.conns.get(conn).map_or(0, |(_, _, _, caps)| *caps);
+                    Self::send_sync_to(&self.router, &mut self.queue, *conn, *node, caps, true);
                 }
             }
             FeatureSharedInput::Connection(event) => match event {
                 ConnectionEvent::Connected(ctx, _) => {
                     log::info!("[RouterSync] Connection {} connected", ctx.pair);
-                    let metric = Metric::new(INIT_RTT_MS, vec![ctx.node], INIT_BW);
-                    self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric.clone()));
-                    self.router.set_direct(ctx.conn, metric);
-                    Self::send_sync_to(&self.router, &mut self.queue, ctx.conn, ctx.node);
+                    let metric = Metric::new(INIT_RTT_MS, vec![ctx.node], INIT_BW).with_mtu(DEFAULT_MTU).with_penalty(self.penalty_of(ctx.conn));
+                    self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric, 0));
                 }
                 ConnectionEvent::Stats(ctx, stats) => {
-                    log::debug!("[RouterSync] Connection {} stats rtt_ms {}", ctx.pair, stats.rtt_ms);
-                    let metric = Metric::new(stats.rtt_ms as u16, vec![ctx.node], INIT_BW);
-                    self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric.clone()));
+                    log::debug!("[RouterSync] Connection {} stats rtt_ms {} mtu {}", ctx.pair, stats.rtt_ms, stats.mtu);
+                    let metric = Metric::new(stats.rtt_ms as u16, vec![ctx.node], INIT_BW).with_mtu(stats.mtu).with_penalty(self.penalty_of(ctx.conn));
+                    self.conns.insert(ctx.conn, (ctx.node, ctx.pair, metric.clone(), stats.caps));
+                    if self.primaries.get(&ctx.node) == Some(&ctx.conn) {
+                        self.router.set_direct(ctx.conn, metric);
+                    }
+                }
+                ConnectionEvent::Primary(ctx) => {
+                    let (_, _, metric, caps) = return_if_none!(self.conns.get(&ctx.conn));
+                    let (metric, caps) = (metric.clone(), *caps);
+                    if let Some(old) = self.primaries.insert(ctx.node, ctx.conn) {
+                        if old == ctx.conn {
+                            return;
+                        }
+                        self.router.del_direct(old);
+                    }
+                    log::info!("[RouterSync] Connection {} is primary of {}", ctx.pair, ctx.node);
                     self.router.set_direct(ctx.conn, metric);
+                    Self::send_sync_to(&self.router, &mut self.queue, ctx.conn, ctx.node, caps, self.draining);
                 }
-                ConnectionEvent::Disconnected(ctx) => {
+                ConnectionEvent::Disconnected(ctx, _) => {
                     log::info!("[RouterSync] Connection {} disconnected", ctx.pair);
                     self.conns.remove(&ctx.conn);
-                    self.router.del_direct(ctx.conn);
+                    if self.primaries.get(&ctx.node) == Some(&ctx.conn) {
+                        self.primaries.remove(&ctx.node);
+                        self.router.del_direct(ctx.conn);
+                    }
                 }
+                ConnectionEvent::ConnectError(..) | ConnectionEvent::GaveUp(_) => {}
             },
         }
     }
@@ -104,9 +173,11 @@ impl<UserData> Feature<UserData, Control, Event, ToController, ToWorker> for Rou
                 log::warn!("[RouterSync] reject unsecure message");
                 return;
             }
-            if let Some((_node, _remote, metric)) = self.conns.get(&ctx.conn) {
-                if let Ok(sync) = bincode::deserialize::<RouterSync>(&buf) {
-                    self.router.apply_sync(ctx.conn, metric.clone(), sync);
+            // both sides select primary independently, so the sync is applied to our primary connection of the node
+            let primary = self.conns.get(&ctx.conn).and_then(|(node, ..)| self.primaries.get(node));
+            if let Some((conn, (_node, _remote, metric, _))) = primary.and_then(|conn| Some((*conn, self.conns.get(conn)?))) {
+                if let Some(sync) = Self::parse_sync(&buf) {
+                    self.router.apply_sync(conn, metric.clone(), sync);
                 } else {
                     log::warn!("[RouterSync] Receive invalid sync from {}", ctx.pair);
                 }
@@ -187,7 +258,63 @@ impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for RouterSyncFeatureWo
 
 #[cfg(test)]
 mod tests {
+    use atm0s_sdn_identity::{ConnId, NodeId};
     use atm0s_sdn_router::core::{Metric, RegistrySync, RouterSync, TableSync};
+    use sans_io_runtime::TaskSwitcherChild;
+
+    use crate::{
+        base::{ConnectionCtx, ConnectionEvent, DisconnectReason, Feature, FeatureContext, FeatureOutput, FeatureSharedInput, MockDecryptor, MockEncryptor, SecureContext},
+        data_plane::{NetPair, TransportPenalty},
+    };
+
+    use super::RouterSyncFeature;
+
+    fn ctx(node: NodeId, session: u64, local: &str) -> ConnectionCtx {
+        ConnectionCtx {
+            conn: ConnId::from_out(0, session),
+            node,
+            pair: NetPair::new_str(local, "127.0.0.1:2000").expect("Should parse"),
+        }
+    }
+
+    fn connected(ctx: ConnectionCtx) -> FeatureSharedInput {
+        let secure = SecureContext {
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(MockDecryptor::new()),
+        };
+        FeatureSharedInput::Connection(ConnectionEvent::Connected(ctx, secure))
+    }
+
+    fn pop_sync_conns(feature: &mut RouterSyncFeature<()>) -> Vec<ConnId> {
+        let mut conns = vec![];
+        while let Some(out) = feature.pop_output(0) {
+            if let FeatureOutput::SendDirect(conn, ..) = out {
+                conns.push(conn);
+            }
+        }
+        conns
+    }
+
+    #[test]
+    fn sync_only_over_primary_conn() {
+        let feature_ctx = FeatureContext { node_id: 1, session: 0 };
+        let mut feature = RouterSyncFeature::<()>::new(1, vec![], TransportPenalty::default());
+        let primary = ctx(2, 1, "127.0.0.1:1000");
+        let standby = ctx(2, 2, "127.0.0.2:1000");
+
+        feature.on_shared_input(&feature_ctx, 0, connected(primary.clone()));
+        feature.on_shared_input(&feature_ctx, 0, connected(standby.clone()));
+        feature.on_shared_input(&feature_ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Primary(primary.clone())));
+        assert_eq!(pop_sync_conns(&mut feature), vec![primary.conn]);
+
+        feature.on_shared_input(&feature_ctx, 0, FeatureSharedInput::Tick(1));
+        assert_eq!(pop_sync_conns(&mut feature), vec![primary.conn]);
+
+        // standby is used after the primary is lost
+        feature.on_shared_input(&feature_ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Disconnected(primary, DisconnectReason::Timeout)));
+        feature.on_shared_input(&feature_ctx, 0, FeatureSharedInput::Connection(ConnectionEvent::Primary(standby.clone())));
+        assert_eq!(pop_sync_conns(&mut feature), vec![standby.conn]);
+    }
 
     #[test]
     fn router_sync_should_fit_udp() {
@@ -215,4 +342,18 @@ mod tests {
         let sync_msg_len = bincode::serialize(&sync).expect("").len();
         assert!(sync_msg_len <= MAX_SIZE, "SYNC msg not fit in UDP {} vs {}", sync_msg_len, MAX_SIZE);
     }
+
+    #[test]
+    fn parse_sync_with_and_without_ext() {
+        let sync = RouterSync(
+            RegistrySync(vec![(1, Metric::new(0, vec![1, 2], 0).with_mtu(1400))]),
+            [Some(TableSync(vec![(3, Metric::new(0, vec![3, 2], 0).with_mtu(1300))])), None, None, None],
+        );
+
+        let legacy = RouterSyncFeature::<()>::parse_sync(&bincode::serialize(&sync).expect("Should serialize")).expect("Should parse");
+        assert_eq!(legacy.ext().0.iter().map(|ext| ext.mtu).collect::<Vec<_>>(), vec![u16::MAX, u16::MAX]);
+
+        let extended = RouterSyncFeature::<()>::parse_sync(&bincode::serialize(&(&sync, sync.ext())).expect("Should serialize")).expect("Should parse");
+        assert_eq!(extended.ext(), sync.ext());
+    }
 }
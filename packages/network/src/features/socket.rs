@@ -9,8 +9,8 @@ use atm0s_sdn_router::RouteRule;
 use sans_io_runtime::{collections::DynamicDeque, return_if_none, TaskSwitcherChild};
 
 use crate::base::{
-    Buffer, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput, FeatureWorkerOutput,
-    NetOutgoingMeta, Ttl,
+    Buffer, CongestionEchoLimiter, Feature, FeatureContext, FeatureControlActor, FeatureInput, FeatureOutput, FeatureSharedInput, FeatureWorker, FeatureWorkerContext, FeatureWorkerInput,
+    FeatureWorkerOutput, MsgPriority, NetIncomingMeta, NetOutgoingMeta, Ttl,
 };
 
 pub const FEATURE_ID: u8 = 7;
@@ -28,6 +28,8 @@ pub enum Control {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Event {
     RecvFrom(u16, NodeId, u16, Buffer, u8),
+    /// Remote socket echoes that our packets from local port are experiencing congestion
+    Congestion(u16, NodeId, u16),
 }
 
 #[derive(Debug, Clone)]
@@ -50,6 +52,7 @@ pub type WorkerOutput<UserData> = FeatureWorkerOutput<UserData, Control, Event,
 
 pub struct SocketFeature<UserData> {
     sockets: HashMap<u16, Socket<UserData>>,
+    congestion_echo: CongestionEchoLimiter<(NodeId, u16)>,
     queue: VecDeque<Output<UserData>>,
 }
 
@@ -65,15 +68,20 @@ impl<UserData> Default for SocketFeature<UserData> {
     fn default() -> Self {
         Self {
             sockets: HashMap::new(),
+            congestion_echo: Default::default(),
             queue: VecDeque::new(),
         }
     }
 }
 
 impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController, ToWorker<UserData>> for SocketFeature<UserData> {
-    fn on_shared_input(&mut self, _ctx: &FeatureContext, _now: u64, _input: FeatureSharedInput) {}
+    fn on_shared_input(&mut self, _ctx: &FeatureContext, now: u64, input: FeatureSharedInput) {
+        if let FeatureSharedInput::Tick(_) = input {
+            self.congestion_echo.on_tick(now);
+        }
+    }
 
-    fn on_input(&mut self, ctx: &FeatureContext, _now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
+    fn on_input(&mut self, ctx: &FeatureContext, now_ms: u64, input: FeatureInput<'_, UserData, Control, ToController>) {
         match input {
             FeatureInput::Control(actor, control) => match control {
                 Control::Bind(port) => {
@@ -161,6 +169,14 @@ impl<UserData: Copy + Debug + Eq> Feature<UserData, Control, Event, ToController
                     return;
                 };
                 if let Some(socket) = self.sockets.get(&pkt_dest) {
+                    if meta.congestion_echo {
+                        self.queue.push_back(FeatureOutput::Event(socket.actor, Event::Congestion(pkt_dest, from_node, pkt_src)));
+                        return;
+                    }
+                    if meta.congested && self.congestion_echo.should_echo(now_ms, (from_node, pkt_src)) {
+                        let (rule, meta, buf) = congestion_echo(pkt_dest, from_node, pkt_src);
+                        self.queue.push_back(FeatureOutput::SendRoute(rule, meta, buf));
+                    }
                     if let Some((dest_node, dest_port)) = socket.target {
                         if dest_node != from_node {
                             log::warn!("[SocketFeature] Recv failed, node mismatch: {} != {}", dest_node, from_node);
@@ -192,13 +208,23 @@ impl<UserData> TaskSwitcherChild<Output<UserData>> for SocketFeature<UserData> {
 
 pub struct SocketFeatureWorker<UserData> {
     sockets: HashMap<u16, Socket<UserData>>,
+    congestion_echo: CongestionEchoLimiter<(NodeId, u16)>,
     queue: DynamicDeque<WorkerOutput<UserData>, 16>,
 }
 
 impl<UserData: Copy> SocketFeatureWorker<UserData> {
-    fn process_incoming(&mut self, from_node: NodeId, mut buf: Buffer, meta: u8) {
+    fn process_incoming(&mut self, now_ms: u64, from_node: NodeId, mut buf: Buffer, net_meta: &NetIncomingMeta) {
+        let meta = net_meta.meta;
         let (pkt_src, pkt_dest) = return_if_none!(extract_meta(&mut buf));
         let socket = return_if_none!(self.sockets.get(&pkt_dest));
+        if net_meta.congestion_echo {
+            self.queue.push_back(FeatureWorkerOutput::Event(socket.actor, Event::Congestion(pkt_dest, from_node, pkt_src)));
+            return;
+        }
+        if net_meta.congested && self.congestion_echo.should_echo(now_ms, (from_node, pkt_src)) {
+            let (rule, meta, buf) = congestion_echo(pkt_dest, from_node, pkt_src);
+            self.queue.push_back(FeatureWorkerOutput::SendRoute(rule, meta, buf));
+        }
         if let Some((dest_node, dest_port)) = socket.target {
             if dest_node != from_node {
                 log::warn!("[SocketFeature] Recv failed, node mismatch: {} != {}", dest_node, from_node);
@@ -219,17 +245,22 @@ impl<UserData> Default for SocketFeatureWorker<UserData> {
     fn default() -> Self {
         Self {
             sockets: HashMap::new(),
+            congestion_echo: Default::default(),
             queue: Default::default(),
         }
     }
 }
 
 impl<UserData: Clone + Copy + Eq> FeatureWorker<UserData, Control, Event, ToController, ToWorker<UserData>> for SocketFeatureWorker<UserData> {
-    fn on_input(&mut self, _ctx: &mut FeatureWorkerContext, _now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker<UserData>>) {
+    fn on_tick(&mut self, _ctx: &mut FeatureWorkerContext, now: u64, _tick_count: u64) {
+        self.congestion_echo.on_tick(now);
+    }
+
+    fn on_input(&mut self, _ctx: &mut FeatureWorkerContext, now: u64, input: FeatureWorkerInput<UserData, Control, ToWorker<UserData>>) {
         match input {
             FeatureWorkerInput::Network(_conn, meta, buf) => {
                 let from_node = return_if_none!(meta.source);
-                self.process_incoming(from_node, buf, meta.meta);
+                self.process_incoming(now, from_node, buf, &meta);
             }
             FeatureWorkerInput::FromController(_, control) => match control {
                 ToWorker::BindSocket(port, actor) => {
@@ -278,7 +309,7 @@ impl<UserData: Clone + Copy + Eq> FeatureWorker<UserData, Control, Event, ToCont
             }
             FeatureWorkerInput::Local(meta, buf) => {
                 let from_node = return_if_none!(meta.source);
-                self.process_incoming(from_node, buf, meta.meta);
+                self.process_incoming(now, from_node, buf, &meta);
             }
             _ => {}
         }
@@ -292,6 +323,15 @@ impl<UserData> TaskSwitcherChild<WorkerOutput<UserData>> for SocketFeatureWorker
     }
 }
 
+/// Build an empty packet from local port to the remote socket, which is flagged with congestion echo
+fn congestion_echo(local_port: u16, remote_node: NodeId, remote_port: u16) -> (RouteRule, NetOutgoingMeta, Buffer) {
+    log::debug!("[SocketFeature] echo congestion from {local_port} to {remote_node}:{remote_port}");
+    let mut buf = Buffer::new(4, 0);
+    embed_meta(local_port, remote_port, &mut buf);
+    let meta = NetOutgoingMeta::new(true, Ttl::default(), 0, false).set_priority(MsgPriority::Control).set_congestion_echo(true);
+    (RouteRule::ToNode(remote_node), meta, buf)
+}
+
 fn embed_meta(src: u16, dest: u16, data: &mut Buffer) {
     data.ensure_front(4);
     data.push_front(&dest.to_be_bytes());
@@ -1,9 +1,11 @@
 #![allow(clippy::bool_assert_comparison)]
 
+use std::net::SocketAddr;
+
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
 use atm0s_sdn_router::RouteRule;
 use base::{FeatureControlActor, NeighboursControl, NetIncomingMeta, NetOutgoingMeta, SecureContext, ServiceControlActor, ServiceId};
-use data_plane::NetPair;
+use data_plane::{NetPair, ShapingKey, ShapingStats};
 use features::{Features, FeaturesControl, FeaturesEvent, FeaturesToController, FeaturesToWorker};
 use sans_io_runtime::Buffer;
 
@@ -15,12 +17,24 @@ pub mod data_plane;
 pub mod features;
 pub mod secure;
 pub mod services;
+#[cfg(feature = "simulator")]
+pub mod simulator;
 pub mod worker;
 
 #[derive(Debug, Clone)]
 pub enum ExtIn<UserData, ServicesControl> {
     ConnectTo(NodeAddr),
+    /// Same as ConnectTo but the node is reconnected with backoff after it is lost, until DisconnectFrom or gave up
+    ConnectToPersistent(NodeAddr),
+    /// Same as ConnectToPersistent but the node is never given up, used for seeds which the node bootstraps from
+    ConnectToSeed(NodeAddr),
     DisconnectFrom(NodeId),
+    /// Bind one more local addr at runtime, for example after the host got a new ip. Connected neighbours are
+    /// also connected over the new addr and the advertised node addr is updated. Port 0 is rejected because the
+    /// chosen port can't be advertised
+    AddBind(SocketAddr),
+    /// Close a bound addr, UDP connections over it are migrated to remaining bind addrs and it is not advertised anymore
+    RemoveBind(SocketAddr),
     FeaturesControl(UserData, FeaturesControl),
     ServicesControl(ServiceId, UserData, ServicesControl),
 }
@@ -29,6 +43,8 @@ pub enum ExtIn<UserData, ServicesControl> {
 pub enum ExtOut<UserData, ServicesEvent> {
     FeaturesEvent(UserData, FeaturesEvent),
     ServicesEvent(ServiceId, UserData, ServicesEvent),
+    /// Relayed packets are dropped by traffic shaping, stats are accumulated per worker
+    RateLimited(ShapingKey, ShapingStats),
 }
 
 #[derive(Debug, Clone)]
@@ -43,6 +59,10 @@ pub enum LogicControl<UserData, SC, SE, TC> {
     ServiceEvent(ServiceId, FeaturesEvent),
     ExtFeaturesEvent(UserData, FeaturesEvent),
     ExtServicesEvent(ServiceId, UserData, SE),
+    /// Relayed packets of a worker in last tick while draining, first u16 is worker id
+    TransitLoad(u16, u64),
+    /// Bytes sent and received per connection of a worker in last tick
+    Traffic(Vec<(ConnId, u64, u64)>),
 }
 
 #[derive(Debug, Clone)]
@@ -53,6 +73,12 @@ pub enum LogicEvent<UserData, SE, TW> {
 
     Pin(ConnId, NodeId, NetPair, SecureContext),
     UnPin(ConnId),
+    /// Capabilities of remote node, negotiated after connected
+    SetCapabilities(ConnId, u32),
+    /// Path mtu of the connection, discovered by probing
+    SetMtu(ConnId, u16),
+    /// Link queueing delay of the connection is high, relayed packets over it are marked as congested
+    SetCongested(ConnId, bool),
     /// first bool is flag for broadcast or not
     Feature(bool, FeaturesToWorker<UserData>),
     Service(ServiceId, TW),
@@ -60,6 +86,12 @@ pub enum LogicEvent<UserData, SE, TW> {
     ExtFeaturesEvent(u16, UserData, FeaturesEvent),
     /// first u16 is worker id
     ExtServicesEvent(u16, ServiceId, UserData, SE),
+    /// Drain before shutdown is finished, workers can stop now
+    Drained,
+    /// Open a socket on the addr in all workers
+    Bind(SocketAddr),
+    /// Close the socket of the addr in all workers
+    Unbind(SocketAddr),
 }
 
 pub enum LogicEventDest {
@@ -73,6 +105,12 @@ impl<UserData, SE, TW> LogicEvent<UserData, SE, TW> {
         match self {
             LogicEvent::Pin(..) => LogicEventDest::Broadcast,
             LogicEvent::UnPin(..) => LogicEventDest::Broadcast,
+            LogicEvent::SetCapabilities(..) => LogicEventDest::Broadcast,
+            LogicEvent::SetMtu(..) => LogicEventDest::Broadcast,
+            LogicEvent::SetCongested(..) => LogicEventDest::Broadcast,
+            LogicEvent::Drained => LogicEventDest::Broadcast,
+            LogicEvent::Bind(..) => LogicEventDest::Broadcast,
+            LogicEvent::Unbind(..) => LogicEventDest::Broadcast,
             LogicEvent::Service(..) => LogicEventDest::Broadcast,
             LogicEvent::Feature(true, ..) => LogicEventDest::Broadcast,
             LogicEvent::Feature(false, ..) => LogicEventDest::Any,
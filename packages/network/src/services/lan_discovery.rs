@@ -0,0 +1,347 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
+use atm0s_sdn_utils::hash::hash_str;
+use sans_io_runtime::{collections::DynamicDeque, return_if_err, return_if_none};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    base::{ConnectionEvent, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    features::{neighbours::Control as NeighbourControl, FeaturesControl, FeaturesEvent},
+};
+
+use super::{add_bind_addr, remove_bind_addr};
+
+const ANNOUNCE_INTERVAL_MS: u64 = 5_000;
+const RETRY_CONNECT_MS: u64 = 30_000;
+const ANNOUNCE_MAGIC: u32 = 0x61746d30; // "atm0"
+
+pub const SERVICE_ID: u8 = 4;
+pub const SERVICE_NAME: &str = "lan_discovery";
+
+/// Datagram transport which is shared by nodes in the same L2 segment, like UDP multicast.
+/// The controller is sans-io, so the transport is injected and polled on each tick, `recv` must not block.
+pub trait LanTransport: Send + Sync {
+    fn send(&self, buf: &[u8]);
+    fn recv(&self) -> Option<Vec<u8>>;
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Announce {
+    magic: u32,
+    /// hash of cluster token, only nodes with the same token are connected
+    token: u64,
+    addr: Vec<u8>,
+}
+
+fn neighbour_control<UserData, SE, TW>(c: NeighbourControl) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::Neighbours(c))
+}
+
+/// Zero-config discovery for local clusters. Nodes announce their address periodically through the LanTransport
+/// and connect to announced nodes which share the same cluster token.
+pub struct LanDiscoveryService<UserData, SC, SE, TC, TW> {
+    node_addr: NodeAddr,
+    token: u64,
+    transport: Arc<dyn LanTransport>,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    conns: HashMap<NodeId, Vec<ConnId>>,
+    last_connect: HashMap<NodeId, u64>,
+    last_announce_ms: Option<u64>,
+    _tmp: std::marker::PhantomData<(SC, TC, TW)>,
+}
+
+impl<UserData, SC, SE, TC, TW> LanDiscoveryService<UserData, SC, SE, TC, TW> {
+    pub fn new(node_addr: NodeAddr, token: &str, transport: Arc<dyn LanTransport>) -> Self {
+        log::info!("Creating LanDiscoveryService for node {node_addr}");
+        Self {
+            node_addr,
+            token: hash_str(token),
+            transport,
+            queue: VecDeque::new(),
+            conns: HashMap::new(),
+            last_connect: HashMap::new(),
+            last_announce_ms: None,
+            _tmp: std::marker::PhantomData,
+        }
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        while let Some(buf) = self.transport.recv() {
+            self.on_announce(now, &buf);
+        }
+
+        if !matches!(self.last_announce_ms, Some(last) if last + ANNOUNCE_INTERVAL_MS > now) {
+            self.last_announce_ms = Some(now);
+            let announce = Announce {
+                magic: ANNOUNCE_MAGIC,
+                token: self.token,
+                addr: self.node_addr.to_vec(),
+            };
+            self.transport.send(&bincode::serialize(&announce).expect("Should to bytes"));
+        }
+    }
+
+    /// Announce at next tick when our addr is changed by runtime binds
+    fn set_node_addr(&mut self, node_addr: NodeAddr) {
+        if node_addr != self.node_addr {
+            log::info!("LanDiscoveryService advertised addr changed {} => {node_addr}", self.node_addr);
+            self.node_addr = node_addr;
+            self.last_announce_ms = None;
+        }
+    }
+
+    fn on_announce(&mut self, now: u64, buf: &[u8]) {
+        let announce = return_if_err!(bincode::deserialize::<Announce>(buf));
+        if announce.magic != ANNOUNCE_MAGIC || announce.token != self.token {
+            return;
+        }
+        let addr = return_if_none!(NodeAddr::from_vec(&announce.addr));
+        let node = addr.node_id();
+        if node == self.node_addr.node_id() || self.conns.contains_key(&node) {
+            return;
+        }
+        if self.last_connect.get(&node).is_some_and(|last| *last + RETRY_CONNECT_MS > now) {
+            return;
+        }
+        log::info!("LanDiscoveryService node {node} announced => connect {addr}");
+        self.last_connect.insert(node, now);
+        self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr)));
+    }
+}
+
+impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LanDiscoveryService<UserData, SC, SE, TC, TW> {
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        match input {
+            ServiceSharedInput::Tick(_) => self.on_tick(now),
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
+                self.conns.entry(ctx.node).or_default().push(ctx.conn);
+                self.last_connect.remove(&ctx.node);
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx, _)) => {
+                let entry = self.conns.entry(ctx.node).or_default();
+                entry.retain(|&conn| conn != ctx.conn);
+                if entry.is_empty() {
+                    self.conns.remove(&ctx.node);
+                }
+            }
+            ServiceSharedInput::Bind(addr) => self.set_node_addr(add_bind_addr(&self.node_addr, addr)),
+            ServiceSharedInput::Unbind(addr) => self.set_node_addr(remove_bind_addr(&self.node_addr, addr)),
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {}
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct LanDiscoveryServiceWorker<UserData, SC, SE, TC> {
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LanDiscoveryServiceWorker<UserData, SC, SE, TC> {
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct LanDiscoveryServiceBuilder<UserData, SC, SE, TC, TW> {
+    _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW)>,
+    node_addr: NodeAddr,
+    token: String,
+    transport: Arc<dyn LanTransport>,
+}
+
+impl<UserData, SC, SE, TC, TW> LanDiscoveryServiceBuilder<UserData, SC, SE, TC, TW> {
+    pub fn new(node_addr: NodeAddr, token: &str, transport: Arc<dyn LanTransport>) -> Self {
+        Self {
+            _tmp: std::marker::PhantomData,
+            node_addr,
+            token: token.to_string(),
+            transport,
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for LanDiscoveryServiceBuilder<UserData, SC, SE, TC, TW>
+where
+    UserData: 'static + Debug + Send + Sync,
+    SC: 'static + Debug + Send + Sync,
+    SE: 'static + Debug + Send + Sync,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(LanDiscoveryService::new(self.node_addr.clone(), &self.token, self.transport.clone()))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(LanDiscoveryServiceWorker { queue: Default::default() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, Protocol};
+    use parking_lot::Mutex;
+
+    use crate::{
+        base::{ConnectionCtx, ConnectionEvent, MockDecryptor, MockEncryptor, SecureContext, Service, ServiceCtx, ServiceOutput, ServiceSharedInput},
+        data_plane::NetPair,
+        features::{neighbours, FeaturesControl},
+    };
+
+    use super::{LanDiscoveryService, LanTransport, ANNOUNCE_INTERVAL_MS, RETRY_CONNECT_MS};
+
+    /// In-memory broadcast domain, each transport receives all packets which are sent by others
+    #[derive(Default)]
+    struct MockLan {
+        inboxes: Mutex<Vec<Vec<Vec<u8>>>>,
+    }
+
+    struct MockTransport {
+        lan: Arc<MockLan>,
+        index: usize,
+    }
+
+    impl MockTransport {
+        fn new(lan: &Arc<MockLan>) -> Arc<Self> {
+            let mut inboxes = lan.inboxes.lock();
+            inboxes.push(vec![]);
+            Arc::new(Self {
+                lan: lan.clone(),
+                index: inboxes.len() - 1,
+            })
+        }
+    }
+
+    impl LanTransport for MockTransport {
+        fn send(&self, buf: &[u8]) {
+            for (i, inbox) in self.lan.inboxes.lock().iter_mut().enumerate() {
+                if i != self.index {
+                    inbox.push(buf.to_vec());
+                }
+            }
+        }
+
+        fn recv(&self) -> Option<Vec<u8>> {
+            self.lan.inboxes.lock()[self.index].pop()
+        }
+    }
+
+    fn node_addr(node: u32) -> NodeAddr {
+        let mut builder = NodeAddrBuilder::new(node);
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(node as u16));
+        builder.addr()
+    }
+
+    fn connect_cmd(addr: NodeAddr) -> Option<ServiceOutput<(), FeaturesControl, (), ()>> {
+        Some(ServiceOutput::FeatureControl(FeaturesControl::Neighbours(neighbours::Control::ConnectTo(addr))))
+    }
+
+    #[test]
+    fn should_connect_announced_node_with_same_token() {
+        let lan = Arc::new(MockLan::default());
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service1 = LanDiscoveryService::<(), (), (), (), ()>::new(node_addr(1), "cluster", MockTransport::new(&lan));
+        let mut service2 = LanDiscoveryService::<(), (), (), (), ()>::new(node_addr(2), "cluster", MockTransport::new(&lan));
+        let mut service3 = LanDiscoveryService::<(), (), (), (), ()>::new(node_addr(3), "other", MockTransport::new(&lan));
+
+        // all nodes announce on first tick
+        service2.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        service3.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        service1.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        assert_eq!(service1.pop_output2(0), connect_cmd(node_addr(2)));
+        assert_eq!(service1.pop_output2(0), None);
+
+        // not retry before timeout
+        service2.on_shared_input(&ctx, ANNOUNCE_INTERVAL_MS, ServiceSharedInput::Tick(1));
+        service1.on_shared_input(&ctx, ANNOUNCE_INTERVAL_MS, ServiceSharedInput::Tick(1));
+        assert_eq!(service1.pop_output2(ANNOUNCE_INTERVAL_MS), None);
+
+        service2.on_shared_input(&ctx, RETRY_CONNECT_MS, ServiceSharedInput::Tick(2));
+        service1.on_shared_input(&ctx, RETRY_CONNECT_MS, ServiceSharedInput::Tick(2));
+        assert_eq!(service1.pop_output2(RETRY_CONNECT_MS), connect_cmd(node_addr(2)));
+
+        // connected nodes are not connected again
+        let secure = SecureContext {
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(MockDecryptor::new()),
+        };
+        let conn = ConnectionCtx {
+            conn: ConnId::from_out(0, 0),
+            node: 2,
+            pair: NetPair::new_str("127.0.0.1:1", "127.0.0.1:2").expect("Should parse"),
+        };
+        service1.on_shared_input(&ctx, RETRY_CONNECT_MS, ServiceSharedInput::Connection(ConnectionEvent::Connected(conn, secure)));
+        service2.on_shared_input(&ctx, RETRY_CONNECT_MS * 3, ServiceSharedInput::Tick(3));
+        service1.on_shared_input(&ctx, RETRY_CONNECT_MS * 3, ServiceSharedInput::Tick(3));
+        assert_eq!(service1.pop_output2(RETRY_CONNECT_MS * 3), None);
+    }
+
+    #[test]
+    fn should_announce_runtime_bind_addrs() {
+        let lan = Arc::new(MockLan::default());
+        let ctx = ServiceCtx { node_id: 1, session: 0 };
+        let mut service1 = LanDiscoveryService::<(), (), (), (), ()>::new(node_addr(1), "cluster", MockTransport::new(&lan));
+        let mut service2 = LanDiscoveryService::<(), (), (), (), ()>::new(node_addr(2), "cluster", MockTransport::new(&lan));
+
+        service1.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        service2.on_shared_input(&ctx, 0, ServiceSharedInput::Tick(0));
+        assert_eq!(service2.pop_output2(0), connect_cmd(node_addr(1)));
+
+        // the changed addr is announced at next tick without waiting for the announce interval
+        let mut builder = NodeAddrBuilder::new(1);
+        builder.add_protocol(Protocol::Ip4([10, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(1));
+        service1.on_shared_input(&ctx, 100, ServiceSharedInput::Bind("10.0.0.1:1".parse().expect("Should parse")));
+        service1.on_shared_input(&ctx, 100, ServiceSharedInput::Unbind("127.0.0.1:1".parse().expect("Should parse")));
+        service1.on_shared_input(&ctx, 100, ServiceSharedInput::Tick(1));
+        service2.on_shared_input(&ctx, RETRY_CONNECT_MS, ServiceSharedInput::Tick(1));
+        assert_eq!(service2.pop_output2(RETRY_CONNECT_MS), connect_cmd(builder.addr()));
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     collections::{HashMap, VecDeque},
     fmt::Debug,
+    net::SocketAddr,
 };
 
 use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId};
@@ -16,6 +17,8 @@ use crate::{
     },
 };
 
+use super::{add_bind_addr, merge_observed_addrs, remove_bind_addr};
+
 const RETRY_CONNECT_MS: u64 = 60_000; //60 seconds
 const WAIT_DISCONNECT_MS: u64 = 60_000; //60 seconds
 
@@ -31,7 +34,11 @@ fn neighbour_control<UserData, SE, TW>(c: NeighbourControl) -> ServiceOutput<Use
 }
 
 pub struct ManualDiscoveryService<UserData, SC, SE, TC, TW> {
+    /// configured addr, observed addresses are merged into it for advertising
+    base_addr: NodeAddr,
     node_addr: NodeAddr,
+    observed_addrs: Vec<SocketAddr>,
+    local_maps: Vec<Map>,
     queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
     nodes: HashMap<NodeId, NodeAddr>,
     conns: HashMap<NodeId, Vec<ConnId>>,
@@ -45,11 +52,13 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryService<UserData, SC, SE, TC, TW>
         log::info!("Creating ManualDiscoveryService for node {node_addr} with local tags {local_tags:?} and connect tags {connect_tags:?}");
 
         let mut queue = VecDeque::new();
+        let mut local_maps = vec![];
 
         for local_tag in local_tags.iter() {
             let map = Map(hash_str(local_tag));
             log::info!("Setting local tag: {local_tag} by set key {map}");
             queue.push_back(kv_control(KvControl::MapCmd(map, MapControl::Set(Key(0), node_addr.to_vec()))));
+            local_maps.push(map);
         }
 
         for connect_tag in connect_tags.iter() {
@@ -59,7 +68,10 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryService<UserData, SC, SE, TC, TW>
         }
 
         Self {
+            base_addr: node_addr.clone(),
             node_addr,
+            observed_addrs: vec![],
+            local_maps,
             nodes: HashMap::new(),
             conns: HashMap::new(),
             queue,
@@ -93,6 +105,23 @@ impl<UserData, SC, SE, TC, TW> ManualDiscoveryService<UserData, SC, SE, TC, TW>
             self.removing_list.remove(&node);
         }
     }
+
+    fn on_observed_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.observed_addrs = addrs;
+        self.update_node_addr();
+    }
+
+    fn update_node_addr(&mut self) {
+        let node_addr = merge_observed_addrs(&self.base_addr, &self.observed_addrs);
+        if node_addr == self.node_addr {
+            return;
+        }
+        log::info!("ManualDiscoveryService advertised addr changed {} => {node_addr}", self.node_addr);
+        self.node_addr = node_addr;
+        for map in self.local_maps.iter() {
+            self.queue.push_back(kv_control(KvControl::MapCmd(*map, MapControl::Set(Key(0), self.node_addr.to_vec()))));
+        }
+    }
 }
 
 impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for ManualDiscoveryService<UserData, SC, SE, TC, TW> {
@@ -111,7 +140,7 @@ impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl,
                 let entry = self.conns.entry(ctx.node).or_default();
                 entry.push(ctx.conn);
             }
-            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx, _)) => {
                 let entry = self.conns.entry(ctx.node).or_default();
                 entry.retain(|&conn| conn != ctx.conn);
 
@@ -120,6 +149,15 @@ impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl,
                     self.conns.remove(&ctx.node);
                 }
             }
+            ServiceSharedInput::ObservedAddrs(addrs) => self.on_observed_addrs(addrs),
+            ServiceSharedInput::Bind(addr) => {
+                self.base_addr = add_bind_addr(&self.base_addr, addr);
+                self.update_node_addr();
+            }
+            ServiceSharedInput::Unbind(addr) => {
+                self.base_addr = remove_bind_addr(&self.base_addr, addr);
+                self.update_node_addr();
+            }
             _ => {}
         }
     }
@@ -230,11 +268,13 @@ where
 
 #[cfg(test)]
 mod test {
+    use std::net::SocketAddr;
+
     use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, Protocol};
     use atm0s_sdn_utils::hash::hash_str;
 
     use crate::{
-        base::{Service, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput},
+        base::{DisconnectReason, Service, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput},
         features::{
             dht_kv::{self, Key, Map, MapControl, MapEvent},
             neighbours, FeaturesControl, FeaturesEvent,
@@ -337,7 +377,11 @@ mod test {
         service.on_shared_input(&ctx, 200, ServiceSharedInput::Tick(0));
         assert_eq!(service.pop_output2(200), None);
 
-        service.on_input(&ctx, 300, neighbour_event(neighbours::Event::Disconnected(addr2.node_id(), ConnId::from_out(0, 0))));
+        service.on_input(
+            &ctx,
+            300,
+            neighbour_event(neighbours::Event::Disconnected(addr2.node_id(), ConnId::from_out(0, 0), DisconnectReason::Timeout)),
+        );
 
         service.on_shared_input(&ctx, 300, ServiceSharedInput::Tick(0));
         assert_eq!(service.pop_output2(300), None);
@@ -346,4 +390,78 @@ mod test {
         assert_eq!(service.pop_output2(RETRY_CONNECT_MS), Some(neighbour_cmd(neighbours::Control::ConnectTo(addr2.clone()))));
         assert_eq!(service.pop_output2(RETRY_CONNECT_MS), None);
     }
+
+    #[test]
+    fn should_advertise_observed_addrs() {
+        let addr1 = node_addr(100);
+
+        let ctx = ServiceCtx { node_id: 100, session: 0 };
+        let mut service = ManualDiscoveryService::<(), (), (), (), ()>::new(addr1.clone(), vec!["local".into()], vec![]);
+        let local_map = Map(hash_str("local"));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec()))));
+
+        let public: SocketAddr = "1.2.3.4:5000".parse().expect("Should parse");
+        let mut builder = NodeAddrBuilder::new(100);
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(100));
+        builder.add_protocol(Protocol::Ip4([1, 2, 3, 4].into()));
+        builder.add_protocol(Protocol::Udp(5000));
+        let addr1_public = builder.addr();
+
+        service.on_shared_input(&ctx, 100, ServiceSharedInput::ObservedAddrs(vec![public]));
+        assert_eq!(service.pop_output2(100), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1_public.to_vec()))));
+        assert_eq!(service.pop_output2(100), None);
+
+        // same addr is not published again
+        service.on_shared_input(&ctx, 200, ServiceSharedInput::ObservedAddrs(vec![public]));
+        assert_eq!(service.pop_output2(200), None);
+
+        // observed addr which is already configured is not duplicated
+        service.on_shared_input(&ctx, 300, ServiceSharedInput::ObservedAddrs(vec!["127.0.0.1:100".parse().expect("Should parse")]));
+        assert_eq!(service.pop_output2(300), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec()))));
+        assert_eq!(service.pop_output2(300), None);
+    }
+
+    #[test]
+    fn should_advertise_runtime_bind_addrs() {
+        let mut builder = NodeAddrBuilder::new(100);
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(100));
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Tcp(100));
+        let addr1 = builder.addr();
+
+        let ctx = ServiceCtx { node_id: 100, session: 0 };
+        let mut service = ManualDiscoveryService::<(), (), (), (), ()>::new(addr1.clone(), vec!["local".into()], vec![]);
+        let local_map = Map(hash_str("local"));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(local_map, MapControl::Set(Key(0), addr1.to_vec()))));
+
+        let public: SocketAddr = "1.2.3.4:5000".parse().expect("Should parse");
+        service.on_shared_input(&ctx, 100, ServiceSharedInput::ObservedAddrs(vec![public]));
+        assert!(service.pop_output2(100).is_some());
+
+        // new bind addr is advertised before observed addrs
+        let mut builder = NodeAddrBuilder::new(100);
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(100));
+        builder.add_protocol(Protocol::Ip4([127, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Tcp(100));
+        builder.add_protocol(Protocol::Ip4([10, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(200));
+        builder.add_protocol(Protocol::Ip4([1, 2, 3, 4].into()));
+        builder.add_protocol(Protocol::Udp(5000));
+        service.on_shared_input(&ctx, 200, ServiceSharedInput::Bind("10.0.0.1:200".parse().expect("Should parse")));
+        assert_eq!(service.pop_output2(200), Some(map_cmd(local_map, MapControl::Set(Key(0), builder.addr().to_vec()))));
+        assert_eq!(service.pop_output2(200), None);
+
+        // removed bind addr is not advertised anymore with other transports over the same socket addr
+        let mut builder = NodeAddrBuilder::new(100);
+        builder.add_protocol(Protocol::Ip4([10, 0, 0, 1].into()));
+        builder.add_protocol(Protocol::Udp(200));
+        builder.add_protocol(Protocol::Ip4([1, 2, 3, 4].into()));
+        builder.add_protocol(Protocol::Udp(5000));
+        service.on_shared_input(&ctx, 300, ServiceSharedInput::Unbind("127.0.0.1:100".parse().expect("Should parse")));
+        assert_eq!(service.pop_output2(300), Some(map_cmd(local_map, MapControl::Set(Key(0), builder.addr().to_vec()))));
+        assert_eq!(service.pop_output2(300), None);
+    }
 }
@@ -0,0 +1,764 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Debug,
+};
+
+use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::RouteRule;
+use sans_io_runtime::{collections::DynamicDeque, return_if_none};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    base::{
+        ConnectionEvent, NetOutgoingMeta, Service, ServiceBuilder, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx,
+        ServiceWorkerInput, ServiceWorkerOutput,
+    },
+    features::{data, FeaturesControl, FeaturesEvent},
+};
+
+pub const SERVICE_ID: u8 = 3;
+pub const SERVICE_NAME: &str = "membership";
+
+/// Data feature port of membership messages. Visualization uses port 0, so apps which listen on data ports
+/// must avoid this one or create the service with another port by `MembershipServiceBuilder::new_with_port`
+pub const DEFAULT_DATA_PORT: u16 = 1;
+pub const PROBE_INTERVAL_MS: u64 = 1000;
+/// After this timeout without ack, indirect probes are sent through other members
+const PROBE_TIMEOUT_MS: u64 = 500;
+const INDIRECT_PROBES: usize = 3;
+pub const SUSPECT_TIMEOUT_MS: u64 = 5000;
+/// Dead members are kept for rejecting old gossip about them
+const DEAD_RETAIN_MS: u64 = 30000;
+const GOSSIP_RETRANSMIT: u8 = 4;
+const MAX_PIGGYBACK: usize = 8;
+
+fn data_cmd<UserData, SE, TW>(cmd: data::Control) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::Data(cmd))
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum MemberState {
+    Alive,
+    Suspect,
+    Dead,
+    Left,
+}
+
+impl MemberState {
+    fn is_live(&self) -> bool {
+        matches!(self, MemberState::Alive | MemberState::Suspect)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Control<Meta> {
+    Subscribe,
+    GetAll,
+    UpdateMeta(Meta),
+    /// Leave the cluster gracefully, other members will remove this node without waiting for failure detection
+    Leave,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event<Meta> {
+    GotAll(Vec<(NodeId, Meta)>),
+    Joined(NodeId, Meta),
+    Updated(NodeId, Meta),
+    Left(NodeId),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+struct Update<Meta> {
+    node: NodeId,
+    incarnation: u64,
+    state: MemberState,
+    meta: Meta,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Message<Meta> {
+    Ping {
+        seq: u32,
+        gossip: Vec<Update<Meta>>,
+    },
+    Ack {
+        seq: u32,
+        gossip: Vec<Update<Meta>>,
+    },
+    /// Ask the receiver to probe the target on behalf of the sender
+    PingReq {
+        seq: u32,
+        target: NodeId,
+        gossip: Vec<Update<Meta>>,
+    },
+    /// Full state exchange, which is sent to new neighbours. `reply` requests the receiver to send back its state
+    Sync {
+        members: Vec<Update<Meta>>,
+        reply: bool,
+    },
+}
+
+struct Member<Meta> {
+    incarnation: u64,
+    state: MemberState,
+    meta: Meta,
+    changed_ms: u64,
+}
+
+struct Probe {
+    target: NodeId,
+    seq: u32,
+    sent_ms: u64,
+    indirect: bool,
+    acked: bool,
+}
+
+/// SWIM style membership, which uses the data feature for probing and piggybacks gossip updates in probe messages.
+/// Failed members are detected by direct and indirect probes, then marked as suspect before being removed.
+pub struct MembershipService<UserData, SC, SE, TC, TW, Meta> {
+    node_id: NodeId,
+    data_port: u16,
+    incarnation: u64,
+    meta: Meta,
+    left: bool,
+    members: BTreeMap<NodeId, Member<Meta>>,
+    gossip: Vec<(Update<Meta>, u8)>,
+    probe: Option<Probe>,
+    last_probe_ms: u64,
+    probe_seq: u32,
+    /// probes which are sent on behalf of other members, local seq => (requester, requester seq)
+    indirect: HashMap<u32, (NodeId, u32, u64)>,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    subscribers: Vec<ServiceControlActor<UserData>>,
+    _tmp: std::marker::PhantomData<(SC, TC)>,
+}
+
+impl<UserData: Copy, SC, SE, TC, TW, Meta: Clone + PartialEq + Serialize> MembershipService<UserData, SC, SE, TC, TW, Meta>
+where
+    SE: From<Event<Meta>>,
+{
+    pub fn new(node_id: NodeId, meta: Meta, data_port: u16) -> Self {
+        Self {
+            node_id,
+            data_port,
+            incarnation: 0,
+            meta,
+            left: false,
+            members: BTreeMap::new(),
+            gossip: Vec::new(),
+            probe: None,
+            last_probe_ms: 0,
+            probe_seq: 0,
+            indirect: HashMap::new(),
+            queue: VecDeque::from([data_cmd(data::Control::DataListen(data_port))]),
+            subscribers: Vec::new(),
+            _tmp: std::marker::PhantomData,
+        }
+    }
+
+    fn fire_event(&mut self, event: Event<Meta>) {
+        for sub in self.subscribers.iter() {
+            self.queue.push_back(ServiceOutput::Event(*sub, event.clone().into()));
+        }
+    }
+
+    fn local_update(&self) -> Update<Meta> {
+        Update {
+            node: self.node_id,
+            incarnation: self.incarnation,
+            state: if self.left {
+                MemberState::Left
+            } else {
+                MemberState::Alive
+            },
+            meta: self.meta.clone(),
+        }
+    }
+
+    fn all_updates(&self) -> Vec<Update<Meta>> {
+        let mut updates = vec![self.local_update()];
+        for (node, member) in self.members.iter() {
+            updates.push(Update {
+                node: *node,
+                incarnation: member.incarnation,
+                state: member.state,
+                meta: member.meta.clone(),
+            });
+        }
+        updates
+    }
+
+    fn live_members(&self) -> Vec<NodeId> {
+        self.members.iter().filter(|(_, m)| m.state.is_live()).map(|(n, _)| *n).collect()
+    }
+
+    fn send(&mut self, dest: NodeId, msg: &Message<Meta>) {
+        let buf = bincode::serialize(msg).expect("Should to bytes");
+        let meta = NetOutgoingMeta::new(true, Default::default(), 0, true);
+        self.queue.push_back(data_cmd(data::Control::DataSendRule(self.data_port, RouteRule::ToNode(dest), meta, buf)));
+    }
+
+    fn spread(&mut self, update: Update<Meta>) {
+        self.gossip.retain(|(u, _)| u.node != update.node);
+        self.gossip.push((update, 0));
+    }
+
+    /// Take updates which are transmitted least, each update is sent at most GOSSIP_RETRANSMIT times
+    fn piggyback(&mut self) -> Vec<Update<Meta>> {
+        self.gossip.sort_by_key(|(_, count)| *count);
+        let mut updates = vec![];
+        for (update, count) in self.gossip.iter_mut().take(MAX_PIGGYBACK) {
+            *count += 1;
+            updates.push(update.clone());
+        }
+        self.gossip.retain(|(_, count)| *count < GOSSIP_RETRANSMIT);
+        updates
+    }
+
+    fn set_state(&mut self, now: u64, node: NodeId, state: MemberState) {
+        let member = return_if_none!(self.members.get_mut(&node));
+        if member.state == state {
+            return;
+        }
+        log::info!("[Membership] member {node} {:?} => {:?}", member.state, state);
+        let was_live = member.state.is_live();
+        member.state = state;
+        member.changed_ms = now;
+        let update = Update {
+            node,
+            incarnation: member.incarnation,
+            state,
+            meta: member.meta.clone(),
+        };
+        self.spread(update);
+        if was_live && !state.is_live() {
+            self.fire_event(Event::Left(node));
+        }
+    }
+
+    /// Merge an update with SWIM override rules, changed updates are spread to other members
+    fn apply(&mut self, now: u64, update: Update<Meta>) {
+        if update.node == self.node_id {
+            // refute suspicion about ourself by increasing incarnation
+            if !self.left && matches!(update.state, MemberState::Suspect | MemberState::Dead) && update.incarnation >= self.incarnation {
+                self.incarnation = update.incarnation + 1;
+                log::info!("[Membership] refute {:?} about ourself with incarnation {}", update.state, self.incarnation);
+                self.spread(self.local_update());
+            }
+            return;
+        }
+
+        match self.members.get_mut(&update.node) {
+            None => {
+                if !update.state.is_live() {
+                    return;
+                }
+                log::info!("[Membership] member {} joined with incarnation {}", update.node, update.incarnation);
+                self.members.insert(
+                    update.node,
+                    Member {
+                        incarnation: update.incarnation,
+                        state: update.state,
+                        meta: update.meta.clone(),
+                        changed_ms: now,
+                    },
+                );
+                self.fire_event(Event::Joined(update.node, update.meta.clone()));
+            }
+            Some(member) => {
+                let newer = match (member.state, update.state) {
+                    (MemberState::Dead | MemberState::Left, _) => update.state.is_live() && update.incarnation > member.incarnation,
+                    (_, MemberState::Alive) => update.incarnation > member.incarnation,
+                    (MemberState::Alive, MemberState::Suspect) => update.incarnation >= member.incarnation,
+                    (MemberState::Suspect, MemberState::Suspect) => update.incarnation > member.incarnation,
+                    (_, MemberState::Dead | MemberState::Left) => update.incarnation >= member.incarnation,
+                };
+                if !newer {
+                    return;
+                }
+                let was_live = member.state.is_live();
+                let meta_changed = member.meta != update.meta;
+                member.incarnation = update.incarnation;
+                member.state = update.state;
+                member.meta = update.meta.clone();
+                member.changed_ms = now;
+                match (was_live, update.state.is_live()) {
+                    (true, false) => self.fire_event(Event::Left(update.node)),
+                    (false, true) => self.fire_event(Event::Joined(update.node, update.meta.clone())),
+                    (true, true) if meta_changed => self.fire_event(Event::Updated(update.node, update.meta.clone())),
+                    _ => {}
+                }
+            }
+        }
+        self.spread(update);
+    }
+
+    fn apply_all(&mut self, now: u64, updates: Vec<Update<Meta>>) {
+        for update in updates {
+            self.apply(now, update);
+        }
+    }
+
+    fn on_tick(&mut self, now: u64) {
+        if self.left {
+            return;
+        }
+
+        if let Some(probe) = &mut self.probe {
+            if !probe.acked && !probe.indirect && now >= probe.sent_ms + PROBE_TIMEOUT_MS {
+                probe.indirect = true;
+                let (target, seq) = (probe.target, probe.seq);
+                let relays = self.live_members().into_iter().filter(|n| *n != target).take(INDIRECT_PROBES).collect::<Vec<_>>();
+                log::debug!("[Membership] probe {target} timeout => send indirect probes through {relays:?}");
+                for relay in relays {
+                    let gossip = self.piggyback();
+                    self.send(relay, &Message::PingReq { seq, target, gossip });
+                }
+            }
+        }
+
+        if now >= self.last_probe_ms + PROBE_INTERVAL_MS {
+            self.last_probe_ms = now;
+            if let Some(probe) = &self.probe {
+                if !probe.acked && self.members.get(&probe.target).map(|m| m.state) == Some(MemberState::Alive) {
+                    self.set_state(now, probe.target, MemberState::Suspect);
+                }
+            }
+            self.start_probe(now);
+        }
+
+        let mut dead = vec![];
+        let mut removed = vec![];
+        for (node, member) in self.members.iter() {
+            match member.state {
+                MemberState::Suspect if now >= member.changed_ms + SUSPECT_TIMEOUT_MS => dead.push(*node),
+                MemberState::Dead | MemberState::Left if now >= member.changed_ms + DEAD_RETAIN_MS => removed.push(*node),
+                _ => {}
+            }
+        }
+        for node in dead {
+            self.set_state(now, node, MemberState::Dead);
+        }
+        for node in removed {
+            self.members.remove(&node);
+        }
+        self.indirect.retain(|_, (_, _, sent_ms)| now < *sent_ms + PROBE_INTERVAL_MS);
+    }
+
+    /// Probe members in round robin order
+    fn start_probe(&mut self, now: u64) {
+        let live = self.live_members();
+        let last = self.probe.take().map(|p| p.target).unwrap_or(self.node_id);
+        let target = return_if_none!(live.iter().find(|n| **n > last).or(live.first()).copied());
+        let seq = self.probe_seq;
+        self.probe_seq = self.probe_seq.wrapping_add(1);
+        self.probe = Some(Probe {
+            target,
+            seq,
+            sent_ms: now,
+            indirect: false,
+            acked: false,
+        });
+        let gossip = self.piggyback();
+        self.send(target, &Message::Ping { seq, gossip });
+    }
+
+    fn on_msg(&mut self, now: u64, from: NodeId, msg: Message<Meta>) {
+        match msg {
+            Message::Ping { seq, gossip } => {
+                self.apply_all(now, gossip);
+                let gossip = self.piggyback();
+                self.send(from, &Message::Ack { seq, gossip });
+            }
+            Message::Ack { seq, gossip } => {
+                self.apply_all(now, gossip);
+                if let Some((requester, requester_seq, _)) = self.indirect.remove(&seq) {
+                    let gossip = self.piggyback();
+                    self.send(requester, &Message::Ack { seq: requester_seq, gossip });
+                } else if let Some(probe) = &mut self.probe {
+                    if probe.seq == seq {
+                        probe.acked = true;
+                    }
+                }
+            }
+            Message::PingReq { seq, target, gossip } => {
+                self.apply_all(now, gossip);
+                let local_seq = self.probe_seq;
+                self.probe_seq = self.probe_seq.wrapping_add(1);
+                self.indirect.insert(local_seq, (from, seq, now));
+                let gossip = self.piggyback();
+                self.send(target, &Message::Ping { seq: local_seq, gossip });
+            }
+            Message::Sync { members, reply } => {
+                self.apply_all(now, members);
+                if reply {
+                    let members = self.all_updates();
+                    self.send(from, &Message::Sync { members, reply: false });
+                }
+            }
+        }
+    }
+}
+
+impl<UserData: Copy + Eq, SC, SE, TC, TW, Meta> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for MembershipService<UserData, SC, SE, TC, TW, Meta>
+where
+    Meta: Debug + Clone + PartialEq + Serialize + DeserializeOwned,
+    SC: From<Control<Meta>> + TryInto<Control<Meta>>,
+    SE: From<Event<Meta>> + TryInto<Event<Meta>>,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        match input {
+            ServiceSharedInput::Tick(_) => self.on_tick(now),
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) if !self.left => {
+                log::info!("[Membership] new neighbour {} => sync members", ctx.node);
+                let members = self.all_updates();
+                self.send(ctx.node, &Message::Sync { members, reply: true });
+            }
+            _ => {}
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {
+        match input {
+            ServiceInput::FeatureEvent(FeaturesEvent::Data(data::Event::Recv(port, meta, buf))) => {
+                if port != self.data_port || self.left {
+                    return;
+                }
+                if !meta.secure {
+                    log::warn!("[Membership] reject unsecure message");
+                    return;
+                }
+                let from = return_if_none!(meta.source);
+                if let Ok(msg) = bincode::deserialize::<Message<Meta>>(&buf) {
+                    self.on_msg(now, from, msg);
+                }
+            }
+            ServiceInput::Control(actor, control) => {
+                let mut push_all = || {
+                    let all = self.members.iter().filter(|(_, m)| m.state.is_live()).map(|(n, m)| (*n, m.meta.clone())).collect();
+                    self.queue.push_back(ServiceOutput::Event(actor, Event::GotAll(all).into()));
+                };
+                if let Ok(control) = control.try_into() {
+                    match control {
+                        Control::GetAll => push_all(),
+                        Control::Subscribe => {
+                            if !self.subscribers.contains(&actor) {
+                                self.subscribers.push(actor);
+                                push_all();
+                            }
+                        }
+                        Control::UpdateMeta(meta) => {
+                            self.meta = meta;
+                            self.incarnation += 1;
+                            self.spread(self.local_update());
+                        }
+                        Control::Leave => {
+                            if !self.left {
+                                log::info!("[Membership] leaving cluster");
+                                self.left = true;
+                                self.incarnation += 1;
+                                let members = vec![self.local_update()];
+                                for node in self.live_members() {
+                                    self.send(
+                                        node,
+                                        &Message::Sync {
+                                            members: members.clone(),
+                                            reply: false,
+                                        },
+                                    );
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct MembershipServiceWorker<UserData, SC, SE, TC> {
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for MembershipServiceWorker<UserData, SC, SE, TC> {
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct MembershipServiceBuilder<UserData, SC, SE, TC, TW, Meta> {
+    node_id: NodeId,
+    meta: Meta,
+    data_port: u16,
+    _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW, Meta)>,
+}
+
+impl<UserData, SC, SE, TC, TW, Meta> MembershipServiceBuilder<UserData, SC, SE, TC, TW, Meta> {
+    pub fn new(node_id: NodeId, meta: Meta) -> Self {
+        Self::new_with_port(node_id, meta, DEFAULT_DATA_PORT)
+    }
+
+    /// Use another data port when the default one is used by the app, all members must use same port
+    pub fn new_with_port(node_id: NodeId, meta: Meta, data_port: u16) -> Self {
+        Self {
+            node_id,
+            meta,
+            data_port,
+            _tmp: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC, TW, Meta> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for MembershipServiceBuilder<UserData, SC, SE, TC, TW, Meta>
+where
+    UserData: 'static + Debug + Send + Sync + Copy + Eq,
+    Meta: 'static + Debug + Serialize + DeserializeOwned + Clone + PartialEq + Send + Sync,
+    SC: 'static + Debug + Send + Sync + From<Control<Meta>> + TryInto<Control<Meta>>,
+    SE: 'static + Debug + Send + Sync + From<Event<Meta>> + TryInto<Event<Meta>>,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(MembershipService::new(self.node_id, self.meta.clone(), self.data_port))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(MembershipServiceWorker { queue: Default::default() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use atm0s_sdn_identity::NodeId;
+    use atm0s_sdn_router::RouteRule;
+
+    use crate::{
+        base::{NetIncomingMeta, Service, ServiceControlActor, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, Ttl},
+        features::{data, FeaturesControl, FeaturesEvent},
+    };
+
+    use super::{Control, Event, MemberState, MembershipService, Message, Update, DEFAULT_DATA_PORT, PROBE_INTERVAL_MS, PROBE_TIMEOUT_MS, SUSPECT_TIMEOUT_MS};
+
+    type TestService = MembershipService<(), Control<u8>, Event<u8>, (), (), u8>;
+
+    fn alive(node: NodeId, meta: u8) -> Update<u8> {
+        Update {
+            node,
+            incarnation: 0,
+            state: MemberState::Alive,
+            meta,
+        }
+    }
+
+    fn recv(from: NodeId, msg: Message<u8>) -> ServiceInput<(), FeaturesEvent, Control<u8>, ()> {
+        let meta = NetIncomingMeta::new(Some(from), Ttl::default(), 0, true);
+        ServiceInput::FeatureEvent(FeaturesEvent::Data(data::Event::Recv(DEFAULT_DATA_PORT, meta, bincode::serialize(&msg).expect("Should serialize"))))
+    }
+
+    type SentMsgs = Vec<(NodeId, Message<u8>)>;
+
+    /// Collect sent messages and events
+    fn pop_all(service: &mut TestService, now: u64) -> (SentMsgs, Vec<Event<u8>>) {
+        let mut msgs = vec![];
+        let mut events = vec![];
+        while let Some(out) = service.pop_output2(now) {
+            match out {
+                ServiceOutput::FeatureControl(FeaturesControl::Data(data::Control::DataSendRule(DEFAULT_DATA_PORT, RouteRule::ToNode(dest), _, buf))) => {
+                    msgs.push((dest, bincode::deserialize(&buf).expect("Should deserialize")));
+                }
+                ServiceOutput::Event(_, event) => events.push(event),
+                _ => {}
+            }
+        }
+        (msgs, events)
+    }
+
+    fn create(node: NodeId) -> (TestService, ServiceCtx) {
+        let mut service = TestService::new(node, node as u8, DEFAULT_DATA_PORT);
+        let ctx = ServiceCtx { node_id: node, session: 0 };
+        service.on_input(&ctx, 0, ServiceInput::Control(ServiceControlActor::Controller(()), Control::Subscribe));
+        assert_eq!(pop_all(&mut service, 0).1, vec![Event::GotAll(vec![])]);
+        (service, ctx)
+    }
+
+    #[test]
+    fn should_join_with_sync() {
+        let (mut service, ctx) = create(1);
+
+        service.on_input(
+            &ctx,
+            100,
+            recv(
+                2,
+                Message::Sync {
+                    members: vec![alive(2, 2), alive(3, 3)],
+                    reply: true,
+                },
+            ),
+        );
+        let (msgs, events) = pop_all(&mut service, 100);
+        assert_eq!(events, vec![Event::Joined(2, 2), Event::Joined(3, 3)]);
+        assert_eq!(msgs.len(), 1);
+        assert!(matches!(&msgs[0], (2, Message::Sync { members, reply: false }) if members.len() == 3));
+
+        // meta update is exposed
+        let update = Update { incarnation: 1, ..alive(3, 4) };
+        service.on_input(&ctx, 200, recv(2, Message::Ping { seq: 0, gossip: vec![update] }));
+        let (msgs, events) = pop_all(&mut service, 200);
+        assert_eq!(events, vec![Event::Updated(3, 4)]);
+        assert!(matches!(&msgs[0], (2, Message::Ack { seq: 0, .. })));
+    }
+
+    #[test]
+    fn should_detect_failure_with_indirect_probe() {
+        let (mut service, ctx) = create(1);
+        service.on_input(
+            &ctx,
+            0,
+            recv(
+                2,
+                Message::Sync {
+                    members: vec![alive(2, 2), alive(3, 3)],
+                    reply: false,
+                },
+            ),
+        );
+        pop_all(&mut service, 0);
+
+        service.on_shared_input(&ctx, PROBE_INTERVAL_MS, ServiceSharedInput::Tick(0));
+        let (msgs, _) = pop_all(&mut service, PROBE_INTERVAL_MS);
+        assert!(matches!(msgs.as_slice(), [(2, Message::Ping { seq: 0, .. })]));
+
+        // no ack => indirect probe through node 3
+        service.on_shared_input(&ctx, PROBE_INTERVAL_MS + PROBE_TIMEOUT_MS, ServiceSharedInput::Tick(1));
+        let (msgs, _) = pop_all(&mut service, PROBE_INTERVAL_MS + PROBE_TIMEOUT_MS);
+        assert!(matches!(msgs.as_slice(), [(3, Message::PingReq { seq: 0, target: 2, .. })]));
+
+        // still no ack => node 2 is suspected and node 3 is probed
+        service.on_shared_input(&ctx, PROBE_INTERVAL_MS * 2, ServiceSharedInput::Tick(2));
+        let (msgs, events) = pop_all(&mut service, PROBE_INTERVAL_MS * 2);
+        assert_eq!(events, vec![]);
+        match msgs.as_slice() {
+            [(3, Message::Ping { seq: 1, gossip })] => assert!(gossip.contains(&Update {
+                state: MemberState::Suspect,
+                ..alive(2, 2)
+            })),
+            _ => panic!("Should probe node 3, got {msgs:?}"),
+        }
+        service.on_input(&ctx, PROBE_INTERVAL_MS * 2 + 10, recv(3, Message::Ack { seq: 1, gossip: vec![] }));
+
+        // suspect timeout => dead
+        let now = PROBE_INTERVAL_MS * 2 + SUSPECT_TIMEOUT_MS;
+        service.on_shared_input(&ctx, now, ServiceSharedInput::Tick(3));
+        let (_, events) = pop_all(&mut service, now);
+        assert_eq!(events, vec![Event::Left(2)]);
+
+        service.on_input(&ctx, now, ServiceInput::Control(ServiceControlActor::Controller(()), Control::GetAll));
+        assert_eq!(pop_all(&mut service, now).1, vec![Event::GotAll(vec![(3, 3)])]);
+    }
+
+    #[test]
+    fn should_refute_suspicion() {
+        let (mut service, ctx) = create(1);
+        let suspect = Update {
+            state: MemberState::Suspect,
+            ..alive(1, 1)
+        };
+        service.on_input(
+            &ctx,
+            100,
+            recv(
+                2,
+                Message::Ping {
+                    seq: 0,
+                    gossip: vec![alive(2, 2), suspect],
+                },
+            ),
+        );
+        let (msgs, _) = pop_all(&mut service, 100);
+        match msgs.as_slice() {
+            [(2, Message::Ack { seq: 0, gossip })] => assert!(gossip.contains(&Update { incarnation: 1, ..alive(1, 1) })),
+            _ => panic!("Should ack, got {msgs:?}"),
+        }
+    }
+
+    #[test]
+    fn should_handle_leave() {
+        let (mut service, ctx) = create(1);
+        service.on_input(
+            &ctx,
+            0,
+            recv(
+                2,
+                Message::Sync {
+                    members: vec![alive(2, 2), alive(3, 3)],
+                    reply: false,
+                },
+            ),
+        );
+        pop_all(&mut service, 0);
+
+        // remote leave
+        let left = Update {
+            incarnation: 1,
+            state: MemberState::Left,
+            ..alive(3, 3)
+        };
+        service.on_input(&ctx, 100, recv(3, Message::Sync { members: vec![left], reply: false }));
+        assert_eq!(pop_all(&mut service, 100).1, vec![Event::Left(3)]);
+
+        // local leave
+        service.on_input(&ctx, 200, ServiceInput::Control(ServiceControlActor::Controller(()), Control::Leave));
+        let (msgs, _) = pop_all(&mut service, 200);
+        match msgs.as_slice() {
+            [(2, Message::Sync { members, reply: false })] => assert_eq!(
+                members,
+                &vec![Update {
+                    incarnation: 1,
+                    state: MemberState::Left,
+                    ..alive(1, 1)
+                }]
+            ),
+            _ => panic!("Should send leave, got {msgs:?}"),
+        }
+    }
+}
@@ -1,2 +1,97 @@
+use std::net::{IpAddr, SocketAddr};
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, Protocol};
+
+pub mod lan_discovery;
 pub mod manual_discovery;
+pub mod membership;
+pub mod topology;
 pub mod visualization;
+
+/// Append observed addresses which are not in the configured addr, the configured ones are kept first
+pub(crate) fn merge_observed_addrs(base: &NodeAddr, observed: &[SocketAddr]) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(base.node_id());
+    let mut exists = vec![];
+    let mut ip = None;
+    for protocol in base.multiaddr().iter() {
+        match protocol {
+            Protocol::Ip4(i) => ip = Some(IpAddr::V4(i)),
+            Protocol::Ip6(i) => ip = Some(IpAddr::V6(i)),
+            Protocol::Udp(port) => {
+                if let Some(ip) = ip {
+                    exists.push(SocketAddr::new(ip, port));
+                }
+            }
+            _ => {}
+        }
+        builder.add_protocol(protocol);
+    }
+    for addr in observed.iter().filter(|addr| !exists.contains(addr)) {
+        match addr.ip() {
+            IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+            IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+        }
+        builder.add_protocol(Protocol::Udp(addr.port()));
+    }
+    builder.addr()
+}
+
+/// Split addr parts into groups which start with an ip, parts before the first ip are kept in a group without host
+fn host_groups(addr: &NodeAddr) -> Vec<Vec<Protocol<'_>>> {
+    let mut groups: Vec<Vec<Protocol<'_>>> = vec![];
+    for protocol in addr.multiaddr().iter() {
+        match (&protocol, groups.last_mut()) {
+            (Protocol::Ip4(_) | Protocol::Ip6(_), _) | (_, None) => groups.push(vec![protocol]),
+            (_, Some(group)) => group.push(protocol),
+        }
+    }
+    groups
+}
+
+fn host_ip(group: &[Protocol<'_>]) -> Option<IpAddr> {
+    match group.first() {
+        Some(Protocol::Ip4(ip)) => Some(IpAddr::V4(*ip)),
+        Some(Protocol::Ip6(ip)) => Some(IpAddr::V6(*ip)),
+        _ => None,
+    }
+}
+
+/// Append a bind addr which is added at runtime, only UDP is listened on it
+pub(crate) fn add_bind_addr(base: &NodeAddr, bind: SocketAddr) -> NodeAddr {
+    let exists = host_groups(base).iter().any(|group| host_ip(group) == Some(bind.ip()) && group.contains(&Protocol::Udp(bind.port())));
+    if exists {
+        return base.clone();
+    }
+    let mut builder = NodeAddrBuilder::new(base.node_id());
+    for protocol in base.multiaddr().iter() {
+        builder.add_protocol(protocol);
+    }
+    match bind.ip() {
+        IpAddr::V4(ip) => builder.add_protocol(Protocol::Ip4(ip)),
+        IpAddr::V6(ip) => builder.add_protocol(Protocol::Ip6(ip)),
+    }
+    builder.add_protocol(Protocol::Udp(bind.port()));
+    builder.addr()
+}
+
+/// Remove a bind addr which is removed at runtime with the transports which share its socket addr.
+/// WebSocket addrs are kept because they have their own listener
+pub(crate) fn remove_bind_addr(base: &NodeAddr, bind: SocketAddr) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(base.node_id());
+    for group in host_groups(base) {
+        let same_socket = host_ip(&group) == Some(bind.ip())
+            && !group.iter().any(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_)))
+            && group.iter().any(|p| match p {
+                Protocol::Udp(port) | Protocol::Tcp(port) => *port == bind.port(),
+                Protocol::Memory(port) => *port == bind.port() as u64,
+                _ => false,
+            });
+        if same_socket {
+            continue;
+        }
+        for protocol in group {
+            builder.add_protocol(protocol);
+        }
+    }
+    builder.addr()
+}
@@ -0,0 +1,476 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    net::SocketAddr,
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId, NodeIdType};
+use atm0s_sdn_utils::hash::hash_str;
+use sans_io_runtime::collections::DynamicDeque;
+
+use crate::{
+    base::{ConnectionEvent, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    features::{
+        dht_kv::{Control as KvControl, Event as KvEvent, Key, Map, MapControl, MapEvent},
+        neighbours::Control as NeighbourControl,
+        FeaturesControl, FeaturesEvent,
+    },
+};
+
+use super::{add_bind_addr, merge_observed_addrs, remove_bind_addr};
+
+const CHECK_INTERVAL_MS: u64 = 5_000;
+const CONNECT_TIMEOUT_MS: u64 = 30_000;
+const CANDIDATES_MAP: &str = "atm0s.topology.candidates";
+
+pub const SERVICE_ID: u8 = 2;
+pub const SERVICE_NAME: &str = "topology";
+
+/// Layer of a neighbour, compared with local node id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TopologyLayer {
+    /// Same geo1, geo2 and group
+    SameGroup,
+    /// Same geo1 and geo2 but other group
+    SameGeo2,
+    /// Other geo1 or geo2
+    CrossGeo,
+}
+
+impl TopologyLayer {
+    pub fn of(local: NodeId, remote: NodeId) -> Self {
+        match local.eq_util_layer(&remote) {
+            0 | 1 => Self::SameGroup,
+            2 => Self::SameGeo2,
+            _ => Self::CrossGeo,
+        }
+    }
+}
+
+/// Target neighbours count in each layer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TopologyCfg {
+    pub same_group: usize,
+    pub same_geo2: usize,
+    pub cross_geo: usize,
+}
+
+impl Default for TopologyCfg {
+    fn default() -> Self {
+        Self {
+            same_group: 4,
+            same_geo2: 2,
+            cross_geo: 1,
+        }
+    }
+}
+
+impl TopologyCfg {
+    fn target(&self, layer: TopologyLayer) -> usize {
+        match layer {
+            TopologyLayer::SameGroup => self.same_group,
+            TopologyLayer::SameGeo2 => self.same_geo2,
+            TopologyLayer::CrossGeo => self.cross_geo,
+        }
+    }
+}
+
+fn kv_control<UserData, SE, TW>(c: KvControl) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::DhtKv(c))
+}
+
+fn neighbour_control<UserData, SE, TW>(c: NeighbourControl) -> ServiceOutput<UserData, FeaturesControl, SE, TW> {
+    ServiceOutput::FeatureControl(FeaturesControl::Neighbours(c))
+}
+
+/// Keep a target degree per layer. All nodes publish their address in a shared DHT map which is used as candidates list,
+/// missing neighbours are connected by closest distance and the worst RTT links are pruned when a layer has too many neighbours.
+/// Only links which are created by this service are pruned, so manual seeds are kept.
+pub struct TopologyService<UserData, SC, SE, TC, TW> {
+    base_addr: NodeAddr,
+    node_addr: NodeAddr,
+    observed_addrs: Vec<SocketAddr>,
+    cfg: TopologyCfg,
+    map: Map,
+    queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
+    candidates: HashMap<NodeId, NodeAddr>,
+    /// connection => (node, rtt_ms)
+    conns: HashMap<ConnId, (NodeId, u32)>,
+    connecting: HashMap<NodeId, u64>,
+    managed: HashSet<NodeId>,
+    last_check_ms: u64,
+    _tmp: std::marker::PhantomData<(SC, TC, TW)>,
+}
+
+impl<UserData, SC, SE, TC, TW> TopologyService<UserData, SC, SE, TC, TW> {
+    pub fn new(node_addr: NodeAddr, cfg: TopologyCfg) -> Self {
+        log::info!("Creating TopologyService for node {node_addr} with {cfg:?}");
+        let map = Map(hash_str(CANDIDATES_MAP));
+        Self {
+            base_addr: node_addr.clone(),
+            queue: VecDeque::from([
+                kv_control(KvControl::MapCmd(map, MapControl::Set(Key(0), node_addr.to_vec()))),
+                kv_control(KvControl::MapCmd(map, MapControl::Sub)),
+            ]),
+            node_addr,
+            observed_addrs: vec![],
+            cfg,
+            map,
+            candidates: HashMap::new(),
+            conns: HashMap::new(),
+            connecting: HashMap::new(),
+            managed: HashSet::new(),
+            last_check_ms: 0,
+            _tmp: std::marker::PhantomData,
+        }
+    }
+
+    /// Connected nodes in a layer with the best RTT of all connections to each node
+    fn neighbours(&self, layer: TopologyLayer) -> HashMap<NodeId, u32> {
+        let local = self.node_addr.node_id();
+        let mut nodes: HashMap<NodeId, u32> = HashMap::new();
+        for (node, rtt) in self.conns.values().filter(|(node, _)| TopologyLayer::of(local, *node) == layer) {
+            let entry = nodes.entry(*node).or_insert(*rtt);
+            *entry = (*entry).min(*rtt);
+        }
+        nodes
+    }
+
+    fn check_topology(&mut self, now: u64) {
+        self.connecting.retain(|_, at| *at + CONNECT_TIMEOUT_MS > now);
+        let local = self.node_addr.node_id();
+        for layer in [TopologyLayer::SameGroup, TopologyLayer::SameGeo2, TopologyLayer::CrossGeo] {
+            let target = self.cfg.target(layer);
+            let neighbours = self.neighbours(layer);
+            let connecting = self
+                .connecting
+                .keys()
+                .filter(|node| TopologyLayer::of(local, **node) == layer && !neighbours.contains_key(node))
+                .count();
+
+            if neighbours.len() + connecting < target {
+                let mut candidates = self
+                    .candidates
+                    .keys()
+                    .filter(|node| TopologyLayer::of(local, **node) == layer && !neighbours.contains_key(node) && !self.connecting.contains_key(node))
+                    .copied()
+                    .collect::<Vec<_>>();
+                candidates.sort_by_key(|node| local.distance(node));
+                for node in candidates.into_iter().take(target - neighbours.len() - connecting) {
+                    let addr = self.candidates.get(&node).expect("Should have candidate").clone();
+                    log::info!("TopologyService layer {layer:?} has {} / {target} neighbours => connect {addr}", neighbours.len());
+                    self.connecting.insert(node, now);
+                    self.managed.insert(node);
+                    self.queue.push_back(neighbour_control(NeighbourControl::ConnectTo(addr)));
+                }
+            } else if neighbours.len() > target {
+                // prune only one link each check for avoiding flapping
+                let worst = neighbours.iter().filter(|(node, _)| self.managed.contains(node)).max_by_key(|(node, rtt)| (**rtt, **node));
+                if let Some((node, rtt)) = worst {
+                    log::info!("TopologyService layer {layer:?} has {} / {target} neighbours => prune {node} with rtt {rtt}", neighbours.len());
+                    self.managed.remove(node);
+                    self.queue.push_back(neighbour_control(NeighbourControl::DisconnectFrom(*node)));
+                }
+            }
+        }
+    }
+
+    fn on_observed_addrs(&mut self, addrs: Vec<SocketAddr>) {
+        self.observed_addrs = addrs;
+        self.update_node_addr();
+    }
+
+    fn update_node_addr(&mut self) {
+        let node_addr = merge_observed_addrs(&self.base_addr, &self.observed_addrs);
+        if node_addr != self.node_addr {
+            self.node_addr = node_addr;
+            self.queue.push_back(kv_control(KvControl::MapCmd(self.map, MapControl::Set(Key(0), self.node_addr.to_vec()))));
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC: Debug, TW: Debug> Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for TopologyService<UserData, SC, SE, TC, TW> {
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, now: u64, input: ServiceSharedInput) {
+        match input {
+            ServiceSharedInput::Tick(_) => {
+                if self.last_check_ms + CHECK_INTERVAL_MS <= now {
+                    self.last_check_ms = now;
+                    self.check_topology(now);
+                }
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx, _)) => {
+                self.connecting.remove(&ctx.node);
+                self.conns.insert(ctx.conn, (ctx.node, u32::MAX));
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Stats(ctx, stats)) => {
+                if let Some((_, rtt)) = self.conns.get_mut(&ctx.conn) {
+                    *rtt = stats.rtt_ms;
+                }
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx, _)) => {
+                self.conns.remove(&ctx.conn);
+                if !self.conns.values().any(|(node, _)| *node == ctx.node) {
+                    self.managed.remove(&ctx.node);
+                }
+            }
+            ServiceSharedInput::Connection(ConnectionEvent::Primary(_) | ConnectionEvent::ConnectError(..) | ConnectionEvent::GaveUp(_)) => {}
+            ServiceSharedInput::ObservedAddrs(addrs) => self.on_observed_addrs(addrs),
+            ServiceSharedInput::Bind(addr) => {
+                self.base_addr = add_bind_addr(&self.base_addr, addr);
+                self.update_node_addr();
+            }
+            ServiceSharedInput::Unbind(addr) => {
+                self.base_addr = remove_bind_addr(&self.base_addr, addr);
+                self.update_node_addr();
+            }
+        }
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, input: ServiceInput<UserData, FeaturesEvent, SC, TC>) {
+        if let ServiceInput::FeatureEvent(FeaturesEvent::DhtKv(KvEvent::MapEvent(map, event))) = input {
+            if map != self.map {
+                return;
+            }
+            match event {
+                MapEvent::OnSet(_, source, value) => {
+                    if source == self.node_addr.node_id() {
+                        return;
+                    }
+                    if let Some(addr) = NodeAddr::from_vec(&value) {
+                        log::debug!("TopologyService candidate {source} added => {addr}");
+                        self.candidates.insert(source, addr);
+                    }
+                }
+                MapEvent::OnDel(_, source) => {
+                    log::debug!("TopologyService candidate {source} removed");
+                    self.candidates.remove(&source);
+                }
+                MapEvent::OnRelaySelected(_) => {}
+            }
+        }
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<UserData, FeaturesControl, SE, TW>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct TopologyServiceWorker<UserData, SC, SE, TC> {
+    queue: DynamicDeque<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>, 8>,
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for TopologyServiceWorker<UserData, SC, SE, TC> {
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, input: ServiceWorkerInput<UserData, FeaturesEvent, SC, TW>) {
+        match input {
+            ServiceWorkerInput::Control(actor, control) => self.queue.push_back(ServiceWorkerOutput::ForwardControlToController(actor, control)),
+            ServiceWorkerInput::FeatureEvent(event) => self.queue.push_back(ServiceWorkerOutput::ForwardFeatureEventToController(event)),
+            ServiceWorkerInput::FromController(_) => {}
+        }
+    }
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC>> {
+        self.queue.pop_front()
+    }
+}
+
+pub struct TopologyServiceBuilder<UserData, SC, SE, TC, TW> {
+    _tmp: std::marker::PhantomData<(UserData, SC, SE, TC, TW)>,
+    node_addr: NodeAddr,
+    cfg: TopologyCfg,
+}
+
+impl<UserData, SC, SE, TC, TW> TopologyServiceBuilder<UserData, SC, SE, TC, TW> {
+    pub fn new(node_addr: NodeAddr, cfg: TopologyCfg) -> Self {
+        Self {
+            _tmp: std::marker::PhantomData,
+            node_addr,
+            cfg,
+        }
+    }
+}
+
+impl<UserData, SC, SE, TC, TW> ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW> for TopologyServiceBuilder<UserData, SC, SE, TC, TW>
+where
+    UserData: 'static + Debug + Send + Sync,
+    SC: 'static + Debug + Send + Sync,
+    SE: 'static + Debug + Send + Sync,
+    TC: 'static + Debug + Send + Sync,
+    TW: 'static + Debug + Send + Sync,
+{
+    fn service_id(&self) -> u8 {
+        SERVICE_ID
+    }
+
+    fn service_name(&self) -> &str {
+        SERVICE_NAME
+    }
+
+    fn create(&self) -> Box<dyn Service<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(TopologyService::new(self.node_addr.clone(), self.cfg))
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>> {
+        Box::new(TopologyServiceWorker { queue: Default::default() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use atm0s_sdn_identity::{ConnId, NodeAddr, NodeId, NodeIdType};
+    use atm0s_sdn_utils::hash::hash_str;
+
+    use crate::{
+        base::{ConnectionCtx, ConnectionEvent, ConnectionStats, MockDecryptor, MockEncryptor, SecureContext, Service, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput},
+        data_plane::NetPair,
+        features::{
+            dht_kv::{self, Key, Map, MapControl, MapEvent},
+            neighbours, FeaturesControl, FeaturesEvent,
+        },
+    };
+
+    use super::{TopologyCfg, TopologyLayer, TopologyService, CANDIDATES_MAP, CHECK_INTERVAL_MS};
+
+    fn map_cmd<SE, TC>(control: MapControl) -> ServiceOutput<(), FeaturesControl, SE, TC> {
+        ServiceOutput::FeatureControl(FeaturesControl::DhtKv(dht_kv::Control::MapCmd(Map(hash_str(CANDIDATES_MAP)), control)))
+    }
+
+    fn candidate_event<SC, TC>(node: NodeId) -> ServiceInput<(), FeaturesEvent, SC, TC> {
+        let event = MapEvent::OnSet(Key(0), node, NodeAddr::empty(node).to_vec());
+        ServiceInput::FeatureEvent(FeaturesEvent::DhtKv(dht_kv::Event::MapEvent(Map(hash_str(CANDIDATES_MAP)), event)))
+    }
+
+    fn neighbour_cmd<SE, TC>(control: neighbours::Control) -> ServiceOutput<(), FeaturesControl, SE, TC> {
+        ServiceOutput::FeatureControl(FeaturesControl::Neighbours(control))
+    }
+
+    fn ctx(node: NodeId, session: u64) -> ConnectionCtx {
+        ConnectionCtx {
+            conn: ConnId::from_out(0, session),
+            node,
+            pair: NetPair::new_str("127.0.0.1:1000", "127.0.0.1:1001").expect("Should parse"),
+        }
+    }
+
+    fn connected(node: NodeId, session: u64) -> ServiceSharedInput {
+        let secure = SecureContext {
+            encryptor: Box::new(MockEncryptor::new()),
+            decryptor: Box::new(MockDecryptor::new()),
+        };
+        ServiceSharedInput::Connection(ConnectionEvent::Connected(ctx(node, session), secure))
+    }
+
+    fn stats(node: NodeId, session: u64, rtt_ms: u32) -> ServiceSharedInput {
+        ServiceSharedInput::Connection(ConnectionEvent::Stats(
+            ctx(node, session),
+            ConnectionStats {
+                rtt_ms,
+                mtu: 1500,
+                ..Default::default()
+            },
+        ))
+    }
+
+    #[test]
+    fn layer_of_node() {
+        let local = NodeId::build(1, 1, 1, 1);
+        assert_eq!(TopologyLayer::of(local, NodeId::build(1, 1, 1, 2)), TopologyLayer::SameGroup);
+        assert_eq!(TopologyLayer::of(local, NodeId::build(1, 1, 2, 1)), TopologyLayer::SameGeo2);
+        assert_eq!(TopologyLayer::of(local, NodeId::build(1, 2, 1, 1)), TopologyLayer::CrossGeo);
+        assert_eq!(TopologyLayer::of(local, NodeId::build(2, 1, 1, 1)), TopologyLayer::CrossGeo);
+    }
+
+    #[test]
+    fn should_connect_closest_candidates_until_target() {
+        let local = NodeId::build(1, 1, 1, 1);
+        let cfg = TopologyCfg {
+            same_group: 2,
+            same_geo2: 1,
+            cross_geo: 0,
+        };
+        let ctx = ServiceCtx { node_id: local, session: 0 };
+        let mut service = TopologyService::<(), (), (), (), ()>::new(NodeAddr::empty(local), cfg);
+        assert_eq!(service.pop_output2(0), Some(map_cmd(MapControl::Set(Key(0), NodeAddr::empty(local).to_vec()))));
+        assert_eq!(service.pop_output2(0), Some(map_cmd(MapControl::Sub)));
+
+        for node in [
+            NodeId::build(1, 1, 1, 2),
+            NodeId::build(1, 1, 1, 3),
+            NodeId::build(1, 1, 1, 4),
+            NodeId::build(1, 1, 2, 1),
+            NodeId::build(2, 1, 1, 1),
+        ] {
+            service.on_input(&ctx, 100, candidate_event(node));
+        }
+
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS, ServiceSharedInput::Tick(0));
+        assert_eq!(
+            service.pop_output2(CHECK_INTERVAL_MS),
+            Some(neighbour_cmd(neighbours::Control::ConnectTo(NodeAddr::empty(NodeId::build(1, 1, 1, 3)))))
+        );
+        assert_eq!(
+            service.pop_output2(CHECK_INTERVAL_MS),
+            Some(neighbour_cmd(neighbours::Control::ConnectTo(NodeAddr::empty(NodeId::build(1, 1, 1, 2)))))
+        );
+        assert_eq!(
+            service.pop_output2(CHECK_INTERVAL_MS),
+            Some(neighbour_cmd(neighbours::Control::ConnectTo(NodeAddr::empty(NodeId::build(1, 1, 2, 1)))))
+        );
+        assert_eq!(service.pop_output2(CHECK_INTERVAL_MS), None);
+
+        // connecting nodes are not connected again
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS * 2, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(CHECK_INTERVAL_MS * 2), None);
+    }
+
+    #[test]
+    fn should_prune_worst_rtt_link() {
+        let local = NodeId::build(1, 1, 1, 1);
+        let cfg = TopologyCfg {
+            same_group: 2,
+            same_geo2: 0,
+            cross_geo: 0,
+        };
+        let ctx = ServiceCtx { node_id: local, session: 0 };
+        let mut service = TopologyService::<(), (), (), (), ()>::new(NodeAddr::empty(local), cfg);
+        while service.pop_output2(0).is_some() {}
+
+        let nodes = [NodeId::build(1, 1, 1, 2), NodeId::build(1, 1, 1, 3), NodeId::build(1, 1, 1, 4)];
+        for node in nodes {
+            service.on_input(&ctx, 100, candidate_event(node));
+        }
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS, ServiceSharedInput::Tick(0));
+        while service.pop_output2(CHECK_INTERVAL_MS).is_some() {}
+
+        // all nodes are connected, one of them is a manual link which is not pruned
+        for (i, node) in nodes.iter().enumerate() {
+            service.on_shared_input(&ctx, CHECK_INTERVAL_MS + 100, connected(*node, i as u64));
+        }
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS + 100, connected(NodeId::build(1, 1, 1, 5), 10));
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS + 200, stats(nodes[0], 0, 50));
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS + 200, stats(nodes[1], 1, 10));
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS + 200, stats(nodes[2], 2, 20));
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS + 200, stats(NodeId::build(1, 1, 1, 5), 10, 100));
+
+        service.on_shared_input(&ctx, CHECK_INTERVAL_MS * 2, ServiceSharedInput::Tick(0));
+        assert_eq!(service.pop_output2(CHECK_INTERVAL_MS * 2), Some(neighbour_cmd(neighbours::Control::DisconnectFrom(nodes[0]))));
+        assert_eq!(service.pop_output2(CHECK_INTERVAL_MS * 2), None);
+    }
+}
@@ -5,7 +5,7 @@ use std::{
 };
 
 use atm0s_sdn_identity::{ConnId, NodeId};
-use atm0s_sdn_router::{RouteRule, ServiceBroadcastLevel};
+use atm0s_sdn_router::{next_broadcast_seq, RouteRule, ServiceBroadcastLevel};
 use sans_io_runtime::collections::DynamicDeque;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
@@ -67,7 +67,7 @@ enum Message<Info> {
 pub struct VisualizationService<UserData, SC, SE, TC, TW, Info> {
     info: Info,
     last_ping: u64,
-    broadcast_seq: u16,
+    broadcast_seq: u32,
     queue: VecDeque<ServiceOutput<UserData, FeaturesControl, SE, TW>>,
     conns: BTreeMap<ConnId, ConnectionInfo>,
     network_nodes: BTreeMap<NodeId, NodeInfo<Info>>,
@@ -134,7 +134,7 @@ where
                     self.last_ping = now;
                     let msg = Message::Snapshot(ctx.node_id, self.info.clone(), self.conns.values().cloned().collect::<Vec<_>>());
                     let seq = self.broadcast_seq;
-                    self.broadcast_seq = self.broadcast_seq.wrapping_add(1);
+                    self.broadcast_seq = next_broadcast_seq(self.broadcast_seq);
                     self.queue.push_back(data_cmd(data::Control::DataSendRule(
                         DATA_PORT,
                         RouteRule::ToServices(SERVICE_ID, ServiceBroadcastLevel::Global, seq),
@@ -167,10 +167,12 @@ where
                 });
                 entry.rtt_ms = stats.rtt_ms;
             }
-            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx)) => {
+            ServiceSharedInput::Connection(ConnectionEvent::Disconnected(ctx, _)) => {
                 log::info!("[Visualization] Connection from {} to {} is disconnected", ctx.pair, ctx.node);
                 self.conns.remove(&ctx.conn);
             }
+            ServiceSharedInput::Connection(ConnectionEvent::Primary(_) | ConnectionEvent::ConnectError(..) | ConnectionEvent::GaveUp(_)) => {}
+            ServiceSharedInput::ObservedAddrs(_) | ServiceSharedInput::Bind(_) | ServiceSharedInput::Unbind(_) => {}
         }
     }
 
@@ -305,7 +307,9 @@ mod test {
     use serde::{Deserialize, Serialize};
 
     use crate::{
-        base::{ConnectionCtx, ConnectionEvent, MockDecryptor, MockEncryptor, NetIncomingMeta, NetOutgoingMeta, SecureContext, Service, ServiceCtx, ServiceInput, ServiceSharedInput, Ttl},
+        base::{
+            ConnectionCtx, ConnectionEvent, DisconnectReason, MockDecryptor, MockEncryptor, NetIncomingMeta, NetOutgoingMeta, SecureContext, Service, ServiceCtx, ServiceInput, ServiceSharedInput, Ttl,
+        },
         data_plane::NetPair,
         features::{
             data::{Control as DataControl, Event as DataEvent},
@@ -338,11 +342,14 @@ mod test {
     }
 
     fn disconnected_event(node: NodeId) -> ConnectionEvent {
-        ConnectionEvent::Disconnected(ConnectionCtx {
-            conn: ConnId::from_in(0, node as u64),
-            node,
-            pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
-        })
+        ConnectionEvent::Disconnected(
+            ConnectionCtx {
+                conn: ConnId::from_in(0, node as u64),
+                node,
+                pair: NetPair::new_str("1.1.1.1:1000", "2.2.2.2:2000").expect("Should parse pair"),
+            },
+            DisconnectReason::Local,
+        )
     }
 
     #[test]
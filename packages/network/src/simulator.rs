@@ -0,0 +1,708 @@
+//!
+//! In-memory network simulator for deterministic multi-node tests without sockets.
+//! Each node is a SdnWorker with a controller and a single data worker, packets between nodes are
+//! delivered over simulated links with latency, loss, bandwidth and partitions. Time only moves with
+//! `process`, and loss is decided by a seeded rng, so same test always gives same result.
+//! Failures can be scripted by cutting links, latency spikes, reordering or duplicating packets and
+//! crashing then restarting nodes.
+//!
+//! The module is only built with the `simulator` feature, which is not enabled by default. Logs are emitted
+//! with the `log` crate, tests can print them with any logger like env_logger.
+//!
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Debug;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+
+use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
+use atm0s_sdn_router::shadow::{BroadcastKey, ShadowRouterHistory};
+use atm0s_sdn_router::{RouteAction, RouteRule};
+use parking_lot::Mutex;
+use rand::rngs::mock::StepRng;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use sans_io_runtime::{TaskSwitcher, TaskSwitcherChild};
+
+use crate::base::{Buffer, DnsResolver, ServiceBuilder};
+use crate::controller_plane::{ControllerPlaneCfg, DrainCfg, KeepaliveCfg, NeighboursLimits};
+use crate::data_plane::{self, DataPlaneCfg, NetPair, RateLimit, TransportPenalty};
+use crate::features::{FeaturesControl, FeaturesEvent};
+use crate::secure::{HandshakeBuilderXDA, StaticKeyAuthorization};
+use crate::worker::{SdnWorker, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput};
+use crate::{ExtIn, ExtOut};
+
+#[derive(Debug)]
+pub enum TestNodeIn<SC> {
+    Ext(ExtIn<(), SC>),
+    ExtWorker(ExtIn<(), SC>),
+    Udp(NetPair, Buffer),
+    /// Frame over a non-UDP transport, which is reliable and has no size limit
+    Frame(NetPair, Buffer),
+    #[cfg(feature = "vpn")]
+    Tun(Buffer),
+}
+
+#[derive(Debug)]
+pub enum TestNodeOut<SE> {
+    Ext(ExtOut<(), SE>),
+    ExtWorker(ExtOut<(), SE>),
+    Udp(Vec<NetPair>, Buffer),
+    /// Frame over a non-UDP transport, which is reliable and has no size limit
+    Frame(NetPair, Buffer),
+    #[cfg(feature = "vpn")]
+    Tun(Buffer),
+    Continue,
+}
+
+pub fn build_addr(node_id: NodeId) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node_id);
+    builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+    builder.add_protocol(Protocol::Udp(node_id as u16));
+    builder.addr()
+}
+
+/// Same as build_addr with a tcp addr at same port, which is used when UDP is blocked
+pub fn build_addr_with_tcp(node_id: NodeId) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node_id);
+    builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+    builder.add_protocol(Protocol::Udp(node_id as u16));
+    builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+    builder.add_protocol(Protocol::Tcp(node_id as u16));
+    builder.addr()
+}
+
+/// Only a WebSocket addr at same port, which is how browsers and restricted clients see a node
+pub fn build_addr_ws_only(node_id: NodeId) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(node_id);
+    builder.add_protocol(Protocol::Ip4(Ipv4Addr::LOCALHOST));
+    builder.add_protocol(Protocol::Tcp(node_id as u16));
+    builder.add_protocol(Protocol::Ws("/".into()));
+    builder.addr()
+}
+
+#[derive(Debug, Default)]
+struct SingleThreadDataWorkerHistory {
+    queue: Mutex<Vec<(Option<NodeId>, u8, BroadcastKey)>>,
+    #[allow(clippy::type_complexity)]
+    map: Mutex<HashMap<(Option<NodeId>, u8, BroadcastKey), bool>>,
+}
+
+impl ShadowRouterHistory for SingleThreadDataWorkerHistory {
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u32) -> bool {
+        let mut map = self.map.lock();
+        let mut queue = self.queue.lock();
+        let (checks, records) = BroadcastKey::of(seq);
+        if checks.iter().any(|key| map.contains_key(&(from, service, *key))) {
+            log::debug!("already_received_broadcast from {:?} service {} seq {}", from, service, seq);
+            return true;
+        }
+        for key in records {
+            map.insert((from, service, key), true);
+        }
+        if queue.len() > 100 {
+            let pair = queue.remove(0);
+            map.remove(&pair);
+        }
+        false
+    }
+
+    fn set_ts(&self, _now: u64) {}
+}
+
+/// Optional settings of TestNode, default is a single bind addr which is derived from node id
+#[derive(Default)]
+pub struct TestNodeCfg {
+    pub bind_addrs: Vec<SocketAddr>,
+    pub resolver: Option<Arc<dyn DnsResolver>>,
+    pub max_conns_per_node: Option<usize>,
+    pub limits: NeighboursLimits,
+    pub keepalive: KeepaliveCfg,
+    pub transport_penalty: TransportPenalty,
+    pub drain: Option<DrainCfg>,
+    pub send_budget: Option<RateLimit>,
+}
+
+pub struct TestNode<SC, SE, TC, TW> {
+    node_id: NodeId,
+    /// UDP addrs which are bound, they are changed at runtime by AddBind and RemoveBind
+    binds: HashSet<SocketAddr>,
+    worker: SdnWorker<(), SC, SE, TC, TW>,
+}
+
+#[allow(clippy::type_complexity)]
+impl<SC: Debug, SE: Debug, TC: Debug, TW: Debug> TestNode<SC, SE, TC, TW> {
+    pub fn new(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>) -> Self {
+        Self::new_with_cfg(node_id, session, services, TestNodeCfg::default())
+    }
+
+    pub fn new_with_cfg(node_id: NodeId, session: u64, services: Vec<Arc<dyn ServiceBuilder<(), FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>, cfg: TestNodeCfg) -> Self {
+        let authorization: Arc<StaticKeyAuthorization> = Arc::new(StaticKeyAuthorization::new("demo-key"));
+        let handshake_builder = Arc::new(HandshakeBuilderXDA);
+        let random = Box::new(StepRng::new(1000, 5));
+        let history = Arc::new(SingleThreadDataWorkerHistory::default());
+        let bind_addrs = if cfg.bind_addrs.is_empty() {
+            vec![node_to_addr(node_id)]
+        } else {
+            cfg.bind_addrs
+        };
+        Self {
+            node_id,
+            binds: bind_addrs.iter().copied().collect(),
+            worker: SdnWorker::new(SdnWorkerCfg {
+                node_id,
+                tick_ms: 1,
+                controller: Some(ControllerPlaneCfg {
+                    session,
+                    bind_addrs,
+                    services: services.clone(),
+                    authorization,
+                    handshake_builder,
+                    resolver: cfg.resolver,
+                    reconnect: Default::default(),
+                    keepalive: cfg.keepalive,
+                    max_conns_per_node: cfg.max_conns_per_node,
+                    limits: cfg.limits,
+                    transport_penalty: cfg.transport_penalty,
+                    drain: cfg.drain,
+                    random,
+                    history: history.clone(),
+                }),
+                data: DataPlaneCfg {
+                    worker_id: 0,
+                    services,
+                    history,
+                    shaping: Default::default(),
+                    send_budget: cfg.send_budget,
+                },
+            }),
+        }
+    }
+
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    pub fn addr(&self) -> NodeAddr {
+        build_addr(self.node_id)
+    }
+
+    pub fn tick(&mut self, now: u64) {
+        self.worker.on_tick(now);
+    }
+
+    pub fn shutdown(&mut self, now: u64) {
+        self.worker.on_event(now, SdnWorkerInput::ShutdownRequest);
+    }
+
+    /// Running tasks of worker, 0 after shutdown finished
+    pub fn tasks(&self) -> usize {
+        self.worker.tasks()
+    }
+
+    pub fn route(&self, rule: RouteRule) -> RouteAction<NetPair> {
+        self.worker.route(rule)
+    }
+
+    pub fn is_bound(&self, addr: &SocketAddr) -> bool {
+        self.binds.contains(addr)
+    }
+
+    pub fn on_input(&mut self, now: u64, input: TestNodeIn<SC>) {
+        let input = match input {
+            TestNodeIn::Ext(ext_in) => SdnWorkerInput::Ext(ext_in),
+            TestNodeIn::ExtWorker(ext_in) => SdnWorkerInput::ExtWorker(ext_in),
+            TestNodeIn::Udp(addr, buf) => SdnWorkerInput::Net(data_plane::NetInput::UdpPacket(addr, buf)),
+            TestNodeIn::Frame(pair, buf) => SdnWorkerInput::Net(data_plane::NetInput::Frame(pair, buf)),
+            #[cfg(feature = "vpn")]
+            TestNodeIn::Tun(buf) => SdnWorkerInput::Net(data_plane::NetInput::TunPacket(buf)),
+        };
+        self.worker.on_event(now, input);
+    }
+
+    pub fn pop_output(&mut self, now: u64) -> Option<TestNodeOut<SE>> {
+        let output = self.worker.pop_output(now)?;
+        Some(self.process_worker_output(now, output))
+    }
+
+    fn process_worker_output(&mut self, now: u64, output: SdnWorkerOutput<(), SC, SE, TC, TW>) -> TestNodeOut<SE> {
+        match output {
+            SdnWorkerOutput::Ext(ext) => TestNodeOut::Ext(ext),
+            SdnWorkerOutput::ExtWorker(ext) => TestNodeOut::ExtWorker(ext),
+            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPacket(dest, data)) => TestNodeOut::Udp(vec![dest], data),
+            SdnWorkerOutput::Net(data_plane::NetOutput::UdpPackets(dests, data)) => TestNodeOut::Udp(dests, data),
+            SdnWorkerOutput::Net(data_plane::NetOutput::Frame(dest, data)) => TestNodeOut::Frame(dest, data),
+            SdnWorkerOutput::Net(data_plane::NetOutput::Bind(addr)) => {
+                self.binds.insert(addr);
+                TestNodeOut::Continue
+            }
+            SdnWorkerOutput::Net(data_plane::NetOutput::Unbind(addr)) => {
+                self.binds.remove(&addr);
+                TestNodeOut::Continue
+            }
+            #[cfg(feature = "vpn")]
+            SdnWorkerOutput::Net(data_plane::NetOutput::TunPacket(data)) => TestNodeOut::Tun(data),
+            SdnWorkerOutput::Bus(bus) => {
+                self.worker.on_event(now, SdnWorkerInput::Bus(bus));
+                TestNodeOut::Continue
+            }
+            SdnWorkerOutput::ShutdownResponse => {
+                log::info!("[TestNode {}] shutdown finished", self.node_id);
+                TestNodeOut::Continue
+            }
+            SdnWorkerOutput::Continue => TestNodeOut::Continue,
+        }
+    }
+}
+
+/// Same with receive buffer size inside sans-io-runtime, bigger datagrams will be truncated in real network
+const MAX_UDP_SIZE: usize = 1500;
+
+pub fn addr_to_node(addr: SocketAddr) -> NodeId {
+    addr.port() as u32
+}
+
+pub fn node_to_addr(node: NodeId) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), node as u16)
+}
+
+/// Quality of a link between two nodes, same in both directions. Default is a perfect link which
+/// delivers packets immediately.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LinkCfg {
+    /// One-way latency
+    pub latency_ms: u64,
+    /// Percent of UDP packets which are dropped, streams are reliable so their frames are never dropped
+    pub loss_percent: u8,
+    /// Packets are queued behind previous packets of same direction, None for unlimited
+    pub bandwidth_kbps: Option<u32>,
+    /// Percent of UDP packets which are delivered twice
+    pub duplicate_percent: u8,
+    /// Percent of UDP packets which are delayed up to REORDER_MAX_DELAY_MS more, so later packets overtake them
+    pub reorder_percent: u8,
+}
+
+impl LinkCfg {
+    pub fn new(latency_ms: u64, loss_percent: u8, bandwidth_kbps: Option<u32>) -> Self {
+        Self {
+            latency_ms,
+            loss_percent,
+            bandwidth_kbps,
+            duplicate_percent: 0,
+            reorder_percent: 0,
+        }
+    }
+
+    pub fn with_duplicate(mut self, percent: u8) -> Self {
+        self.duplicate_percent = percent;
+        self
+    }
+
+    pub fn with_reorder(mut self, percent: u8) -> Self {
+        self.reorder_percent = percent;
+        self
+    }
+}
+
+/// Max extra delay of reordered packets
+const REORDER_MAX_DELAY_MS: u64 = 50;
+
+fn link_key(a: NodeId, b: NodeId) -> (NodeId, NodeId) {
+    (a.min(b), a.max(b))
+}
+
+pub struct NetworkSimulator<SC, SE, TC: Clone, TW: Clone> {
+    clock_ms: u64,
+    input: VecDeque<(NodeId, ExtIn<(), SC>)>,
+    input_worker: VecDeque<(NodeId, ExtIn<(), SC>)>,
+    output: VecDeque<(NodeId, ExtOut<(), SE>)>,
+    output_worker: VecDeque<(NodeId, ExtOut<(), SE>)>,
+    nodes: Vec<TestNode<SC, SE, TC, TW>>,
+    nodes_index: HashMap<NodeId, usize>,
+    udp_blocked: HashSet<NodeId>,
+    default_link: LinkCfg,
+    links: HashMap<(NodeId, NodeId), LinkCfg>,
+    /// time which each direction of a bandwidth limited link is busy until
+    links_busy: HashMap<(NodeId, NodeId), u64>,
+    /// nodes in different groups can't reach each other, nodes which are not listed are in group 0
+    groups: HashMap<NodeId, usize>,
+    cut_links: HashSet<(NodeId, NodeId)>,
+    /// extra latency of links and the time it ends
+    spikes: HashMap<(NodeId, NodeId), (u64, u64)>,
+    /// crashed nodes are not ticked and drop all packets until they are restarted
+    crashed: HashSet<NodeId>,
+    /// packets which are delayed by links, ordered by delivery time then sending order
+    in_flight: BTreeMap<(u64, u64), (NodeId, TestNodeIn<SC>)>,
+    in_flight_seq: u64,
+    rng: StdRng,
+    switcher: TaskSwitcher,
+}
+
+impl<SC: Debug, SE: Debug, TC: Debug + Clone, TW: Debug + Clone> NetworkSimulator<SC, SE, TC, TW> {
+    pub fn new(started_ms: u64) -> Self {
+        Self::new_with_seed(started_ms, 0)
+    }
+
+    /// Same seed gives same packet loss decisions, so lossy tests are still deterministic
+    pub fn new_with_seed(started_ms: u64, seed: u64) -> Self {
+        Self {
+            clock_ms: started_ms,
+            input: VecDeque::new(),
+            output: VecDeque::new(),
+            input_worker: VecDeque::new(),
+            output_worker: VecDeque::new(),
+            nodes: Vec::new(),
+            nodes_index: HashMap::new(),
+            udp_blocked: HashSet::new(),
+            default_link: LinkCfg::default(),
+            links: HashMap::new(),
+            links_busy: HashMap::new(),
+            groups: HashMap::new(),
+            cut_links: HashSet::new(),
+            spikes: HashMap::new(),
+            crashed: HashSet::new(),
+            in_flight: BTreeMap::new(),
+            in_flight_seq: 0,
+            rng: StdRng::seed_from_u64(seed),
+            switcher: TaskSwitcher::new(0),
+        }
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.clock_ms
+    }
+
+    pub fn control(&mut self, node: NodeId, control: ExtIn<(), SC>) {
+        self.input.push_back((node, control));
+    }
+
+    pub fn pop_res(&mut self) -> Option<(NodeId, ExtOut<(), SE>)> {
+        self.output.pop_front()
+    }
+
+    pub fn control_worker(&mut self, node: NodeId, control: ExtIn<(), SC>) {
+        self.input_worker.push_back((node, control));
+    }
+
+    pub fn pop_res_worker(&mut self) -> Option<(NodeId, ExtOut<(), SE>)> {
+        self.output_worker.pop_front()
+    }
+
+    pub fn shutdown(&mut self, node: NodeId) {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.switcher.flag_task(node_index);
+        self.nodes[node_index].shutdown(self.clock_ms);
+    }
+
+    pub fn tasks(&self, node: NodeId) -> usize {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.nodes[node_index].tasks()
+    }
+
+    /// Drop all UDP packets from and to the node, streams still work
+    pub fn block_udp(&mut self, node: NodeId) {
+        self.udp_blocked.insert(node);
+    }
+
+    /// Link quality which is used for all links without own setting
+    pub fn set_default_link(&mut self, cfg: LinkCfg) {
+        self.default_link = cfg;
+    }
+
+    /// Link quality between two nodes, in both directions
+    pub fn set_link(&mut self, a: NodeId, b: NodeId, cfg: LinkCfg) {
+        self.links.insert(link_key(a, b), cfg);
+    }
+
+    pub fn link(&self, a: NodeId, b: NodeId) -> LinkCfg {
+        self.links.get(&link_key(a, b)).copied().unwrap_or(self.default_link)
+    }
+
+    /// Split nodes from all other nodes, packets between them and others are dropped until heal.
+    /// Calling it again creates another group, so the network can be split into many parts.
+    pub fn partition(&mut self, nodes: &[NodeId]) {
+        let group = self.groups.values().max().copied().unwrap_or(0) + 1;
+        for node in nodes {
+            self.groups.insert(*node, group);
+        }
+    }
+
+    /// Remove all partitions
+    pub fn heal(&mut self) {
+        self.groups.clear();
+    }
+
+    /// Drop all packets between two nodes until the link is restored, other links still work
+    pub fn cut_link(&mut self, a: NodeId, b: NodeId) {
+        self.cut_links.insert(link_key(a, b));
+    }
+
+    pub fn restore_link(&mut self, a: NodeId, b: NodeId) {
+        self.cut_links.remove(&link_key(a, b));
+    }
+
+    /// Add extra latency to a link for a duration from now, a new spike replaces the current one
+    pub fn latency_spike(&mut self, a: NodeId, b: NodeId, extra_ms: u64, duration_ms: u64) {
+        self.spikes.insert(link_key(a, b), (extra_ms, self.clock_ms + duration_ms));
+    }
+
+    pub fn is_reachable(&self, a: NodeId, b: NodeId) -> bool {
+        !self.cut_links.contains(&link_key(a, b)) && self.groups.get(&a).copied().unwrap_or(0) == self.groups.get(&b).copied().unwrap_or(0)
+    }
+
+    /// Stop a node immediately without shutdown, same as a process crash. All packets to it are lost
+    /// and its pending outputs are dropped until it is restarted.
+    pub fn crash(&mut self, node: NodeId) {
+        assert!(self.nodes_index.contains_key(&node), "Node not found");
+        log::info!("Crash node {node}");
+        self.crashed.insert(node);
+        self.in_flight.retain(|_, (dest, _)| *dest != node);
+    }
+
+    /// Replace a crashed node with a new instance, which normally has same NodeId and a new session
+    pub fn restart(&mut self, node: TestNode<SC, SE, TC, TW>) -> NodeAddr {
+        let node_id = node.node_id();
+        let index = *self.nodes_index.get(&node_id).expect("Node not found");
+        assert!(self.crashed.remove(&node_id), "Node {node_id} should be crashed before restart");
+        log::info!("Restart node {node_id}");
+        let addr = node.addr();
+        self.nodes[index] = node;
+        self.links_busy.retain(|(from, dest), _| *from != node_id && *dest != node_id);
+        addr
+    }
+
+    pub fn is_crashed(&self, node: NodeId) -> bool {
+        self.crashed.contains(&node)
+    }
+
+    pub fn add_node(&mut self, node: TestNode<SC, SE, TC, TW>) -> NodeAddr {
+        let index = self.nodes.len();
+        self.nodes_index.insert(node.node_id(), index);
+        let addr = node.addr();
+        self.nodes.push(node);
+        self.switcher.set_tasks(self.nodes.len());
+        addr
+    }
+
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.nodes.iter().map(|n| n.node_id()).collect()
+    }
+
+    /// Route action of a node to the rule, for checking routing state
+    pub fn route(&self, node: NodeId, rule: RouteRule) -> RouteAction<NetPair> {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.nodes[node_index].route(rule)
+    }
+
+    /// All running nodes have a route to all other running nodes
+    pub fn routes_converged(&self) -> bool {
+        let nodes = self.node_ids().into_iter().filter(|node| !self.crashed.contains(node)).collect::<Vec<_>>();
+        nodes.iter().all(|node| {
+            nodes
+                .iter()
+                .filter(|dest| *dest != node)
+                .all(|dest| matches!(self.route(*node, RouteRule::ToNode(*dest)), RouteAction::Next(_)))
+        })
+    }
+
+    /// Process with step until the condition is true, returns spent time or None after max_ms
+    pub fn run_until<F: FnMut(&mut Self) -> bool>(&mut self, step_ms: u64, max_ms: u64, mut cond: F) -> Option<u64> {
+        let started = self.clock_ms;
+        loop {
+            if cond(self) {
+                return Some(self.clock_ms - started);
+            }
+            if self.clock_ms - started >= max_ms {
+                return None;
+            }
+            self.process(step_ms);
+        }
+    }
+
+    /// Panic if all nodes don't have routes to each other after max_ms, returns spent time
+    pub fn assert_converged(&mut self, step_ms: u64, max_ms: u64) -> u64 {
+        match self.run_until(step_ms, max_ms, |sim| sim.routes_converged()) {
+            Some(spent) => spent,
+            None => panic!("Routes are not converged after {max_ms} ms"),
+        }
+    }
+
+    pub fn process(&mut self, delta: u64) {
+        let target_ms = self.clock_ms + delta;
+        self.deliver_in_flight(target_ms);
+        self.clock_ms = target_ms;
+        log::debug!("Tick {} ms", self.clock_ms);
+        self.spikes.retain(|_, (_, end_ms)| *end_ms > target_ms);
+        for i in 0..self.nodes.len() {
+            if self.crashed.contains(&self.nodes[i].node_id()) {
+                continue;
+            }
+            self.switcher.flag_task(i);
+            self.nodes[i].tick(self.clock_ms);
+        }
+
+        while let Some((node, input)) = self.input.pop_front() {
+            let node_index = *self.nodes_index.get(&node).expect("Node not found");
+            if self.crashed.contains(&node) {
+                log::warn!("Drop control to crashed node {node}");
+                continue;
+            }
+            self.nodes[node_index].on_input(self.clock_ms, TestNodeIn::Ext(input));
+        }
+
+        while let Some((node, input)) = self.input_worker.pop_front() {
+            let node_index = *self.nodes_index.get(&node).expect("Node not found");
+            if self.crashed.contains(&node) {
+                log::warn!("Drop worker control to crashed node {node}");
+                continue;
+            }
+            self.nodes[node_index].on_input(self.clock_ms, TestNodeIn::ExtWorker(input));
+        }
+
+        self.pop_outputs(self.clock_ms);
+    }
+
+    /// Deliver delayed packets which arrive before target_ms, each one at its arrival time
+    fn deliver_in_flight(&mut self, target_ms: u64) {
+        while let Some(entry) = self.in_flight.first_entry() {
+            let (at_ms, _) = *entry.key();
+            if at_ms > target_ms {
+                break;
+            }
+            let (dest, input) = entry.remove();
+            self.clock_ms = self.clock_ms.max(at_ms);
+            if let Some(dest_index) = self.nodes_index.get(&dest).copied() {
+                self.switcher.flag_task(dest_index);
+                self.nodes[dest_index].on_input(self.clock_ms, input);
+                self.pop_outputs(self.clock_ms);
+            }
+        }
+    }
+
+    fn pop_outputs(&mut self, now: u64) {
+        while let Some(index) = self.switcher.current() {
+            let node = self.nodes[index].node_id();
+            if self.crashed.contains(&node) {
+                self.switcher.finished(index);
+                continue;
+            }
+            if let Some(out) = self.nodes[index].pop_output(now) {
+                self.process_out(now, node, out);
+            } else {
+                self.switcher.finished(index);
+            }
+        }
+    }
+
+    fn chance(&mut self, percent: u8) -> bool {
+        percent > 0 && self.rng.gen_range(0..100) < percent
+    }
+
+    /// Send a packet over the link between two nodes, pair is already mirrored to the receiver side.
+    /// Only UDP packets are lost, duplicated or reordered, frames of streams are reliable and ordered.
+    fn send_over_link(&mut self, now: u64, from: NodeId, dest: NodeId, pair: NetPair, data: Buffer, reliable: bool) {
+        if !self.is_reachable(from, dest) {
+            log::debug!("Drop packet from {from} to {dest} over cut link or partition");
+            return;
+        }
+        if self.crashed.contains(&dest) {
+            log::debug!("Drop packet from {from} to crashed node {dest}");
+            return;
+        }
+        let link = self.link(from, dest);
+        if !reliable && self.chance(link.loss_percent) {
+            log::debug!("Drop packet from {from} to {dest} by link loss {}%", link.loss_percent);
+            return;
+        }
+        let spike_ms = self.spikes.get(&link_key(from, dest)).map_or(0, |(extra_ms, _)| *extra_ms);
+        let mut at_ms = now + link.latency_ms + spike_ms;
+        if let Some(kbps) = link.bandwidth_kbps {
+            let busy = self.links_busy.entry((from, dest)).or_insert(now);
+            let started = (*busy).max(now);
+            // bits / kbps is transmit time in ms
+            *busy = started + data.len() as u64 * 8 / kbps.max(1) as u64;
+            at_ms = *busy + link.latency_ms + spike_ms;
+        }
+
+        let mut copies = vec![at_ms];
+        if !reliable {
+            if self.chance(link.reorder_percent) {
+                copies[0] += self.rng.gen_range(1..=REORDER_MAX_DELAY_MS);
+            }
+            if self.chance(link.duplicate_percent) {
+                copies.push(at_ms);
+            }
+        }
+        for at_ms in copies {
+            let input = if reliable {
+                TestNodeIn::Frame(pair, data.clone())
+            } else {
+                TestNodeIn::Udp(pair, data.clone())
+            };
+            if at_ms <= now {
+                let dest_index = *self.nodes_index.get(&dest).expect("Node not found");
+                self.switcher.flag_task(dest_index);
+                self.nodes[dest_index].on_input(now, input);
+            } else {
+                self.in_flight_seq += 1;
+                self.in_flight.insert((at_ms, self.in_flight_seq), (dest, input));
+            }
+        }
+    }
+
+    fn process_out(&mut self, now: u64, node: NodeId, out: TestNodeOut<SE>) {
+        let node_index = *self.nodes_index.get(&node).expect("Node not found");
+        self.switcher.flag_task(node_index);
+        match out {
+            TestNodeOut::Ext(out) => {
+                self.output.push_back((node, out));
+            }
+            TestNodeOut::ExtWorker(out) => {
+                self.output_worker.push_back((node, out));
+            }
+            TestNodeOut::Udp(dests, data) => {
+                if data.len() > MAX_UDP_SIZE {
+                    log::warn!("Drop UDP packet from {node} with size {} bigger than {MAX_UDP_SIZE}", data.len());
+                    return;
+                }
+                for dest in dests {
+                    log::debug!("Send UDP packet from {} to {}, buf len {}", dest.local, dest.remote, data.len());
+                    let dest_node = addr_to_node(dest.remote);
+                    if !self.nodes_index.contains_key(&dest_node) {
+                        log::debug!("Drop UDP packet from {node} to not existed node {dest_node}");
+                        continue;
+                    }
+                    if self.udp_blocked.contains(&node) || self.udp_blocked.contains(&dest_node) {
+                        log::debug!("Drop UDP packet from {node} to blocked node {dest_node}");
+                        continue;
+                    }
+                    if !self.nodes[node_index].is_bound(&dest.local) || !self.nodes[self.nodes_index[&dest_node]].is_bound(&dest.remote) {
+                        log::debug!("Drop UDP packet from {} to {} over not bound addr", dest.local, dest.remote);
+                        continue;
+                    }
+                    let in_pair = NetPair::new(dest.remote, dest.local);
+                    self.send_over_link(now, node, dest_node, in_pair, data.clone(), false);
+                }
+            }
+            TestNodeOut::Frame(dest, data) => {
+                // frame transports are reliable and have no size limit, the pair is mirrored at remote side same as UDP
+                let dest_node = addr_to_node(dest.remote);
+                if !self.nodes_index.contains_key(&dest_node) {
+                    log::debug!("Drop {:?} frame from {node} to not existed node {dest_node}", dest.transport);
+                    return;
+                }
+                let in_pair = NetPair {
+                    local: dest.remote,
+                    remote: dest.local,
+                    transport: dest.transport,
+                };
+                self.send_over_link(now, node, dest_node, in_pair, data, true);
+            }
+            #[cfg(feature = "vpn")]
+            TestNodeOut::Tun(_) => {
+                log::debug!("Drop tun packet from {node}, tun devices are not simulated");
+            }
+            TestNodeOut::Continue => {}
+        }
+    }
+}
@@ -1,11 +1,12 @@
 use std::{fmt::Debug, hash::Hash};
 
 use atm0s_sdn_identity::NodeId;
+use atm0s_sdn_router::{RouteAction, RouteRule};
 use sans_io_runtime::{TaskSwitcher, TaskSwitcherBranch, TaskSwitcherChild};
 
 use crate::{
     controller_plane::{self, ControllerPlane, ControllerPlaneCfg},
-    data_plane::{self, CrossWorker, DataPlane, DataPlaneCfg, NetInput, NetOutput},
+    data_plane::{self, CrossWorker, DataPlane, DataPlaneCfg, NetInput, NetOutput, NetPair},
     ExtIn, ExtOut, LogicControl, LogicEvent, LogicEventDest,
 };
 
@@ -87,7 +88,19 @@ where
         tasks
     }
 
+    /// Route action of the worker router table, for checking routing state from outside
+    pub fn route(&self, rule: RouteRule) -> RouteAction<NetPair> {
+        self.data.route(rule, None, None)
+    }
+
     pub fn on_tick(&mut self, now_ms: u64) {
+        if let Some(controller) = &mut self.controller {
+            controller.input(&mut self.switcher).on_fast_tick(now_ms);
+        }
+        if self.data.has_queued_sends() {
+            // poll again for packets which are waiting for send budget
+            self.data.input(&mut self.switcher);
+        }
         if let Some(last_tick) = self.last_tick {
             if now_ms < last_tick + self.tick_ms {
                 return;
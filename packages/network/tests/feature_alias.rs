@@ -10,9 +10,7 @@ use atm0s_sdn_network::{
 };
 use atm0s_sdn_router::ServiceBroadcastLevel;
 
-use crate::simulator::{NetworkSimulator, TestNode};
-
-mod simulator;
+use atm0s_sdn_network::simulator::{NetworkSimulator, TestNode};
 
 struct MockService;
 
@@ -124,7 +122,7 @@ fn feature_alias_two_nodes() {
     let node1 = 1;
     let node2 = 2;
     let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
-    sim.enable_log(log::LevelFilter::Debug);
+    let _ = env_logger::builder().is_test(true).try_init();
 
     let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MockServiceBuilder)]));
     let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MockServiceBuilder)]));
@@ -6,9 +6,7 @@ use atm0s_sdn_network::{
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
-
-mod simulator;
+use atm0s_sdn_network::simulator::{NetworkSimulator, TestNode};
 
 fn control(control: Control) -> ExtIn<(), ()> {
     ExtIn::FeaturesControl((), FeaturesControl::DhtKv(control))
@@ -1,12 +1,34 @@
-use atm0s_sdn_identity::ConnId;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use atm0s_sdn_identity::{ConnId, NodeAddr, NodeAddrBuilder, NodeId, Protocol};
 use atm0s_sdn_network::{
+    base::{AccessControl, AccessRule, DisconnectReason, DnsResolver, NeighboursConnectError, NeighboursDisconnectReason},
+    controller_plane::{Keepalive, KeepaliveCfg, NeighboursLimits},
+    data_plane::{NetPair, Transport},
     features::{neighbours, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
+use parking_lot::Mutex;
+
+use atm0s_sdn_network::simulator::{build_addr, build_addr_with_tcp, build_addr_ws_only, node_to_addr, NetworkSimulator, TestNode, TestNodeCfg};
 
-mod simulator;
+/// Pop neighbours events of all nodes, periodic stats are skipped
+fn pop_events(sim: &mut NetworkSimulator<(), (), (), ()>) -> Vec<(NodeId, neighbours::Event)> {
+    let mut out = vec![];
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(event)) = event {
+            if !matches!(event, neighbours::Event::Stats(..)) {
+                out.push((node, event));
+            }
+        }
+    }
+    out
+}
 
 #[test]
 fn feature_neighbours_two_nodes() {
@@ -27,24 +49,763 @@ fn feature_neighbours_two_nodes() {
         sim.process(500);
     }
 
+    let mut out = pop_events(&mut sim);
+    out.sort_by_key(|a| a.0);
+
+    assert_eq!(
+        out,
+        vec![
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(0, 1000))),
+            (node2, neighbours::Event::Connected(node1, ConnId::from_in(0, 1000))),
+            (node2, neighbours::Event::Primary(node1, ConnId::from_in(0, 1000))),
+        ]
+    );
+}
+
+#[test]
+fn feature_neighbours_list_and_disconnect_reason() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    let mut stats_events = 0;
+    while let Some((_, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Stats(..))) = event {
+            stats_events += 1;
+        }
+    }
+    assert!(stats_events > 0, "Should fire periodic stats");
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::List)));
+    sim.process(10);
+    let mut list = None;
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::List(neighbours))) = event {
+            assert_eq!(node, node1);
+            list = Some(neighbours);
+        }
+    }
+    let list = list.expect("Should answer list");
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].node, node2);
+    assert_eq!(list[0].conn, ConnId::from_out(0, 1000));
+    assert_eq!(list[0].pair, NetPair::new(node_to_addr(node1), node_to_addr(node2)));
+    let stats = list[0].stats.clone().expect("Should have stats");
+    assert_eq!(stats.rtt_ms, 0);
+    assert_eq!(stats.loss_percent, 0);
+    assert!(stats.sent_bytes > 0 && stats.recv_bytes > 0, "Should count traffic, got {stats:?}");
+
+    sim.control(node1, ExtIn::DisconnectFrom(node2));
+    sim.process(500);
+
+    let mut out = pop_events(&mut sim);
+    out.sort_by_key(|a| a.0);
+    assert_eq!(
+        out,
+        vec![
+            (node1, neighbours::Event::Disconnected(node2, ConnId::from_out(0, 1000), DisconnectReason::Local)),
+            (
+                node2,
+                neighbours::Event::Disconnected(node1, ConnId::from_in(0, 1000), DisconnectReason::Remote(NeighboursDisconnectReason::Other))
+            ),
+        ]
+    );
+}
+
+#[test]
+fn feature_neighbours_fast_probe_critical_link() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let keepalive = KeepaliveCfg {
+        critical: Keepalive::FastProbe { interval_ms: 50, misses: 3 },
+        critical_nodes: vec![node2],
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new_with_cfg(node1, 1234, vec![], TestNodeCfg { keepalive, ..Default::default() }));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // 3 seconds with fast probes, all are answered so the link must stay up
+    for _i in 0..60 {
+        sim.process(50);
+    }
+
+    let mut stats_events = 0;
+    let mut out = vec![];
+    while let Some((_, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(event)) = event {
+            match event {
+                neighbours::Event::Stats(..) => stats_events += 1,
+                event => out.push(event),
+            }
+        }
+    }
+    assert_eq!(
+        out,
+        vec![
+            neighbours::Event::Connected(node2, ConnId::from_out(0, 1000)),
+            neighbours::Event::Primary(node2, ConnId::from_out(0, 1000)),
+        ]
+    );
+    // rtt stats are limited to once per second even with 20 probes per second, some more are fired by path mtu changes
+    assert!((1..=10).contains(&stats_events), "Should limit stats events, got {stats_events}");
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::List)));
+    sim.process(10);
+    let mut list = None;
+    while let Some((_, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::List(neighbours))) = event {
+            list = Some(neighbours);
+        }
+    }
+    let list = list.expect("Should answer list");
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].stats.as_ref().expect("Should have stats").loss_percent, 0);
+}
+
+#[test]
+fn feature_neighbours_tcp_fallback_when_udp_blocked() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.block_udp(node2);
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(build_addr_with_tcp(node2)));
+
+    // UDP is not answered, tcp is tried after fallback time
+    for _i in 0..8 {
+        sim.process(500);
+    }
+    assert_eq!(pop_events(&mut sim), vec![]);
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(
+        pop_events(&mut sim),
+        vec![
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(Transport::Tcp.protocol(), 1005))),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(Transport::Tcp.protocol(), 1005))),
+        ]
+    );
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::List)));
+    sim.process(10);
+    let mut list = None;
+    while let Some((_, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::List(neighbours))) = event {
+            list = Some(neighbours);
+        }
+    }
+    let list = list.expect("Should answer list");
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].pair, NetPair::new_tcp(node_to_addr(node1), node_to_addr(node2)));
+    assert_eq!(list[0].pair.transport, Transport::Tcp);
+}
+
+#[test]
+fn feature_neighbours_websocket_leaf_client() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    sim.block_udp(node2);
+
+    // leaf client only knows ws addr of node2, so it connects immediately without waiting for UDP
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(build_addr_ws_only(node2)));
+    sim.process(500);
+    assert_eq!(
+        pop_events(&mut sim),
+        vec![
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(Transport::WebSocket.protocol(), 1000))),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(Transport::WebSocket.protocol(), 1000))),
+        ]
+    );
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::List)));
+    sim.process(10);
+    let mut list = None;
+    while let Some((_, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::List(neighbours))) = event {
+            list = Some(neighbours);
+        }
+    }
+    let list = list.expect("Should answer list");
+    assert_eq!(list.len(), 1);
+    assert_eq!(list[0].pair, NetPair::new_ws(node_to_addr(node1), node_to_addr(node2)));
+}
+
+#[test]
+fn feature_neighbours_punch_through_relay() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    while sim.pop_res().is_some() {}
+
+    // node1 don't know any address of node3, it must be introduced by node2
+    sim.control(node1, ExtIn::ConnectTo(NodeAddr::empty(node3)));
+
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
     let mut out = vec![];
     while let Some((node, out_event)) = sim.pop_res() {
-        out.push((node, out_event));
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Connected(remote, _))) = out_event {
+            out.push((node, remote));
+        }
     }
+    out.sort();
+
+    assert_eq!(out, vec![(node1, node3), (node3, node1)]);
+}
 
-    out.sort_by(|a, b| a.0.cmp(&b.0));
+type Resolved = (String, Vec<IpAddr>);
+
+/// Resolver which answers from a shared table, missing names are failed
+#[derive(Default, Clone)]
+struct TableResolver {
+    table: Arc<Mutex<HashMap<String, Vec<IpAddr>>>>,
+    results: Arc<Mutex<VecDeque<Resolved>>>,
+}
+
+impl DnsResolver for TableResolver {
+    fn resolve(&self, name: &str) {
+        let ips = self.table.lock().get(name).cloned().unwrap_or_default();
+        self.results.lock().push_back((name.to_string(), ips));
+    }
+
+    fn poll(&self) -> Option<Resolved> {
+        self.results.lock().pop_front()
+    }
+}
+
+#[test]
+fn feature_neighbours_dns_seed() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    let resolver = TableResolver::default();
+
+    let _addr1 = sim.add_node(TestNode::new_with_cfg(
+        node1,
+        1234,
+        vec![],
+        TestNodeCfg {
+            resolver: Some(Arc::new(resolver.clone())),
+            ..Default::default()
+        },
+    ));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    let mut seed = NodeAddrBuilder::new(node2);
+    seed.add_protocol(Protocol::Dns4("seed.local".into()));
+    seed.add_protocol(Protocol::Udp(node2 as u16));
+    sim.control(node1, ExtIn::ConnectTo(seed.addr()));
+
+    // seed name is not resolvable yet
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.pop_res(), None);
+
+    // seed is resolved in the next periodic refresh
+    resolver
+        .table
+        .lock()
+        .insert("seed.local".to_string(), vec![IpAddr::V6(Ipv6Addr::LOCALHOST), IpAddr::V4(Ipv4Addr::LOCALHOST)]);
+    for _i in 0..120 {
+        sim.process(500);
+    }
 
+    assert_eq!(
+        pop_events(&mut sim),
+        vec![
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(0, 1000))),
+        ]
+    );
+}
+
+#[test]
+fn feature_neighbours_persistent_reconnect() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectToPersistent(build_addr(node2)));
+
+    // node2 is offline, some attempts are failed
+    for _i in 0..200 {
+        sim.process(500);
+    }
+    assert_eq!(pop_events(&mut sim), vec![(node1, neighbours::Event::ConnectError(node2, NeighboursConnectError::Timeout)); 3]);
+
+    // node2 is online then it is connected by the next attempt
+    sim.add_node(TestNode::new(node2, 1235, vec![]));
+    for _i in 0..200 {
+        sim.process(500);
+    }
+
+    let res = pop_events(&mut sim);
+    assert_eq!(res.len(), 2, "Should connected and select primary, got {res:?}");
+    assert!(matches!(res[0], (1, neighbours::Event::Connected(2, _))), "Should connected, got {res:?}");
+    assert!(matches!(res[1], (1, neighbours::Event::Primary(2, _))), "Should select primary, got {res:?}");
+}
+
+#[test]
+fn feature_neighbours_persistent_gave_up() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectToPersistent(build_addr(node2)));
+
+    // default policy is 20 retries, each attempt is timeout after 30 seconds and delay is capped at 60 seconds
+    for _i in 0..4000 {
+        sim.process(500);
+    }
+
+    let mut expected = vec![(node1, neighbours::Event::ConnectError(node2, NeighboursConnectError::Timeout)); 21];
+    expected.push((node1, neighbours::Event::GaveUp(node2)));
+    assert_eq!(pop_events(&mut sim), expected);
+}
+
+#[test]
+fn feature_neighbours_seed_never_gave_up() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectToSeed(build_addr(node2)));
+
+    // same duration as persistent_gave_up, seed is still retried after the 20 attempts limit
+    for _i in 0..4000 {
+        sim.process(500);
+    }
+
+    let events = pop_events(&mut sim);
+    assert!(events.len() > 21, "Should retry more than max attempts, got {} events", events.len());
+    assert!(events.iter().all(|e| *e == (node1, neighbours::Event::ConnectError(node2, NeighboursConnectError::Timeout))));
+}
+
+#[test]
+fn feature_neighbours_dedup_conns() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // each node has two bind addrs, so 4 connections are created between them
+    let cfg = |node: NodeId| TestNodeCfg {
+        bind_addrs: vec![
+            SocketAddr::new(Ipv4Addr::new(127, 0, 0, 1).into(), node as u16),
+            SocketAddr::new(Ipv4Addr::new(127, 0, 0, 2).into(), node as u16),
+        ],
+        max_conns_per_node: Some(1),
+        ..Default::default()
+    };
+    sim.add_node(TestNode::new_with_cfg(node1, 1234, vec![], cfg(node1)));
+    sim.add_node(TestNode::new_with_cfg(node2, 1235, vec![], cfg(node2)));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    let mut addr2 = NodeAddrBuilder::new(node2);
+    addr2.add_protocol(Protocol::Ip4(Ipv4Addr::new(127, 0, 0, 1)));
+    addr2.add_protocol(Protocol::Udp(node2 as u16));
+    addr2.add_protocol(Protocol::Ip4(Ipv4Addr::new(127, 0, 0, 2)));
+    addr2.add_protocol(Protocol::Udp(node2 as u16));
+    sim.control(node1, ExtIn::ConnectTo(addr2.addr()));
+
+    for _i in 0..10 {
+        sim.process(500);
+    }
+
+    // only node1 prunes because it has lower node id, both sides see same live connections
+    let mut live: HashMap<NodeId, Vec<ConnId>> = HashMap::new();
+    let mut primary: HashMap<NodeId, ConnId> = HashMap::new();
+    let mut connected = 0;
+    while let Some((node, out)) = sim.pop_res() {
+        match out {
+            ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Connected(_, conn))) => {
+                connected += 1;
+                live.entry(node).or_default().push(conn);
+            }
+            ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Disconnected(_, conn, _))) => live.entry(node).or_default().retain(|c| *c != conn),
+            ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Primary(_, conn))) => {
+                primary.insert(node, conn);
+            }
+            _ => {}
+        }
+    }
+
+    assert_eq!(connected, 8);
+    assert_eq!(live[&node1].len(), 1);
+    assert_eq!(live[&node2].len(), 1);
+    assert_eq!(live[&node1].first(), primary.get(&node1));
+    assert_eq!(live[&node2].first(), primary.get(&node2));
+    assert_eq!(live[&node1][0].session(), live[&node2][0].session());
+
+    // connecting again must not reopen pruned pairs while the node is still connected
+    sim.control(node1, ExtIn::ConnectTo(addr2.addr()));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    let mut reconnected = 0;
+    while let Some((_node, out)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Connected(..))) = out {
+            reconnected += 1;
+        }
+    }
+    assert_eq!(reconnected, 0);
+}
+
+#[test]
+fn feature_neighbours_access_list() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2.clone()));
+
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    while sim.pop_res().is_some() {}
+
+    // existing connection is closed after denied
+    sim.control(
+        node1,
+        ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Access(AccessControl::Deny(AccessRule::Node(node2))))),
+    );
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let mut out = vec![];
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Disconnected(remote, _, _))) = event {
+            out.push((node, remote));
+        }
+    }
+    out.sort();
+    assert_eq!(out, vec![(node1, node2), (node2, node1)]);
+
+    // incoming is rejected with reason
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let mut out = vec![];
+    while let Some(res) = sim.pop_res() {
+        out.push(res);
+    }
+    out.sort_by_key(|(node, _)| *node);
     assert_eq!(
         out,
         vec![
             (
                 node1,
-                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))))
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::ConnectError(node2, NeighboursConnectError::Denied)))
             ),
             (
                 node2,
-                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node1, ConnId::from_in(0, 1000))))
+                ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::ConnectError(node1, NeighboursConnectError::Denied)))
+            ),
+        ]
+    );
+
+    // outgoing is skipped
+    sim.control(node1, ExtIn::ConnectTo(addr2.clone()));
+    sim.process(500);
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::ConnectError(node2, NeighboursConnectError::Denied)))
+        ))
+    );
+    assert_eq!(sim.pop_res(), None);
+
+    // connect again after the rule is removed
+    sim.control(
+        node1,
+        ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Access(AccessControl::Remove(AccessRule::Node(node2))))),
+    );
+    sim.process(500);
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let mut out = vec![];
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Connected(remote, _))) = event {
+            out.push((node, remote));
+        }
+    }
+    out.sort();
+    assert_eq!(out, vec![(node1, node2), (node2, node1)]);
+}
+
+#[test]
+fn feature_neighbours_ip_allow_list_outgoing() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    let allow = |range: &str, prefix| {
+        let rule = AccessRule::IpRange(range.parse().expect("Should parse"), prefix);
+        ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Access(AccessControl::Allow(rule))))
+    };
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+
+    // remote ip is out of the range
+    sim.control(node1, allow("10.0.0.0", 8));
+    sim.process(100);
+    sim.control(node1, ExtIn::ConnectTo(addr2.clone()));
+    sim.process(500);
+    assert_eq!(pop_events(&mut sim), vec![(node1, neighbours::Event::ConnectError(node2, NeighboursConnectError::NotAllowed))]);
+
+    // ip only allow list must not block outgoing connects to allowed ips
+    sim.control(node1, allow("127.0.0.0", 8));
+    sim.process(100);
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert!(pop_events(&mut sim).contains(&(node1, neighbours::Event::Connected(node2, ConnId::from_out(0, 1000)))));
+}
+
+#[test]
+fn feature_neighbours_cookie_retry() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // node2 is always under load, requester must echo the cookie before handshake
+    let limits = NeighboursLimits {
+        cookie_threshold: 0,
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new_with_cfg(node2, 1235, vec![], TestNodeCfg { limits, ..Default::default() }));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    sim.process(500);
+
+    let mut out = vec![];
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Connected(remote, _))) = event {
+            out.push((node, remote));
+        }
+    }
+    out.sort();
+    assert_eq!(out, vec![(node1, node2), (node2, node1)]);
+}
+
+#[test]
+fn feature_neighbours_handshake_limits() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let node4 = 4;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // node3 only accepts one connection, node4 don't accept any handshake from unknown addrs
+    let limits3 = NeighboursLimits {
+        max_connections: 1,
+        ..Default::default()
+    };
+    let limits4 = NeighboursLimits {
+        handshakes_burst: 0,
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new_with_cfg(
+        node3,
+        1236,
+        vec![],
+        TestNodeCfg {
+            limits: limits3,
+            ..Default::default()
+        },
+    ));
+    let addr4 = sim.add_node(TestNode::new_with_cfg(
+        node4,
+        1237,
+        vec![],
+        TestNodeCfg {
+            limits: limits4,
+            ..Default::default()
+        },
+    ));
+
+    for node in [node1, node2, node3, node4] {
+        sim.control(node, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    }
+    sim.control(node1, ExtIn::ConnectTo(addr3.clone()));
+    sim.process(500);
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+    sim.control(node1, ExtIn::ConnectTo(addr4));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+
+    let mut out = vec![];
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Connected(remote, _))) = event {
+            out.push((node, remote));
+        }
+    }
+    out.sort();
+    assert_eq!(out, vec![(node1, node3), (node3, node1)]);
+}
+
+#[test]
+fn feature_neighbours_add_remove_bind_migrate() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(pop_events(&mut sim).len(), 4);
+
+    // node1 got a new ip, neighbours are connected over it too
+    let new_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), node1 as u16);
+    sim.control(node1, ExtIn::AddBind(new_addr));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let mut out = pop_events(&mut sim);
+    out.sort_by_key(|a| a.0);
+    assert_eq!(
+        out,
+        vec![
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(0, 1005))),
+            (node2, neighbours::Event::Connected(node1, ConnId::from_in(0, 1005))),
+        ]
+    );
+
+    // old ip is gone, connection over it is closed without waiting for the remote
+    sim.control(node1, ExtIn::RemoveBind(node_to_addr(node1)));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    let mut out = pop_events(&mut sim);
+    out.sort_by_key(|a| a.0);
+    assert_eq!(
+        out,
+        vec![
+            (node1, neighbours::Event::Disconnected(node2, ConnId::from_out(0, 1000), DisconnectReason::Local)),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(0, 1005))),
+            (
+                node2,
+                neighbours::Event::Disconnected(node1, ConnId::from_in(0, 1000), DisconnectReason::Remote(NeighboursDisconnectReason::Other))
             ),
+            (node2, neighbours::Event::Primary(node1, ConnId::from_in(0, 1005))),
+        ]
+    );
+}
+
+#[test]
+fn feature_neighbours_remove_bind_migrate_to_remaining_addr() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let second_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)), node1 as u16);
+    let cfg = TestNodeCfg {
+        bind_addrs: vec![node_to_addr(node1), second_addr],
+        max_conns_per_node: Some(1),
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new_with_cfg(node1, 1234, vec![], cfg));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    // the connection over second addr is pruned, so only the first addr is used
+    let out = pop_events(&mut sim);
+    assert!(out.contains(&(node1, neighbours::Event::Disconnected(node2, ConnId::from_out(0, 1005), DisconnectReason::Local))));
+
+    sim.control(node1, ExtIn::RemoveBind(node_to_addr(node1)));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(
+        pop_events(&mut sim),
+        vec![
+            (node1, neighbours::Event::Disconnected(node2, ConnId::from_out(0, 1000), DisconnectReason::Local)),
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(0, 1010))),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(0, 1010))),
         ]
     );
 }
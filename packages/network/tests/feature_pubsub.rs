@@ -6,9 +6,7 @@ use atm0s_sdn_network::{
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
-
-mod simulator;
+use atm0s_sdn_network::simulator::{NetworkSimulator, TestNode};
 
 fn control(control: Control) -> ExtIn<(), ()> {
     ExtIn::FeaturesControl((), FeaturesControl::PubSub(control))
@@ -72,7 +70,7 @@ fn feature_pubsub_auto_single_node() {
 fn feature_pubsub_auto_single_node_worker() {
     let node_id = 1;
     let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
-    sim.enable_log(log::LevelFilter::Debug);
+    let _ = env_logger::builder().is_test(true).try_init();
     sim.add_node(TestNode::new(node_id, 1234, vec![]));
 
     sim.process(100);
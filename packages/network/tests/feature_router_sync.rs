@@ -5,14 +5,13 @@ use atm0s_sdn_network::{
         NetIncomingMeta, NetOutgoingMeta, Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput,
         ServiceWorkerOutput,
     },
+    controller_plane::DrainCfg,
     features::{data, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut,
 };
 use atm0s_sdn_router::RouteRule;
 
-use crate::simulator::{NetworkSimulator, TestNode};
-
-mod simulator;
+use atm0s_sdn_network::simulator::{NetworkSimulator, TestNode, TestNodeCfg};
 
 struct MockService;
 
@@ -113,7 +112,7 @@ fn feature_router_sync_two_nodes() {
     let node1 = 1;
     let node2 = 2;
     let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
-    sim.enable_log(log::LevelFilter::Debug);
+    let _ = env_logger::builder().is_test(true).try_init();
 
     let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
     let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
@@ -152,3 +151,68 @@ fn feature_router_sync_three_nodes() {
     sim.process(10);
     assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, Some(0)))))));
 }
+
+#[test]
+fn feature_router_sync_drain_before_shutdown() {
+    // node1 <-> node2 <-> node3, node2 is draining
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let drain = TestNodeCfg {
+        drain: Some(DrainCfg::default()),
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new_with_cfg(node2, 1235, vec![], drain));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.shutdown(node2);
+    sim.process(500);
+
+    // node2 is still connected but not used for relaying anymore
+    assert_eq!(sim.tasks(node2), 2);
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node2))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node2, Some(0)))))));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::Ping(node3))));
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+
+    // no transit traffic, node2 disconnects after neighbours reconverged
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(sim.pop_res(), Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Pong(node3, None))))));
+    assert_eq!(sim.tasks(node2), 0);
+}
+
+#[test]
+fn feature_router_sync_shutdown_without_drain() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    // drain is opt-in, node2 disconnects right away
+    sim.shutdown(node2);
+    sim.process(500);
+    assert_eq!(sim.tasks(node2), 0);
+}
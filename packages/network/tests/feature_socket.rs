@@ -1,11 +1,10 @@
 use atm0s_sdn_network::{
+    data_plane::RateLimit,
     features::{socket, FeaturesControl, FeaturesEvent},
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{NetworkSimulator, TestNode};
-
-mod simulator;
+use atm0s_sdn_network::simulator::{LinkCfg, NetworkSimulator, TestNode, TestNodeCfg};
 
 #[test]
 fn feature_socket_single_node() {
@@ -68,6 +67,37 @@ fn feature_socket_two_nodes() {
     );
 }
 
+#[test]
+fn feature_socket_two_nodes_big_payload() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.process(10);
+    let payload = (0..10000).map(|i| i as u8).collect::<Vec<_>>();
+    sim.control(
+        node2,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, payload.clone().into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((node1, ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10000, node2, 10001, payload.into(), 0)))))
+    );
+}
+
 #[test]
 fn feature_socket_three_nodes() {
     // node1 <-> node2 <-> node3
@@ -104,3 +134,169 @@ fn feature_socket_three_nodes() {
         ))
     );
 }
+
+#[test]
+fn feature_socket_queue_over_send_budget() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    // 100KB/s with the min burst 64KB
+    let cfg = || TestNodeCfg {
+        send_budget: Some(RateLimit::new(100_000, 0)),
+        ..Default::default()
+    };
+    let _addr1 = sim.add_node(TestNode::new_with_cfg(node1, 1234, vec![], cfg()));
+    let addr2 = sim.add_node(TestNode::new_with_cfg(node2, 1235, vec![], cfg()));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node2, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.process(1000);
+    while sim.pop_res().is_some() {}
+
+    for i in 0..200_u8 {
+        sim.control(
+            node2,
+            ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, vec![i; 1000].into(), 0))),
+        );
+    }
+
+    let mut received = vec![];
+    let mut pop_received = |sim: &mut NetworkSimulator<(), (), (), ()>| {
+        while let Some((node, event)) = sim.pop_res() {
+            if let ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10000, from, 10001, data, 0))) = event {
+                assert_eq!((node, from), (node1, node2));
+                received.push(data[0]);
+            }
+        }
+        received.len()
+    };
+
+    // only the burst is sent at first, the rest is queued instead of dropped
+    sim.process(1);
+    let first = pop_received(&mut sim);
+    assert!(first > 0 && first < 70, "Should send only the burst, got {first}");
+
+    let spent = sim.run_until(10, 5_000, |sim| pop_received(sim) == 200);
+    assert!(spent.is_some(), "Should deliver all queued packets");
+    assert!(spent.expect("Should have") >= 1000, "Should be paced by the budget");
+    assert_eq!(received, (0..200).collect::<Vec<_>>());
+}
+
+#[test]
+fn feature_socket_congestion_echo_over_relay() {
+    // node1 <-> node2 <-> node3, node2 relays with a small send budget
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new_with_cfg(
+        node2,
+        1235,
+        vec![],
+        TestNodeCfg {
+            send_budget: Some(RateLimit::new(100_000, 0)),
+            ..Default::default()
+        },
+    ));
+    let addr3 = sim.add_node(TestNode::new(node3, 1233, vec![]));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync
+    for _i in 0..4 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.process(1000);
+    while sim.pop_res().is_some() {}
+
+    // within the burst, the relay queue is not backing up
+    sim.control(
+        node3,
+        ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, vec![1; 1000].into(), 0))),
+    );
+    sim.process(10);
+    assert_eq!(
+        sim.pop_res(),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::RecvFrom(10000, node3, 10001, vec![1; 1000].into(), 0)))
+        ))
+    );
+    sim.process(10);
+    assert_eq!(sim.pop_res(), None);
+
+    // over the burst, relay marks packets as congested and node1 echoes it back to node3
+    for _i in 0..200 {
+        sim.control(
+            node3,
+            ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10001, node1, 10000, vec![2; 1000].into(), 0))),
+        );
+    }
+    let congestion = ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::Congestion(10001, node1, 10000)));
+    let spent = sim.run_until(10, 3_000, |sim| {
+        let mut found = false;
+        while let Some((node, event)) = sim.pop_res() {
+            found |= node == node3 && event == congestion;
+        }
+        found
+    });
+    assert!(spent.is_some(), "Source should receive congestion echo");
+}
+
+#[test]
+fn feature_socket_congestion_echo_over_slow_link() {
+    // node1 <-> node2 <-> node3, default config, only the link from node2 to node3 is slow
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1233, vec![]));
+    sim.set_link(node2, node3, LinkCfg::new(5, 0, Some(1000)));
+
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For sync and measuring base rtt
+    for _i in 0..6 {
+        sim.process(500);
+    }
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10000))));
+    sim.control(node3, ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::Bind(10001))));
+    sim.process(1000);
+    while sim.pop_res().is_some() {}
+
+    // sending 1.6Mbps over 1Mbps link builds the queue of the link, then relay marks packets as congested
+    let congestion = ExtOut::FeaturesEvent((), FeaturesEvent::Socket(socket::Event::Congestion(10000, node3, 10001)));
+    let spent = sim.run_until(100, 10_000, |sim| {
+        let mut found = false;
+        while let Some((node, event)) = sim.pop_res() {
+            found |= node == node1 && event == congestion;
+        }
+        for _i in 0..20 {
+            sim.control(
+                node1,
+                ExtIn::FeaturesControl((), FeaturesControl::Socket(socket::Control::SendTo(10000, node3, 10001, vec![2; 1000].into(), 0))),
+            );
+        }
+        found
+    });
+    assert!(spent.is_some(), "Source should receive congestion echo");
+}
@@ -1,15 +1,23 @@
 use std::sync::Arc;
 
-use atm0s_sdn_identity::ConnId;
+use atm0s_sdn_identity::{ConnId, NodeId};
 use atm0s_sdn_network::{
     features::{neighbours, FeaturesControl, FeaturesEvent},
     services::manual_discovery::ManualDiscoveryServiceBuilder,
     ExtIn, ExtOut,
 };
 
-use crate::simulator::{build_addr, NetworkSimulator, TestNode};
+use atm0s_sdn_network::simulator::{build_addr, NetworkSimulator, TestNode};
 
-mod simulator;
+/// Pop next output, periodic neighbour stats are skipped
+fn pop_res_skip_stats(sim: &mut NetworkSimulator<(), (), (), ()>) -> Option<(NodeId, ExtOut<(), ()>)> {
+    loop {
+        let res = sim.pop_res()?;
+        if !matches!(res.1, ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Stats(..)))) {
+            return Some(res);
+        }
+    }
+}
 
 #[test]
 fn service_manual_discovery_three_nodes() {
@@ -41,17 +49,31 @@ fn service_manual_discovery_three_nodes() {
     }
 
     assert_eq!(
-        sim.pop_res(),
+        pop_res_skip_stats(&mut sim),
         Some((
             node1,
             ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))))
         ))
     );
     assert_eq!(
-        sim.pop_res(),
+        pop_res_skip_stats(&mut sim),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Primary(node2, ConnId::from_out(0, 1000))))
+        ))
+    );
+    assert_eq!(
+        pop_res_skip_stats(&mut sim),
         Some((
             node1,
             ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Connected(node3, ConnId::from_out(0, 1005))))
         ))
     );
+    assert_eq!(
+        pop_res_skip_stats(&mut sim),
+        Some((
+            node1,
+            ExtOut::FeaturesEvent((), FeaturesEvent::Neighbours(neighbours::Event::Primary(node3, ConnId::from_out(0, 1005))))
+        ))
+    );
 }
@@ -0,0 +1,145 @@
+use std::sync::Arc;
+
+use atm0s_sdn_network::{
+    features::{data, FeaturesControl, FeaturesEvent},
+    services::membership::{self, Control, Event, MembershipServiceBuilder, DEFAULT_DATA_PORT, PROBE_INTERVAL_MS, SUSPECT_TIMEOUT_MS},
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::RouteRule;
+
+use atm0s_sdn_network::simulator::{NetworkSimulator, TestNode};
+
+fn membership_events(sim: &mut NetworkSimulator<Control<u8>, Event<u8>, (), ()>) -> Vec<(u32, Event<u8>)> {
+    let mut events = vec![];
+    while let Some((node, out)) = sim.pop_res() {
+        if let ExtOut::ServicesEvent(service, (), event) = out {
+            assert_eq!(*service, membership::SERVICE_ID);
+            events.push((node, event));
+        }
+    }
+    events
+}
+
+#[test]
+fn service_membership_join_and_leave() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<Control<u8>, Event<u8>, (), ()>::new(0);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MembershipServiceBuilder::new(node1, 1))]));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MembershipServiceBuilder::new(node2, 2))]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![Arc::new(MembershipServiceBuilder::new(node3, 3))]));
+
+    sim.control(node1, ExtIn::ServicesControl(membership::SERVICE_ID.into(), (), Control::Subscribe));
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    sim.control(node2, ExtIn::ConnectTo(addr3));
+
+    // For connect, sync and gossip
+    for _i in 0..10 {
+        sim.process(500);
+    }
+
+    let mut events = membership_events(&mut sim);
+    events.sort_by_key(|(_, e)| format!("{e:?}"));
+    assert_eq!(events, vec![(node1, Event::GotAll(vec![])), (node1, Event::Joined(node2, 2)), (node1, Event::Joined(node3, 3))]);
+
+    sim.control(node3, ExtIn::ServicesControl(membership::SERVICE_ID.into(), (), Control::Leave));
+    for _i in 0..4 {
+        sim.process(500);
+    }
+    assert_eq!(membership_events(&mut sim), vec![(node1, Event::Left(node3))]);
+}
+
+fn build_mesh(sim: &mut NetworkSimulator<Control<u8>, Event<u8>, (), ()>, nodes: &[u32]) {
+    let mut addrs = vec![];
+    for node in nodes {
+        addrs.push(sim.add_node(TestNode::new(*node, *node as u64, vec![Arc::new(MembershipServiceBuilder::new(*node, *node as u8))])));
+        sim.control(*node, ExtIn::ServicesControl(membership::SERVICE_ID.into(), (), Control::Subscribe));
+    }
+    for (i, node) in nodes.iter().enumerate() {
+        for addr in addrs.iter().skip(i + 1) {
+            sim.control(*node, ExtIn::ConnectTo(addr.clone()));
+        }
+    }
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    membership_events(sim);
+}
+
+#[test]
+fn service_membership_keep_member_over_broken_direct_link() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<Control<u8>, Event<u8>, (), ()>::new(0);
+    build_mesh(&mut sim, &[node1, node2, node3]);
+
+    // direct probes between node1 and node2 are lost but the connection is kept until keepalive timeout,
+    // so node2 is only kept alive by PingReq over node3 or by refuting the suspicion which is gossiped over node3
+    sim.cut_link(node1, node2);
+    for _i in 0..(SUSPECT_TIMEOUT_MS + 3 * PROBE_INTERVAL_MS) / 100 {
+        sim.process(100);
+    }
+    assert_eq!(membership_events(&mut sim), vec![]);
+}
+
+#[test]
+fn service_membership_suspect_then_dead() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<Control<u8>, Event<u8>, (), ()>::new(0);
+    build_mesh(&mut sim, &[node1, node2, node3]);
+
+    sim.crash(node2);
+    let mut events = vec![];
+    let spent = sim.run_until(100, SUSPECT_TIMEOUT_MS + 5 * PROBE_INTERVAL_MS, |sim| {
+        events.extend(membership_events(sim));
+        events.len() == 2
+    });
+    let spent = spent.expect("Should mark crashed member as dead");
+    assert!(spent >= SUSPECT_TIMEOUT_MS, "Should keep member as suspect before dead, spent {spent}");
+    events.sort_by_key(|(node, _)| *node);
+    assert_eq!(events, vec![(node1, Event::Left(node2)), (node3, Event::Left(node2))]);
+}
+
+#[test]
+fn service_membership_custom_data_port() {
+    let node1 = 1;
+    let node2 = 2;
+    let port = 100;
+    let mut sim = NetworkSimulator::<Control<u8>, Event<u8>, (), ()>::new(0);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MembershipServiceBuilder::new_with_port(node1, 1, port))]));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MembershipServiceBuilder::new_with_port(node2, 2, port))]));
+
+    sim.control(node1, ExtIn::ServicesControl(membership::SERVICE_ID.into(), (), Control::Subscribe));
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Data(data::Control::DataListen(DEFAULT_DATA_PORT))));
+    sim.control(node2, ExtIn::ConnectTo(addr1));
+    for _i in 0..10 {
+        sim.process(500);
+    }
+    sim.control(
+        node2,
+        ExtIn::FeaturesControl(
+            (),
+            FeaturesControl::Data(data::Control::DataSendRule(DEFAULT_DATA_PORT, RouteRule::ToNode(node1), Default::default(), vec![1, 2, 3])),
+        ),
+    );
+    sim.process(100);
+
+    let mut events = vec![];
+    let mut app_data = vec![];
+    while let Some((node, out)) = sim.pop_res() {
+        match out {
+            ExtOut::ServicesEvent(_, (), event) => events.push((node, event)),
+            ExtOut::FeaturesEvent((), FeaturesEvent::Data(data::Event::Recv(port, _, buf))) => app_data.push((node, port, buf)),
+            _ => {}
+        }
+    }
+    events.sort_by_key(|(_, e)| format!("{e:?}"));
+    assert_eq!(events, vec![(node1, Event::GotAll(vec![])), (node1, Event::Joined(node2, 2))]);
+    assert_eq!(app_data, vec![(node1, DEFAULT_DATA_PORT, vec![1, 2, 3])]);
+}
@@ -7,9 +7,7 @@ use atm0s_sdn_network::{
 };
 use serde::{Deserialize, Serialize};
 
-use crate::simulator::{node_to_addr, NetworkSimulator, TestNode};
-
-mod simulator;
+use atm0s_sdn_network::simulator::{node_to_addr, NetworkSimulator, TestNode};
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone)]
 struct NodeInfo(u8);
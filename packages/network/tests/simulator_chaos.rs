@@ -0,0 +1,326 @@
+use std::sync::Arc;
+
+use atm0s_sdn_identity::{ConnId, NodeId};
+use atm0s_sdn_network::{
+    base::{Service, ServiceBuilder, ServiceCtx, ServiceInput, ServiceOutput, ServiceSharedInput, ServiceWorker, ServiceWorkerCtx, ServiceWorkerInput, ServiceWorkerOutput},
+    data_plane::NetPair,
+    features::{
+        alias::{self, FoundLocation},
+        dht_kv::{self, Key, Map, MapControl, MapEvent},
+        neighbours,
+        pubsub::{self, ChannelControl, ChannelEvent, ChannelId},
+        FeaturesControl, FeaturesEvent,
+    },
+    simulator::{node_to_addr, LinkCfg, NetworkSimulator, TestNode},
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::{RouteAction, RouteRule, ServiceBroadcastLevel};
+
+struct MockService;
+
+impl Service<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for MockService {
+    fn service_id(&self) -> u8 {
+        0
+    }
+
+    fn service_name(&self) -> &str {
+        "mock"
+    }
+
+    fn on_input(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceInput<(), FeaturesEvent, (), ()>) {}
+
+    fn on_shared_input<'a>(&mut self, _ctx: &ServiceCtx, _now: u64, _input: ServiceSharedInput) {}
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceOutput<(), FeaturesControl, (), ()>> {
+        None
+    }
+}
+
+struct MockServiceWorker;
+
+impl ServiceWorker<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for MockServiceWorker {
+    fn service_id(&self) -> u8 {
+        0
+    }
+
+    fn service_name(&self) -> &str {
+        "mock"
+    }
+
+    fn on_tick(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _tick_count: u64) {}
+
+    fn on_input(&mut self, _ctx: &ServiceWorkerCtx, _now: u64, _input: ServiceWorkerInput<(), FeaturesEvent, (), ()>) {}
+
+    fn pop_output2(&mut self, _now: u64) -> Option<ServiceWorkerOutput<(), FeaturesControl, FeaturesEvent, (), (), ()>> {
+        None
+    }
+}
+
+struct MockServiceBuilder;
+
+impl ServiceBuilder<(), FeaturesControl, FeaturesEvent, (), (), (), ()> for MockServiceBuilder {
+    fn service_id(&self) -> u8 {
+        0
+    }
+
+    fn service_name(&self) -> &str {
+        "mock"
+    }
+
+    fn create(&self) -> Box<dyn Service<(), FeaturesControl, FeaturesEvent, (), (), (), ()>> {
+        Box::new(MockService)
+    }
+
+    fn create_worker(&self) -> Box<dyn ServiceWorker<(), FeaturesControl, FeaturesEvent, (), (), (), ()>> {
+        Box::new(MockServiceWorker)
+    }
+}
+
+fn neighbours_events(sim: &mut NetworkSimulator<(), (), (), ()>) -> Vec<(u32, neighbours::Event)> {
+    let mut out = vec![];
+    while let Some((node, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(event)) = event {
+            if !matches!(event, neighbours::Event::Stats(..)) {
+                out.push((node, event));
+            }
+        }
+    }
+    out
+}
+
+fn alias_result(sim: &mut NetworkSimulator<(), (), (), ()>, node: NodeId, alias: u64) -> Option<Option<FoundLocation>> {
+    let mut out = None;
+    while let Some((from, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Alias(alias::Event::QueryResult(found_alias, location))) = event {
+            if from == node && found_alias == alias {
+                out = Some(location);
+            }
+        }
+    }
+    out
+}
+
+fn location_node(location: &FoundLocation) -> Option<NodeId> {
+    match location {
+        FoundLocation::Local => None,
+        FoundLocation::Notify(node) | FoundLocation::CachedHint(node) | FoundLocation::RemoteHint(node) | FoundLocation::RemoteScan(node) => Some(*node),
+    }
+}
+
+fn next_hop(sim: &NetworkSimulator<(), (), (), ()>, node: u32, dest: u32) -> Option<u32> {
+    match sim.route(node, RouteRule::ToNode(dest)) {
+        RouteAction::Next(pair) => Some(pair.remote.port() as u32),
+        _ => None,
+    }
+}
+
+#[test]
+fn chaos_cut_link_reroute_pubsub() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectToPersistent(addr2.clone()));
+    sim.control(node2, ExtIn::ConnectToPersistent(addr3.clone()));
+    sim.control(node1, ExtIn::ConnectToPersistent(addr3));
+    sim.assert_converged(100, 5_000);
+    assert_eq!(next_hop(&sim, node1, node3), Some(node3));
+
+    let channel = ChannelId(1000);
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::PubSub(pubsub::Control(channel, ChannelControl::SubSource(node3)))));
+    sim.process(10);
+
+    sim.cut_link(node1, node3);
+    let spent = sim.run_until(500, 30_000, |sim| next_hop(sim, node1, node3) == Some(node2));
+    assert!(spent.is_some(), "Should reroute over node2");
+
+    while sim.pop_res().is_some() {}
+    let value = vec![1, 2, 3];
+    sim.control(
+        node3,
+        ExtIn::FeaturesControl((), FeaturesControl::PubSub(pubsub::Control(channel, ChannelControl::PubData(value.clone())))),
+    );
+    sim.process(10);
+    let expected = ExtOut::FeaturesEvent((), FeaturesEvent::PubSub(pubsub::Event(channel, ChannelEvent::SourceData(node3, value))));
+    assert_eq!(sim.pop_res(), Some((node1, expected)));
+
+    // direct link is used again after restored
+    sim.restore_link(node1, node3);
+    let spent = sim.run_until(500, 120_000, |sim| next_hop(sim, node1, node3) == Some(node3));
+    assert!(spent.is_some(), "Should use direct link after restored");
+}
+
+#[test]
+fn chaos_crash_restart_with_new_session() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectToPersistent(addr2));
+    sim.assert_converged(100, 5_000);
+    assert_eq!(
+        neighbours_events(&mut sim),
+        vec![
+            (node1, neighbours::Event::Connected(node2, ConnId::from_out(0, 1000))),
+            (node1, neighbours::Event::Primary(node2, ConnId::from_out(0, 1000))),
+        ]
+    );
+
+    sim.crash(node2);
+    assert!(sim.is_crashed(node2));
+    let spent = sim.run_until(500, 30_000, |sim| next_hop(sim, node1, node2).is_none());
+    assert!(spent.is_some(), "Should remove route to crashed node");
+
+    sim.restart(TestNode::new(node2, 2000, vec![]));
+    sim.assert_converged(500, 120_000);
+    assert!(neighbours_events(&mut sim)
+        .iter()
+        .any(|(node, event)| *node == node1 && matches!(event, neighbours::Event::Connected(node, _) if *node == node2)));
+}
+
+#[test]
+fn chaos_dht_kv_resubscribe_after_relay_restart() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let _addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node2, ExtIn::ConnectToPersistent(addr1));
+    sim.assert_converged(100, 5_000);
+
+    // map 1 is located at node1
+    let key = Map(1);
+    let sub_key = Key(2000);
+    let kv = |control| ExtIn::FeaturesControl((), FeaturesControl::DhtKv(dht_kv::Control::MapCmd(key, control)));
+    let on_set = |value: Vec<u8>| ExtOut::FeaturesEvent((), FeaturesEvent::DhtKv(dht_kv::Event::MapEvent(key, MapEvent::OnSet(sub_key, node1, value))));
+    sim.control(node2, kv(MapControl::Sub));
+    sim.process(100);
+    while sim.pop_res().is_some() {}
+
+    sim.crash(node1);
+    sim.process(1000);
+    sim.restart(TestNode::new(node1, 2000, vec![]));
+    sim.assert_converged(500, 120_000);
+
+    let spent = sim.run_until(500, 30_000, |sim| {
+        sim.control(node1, kv(MapControl::Set(sub_key, vec![1])));
+        sim.process(100);
+        let mut received = false;
+        while let Some((node, event)) = sim.pop_res() {
+            received |= node == node2 && event == on_set(vec![1]);
+        }
+        received
+    });
+    assert!(spent.is_some(), "Subscriber should receive value from restarted relay");
+}
+
+#[test]
+fn chaos_latency_spike_duplicate_reorder_keep_connection() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new_with_seed(0, 2);
+    sim.set_link(node1, node2, LinkCfg::new(10, 0, None).with_duplicate(20).with_reorder(20));
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    sim.assert_converged(100, 5_000);
+
+    sim.latency_spike(node1, node2, 2000, 5000);
+    for _i in 0..20 {
+        sim.process(500);
+    }
+    let events = neighbours_events(&mut sim);
+    assert!(
+        !events.iter().any(|(_, event)| matches!(event, neighbours::Event::Disconnected(..))),
+        "Should not disconnect, got {events:?}"
+    );
+    assert_eq!(sim.route(node1, RouteRule::ToNode(node2)), RouteAction::Next(NetPair::new(node_to_addr(node1), node_to_addr(node2))));
+}
+
+#[test]
+fn chaos_alias_recover_after_owner_restart() {
+    // node1 <-> node2 <-> node3, alias is registered at node3
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![Arc::new(MockServiceBuilder)]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![Arc::new(MockServiceBuilder)]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![Arc::new(MockServiceBuilder)]));
+
+    sim.control(node1, ExtIn::ConnectToPersistent(addr2));
+    sim.control(node2, ExtIn::ConnectToPersistent(addr3));
+    sim.assert_converged(100, 5_000);
+
+    let alias_v = 1000;
+    let service = 0;
+    let level = ServiceBroadcastLevel::Global;
+    let register = ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::Register { alias: alias_v, service, level }));
+    let query = ExtIn::FeaturesControl((), FeaturesControl::Alias(alias::Control::Query { alias: alias_v, service, level }));
+
+    sim.control(node3, register.clone());
+    sim.process(10);
+    sim.process(alias::HINT_TIMEOUT_MS);
+    sim.control(node1, query.clone());
+    sim.process(alias::HINT_TIMEOUT_MS + alias::SCAN_TIMEOUT_MS);
+    let found = alias_result(&mut sim, node1, alias_v).expect("Should have query result");
+    assert_eq!(found.as_ref().and_then(location_node), Some(node3));
+
+    // the owner crashes and comes back with a new session, without the alias until it is registered again
+    sim.crash(node3);
+    sim.process(1000);
+    sim.restart(TestNode::new(node3, 2000, vec![Arc::new(MockServiceBuilder)]));
+    sim.assert_converged(500, 120_000);
+    sim.control(node3, register);
+
+    let spent = sim.run_until(500, 30_000, |sim| {
+        sim.control(node1, query.clone());
+        sim.process(alias::HINT_TIMEOUT_MS + alias::SCAN_TIMEOUT_MS);
+        matches!(alias_result(sim, node1, alias_v), Some(Some(location)) if location_node(&location) == Some(node3))
+    });
+    assert!(spent.is_some(), "Should find alias at restarted node");
+}
+
+#[test]
+fn chaos_partition_then_heal_recover_routes() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectToPersistent(addr2.clone()));
+    sim.control(node2, ExtIn::ConnectToPersistent(addr3.clone()));
+    sim.control(node1, ExtIn::ConnectToPersistent(addr3));
+    sim.assert_converged(100, 5_000);
+
+    sim.partition(&[node3]);
+    assert!(!sim.is_reachable(node1, node3));
+    assert!(sim.is_reachable(node1, node2));
+    let spent = sim.run_until(500, 60_000, |sim| next_hop(sim, node1, node3).is_none() && next_hop(sim, node2, node3).is_none());
+    assert!(spent.is_some(), "Should remove routes to partitioned node");
+    assert_eq!(next_hop(&sim, node1, node2), Some(node2));
+
+    sim.heal();
+    sim.assert_converged(500, 120_000);
+    assert_eq!(next_hop(&sim, node1, node3), Some(node3));
+    assert_eq!(next_hop(&sim, node3, node2), Some(node2));
+}
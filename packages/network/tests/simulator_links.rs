@@ -0,0 +1,91 @@
+use atm0s_sdn_identity::ConnId;
+use atm0s_sdn_network::{
+    features::{neighbours, FeaturesControl, FeaturesEvent},
+    simulator::{node_to_addr, LinkCfg, NetworkSimulator, TestNode},
+    ExtIn, ExtOut,
+};
+use atm0s_sdn_router::{RouteAction, RouteRule};
+
+fn last_rtt(sim: &mut NetworkSimulator<(), (), (), ()>) -> Option<u32> {
+    let mut rtt = None;
+    while let Some((_, event)) = sim.pop_res() {
+        if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Stats(_, _, stats))) = event {
+            rtt = Some(stats.rtt_ms);
+        }
+    }
+    rtt
+}
+
+#[test]
+fn simulator_link_latency_is_measured_as_rtt() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+    sim.set_link(node1, node2, LinkCfg::new(20, 0, None));
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectTo(addr2));
+    for _i in 0..40 {
+        sim.process(100);
+    }
+
+    assert_eq!(last_rtt(&mut sim), Some(40));
+}
+
+#[test]
+fn simulator_lossy_chain_converge() {
+    let node1 = 1;
+    let node2 = 2;
+    let node3 = 3;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new_with_seed(0, 1);
+    sim.set_default_link(LinkCfg::new(30, 10, Some(10_000)));
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+    let addr3 = sim.add_node(TestNode::new(node3, 1236, vec![]));
+
+    sim.control(node1, ExtIn::ConnectToPersistent(addr2));
+    sim.control(node2, ExtIn::ConnectToPersistent(addr3));
+    sim.assert_converged(100, 20_000);
+
+    // node1 reaches node3 over node2
+    match sim.route(node1, RouteRule::ToNode(node3)) {
+        RouteAction::Next(pair) => assert_eq!(pair.remote, node_to_addr(node2)),
+        action => panic!("Should route over node2, got {action:?}"),
+    }
+}
+
+#[test]
+fn simulator_partition_and_heal() {
+    let node1 = 1;
+    let node2 = 2;
+    let mut sim = NetworkSimulator::<(), (), (), ()>::new(0);
+
+    let _addr1 = sim.add_node(TestNode::new(node1, 1234, vec![]));
+    let addr2 = sim.add_node(TestNode::new(node2, 1235, vec![]));
+
+    sim.control(node1, ExtIn::FeaturesControl((), FeaturesControl::Neighbours(neighbours::Control::Sub)));
+    sim.control(node1, ExtIn::ConnectToPersistent(addr2));
+    sim.assert_converged(100, 5_000);
+
+    sim.partition(&[node2]);
+    assert!(!sim.is_reachable(node1, node2));
+    let lost = sim.run_until(500, 30_000, |sim| {
+        let mut disconnected = false;
+        while let Some((_, event)) = sim.pop_res() {
+            if let ExtOut::FeaturesEvent(_, FeaturesEvent::Neighbours(neighbours::Event::Disconnected(node, conn, _))) = event {
+                assert_eq!((node, conn), (node2, ConnId::from_out(0, 1000)));
+                disconnected = true;
+            }
+        }
+        disconnected
+    });
+    assert!(lost.is_some(), "Should disconnect after partition");
+    assert!(!sim.routes_converged());
+
+    sim.heal();
+    sim.assert_converged(500, 60_000);
+}
@@ -9,23 +9,43 @@ use std::{
 
 use atm0s_sdn_identity::{NodeAddr, NodeAddrBuilder, NodeId, Protocol};
 use atm0s_sdn_network::{
-    base::{Authorization, HandshakeBuilder, ServiceBuilder},
+    base::{Authorization, DnsResolver, HandshakeBuilder, ServiceBuilder},
+    controller_plane::{DrainCfg, KeepaliveCfg, NeighboursLimits, ReconnectCfg},
+    data_plane::{FrameTransport, MemoryNetwork, MemoryTransport, RateLimit, ShapingCfg, Transport, TransportPenalty},
     features::{FeaturesControl, FeaturesEvent},
     secure::{HandshakeBuilderXDA, StaticKeyAuthorization},
-    services::{manual_discovery, visualization},
+    services::{lan_discovery, manual_discovery, topology, visualization},
 };
+use parking_lot::Mutex;
 use rand::{thread_rng, RngCore};
 use sans_io_runtime::backend::Backend;
 use serde::{de::DeserializeOwned, Serialize};
 
 use crate::{
     history::DataWorkerHistory,
+    resolver::SystemDnsResolver,
+    tcp::TcpTransport,
     worker_inner::{ControllerCfg, SdnController, SdnExtIn, SdnInnerCfg, SdnOwner, SdnWorkerInner},
+    ws::WsTransport,
 };
 
+/// Interval of polling frame transports, which is the controller worker tick when any of them is added.
+/// Runtime backends have no stream sockets, so non-blocking streams are polled instead of waiting for readiness
+const STREAM_POLL_MS: u64 = 10;
+/// Interval of polling send queues which are waiting for send budget, which is all workers tick when a budget is set
+const SEND_QUEUE_POLL_MS: u64 = 10;
+
 pub struct SdnBuilder<UserData, SC, SE, TC, TW, NodeInfo> {
     auth: Option<Arc<dyn Authorization>>,
     handshake: Option<Arc<dyn HandshakeBuilder>>,
+    resolver: Option<Arc<dyn DnsResolver>>,
+    reconnect: ReconnectCfg,
+    keepalive: KeepaliveCfg,
+    max_conns_per_node: Option<usize>,
+    limits: NeighboursLimits,
+    transport_penalty: TransportPenalty,
+    drain: Option<DrainCfg>,
+    transports: Vec<Box<dyn FrameTransport>>,
     node_addr: NodeAddr,
     node_id: NodeId,
     session: u64,
@@ -33,6 +53,8 @@ pub struct SdnBuilder<UserData, SC, SE, TC, TW, NodeInfo> {
     tick_ms: u64,
     visualization_collector: bool,
     seeds: Vec<NodeAddr>,
+    shaping: ShapingCfg,
+    send_budget: Option<RateLimit>,
     #[allow(clippy::type_complexity)]
     services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     #[cfg(feature = "vpn")]
@@ -60,6 +82,14 @@ where
         Self {
             auth: None,
             handshake: None,
+            resolver: None,
+            reconnect: ReconnectCfg::default(),
+            keepalive: KeepaliveCfg::default(),
+            max_conns_per_node: None,
+            limits: NeighboursLimits::default(),
+            transport_penalty: TransportPenalty::default(),
+            drain: None,
+            transports: vec![],
             node_addr,
             node_id,
             tick_ms: 1000,
@@ -67,6 +97,8 @@ where
             bind_addrs: bind_addrs.to_vec(),
             visualization_collector: false,
             seeds: vec![],
+            shaping: ShapingCfg::default(),
+            send_budget: None,
             services: vec![],
             #[cfg(feature = "vpn")]
             vpn_enable: false,
@@ -82,6 +114,52 @@ where
         self.node_addr.clone()
     }
 
+    /// Listen tcp on same addrs as UDP and advertise them in node addr, other nodes use them when UDP is blocked.
+    /// This need to be called before adding services which announce node addr.
+    /// Streams are plain tcp without TLS and polled from the controller worker, see `TcpTransport` for limitations.
+    pub fn enable_tcp(&mut self) {
+        self.add_transport(Box::new(TcpTransport::default()));
+        self.node_addr = add_same_port_addrs(&self.node_addr, Protocol::Tcp);
+        log::info!("Enabled tcp, node addr {}", self.node_addr);
+    }
+
+    /// Dial WebSocket addrs of other nodes, which is needed for leaf clients behind http-only firewalls.
+    pub fn enable_websocket(&mut self) {
+        if !self.transports.iter().any(|t| t.transport() == Transport::WebSocket) {
+            self.add_transport(Box::new(WsTransport::default()));
+        }
+    }
+
+    /// Listen WebSocket on addr and advertise it in node addr, so browsers and restricted clients can join.
+    /// Unspecified ip is advertised with same ips as UDP addrs. The addr must differ from other http servers of the app,
+    /// see `WsTransport` for why it has its own listener.
+    /// This need to be called before adding services which announce node addr.
+    pub fn set_websocket_listen(&mut self, addr: SocketAddr) {
+        let mut ws = WsTransport::default();
+        ws.listen(addr).expect("Should listen websocket");
+        self.add_transport(Box::new(ws));
+        self.node_addr = add_ws_addrs(&self.node_addr, addr);
+        log::info!("Listen websocket on {addr}, node addr {}", self.node_addr);
+    }
+
+    /// Connect with other nodes in same process over the in-memory network, memory addrs `/memory/{port}`
+    /// with same ips and ports as UDP addrs are advertised in node addr.
+    /// This need to be called before adding services which announce node addr.
+    pub fn enable_memory(&mut self, network: Arc<MemoryNetwork>) {
+        self.add_transport(Box::new(MemoryTransport::new(network)));
+        self.node_addr = add_same_port_addrs(&self.node_addr, |port| Protocol::Memory(port as u64));
+        log::info!("Enabled memory transport, node addr {}", self.node_addr);
+    }
+
+    /// Add a custom transport, which replaces the added transport of same kind.
+    /// Frame transports are owned and polled by the controller worker.
+    pub fn add_transport(&mut self, transport: Box<dyn FrameTransport>) {
+        let kind = transport.transport();
+        self.transports.retain(|t| t.transport() != kind);
+        self.transports.push(transport);
+    }
+
+    /// Seeds are connected as persistent nodes, which are reconnected after outages and never given up
     pub fn add_seed(&mut self, addr: NodeAddr) {
         self.seeds.push(addr);
     }
@@ -96,16 +174,74 @@ where
         self.handshake = Some(Arc::new(handshake));
     }
 
+    /// Setting reconnect policy for persistent nodes, `max_attempts` is not applied to seeds
+    pub fn set_reconnect(&mut self, cfg: ReconnectCfg) {
+        self.reconnect = cfg;
+    }
+
+    /// Setting keepalive per connection class, fast probe mode makes the controller worker tick more often
+    pub fn set_keepalive(&mut self, cfg: KeepaliveCfg) {
+        self.keepalive = cfg;
+    }
+
+    /// Keep only best `max` connections by rtt to each node, other connections between bind addrs and remote addrs are closed
+    pub fn set_max_conns_per_node(&mut self, max: usize) {
+        self.max_conns_per_node = Some(max);
+    }
+
+    /// Setting connection limits and handshake flood protection
+    pub fn set_neighbours_limits(&mut self, limits: NeighboursLimits) {
+        self.limits = limits;
+    }
+
+    /// Setting resolver for dns seeds like `/dns4/seed.example.com/udp/10000`, default is SystemDnsResolver
+    pub fn set_resolver<R: DnsResolver + 'static>(&mut self, resolver: R) {
+        self.resolver = Some(Arc::new(resolver));
+    }
+
     /// Setting visualization collector mode
     pub fn set_visualization_collector(&mut self, value: bool) {
         self.visualization_collector = value;
     }
 
+    /// Setting extra route cost per transport, paths over UDP are preferred by default
+    pub fn set_transport_penalty(&mut self, penalty: TransportPenalty) {
+        self.transport_penalty = penalty;
+    }
+
+    /// Enable draining at shutdown, routes are withdrawn and neighbours are kept until transit traffic stops.
+    /// Default is disabled, neighbours are disconnected immediately
+    pub fn set_drain(&mut self, cfg: DrainCfg) {
+        self.drain = Some(cfg);
+    }
+
+    /// Setting traffic shaping for relayed packets, limits are applied inside each worker
+    pub fn set_shaping(&mut self, shaping: ShapingCfg) {
+        self.shaping = shaping;
+    }
+
+    /// Setting bytes which can be sent to each remote pair, packets over it are queued and sent by priority class.
+    /// Relayed packets are marked with congestion experienced when the backlog of a pair is more than 20ms of its budget.
+    /// Default is unlimited, then packets are never kept in queues and congestion is only signalled by link queueing delay
+    pub fn set_send_budget(&mut self, budget: RateLimit) {
+        self.send_budget = Some(budget);
+    }
+
     /// Setting manual discovery
     pub fn set_manual_discovery(&mut self, local_tags: Vec<String>, connect_tags: Vec<String>) {
         self.add_service(Arc::new(manual_discovery::ManualDiscoveryServiceBuilder::new(self.node_addr.clone(), local_tags, connect_tags)));
     }
 
+    /// Setting automatic neighbour selection, which keeps a target degree per layer
+    pub fn set_topology(&mut self, cfg: topology::TopologyCfg) {
+        self.add_service(Arc::new(topology::TopologyServiceBuilder::new(self.node_addr.clone(), cfg)));
+    }
+
+    /// Setting LAN discovery, nodes which announce the same cluster token over the transport are connected automatically
+    pub fn set_lan_discovery(&mut self, token: &str, transport: Arc<dyn lan_discovery::LanTransport>) {
+        self.add_service(Arc::new(lan_discovery::LanDiscoveryServiceBuilder::new(self.node_addr.clone(), token, transport)));
+    }
+
     /// panic if the service already exists
     pub fn add_service(&mut self, service: Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>) {
         for s in self.services.iter() {
@@ -154,19 +290,39 @@ where
 
         let history = Arc::new(DataWorkerHistory::default());
 
+        let mut controller_tick_ms = self.keepalive.fast_interval_ms().map_or(1000, |fast| fast.min(1000));
+        if !self.transports.is_empty() {
+            // frame transports are polled from controller worker tick
+            controller_tick_ms = controller_tick_ms.min(STREAM_POLL_MS);
+        }
+        let mut data_tick_ms = 1000;
+        if self.send_budget.is_some() {
+            controller_tick_ms = controller_tick_ms.min(SEND_QUEUE_POLL_MS);
+            data_tick_ms = SEND_QUEUE_POLL_MS;
+        }
         let mut controller = SdnController::default();
         controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, B>(
-            Duration::from_millis(1000),
+            Duration::from_millis(controller_tick_ms),
             SdnInnerCfg {
                 node_id: self.node_id,
                 tick_ms: self.tick_ms,
                 bind_addrs: self.bind_addrs.to_vec(),
                 services: self.services.clone(),
                 history: history.clone(),
+                shaping: self.shaping.clone(),
+                send_budget: self.send_budget,
                 controller: Some(ControllerCfg {
                     session: self.session,
                     auth: self.auth.unwrap_or_else(|| Arc::new(StaticKeyAuthorization::new("unsecure"))),
                     handshake: self.handshake.unwrap_or_else(|| Arc::new(HandshakeBuilderXDA)),
+                    resolver: self.resolver.unwrap_or_else(|| Arc::new(SystemDnsResolver::default())),
+                    reconnect: self.reconnect,
+                    keepalive: self.keepalive,
+                    max_conns_per_node: self.max_conns_per_node,
+                    limits: self.limits,
+                    transport_penalty: self.transport_penalty,
+                    drain: self.drain,
+                    transports: Mutex::new(self.transports),
                     #[cfg(feature = "vpn")]
                     vpn_tun_device: tun_device,
                 }),
@@ -178,13 +334,15 @@ where
 
         for _ in 1..workers {
             controller.add_worker::<SdnOwner, _, SdnWorkerInner<UserData, SC, SE, TC, TW>, B>(
-                Duration::from_millis(1000),
+                Duration::from_millis(data_tick_ms),
                 SdnInnerCfg {
                     node_id: self.node_id,
                     tick_ms: self.tick_ms,
                     bind_addrs: self.bind_addrs.to_vec(),
                     services: self.services.clone(),
                     history: history.clone(),
+                    shaping: self.shaping.clone(),
+                    send_budget: self.send_budget,
                     controller: None,
                     #[cfg(feature = "vpn")]
                     vpn_tun_fd: queue_fds.pop_front(),
@@ -196,13 +354,60 @@ where
         std::thread::sleep(std::time::Duration::from_millis(100));
 
         for seed in self.seeds {
-            controller.send_to(0, SdnExtIn::ConnectTo(seed));
+            controller.send_to(0, SdnExtIn::ConnectToSeed(seed));
         }
 
         controller
     }
 }
 
+/// Add an addr of other transport with same ip and port for each UDP addr, like `/tcp/{port}`
+fn add_same_port_addrs(addr: &NodeAddr, protocol: fn(u16) -> Protocol<'static>) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(addr.node_id());
+    let mut same_port_addrs = vec![];
+    let mut host = None;
+    for part in addr.multiaddr().iter() {
+        match &part {
+            Protocol::Ip4(_) | Protocol::Ip6(_) => host = Some(part.clone()),
+            Protocol::Udp(port) => {
+                if let Some(host) = host.clone() {
+                    same_port_addrs.push(host);
+                    same_port_addrs.push(protocol(*port));
+                }
+            }
+            _ => {}
+        }
+        builder.add_protocol(part);
+    }
+    for part in same_port_addrs {
+        builder.add_protocol(part);
+    }
+    builder.addr()
+}
+
+/// Add a WebSocket addr `/tcp/{port}/ws` for the listen addr, or for each UDP ip if the listen ip is unspecified
+fn add_ws_addrs(addr: &NodeAddr, listen: SocketAddr) -> NodeAddr {
+    let mut builder = NodeAddrBuilder::new(addr.node_id());
+    let mut hosts = vec![];
+    for part in addr.multiaddr().iter() {
+        if let Protocol::Ip4(_) | Protocol::Ip6(_) = &part {
+            if !hosts.contains(&part) {
+                hosts.push(part.clone());
+            }
+        }
+        builder.add_protocol(part);
+    }
+    if !listen.ip().is_unspecified() {
+        hosts = vec![listen.ip().into()];
+    }
+    for host in hosts {
+        builder.add_protocol(host);
+        builder.add_protocol(Protocol::Tcp(listen.port()));
+        builder.add_protocol(Protocol::Ws("/".into()));
+    }
+    builder.addr()
+}
+
 pub fn generate_node_addr(node_id: u32, bind_addrs: &[SocketAddr], custom_ips: Vec<SocketAddr>) -> NodeAddr {
     let mut addr_builder = NodeAddrBuilder::new(node_id);
     for bind_addr in bind_addrs {
@@ -4,32 +4,38 @@ use std::{
 };
 
 use atm0s_sdn_identity::NodeId;
-use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use atm0s_sdn_router::shadow::{BroadcastKey, ShadowRouterHistory};
 use parking_lot::Mutex;
 
 const HISTORY_TIMEOUT_MS: u64 = 2000;
+/// Max number of remembered broadcasts, each one records HISTORY_KEYS_PER_BROADCAST keys from BroadcastKey::of
+const HISTORY_MAX_BROADCASTS: usize = 10000;
+const HISTORY_KEYS_PER_BROADCAST: usize = 2;
 
 #[derive(Debug, Default)]
 pub struct DataWorkerHistory {
     now_ms: AtomicU64,
     #[allow(clippy::type_complexity)]
-    queue: Mutex<VecDeque<(u64, (Option<NodeId>, u8, u16))>>,
+    queue: Mutex<VecDeque<(u64, (Option<NodeId>, u8, BroadcastKey))>>,
     #[allow(clippy::type_complexity)]
-    map: Mutex<HashMap<(Option<NodeId>, u8, u16), bool>>,
+    map: Mutex<HashMap<(Option<NodeId>, u8, BroadcastKey), bool>>,
 }
 
 impl ShadowRouterHistory for DataWorkerHistory {
-    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u16) -> bool {
+    fn already_received_broadcast(&self, from: Option<NodeId>, service: u8, seq: u32) -> bool {
         let mut map = self.map.lock();
         let mut queue = self.queue.lock();
         let now_ms = self.now_ms.load(std::sync::atomic::Ordering::Relaxed);
-        if map.contains_key(&(from, service, seq)) {
+        let (checks, records) = BroadcastKey::of(seq);
+        if checks.iter().any(|key| map.contains_key(&(from, service, *key))) {
             return true;
         }
 
-        map.insert((from, service, seq), true);
-        queue.push_back((now_ms, (from, service, seq)));
-        if queue.len() > 10000 {
+        for key in records {
+            map.insert((from, service, key), true);
+            queue.push_back((now_ms, (from, service, key)));
+        }
+        while queue.len() > HISTORY_MAX_BROADCASTS * HISTORY_KEYS_PER_BROADCAST {
             let (_ts, pair) = queue.pop_front().expect("queue should not empty");
             map.remove(&pair);
         }
@@ -56,7 +62,7 @@ impl ShadowRouterHistory for DataWorkerHistory {
 mod tests {
     use atm0s_sdn_router::shadow::ShadowRouterHistory;
 
-    use crate::history::HISTORY_TIMEOUT_MS;
+    use crate::history::{HISTORY_MAX_BROADCASTS, HISTORY_TIMEOUT_MS};
 
     use super::DataWorkerHistory;
 
@@ -71,4 +77,19 @@ mod tests {
         history.set_ts(HISTORY_TIMEOUT_MS);
         assert_eq!(history.already_received_broadcast(Some(1), 1, 1), false);
     }
+
+    #[test]
+    fn keep_max_broadcasts() {
+        let history = DataWorkerHistory::default();
+
+        for seq in 0..HISTORY_MAX_BROADCASTS as u32 {
+            assert_eq!(history.already_received_broadcast(Some(1), 1, seq), false);
+        }
+        // oldest broadcast is still remembered at the cap
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 0), true);
+
+        // one more broadcast evicts the oldest one
+        assert_eq!(history.already_received_broadcast(Some(1), 1, HISTORY_MAX_BROADCASTS as u32), false);
+        assert_eq!(history.already_received_broadcast(Some(1), 1, 0), false);
+    }
 }
@@ -0,0 +1,83 @@
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket},
+};
+
+use atm0s_sdn_network::services::lan_discovery::LanTransport;
+use socket2::{Domain, Protocol, Socket, Type};
+
+const MAX_ANNOUNCE_SIZE: usize = 1500;
+
+/// UDP multicast transport for LAN discovery. All nodes in the same segment join the same group and port,
+/// the socket is non-blocking because it is polled from the controller tick.
+pub struct MulticastTransport {
+    socket: UdpSocket,
+    group: SocketAddrV4,
+}
+
+impl MulticastTransport {
+    /// Join multicast `group` on interface `iface`, use `Ipv4Addr::UNSPECIFIED` for letting the system choose
+    pub fn new(group: SocketAddrV4, iface: Ipv4Addr) -> std::io::Result<Self> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(true)?;
+        #[cfg(unix)]
+        socket.set_reuse_port(true)?;
+        socket.bind(&SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, group.port())).into())?;
+        socket.join_multicast_v4(group.ip(), &iface)?;
+        socket.set_multicast_if_v4(&iface)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket: socket.into(), group })
+    }
+}
+
+impl LanTransport for MulticastTransport {
+    fn send(&self, buf: &[u8]) {
+        if let Err(e) = self.socket.send_to(buf, self.group) {
+            log::warn!("[MulticastTransport] send announce to {} error {:?}", self.group, e);
+        }
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut buf = [0; MAX_ANNOUNCE_SIZE];
+        match self.socket.recv_from(&mut buf) {
+            Ok((len, _)) => Some(buf[..len].to_vec()),
+            Err(e) => {
+                if e.kind() != ErrorKind::WouldBlock {
+                    log::warn!("[MulticastTransport] recv error {:?}", e);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{Ipv4Addr, SocketAddrV4},
+        time::{Duration, Instant},
+    };
+
+    use atm0s_sdn_network::services::lan_discovery::LanTransport;
+
+    use super::MulticastTransport;
+
+    #[test]
+    fn loopback_multicast() {
+        let group = SocketAddrV4::new(Ipv4Addr::new(239, 255, 97, 48), 45901);
+        let node1 = MulticastTransport::new(group, Ipv4Addr::LOCALHOST).expect("Should join group");
+        let node2 = MulticastTransport::new(group, Ipv4Addr::LOCALHOST).expect("Should join group");
+
+        node1.send(b"hello");
+        let started = Instant::now();
+        let received = loop {
+            if let Some(buf) = node2.recv() {
+                break buf;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2), "Should receive announce");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(received, b"hello");
+    }
+}
@@ -3,8 +3,8 @@
 use std::{fmt::Debug, hash::Hash};
 
 pub use atm0s_sdn_identity::{ConnDirection, ConnId, NodeAddr, NodeAddrBuilder, NodeId, NodeIdType, Protocol};
-pub use atm0s_sdn_network::controller_plane::ControllerPlaneCfg;
-pub use atm0s_sdn_network::data_plane::DataPlaneCfg;
+pub use atm0s_sdn_network::controller_plane::{ControllerPlaneCfg, DrainCfg, Keepalive, KeepaliveCfg, NeighboursLimits, ReconnectCfg};
+pub use atm0s_sdn_network::data_plane::{DataPlaneCfg, RateLimit, ShapingCfg, ShapingKey, ShapingStats, TransportPenalty};
 use atm0s_sdn_network::features::FeaturesControl;
 pub use atm0s_sdn_network::{
     base, features, secure, services,
@@ -12,23 +12,33 @@ pub use atm0s_sdn_network::{
 };
 pub use atm0s_sdn_network::{
     base::ServiceId,
-    data_plane::{NetInput, NetOutput},
+    data_plane::{FrameTransport, MemoryNetwork, MemoryTransport, NetInput, NetOutput, NetPair, Transport},
 };
 pub use atm0s_sdn_router::{shadow::ShadowRouterHistory, RouteRule, ServiceBroadcastLevel};
 pub use sans_io_runtime;
 
 mod builder;
 mod history;
+mod lan_discovery;
+mod resolver;
+mod tcp;
 mod time;
 mod worker_inner;
+mod ws;
 
 pub use builder::{generate_node_addr, SdnBuilder};
 pub use history::DataWorkerHistory;
+pub use lan_discovery::MulticastTransport;
+pub use resolver::SystemDnsResolver;
+pub use tcp::{StreamFramer, TcpTransport};
 pub use time::{TimePivot, TimeTicker};
 pub use worker_inner::{SdnChannel, SdnController, SdnEvent, SdnExtIn, SdnExtOut, SdnOwner};
+pub use ws::WsTransport;
 
 pub trait SdnControllerUtils<UserData, SC> {
     fn connect_to(&mut self, addr: NodeAddr);
+    /// Connect and reconnect after the node is lost, see ReconnectCfg
+    fn connect_to_persistent(&mut self, addr: NodeAddr);
     fn feature_control(&mut self, userdata: UserData, cmd: FeaturesControl);
     fn service_control(&mut self, service: ServiceId, userdata: UserData, cmd: SC);
 }
@@ -44,6 +54,9 @@ impl<
     fn connect_to(&mut self, addr: NodeAddr) {
         self.send_to(0, SdnExtIn::ConnectTo(addr));
     }
+    fn connect_to_persistent(&mut self, addr: NodeAddr) {
+        self.send_to(0, SdnExtIn::ConnectToPersistent(addr));
+    }
     fn feature_control(&mut self, userdata: UserData, cmd: FeaturesControl) {
         self.send_to(0, SdnExtIn::FeaturesControl(userdata, cmd));
     }
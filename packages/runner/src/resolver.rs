@@ -0,0 +1,65 @@
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, ToSocketAddrs},
+    sync::Arc,
+};
+
+use atm0s_sdn_network::base::DnsResolver;
+use parking_lot::Mutex;
+
+type Resolved = (String, Vec<IpAddr>);
+
+/// Resolver which uses the system resolver, each name is resolved in a short-lived thread for avoiding blocking the controller
+#[derive(Default)]
+pub struct SystemDnsResolver {
+    results: Arc<Mutex<VecDeque<Resolved>>>,
+}
+
+impl DnsResolver for SystemDnsResolver {
+    fn resolve(&self, name: &str) {
+        let name = name.to_string();
+        let results = self.results.clone();
+        std::thread::spawn(move || {
+            let ips = match (name.as_str(), 0).to_socket_addrs() {
+                Ok(addrs) => addrs.map(|addr| addr.ip()).collect::<Vec<_>>(),
+                Err(e) => {
+                    log::warn!("[SystemDnsResolver] resolve {name} error {:?}", e);
+                    vec![]
+                }
+            };
+            results.lock().push_back((name, ips));
+        });
+    }
+
+    fn poll(&self) -> Option<Resolved> {
+        self.results.lock().pop_front()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{IpAddr, Ipv4Addr},
+        time::{Duration, Instant},
+    };
+
+    use atm0s_sdn_network::base::DnsResolver;
+
+    use super::SystemDnsResolver;
+
+    #[test]
+    fn resolve_localhost() {
+        let resolver = SystemDnsResolver::default();
+        resolver.resolve("localhost");
+        let started = Instant::now();
+        let (name, ips) = loop {
+            if let Some(res) = resolver.poll() {
+                break res;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "Should resolve");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(name, "localhost");
+        assert!(ips.contains(&IpAddr::V4(Ipv4Addr::LOCALHOST)));
+    }
+}
@@ -0,0 +1,457 @@
+//! TCP fallback transport for networks which block UDP.
+//!
+//! Limitations:
+//! - TLS is not implemented. Frames carry same bytes as UDP datagrams, which are already authenticated and
+//!   encrypted by the neighbours handshake, so TLS would only hide the framing. Networks which only allow TLS
+//!   can use the WebSocket transport behind a TLS terminating proxy.
+//! - sans-io-runtime backends only have UDP and TUN sockets, so streams are non-blocking std sockets which are
+//!   polled from the controller worker tick (each 10ms when any transport is added). All stream traffic is
+//!   handled by that worker, other workers forward their frames to it, which is fine for a fallback path.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn_network::{
+    base::Buffer,
+    data_plane::{FrameTransport, NetPair, Transport},
+};
+
+pub(crate) const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Bigger frames are treated as a broken stream, normal frames are same size as UDP datagrams
+pub(crate) const MAX_FRAME_SIZE: usize = 1 << 20;
+/// Frames are dropped when the stream is not writable fast enough, same as a full UDP socket buffer
+pub(crate) const MAX_PENDING_BYTES: usize = 1 << 20;
+/// Incoming streams over this are closed right after accepted, which avoids running out of fds
+pub(crate) const MAX_ACCEPTED_STREAMS: usize = 1024;
+/// Streams which receive nothing in this time are closed, neighbours keepalive is much more frequent
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes which are read from a stream in a poll, so a fast sender can't stall the worker
+pub(crate) const MAX_READ_PER_POLL: usize = 64 * 1024;
+/// Bytes of incomplete frames which are buffered over all streams, the stream which goes over it is closed
+pub(crate) const MAX_BUFFERED_BYTES: usize = 64 * 1024 * 1024;
+const READ_BUF_SIZE: usize = 16 * 1024;
+
+/// Length-prefixed framing over a byte stream. Each frame is a u32 big-endian length followed by
+/// the same bytes as an UDP datagram, so TLS or other streams can reuse it.
+#[derive(Default)]
+pub struct StreamFramer {
+    buf: Vec<u8>,
+}
+
+impl StreamFramer {
+    pub fn encode(frame: &[u8], out: &mut Vec<u8>) {
+        out.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        out.extend_from_slice(frame);
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Bytes of incomplete frames
+    pub fn buffered(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Pop next complete frame, Err if the stream is broken
+    pub fn pop(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes([self.buf[0], self.buf[1], self.buf[2], self.buf[3]]) as usize;
+        if len > MAX_FRAME_SIZE {
+            return Err(io::Error::new(ErrorKind::InvalidData, format!("frame size {len} is too big")));
+        }
+        if self.buf.len() < 4 + len {
+            return Ok(None);
+        }
+        let frame = self.buf[4..4 + len].to_vec();
+        self.buf.drain(..4 + len);
+        Ok(Some(frame))
+    }
+}
+
+struct Stream {
+    stream: TcpStream,
+    framer: StreamFramer,
+    pending: Vec<u8>,
+    last_recv: Instant,
+}
+
+impl Stream {
+    fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+        Ok(Self {
+            stream,
+            framer: StreamFramer::default(),
+            pending: vec![],
+            last_recv: Instant::now(),
+        })
+    }
+
+    fn send(&mut self, frame: &[u8]) -> bool {
+        if self.pending.len() + frame.len() > MAX_PENDING_BYTES {
+            return false;
+        }
+        StreamFramer::encode(frame, &mut self.pending);
+        true
+    }
+
+    /// Write pending bytes and read available frames up to MAX_READ_PER_POLL,
+    /// Err if the stream is closed, broken or has more than max_buffered bytes of incomplete frames
+    fn poll(&mut self, frames: &mut Vec<Vec<u8>>, max_buffered: usize) -> io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(len) => {
+                    self.pending.drain(..len);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        let mut buf = [0; READ_BUF_SIZE];
+        let mut read = 0;
+        while read < MAX_READ_PER_POLL {
+            match self.stream.read(&mut buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(len) => {
+                    read += len;
+                    self.last_recv = Instant::now();
+                    self.framer.push(&buf[..len]);
+                    while let Some(frame) = self.framer.pop()? {
+                        frames.push(frame);
+                    }
+                    if self.framer.buffered() > max_buffered {
+                        return Err(io::Error::new(
+                            ErrorKind::InvalidData,
+                            format!("buffered {} bytes is over limit {max_buffered}", self.framer.buffered()),
+                        ));
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// TCP fallback transport for networks which block UDP. It listens on same addrs as UDP sockets and
+/// opens outgoing streams on the first frame to a tcp pair. All sockets are non-blocking because
+/// they are polled from the controller worker tick, connecting is done in a background thread.
+pub struct TcpTransport {
+    listeners: Vec<(SocketAddr, TcpListener)>,
+    streams: HashMap<NetPair, Stream>,
+    /// outgoing streams which are connecting, with frames which are sent before connected, limited to MAX_PENDING_BYTES
+    connecting: HashMap<NetPair, (Vec<Vec<u8>>, usize)>,
+    /// accepted streams, we don't connect back to them after they are closed
+    accepted: HashSet<NetPair>,
+    connected_tx: Sender<(NetPair, io::Result<TcpStream>)>,
+    connected_rx: Receiver<(NetPair, io::Result<TcpStream>)>,
+    max_accepted: usize,
+    max_buffered: usize,
+    idle_timeout: Duration,
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        let (connected_tx, connected_rx) = channel();
+        Self {
+            listeners: vec![],
+            streams: HashMap::new(),
+            connecting: HashMap::new(),
+            accepted: HashSet::new(),
+            connected_tx,
+            connected_rx,
+            max_accepted: MAX_ACCEPTED_STREAMS,
+            max_buffered: MAX_BUFFERED_BYTES,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl TcpTransport {
+    pub fn listen(&mut self, addr: SocketAddr) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        log::info!("[TcpTransport] listen on {addr}");
+        self.listeners.push((addr, listener));
+        Ok(())
+    }
+}
+
+impl FrameTransport for TcpTransport {
+    fn transport(&self) -> Transport {
+        Transport::Tcp
+    }
+
+    fn on_bind(&mut self, addr: SocketAddr) {
+        if let Err(e) = self.listen(addr) {
+            log::error!("[TcpTransport] listen on {addr} error {e:?}");
+        }
+    }
+
+    fn on_unbind(&mut self, addr: SocketAddr) {
+        log::info!("[TcpTransport] stop listen on {addr}");
+        self.listeners.retain(|(local, _)| *local != addr);
+        self.streams.retain(|pair, _| pair.local != addr);
+        self.connecting.retain(|pair, _| pair.local != addr);
+        self.accepted.retain(|pair| pair.local != addr);
+    }
+
+    fn send(&mut self, pair: NetPair, frame: &[u8]) {
+        if let Some(stream) = self.streams.get_mut(&pair) {
+            if !stream.send(frame) {
+                log::warn!("[TcpTransport] stream {pair} is not writable fast enough, drop frame with size {}", frame.len());
+            }
+        } else if let Some((pending, bytes)) = self.connecting.get_mut(&pair) {
+            if *bytes + frame.len() > MAX_PENDING_BYTES {
+                log::warn!("[TcpTransport] stream {pair} is still connecting, drop frame with size {}", frame.len());
+                return;
+            }
+            *bytes += frame.len();
+            pending.push(frame.to_vec());
+        } else if !self.accepted.contains(&pair) && self.listeners.iter().any(|(addr, _)| *addr == pair.local) {
+            log::info!("[TcpTransport] connecting {pair}");
+            self.connecting.insert(pair, (vec![frame.to_vec()], frame.len()));
+            let tx = self.connected_tx.clone();
+            std::thread::spawn(move || {
+                let res = TcpStream::connect_timeout(&pair.remote, CONNECT_TIMEOUT);
+                tx.send((pair, res)).ok();
+            });
+        } else {
+            log::debug!("[TcpTransport] no stream for {pair}, drop frame");
+        }
+    }
+
+    /// Accept, connect, write and read all streams, returns received frames
+    fn poll(&mut self) -> Vec<(NetPair, Buffer)> {
+        for (local, listener) in &self.listeners {
+            loop {
+                match listener.accept() {
+                    Ok((stream, remote)) => {
+                        let pair = NetPair::new_tcp(*local, remote);
+                        if self.accepted.len() >= self.max_accepted {
+                            log::warn!("[TcpTransport] too many accepted streams, reject {pair}");
+                            continue;
+                        }
+                        match Stream::new(stream) {
+                            Ok(stream) => {
+                                log::info!("[TcpTransport] accepted {pair}");
+                                self.accepted.insert(pair);
+                                self.streams.insert(pair, stream);
+                            }
+                            Err(e) => log::warn!("[TcpTransport] setup accepted stream {pair} error {e:?}"),
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        log::warn!("[TcpTransport] accept on {local} error {e:?}");
+                        break;
+                    }
+                }
+            }
+        }
+
+        while let Ok((pair, res)) = self.connected_rx.try_recv() {
+            let (pending, _) = self.connecting.remove(&pair).unwrap_or_default();
+            match res.and_then(Stream::new) {
+                Ok(mut stream) => {
+                    log::info!("[TcpTransport] connected {pair}");
+                    for frame in pending {
+                        stream.send(&frame);
+                    }
+                    self.streams.insert(pair, stream);
+                }
+                Err(e) => log::warn!("[TcpTransport] connect {pair} error {e:?}"),
+            }
+        }
+
+        let mut received = vec![];
+        let mut frames = vec![];
+        let idle_timeout = self.idle_timeout;
+        let max_buffered = self.max_buffered;
+        let mut buffered = self.streams.values().map(|stream| stream.framer.buffered()).sum::<usize>();
+        self.streams.retain(|pair, stream| {
+            // a single frame can't be bigger than MAX_FRAME_SIZE, and all streams share MAX_BUFFERED_BYTES
+            buffered -= stream.framer.buffered();
+            let res = stream.poll(&mut frames, (4 + MAX_FRAME_SIZE).min(max_buffered.saturating_sub(buffered)));
+            received.extend(frames.drain(..).map(|frame| (*pair, Buffer::from(frame))));
+            if let Err(e) = &res {
+                log::info!("[TcpTransport] stream {pair} closed {e:?}");
+                return false;
+            }
+            if stream.last_recv.elapsed() > idle_timeout {
+                log::info!("[TcpTransport] stream {pair} is idle, close it");
+                return false;
+            }
+            buffered += stream.framer.buffered();
+            true
+        });
+        self.accepted.retain(|pair| self.streams.contains_key(pair));
+        received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::{TcpListener, TcpStream},
+        time::{Duration, Instant},
+    };
+
+    use atm0s_sdn_network::data_plane::{FrameTransport, NetPair};
+
+    use super::{StreamFramer, TcpTransport, MAX_PENDING_BYTES, MAX_READ_PER_POLL};
+
+    fn free_addr() -> std::net::SocketAddr {
+        TcpListener::bind("127.0.0.1:0").expect("Should bind").local_addr().expect("Should have addr")
+    }
+
+    /// Wait until the remote side closes the stream
+    fn assert_closed(stream: &mut TcpStream) {
+        stream.set_read_timeout(Some(Duration::from_secs(2))).expect("Should set timeout");
+        let mut buf = [0; 16];
+        assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)), "Stream should be closed");
+    }
+
+    #[test]
+    fn framer_should_split_frames() {
+        let mut out = vec![];
+        StreamFramer::encode(&[1, 2, 3], &mut out);
+        StreamFramer::encode(&[], &mut out);
+        StreamFramer::encode(&[4], &mut out);
+
+        let mut framer = StreamFramer::default();
+        framer.push(&out[..5]);
+        assert_eq!(framer.pop().expect("Should ok"), None);
+        framer.push(&out[5..]);
+        assert_eq!(framer.pop().expect("Should ok"), Some(vec![1, 2, 3]));
+        assert_eq!(framer.pop().expect("Should ok"), Some(vec![]));
+        assert_eq!(framer.pop().expect("Should ok"), Some(vec![4]));
+        assert_eq!(framer.pop().expect("Should ok"), None);
+    }
+
+    #[test]
+    fn framer_should_reject_too_big_frame() {
+        let mut framer = StreamFramer::default();
+        framer.push(&u32::MAX.to_be_bytes());
+        assert!(framer.pop().is_err());
+    }
+
+    #[test]
+    fn accept_cap_and_idle_timeout() {
+        let addr = free_addr();
+        let mut transport = TcpTransport {
+            max_accepted: 1,
+            idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        transport.listen(addr).expect("Should listen");
+
+        let mut client1 = TcpStream::connect(addr).expect("Should connect");
+        let mut client2 = TcpStream::connect(addr).expect("Should connect");
+        transport.poll();
+        assert_eq!(transport.streams.len(), 1);
+        assert_closed(&mut client2);
+
+        std::thread::sleep(Duration::from_millis(300));
+        transport.poll();
+        assert!(transport.streams.is_empty());
+        assert!(transport.accepted.is_empty());
+        assert_closed(&mut client1);
+    }
+
+    #[test]
+    fn read_per_poll_and_buffered_bytes_are_bounded() {
+        let addr = free_addr();
+        let mut transport = TcpTransport {
+            max_buffered: 10_000,
+            ..Default::default()
+        };
+        transport.listen(addr).expect("Should listen");
+
+        // a fast sender only gets MAX_READ_PER_POLL bytes in each poll
+        let mut client1 = TcpStream::connect(addr).expect("Should connect");
+        let mut out = vec![];
+        for _ in 0..(MAX_READ_PER_POLL * 4 / 1000) {
+            StreamFramer::encode(&[1; 996], &mut out);
+        }
+        client1.write_all(&out).expect("Should write");
+        std::thread::sleep(Duration::from_millis(100));
+        let frames = transport.poll().len();
+        assert!(frames > 0 && frames <= MAX_READ_PER_POLL / 1000 + 16, "Should read bounded frames, got {frames}");
+
+        // incomplete frame over buffered limit closes the stream
+        let mut client2 = TcpStream::connect(addr).expect("Should connect");
+        transport.poll();
+        assert_eq!(transport.streams.len(), 2);
+        client2.write_all(&(20_000u32).to_be_bytes()).expect("Should write");
+        client2.write_all(&[2; 12_000]).expect("Should write");
+        std::thread::sleep(Duration::from_millis(100));
+        transport.poll();
+        assert_eq!(transport.streams.len(), 1);
+        assert_closed(&mut client2);
+    }
+
+    #[test]
+    fn connecting_pending_frames_are_bounded() {
+        let addr = free_addr();
+        let mut transport = TcpTransport::default();
+        transport.listen(addr).expect("Should listen");
+
+        let pair = NetPair::new_tcp(addr, free_addr());
+        let frame = vec![0; MAX_PENDING_BYTES / 2];
+        transport.send(pair, &frame);
+        transport.send(pair, &frame);
+        transport.send(pair, &frame);
+        let (pending, bytes) = transport.connecting.get(&pair).expect("Should connecting");
+        assert_eq!(pending.len(), 2);
+        assert_eq!(*bytes, MAX_PENDING_BYTES);
+    }
+
+    #[test]
+    fn loopback_stream() {
+        let addr1 = free_addr();
+        let addr2 = free_addr();
+        let mut node1 = TcpTransport::default();
+        let mut node2 = TcpTransport::default();
+        node1.listen(addr1).expect("Should listen");
+        node2.listen(addr2).expect("Should listen");
+
+        node1.send(NetPair::new_tcp(addr1, addr2), &[1, 2, 3]);
+        let started = Instant::now();
+        let (pair, frame) = loop {
+            node1.poll();
+            if let Some(received) = node2.poll().pop() {
+                break received;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2), "Should receive frame");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(pair.local, addr2);
+        assert_eq!(&*frame, &[1, 2, 3]);
+
+        // answer over the accepted stream
+        node2.send(pair, &[4, 5]);
+        let started = Instant::now();
+        let (pair, frame) = loop {
+            node2.poll();
+            if let Some(received) = node1.poll().pop() {
+                break received;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2), "Should receive answer");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(pair, NetPair::new_tcp(addr1, addr2));
+        assert_eq!(&*frame, &[4, 5]);
+    }
+}
@@ -9,14 +9,15 @@ use std::{
 
 use atm0s_sdn_identity::NodeId;
 use atm0s_sdn_network::{
-    base::{Authorization, HandshakeBuilder, ServiceBuilder},
-    controller_plane::ControllerPlaneCfg,
-    data_plane::{DataPlaneCfg, NetInput, NetOutput, NetPair},
+    base::{Authorization, DnsResolver, HandshakeBuilder, ServiceBuilder},
+    controller_plane::{ControllerPlaneCfg, DrainCfg, KeepaliveCfg, NeighboursLimits, ReconnectCfg},
+    data_plane::{DataPlaneCfg, FrameTransport, NetInput, NetOutput, NetPair, RateLimit, ShapingCfg, TransportPenalty},
     features::{FeaturesControl, FeaturesEvent},
     worker::{SdnWorker, SdnWorkerBusEvent, SdnWorkerCfg, SdnWorkerInput, SdnWorkerOutput},
     ExtIn, ExtOut,
 };
 use atm0s_sdn_router::shadow::ShadowRouterHistory;
+use parking_lot::Mutex;
 use rand::rngs::OsRng;
 use sans_io_runtime::{
     backend::{BackendIncoming, BackendOutgoing},
@@ -45,6 +46,16 @@ pub struct ControllerCfg {
     pub session: u64,
     pub auth: Arc<dyn Authorization>,
     pub handshake: Arc<dyn HandshakeBuilder>,
+    pub resolver: Arc<dyn DnsResolver>,
+    pub reconnect: ReconnectCfg,
+    pub keepalive: KeepaliveCfg,
+    pub max_conns_per_node: Option<usize>,
+    pub limits: NeighboursLimits,
+    pub transport_penalty: TransportPenalty,
+    pub drain: Option<DrainCfg>,
+    /// Transports other than UDP, they are owned and polled by the controller worker.
+    /// Mutex is only for moving them to worker thread because cfg need to be Sync.
+    pub transports: Mutex<Vec<Box<dyn FrameTransport>>>,
     #[cfg(feature = "vpn")]
     pub vpn_tun_device: Option<sans_io_runtime::backend::tun::TunDevice>,
 }
@@ -57,6 +68,8 @@ pub struct SdnInnerCfg<UserData, SC, SE, TC, TW> {
     #[allow(clippy::type_complexity)]
     pub services: Vec<Arc<dyn ServiceBuilder<UserData, FeaturesControl, FeaturesEvent, SC, SE, TC, TW>>>,
     pub history: Arc<dyn ShadowRouterHistory>,
+    pub shaping: ShapingCfg,
+    pub send_budget: Option<RateLimit>,
     #[cfg(feature = "vpn")]
     pub vpn_tun_fd: Option<sans_io_runtime::backend::tun::TunFd>,
 }
@@ -78,6 +91,7 @@ pub struct SdnWorkerInner<UserData, SC, SE, TC, TW> {
     _vpn_tun_device: Option<sans_io_runtime::backend::tun::TunDevice>,
     bind_addrs: HashMap<SocketAddr, usize>,
     bind_slots: HashMap<usize, SocketAddr>,
+    transports: Vec<Box<dyn FrameTransport>>,
     #[cfg(feature = "vpn")]
     tun_backend_slot: Option<usize>,
     #[allow(clippy::type_complexity)]
@@ -98,15 +112,43 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
             }
             SdnWorkerOutput::Net(net) => {
                 let out = match net {
-                    NetOutput::UdpPacket(pair, data) => BackendOutgoing::UdpPacket {
-                        slot: *self.bind_addrs.get(&pair.local)?,
-                        to: pair.remote,
-                        data,
-                    },
+                    NetOutput::UdpPacket(pair, data) => {
+                        if let Some(slot) = self.bind_addrs.get(&pair.local) {
+                            BackendOutgoing::UdpPacket { slot: *slot, to: pair.remote, data }
+                        } else {
+                            // the addr is not bound yet or already removed
+                            log::debug!("Worker {} has no socket for {}, drop packet to {}", self.worker, pair.local, pair.remote);
+                            let out = self.worker_inner.pop_output2(now_ms)?;
+                            return self.convert_output(now_ms, out);
+                        }
+                    }
                     NetOutput::UdpPackets(pairs, data) => {
                         let to = pairs.into_iter().filter_map(|p| self.bind_addrs.get(&p.local).map(|s| (*s, p.remote))).collect::<Vec<_>>();
                         BackendOutgoing::UdpPackets2 { to, data }
                     }
+                    NetOutput::Frame(pair, data) => {
+                        if let Some(transport) = self.transports.iter_mut().find(|t| t.transport() == pair.transport) {
+                            transport.send(pair, &data);
+                        } else {
+                            log::warn!("Worker {} has no {:?} transport, drop frame to {pair}", self.worker, pair.transport);
+                        }
+                        let out = self.worker_inner.pop_output2(now_ms)?;
+                        return self.convert_output(now_ms, out);
+                    }
+                    NetOutput::Bind(addr) => BackendOutgoing::UdpListen { addr, reuse: true },
+                    NetOutput::Unbind(addr) => {
+                        let Some(slot) = self.bind_addrs.remove(&addr) else {
+                            log::warn!("Worker {} has no socket for {addr} to unbind", self.worker);
+                            let out = self.worker_inner.pop_output2(now_ms)?;
+                            return self.convert_output(now_ms, out);
+                        };
+                        log::info!("Worker {} unbind addr {addr} from slot {slot}", self.worker);
+                        self.bind_slots.remove(&slot);
+                        for transport in self.transports.iter_mut() {
+                            transport.on_unbind(addr);
+                        }
+                        BackendOutgoing::UdpUnlisten { slot }
+                    }
                     #[cfg(feature = "vpn")]
                     NetOutput::TunPacket(data) => BackendOutgoing::TunPacket {
                         slot: self.tun_backend_slot.expect("should have tun"),
@@ -163,6 +205,13 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                         bind_addrs: cfg.bind_addrs,
                         authorization: controller.auth,
                         handshake_builder: controller.handshake,
+                        resolver: Some(controller.resolver),
+                        reconnect: controller.reconnect,
+                        keepalive: controller.keepalive,
+                        max_conns_per_node: controller.max_conns_per_node,
+                        limits: controller.limits,
+                        transport_penalty: controller.transport_penalty,
+                        drain: controller.drain,
                         session: controller.session,
                         random: Box::new(OsRng),
                         services: cfg.services.clone(),
@@ -172,6 +221,8 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                         worker_id: worker,
                         services: cfg.services,
                         history: cfg.history,
+                        shaping: cfg.shaping,
+                        send_budget: cfg.send_budget,
                     },
                 }),
                 timer: TimePivot::build(),
@@ -181,6 +232,7 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                 queue,
                 bind_addrs: Default::default(),
                 bind_slots: Default::default(),
+                transports: controller.transports.into_inner(),
                 #[cfg(feature = "vpn")]
                 tun_backend_slot: None,
             }
@@ -196,6 +248,8 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                         worker_id: worker,
                         services: cfg.services,
                         history: cfg.history,
+                        shaping: cfg.shaping,
+                        send_budget: cfg.send_budget,
                     },
                 }),
                 timer: TimePivot::build(),
@@ -205,6 +259,7 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
                 queue,
                 bind_addrs: Default::default(),
                 bind_slots: Default::default(),
+                transports: vec![],
                 #[cfg(feature = "vpn")]
                 tun_backend_slot: None,
             }
@@ -225,6 +280,11 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
 
     fn on_tick(&mut self, now: Instant) {
         let now_ms = self.timer.timestamp_ms(now);
+        for transport in self.transports.iter_mut() {
+            for (pair, data) in transport.poll() {
+                self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::Frame(pair, data)));
+            }
+        }
         self.worker_inner.on_tick(now_ms);
     }
 
@@ -232,15 +292,24 @@ impl<UserData: 'static + Eq + Copy + Hash + Debug, SC: Debug, SE: Debug, TC: Deb
         let now_ms = self.timer.timestamp_ms(now);
         match event {
             WorkerInnerInput::Net(_, event) => match event {
-                BackendIncoming::UdpListenResult { bind: _, result } => {
+                BackendIncoming::UdpListenResult { bind, result } => {
+                    if let Err(e) = &result {
+                        log::error!("Worker {} bind addr {bind} error {e:?}", self.worker);
+                    }
                     if let Ok((addr, slot)) = result {
                         log::info!("Worker {} bind addr {addr} to slot {slot}", self.worker);
                         self.bind_addrs.insert(addr, slot);
                         self.bind_slots.insert(slot, addr);
+                        for transport in self.transports.iter_mut() {
+                            transport.on_bind(addr);
+                        }
                     }
                 }
                 BackendIncoming::UdpPacket { slot, from, data } => {
-                    let local = *self.bind_slots.get(&slot).expect("Should have local addr");
+                    // packets can still arrive in the same cycle after the socket is closed
+                    let Some(local) = self.bind_slots.get(&slot).copied() else {
+                        return;
+                    };
                     let pair = NetPair::new(local, from);
                     self.worker_inner.on_event(now_ms, SdnWorkerInput::Net(NetInput::UdpPacket(pair, data)))
                 }
@@ -0,0 +1,348 @@
+use std::{
+    collections::{HashMap, HashSet},
+    io::{self, ErrorKind},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::mpsc::{channel, Receiver, Sender},
+    time::{Duration, Instant},
+};
+
+use atm0s_sdn_network::{
+    base::Buffer,
+    data_plane::{FrameTransport, NetPair, Transport},
+};
+use tungstenite::{
+    handshake::{server::NoCallback, MidHandshake},
+    protocol::WebSocketConfig,
+    HandshakeError, Message, ServerHandshake, WebSocket,
+};
+
+use crate::tcp::{CONNECT_TIMEOUT, IDLE_TIMEOUT, MAX_ACCEPTED_STREAMS, MAX_FRAME_SIZE, MAX_PENDING_BYTES};
+
+/// Accepted sockets which don't finish the http upgrade in this time are closed
+const HANDSHAKE_TIMEOUT: Duration = CONNECT_TIMEOUT;
+
+type Handshaking = MidHandshake<ServerHandshake<TcpStream, NoCallback>>;
+
+fn ws_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_write_buffer_size: MAX_PENDING_BYTES,
+        max_message_size: Some(MAX_FRAME_SIZE),
+        max_frame_size: Some(MAX_FRAME_SIZE),
+        ..Default::default()
+    }
+}
+
+/// Dial a WebSocket in blocking mode, the socket is switched to non-blocking after handshake
+fn dial(remote: SocketAddr) -> io::Result<WebSocket<TcpStream>> {
+    let stream = TcpStream::connect_timeout(&remote, CONNECT_TIMEOUT)?;
+    stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+    let (ws, _) = tungstenite::client::client_with_config(format!("ws://{remote}/"), stream, Some(ws_config())).map_err(|e| io::Error::other(e.to_string()))?;
+    ws.get_ref().set_read_timeout(None)?;
+    ws.get_ref().set_nonblocking(true)?;
+    ws.get_ref().set_nodelay(true)?;
+    Ok(ws)
+}
+
+struct Socket {
+    ws: WebSocket<TcpStream>,
+    last_recv: Instant,
+}
+
+impl Socket {
+    fn new(ws: WebSocket<TcpStream>) -> Self {
+        Self { ws, last_recv: Instant::now() }
+    }
+}
+
+/// Write buffered messages and read all available binary messages, Err if the socket is closed or broken
+fn poll_socket(socket: &mut Socket, frames: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    let ws = &mut socket.ws;
+    match ws.flush() {
+        Ok(()) => {}
+        Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => {}
+        Err(tungstenite::Error::Io(e)) => return Err(e),
+        Err(e) => return Err(io::Error::other(e.to_string())),
+    }
+    loop {
+        match ws.read() {
+            Ok(Message::Binary(frame)) => {
+                socket.last_recv = Instant::now();
+                frames.push(frame);
+            }
+            Ok(_) => socket.last_recv = Instant::now(),
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(tungstenite::Error::Io(e)) => return Err(e),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        }
+    }
+}
+
+/// WebSocket transport for leaf clients like browsers or agents behind firewalls which only allow http.
+/// Each binary message carries same bytes as an UDP datagram, starting with NeighboursControl handshake.
+/// Same as TcpTransport, all sockets are non-blocking and polled from the controller worker tick.
+///
+/// It has its own listener instead of an endpoint in the poem web server of the bin crate, because poem runs
+/// on an async runtime in another thread, while frames must be handled inside the sans-io controller worker.
+/// A reverse proxy can route a path of the web port to this listener if a single port is needed.
+pub struct WsTransport {
+    listener: Option<(SocketAddr, TcpListener)>,
+    handshaking: Vec<(NetPair, Instant, Handshaking)>,
+    sockets: HashMap<NetPair, Socket>,
+    /// outgoing sockets which are dialing, with frames which are sent before connected, limited to MAX_PENDING_BYTES
+    connecting: HashMap<NetPair, (Vec<Vec<u8>>, usize)>,
+    /// accepted sockets, we don't dial back to them after they are closed
+    accepted: HashSet<NetPair>,
+    connected_tx: Sender<(NetPair, io::Result<WebSocket<TcpStream>>)>,
+    connected_rx: Receiver<(NetPair, io::Result<WebSocket<TcpStream>>)>,
+    /// max accepted sockets, including handshaking ones
+    max_accepted: usize,
+    handshake_timeout: Duration,
+    idle_timeout: Duration,
+}
+
+impl Default for WsTransport {
+    fn default() -> Self {
+        let (connected_tx, connected_rx) = channel();
+        Self {
+            listener: None,
+            handshaking: vec![],
+            sockets: HashMap::new(),
+            connecting: HashMap::new(),
+            accepted: HashSet::new(),
+            connected_tx,
+            connected_rx,
+            max_accepted: MAX_ACCEPTED_STREAMS,
+            handshake_timeout: HANDSHAKE_TIMEOUT,
+            idle_timeout: IDLE_TIMEOUT,
+        }
+    }
+}
+
+impl WsTransport {
+    pub fn listen(&mut self, addr: SocketAddr) -> io::Result<SocketAddr> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        log::info!("[WsTransport] listen on {addr}");
+        self.listener = Some((addr, listener));
+        Ok(addr)
+    }
+
+    fn on_accepted(&mut self, pair: NetPair, ws: WebSocket<TcpStream>) {
+        log::info!("[WsTransport] accepted {pair}");
+        self.accepted.insert(pair);
+        self.sockets.insert(pair, Socket::new(ws));
+    }
+}
+
+impl FrameTransport for WsTransport {
+    fn transport(&self) -> Transport {
+        Transport::WebSocket
+    }
+
+    fn send(&mut self, pair: NetPair, frame: &[u8]) {
+        if let Some(socket) = self.sockets.get_mut(&pair) {
+            match socket.ws.write(Message::Binary(frame.to_vec())) {
+                Ok(()) => {}
+                Err(tungstenite::Error::WriteBufferFull(_)) => log::warn!("[WsTransport] socket {pair} is not writable fast enough, drop frame with size {}", frame.len()),
+                Err(e) => log::debug!("[WsTransport] write to {pair} error {e:?}"),
+            }
+        } else if let Some((pending, bytes)) = self.connecting.get_mut(&pair) {
+            if *bytes + frame.len() > MAX_PENDING_BYTES {
+                log::warn!("[WsTransport] socket {pair} is still dialing, drop frame with size {}", frame.len());
+                return;
+            }
+            *bytes += frame.len();
+            pending.push(frame.to_vec());
+        } else if !self.accepted.contains(&pair) {
+            log::info!("[WsTransport] dialing {pair}");
+            self.connecting.insert(pair, (vec![frame.to_vec()], frame.len()));
+            let tx = self.connected_tx.clone();
+            std::thread::spawn(move || {
+                tx.send((pair, dial(pair.remote))).ok();
+            });
+        } else {
+            log::debug!("[WsTransport] no socket for {pair}, drop frame");
+        }
+    }
+
+    /// Accept, handshake, dial, write and read all sockets, returns received frames
+    fn poll(&mut self) -> Vec<(NetPair, Buffer)> {
+        let mut accepted = vec![];
+        if let Some((local, listener)) = &self.listener {
+            loop {
+                match listener.accept() {
+                    Ok((stream, remote)) => {
+                        let pair = NetPair::new_ws(*local, remote);
+                        if self.accepted.len() + self.handshaking.len() + accepted.len() >= self.max_accepted {
+                            log::warn!("[WsTransport] too many accepted sockets, reject {pair}");
+                            continue;
+                        }
+                        if let Err(e) = stream.set_nonblocking(true).and_then(|_| stream.set_nodelay(true)) {
+                            log::warn!("[WsTransport] setup accepted socket {pair} error {e:?}");
+                            continue;
+                        }
+                        match tungstenite::accept_with_config(stream, Some(ws_config())) {
+                            Ok(ws) => accepted.push((pair, ws)),
+                            Err(HandshakeError::Interrupted(mid)) => self.handshaking.push((pair, Instant::now(), mid)),
+                            Err(HandshakeError::Failure(e)) => log::warn!("[WsTransport] handshake {pair} error {e:?}"),
+                        }
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) => {
+                        log::warn!("[WsTransport] accept on {local} error {e:?}");
+                        break;
+                    }
+                }
+            }
+        }
+
+        for (pair, ws) in accepted {
+            self.on_accepted(pair, ws);
+        }
+
+        for (pair, started, mid) in std::mem::take(&mut self.handshaking) {
+            match mid.handshake() {
+                Ok(ws) => self.on_accepted(pair, ws),
+                Err(HandshakeError::Interrupted(_)) if started.elapsed() > self.handshake_timeout => log::warn!("[WsTransport] handshake {pair} timeout"),
+                Err(HandshakeError::Interrupted(mid)) => self.handshaking.push((pair, started, mid)),
+                Err(HandshakeError::Failure(e)) => log::warn!("[WsTransport] handshake {pair} error {e:?}"),
+            }
+        }
+
+        while let Ok((pair, res)) = self.connected_rx.try_recv() {
+            let (pending, _) = self.connecting.remove(&pair).unwrap_or_default();
+            match res {
+                Ok(mut ws) => {
+                    log::info!("[WsTransport] connected {pair}");
+                    for frame in pending {
+                        ws.write(Message::Binary(frame)).ok();
+                    }
+                    self.sockets.insert(pair, Socket::new(ws));
+                }
+                Err(e) => log::warn!("[WsTransport] dial {pair} error {e:?}"),
+            }
+        }
+
+        let mut received = vec![];
+        let mut frames = vec![];
+        let idle_timeout = self.idle_timeout;
+        self.sockets.retain(|pair, socket| {
+            let res = poll_socket(socket, &mut frames);
+            received.extend(frames.drain(..).map(|frame| (*pair, Buffer::from(frame))));
+            if let Err(e) = &res {
+                log::info!("[WsTransport] socket {pair} closed {e:?}");
+                return false;
+            }
+            if socket.last_recv.elapsed() > idle_timeout {
+                log::info!("[WsTransport] socket {pair} is idle, close it");
+                return false;
+            }
+            true
+        });
+        self.accepted.retain(|pair| self.sockets.contains_key(pair));
+        received
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::TcpStream,
+        time::{Duration, Instant},
+    };
+
+    use atm0s_sdn_network::data_plane::{FrameTransport, NetPair, Transport};
+
+    use super::WsTransport;
+
+    /// Wait until the remote side closes the stream
+    fn assert_closed(stream: &mut TcpStream) {
+        stream.set_read_timeout(Some(Duration::from_secs(2))).expect("Should set timeout");
+        let mut buf = [0; 16];
+        assert!(matches!(stream.read(&mut buf), Ok(0) | Err(_)), "Stream should be closed");
+    }
+
+    #[test]
+    fn handshake_timeout_and_accept_cap() {
+        let mut server = WsTransport {
+            max_accepted: 1,
+            handshake_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let server_addr = server.listen("127.0.0.1:0".parse().expect("Should parse")).expect("Should listen");
+
+        // clients which never send http upgrade
+        let mut client1 = TcpStream::connect(server_addr).expect("Should connect");
+        let mut client2 = TcpStream::connect(server_addr).expect("Should connect");
+        server.poll();
+        assert_eq!(server.handshaking.len(), 1);
+        assert_closed(&mut client2);
+
+        std::thread::sleep(Duration::from_millis(300));
+        server.poll();
+        assert!(server.handshaking.is_empty());
+        assert_closed(&mut client1);
+    }
+
+    #[test]
+    fn close_idle_socket() {
+        let mut server = WsTransport {
+            idle_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let server_addr = server.listen("127.0.0.1:0".parse().expect("Should parse")).expect("Should listen");
+        let mut client = WsTransport::default();
+        client.send(NetPair::new_ws("127.0.0.1:1".parse().expect("Should parse"), server_addr), &[1, 2, 3]);
+
+        let started = Instant::now();
+        while server.poll().is_empty() {
+            client.poll();
+            assert!(started.elapsed() < Duration::from_secs(2), "Should receive frame");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.sockets.len(), 1);
+
+        std::thread::sleep(Duration::from_millis(300));
+        server.poll();
+        assert!(server.sockets.is_empty());
+        assert!(server.accepted.is_empty());
+    }
+
+    #[test]
+    fn loopback_websocket() {
+        let mut server = WsTransport::default();
+        let server_addr = server.listen("127.0.0.1:0".parse().expect("Should parse")).expect("Should listen");
+        let mut client = WsTransport::default();
+        let client_pair = NetPair::new_ws("127.0.0.1:1".parse().expect("Should parse"), server_addr);
+
+        client.send(client_pair, &[1, 2, 3]);
+        let started = Instant::now();
+        let (pair, frame) = loop {
+            client.poll();
+            if let Some(received) = server.poll().pop() {
+                break received;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2), "Should receive frame");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(pair.local, server_addr);
+        assert_eq!(pair.transport, Transport::WebSocket);
+        assert_eq!(&*frame, &[1, 2, 3]);
+
+        // answer over the accepted socket
+        server.send(pair, &[4, 5]);
+        let started = Instant::now();
+        let (pair, frame) = loop {
+            server.poll();
+            if let Some(received) = client.poll().pop() {
+                break received;
+            }
+            assert!(started.elapsed() < Duration::from_secs(2), "Should receive answer");
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(pair, client_pair);
+        assert_eq!(&*frame, &[4, 5]);
+    }
+}